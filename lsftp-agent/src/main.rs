@@ -0,0 +1,647 @@
+//! LSFTP Agent - keeps hardware devices unlocked between client runs
+//!
+//! `lsftp-agent start` opens a Unix socket, prints the shell commands that
+//! export `LSFTP_AUTH_SOCK` and detaches. `lsftp-agent add` then opens a
+//! device inside the agent, entering its PIN once; every `lsftp-client` run
+//! that finds the socket has its challenges signed by the agent instead of
+//! prompting again, and reuses the resumption tickets the agent caches.
+
+use clap::{Parser, Subcommand};
+use lsftp_core::agent::{self, AgentClient, AgentDevice, AgentRequest, AgentResponse};
+use lsftp_core::auth::{HardwareAuth, HardwareAuthFactory, HardwareType};
+use lsftp_core::error::Error;
+use lsftp_core::piv::PinPrompt;
+use lsftp_core::{now_secs, Result, SecretBuffer};
+use std::collections::HashMap;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use zeroize::Zeroizing;
+
+/// Environment variable naming the signature confirmation program
+const ASKPASS_ENV: &str = "LSFTP_ASKPASS";
+
+/// How often the idle lock is checked
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Delay after a wrong unlock passphrase
+const UNLOCK_FAILURE_DELAY: Duration = Duration::from_secs(1);
+
+/// LSFTP Agent - Hardware authentication agent
+#[derive(Parser)]
+#[command(name = "lsftp-agent")]
+#[command(about = "LSFTP agent holding unlocked hardware devices for lsftp-client")]
+#[command(version)]
+pub struct Cli {
+    /// Agent socket (default: $LSFTP_AUTH_SOCK, else $XDG_RUNTIME_DIR/lsftp/agent.sock)
+    #[arg(long, global = true)]
+    socket: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Start the agent and print the shell commands that export its socket
+    Start {
+        /// Stay in the foreground instead of detaching
+        #[arg(long)]
+        foreground: bool,
+
+        /// Drop devices, PINs and tickets after this many idle seconds (0 never)
+        #[arg(long, default_value = "900")]
+        idle_lock: u64,
+
+        /// Ask for confirmation before every signature
+        #[arg(long)]
+        confirm: bool,
+
+        /// Confirmation program, run with a prompt; exit status 0 allows the signature (default: $LSFTP_ASKPASS)
+        #[arg(long)]
+        confirm_program: Option<PathBuf>,
+
+        /// Verbose logging
+        #[arg(short, long)]
+        verbose: bool,
+    },
+
+    /// Open a hardware device in the agent, entering its PIN once
+    Add {
+        /// Device type (tpm, yubikey, smartcard, hsm)
+        #[arg(long)]
+        hardware: String,
+
+        /// Device path or spec; must match the client's --hardware-path for the agent to sign
+        #[arg(long)]
+        hardware_path: Option<String>,
+
+        /// Ask for confirmation before each signature with this device
+        #[arg(long)]
+        confirm: bool,
+
+        /// Do not ask for a PIN (keys whose PIN policy is never)
+        #[arg(long)]
+        no_pin: bool,
+    },
+
+    /// List the devices held by the agent
+    List,
+
+    /// Close a device, or every device and ticket with --all
+    Remove {
+        /// Device identifier
+        #[arg(long, required_unless_present = "all", conflicts_with = "all")]
+        device_id: Option<String>,
+
+        /// Close every device and forget every ticket
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Refuse all requests until unlocked with a passphrase
+    Lock,
+
+    /// Unlock a locked agent
+    Unlock,
+}
+
+/// Agent-wide options
+#[derive(Debug, Clone)]
+struct AgentOptions {
+    idle_lock: Option<Duration>,
+    confirm: bool,
+    confirm_program: Option<PathBuf>,
+}
+
+/// PIN handed over by `lsftp-agent add`
+struct StoredPin(Option<SecretBuffer>);
+
+impl PinPrompt for StoredPin {
+    fn pin(&self, device: &str) -> Result<SecretBuffer> {
+        match &self.0 {
+            Some(pin) => pin.try_clone(),
+            None => Err(Error::HardwareAuth(format!(
+                "No PIN was given to the agent for {}; add it again without --no-pin", device
+            ))),
+        }
+    }
+}
+
+/// Opened device; its mutex lets one signature at a time reach it
+type DeviceHandle = Arc<Mutex<Box<dyn HardwareAuth + Send + Sync>>>;
+
+/// Device opened inside the agent
+struct HeldDevice {
+    info: AgentDevice,
+    auth: DeviceHandle,
+}
+
+/// Mutable agent state
+struct AgentState {
+    devices: Vec<HeldDevice>,
+    tickets: HashMap<String, (Vec<u8>, u64)>,
+    lock: Option<SecretBuffer>,
+    last_activity: Instant,
+}
+
+/// Authentication agent
+struct Agent {
+    options: AgentOptions,
+    state: Mutex<AgentState>,
+}
+
+impl Agent {
+    fn new(options: AgentOptions) -> Self {
+        Self {
+            options,
+            state: Mutex::new(AgentState {
+                devices: Vec::new(),
+                tickets: HashMap::new(),
+                lock: None,
+                last_activity: Instant::now(),
+            }),
+        }
+    }
+
+    /// Serve one client connection until it closes
+    async fn serve(self: Arc<Self>, mut stream: UnixStream) -> Result<()> {
+        // Only processes of the agent's own user may use it
+        let peer = stream.peer_cred()?;
+        let uid = nix::unistd::geteuid().as_raw();
+        if peer.uid() != uid {
+            warn!("Refusing agent connection from uid {} (pid {:?})", peer.uid(), peer.pid());
+            return Ok(());
+        }
+
+        loop {
+            let request: AgentRequest = match agent::read_frame(&mut stream).await {
+                Ok(request) => request,
+                Err(Error::Transport(_)) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let response = self.handle(request).await.unwrap_or_else(|e| AgentResponse::Failure(e.to_string()));
+            agent::write_frame(&mut stream, &response).await?;
+        }
+    }
+
+    /// Answer one request; the state lock is released before anything waits on a
+    /// device or the user, so one slow touch does not stall every other client
+    async fn handle(&self, request: AgentRequest) -> Result<AgentResponse> {
+        let mut state = self.state.lock().await;
+
+        if let Some(lock) = &state.lock {
+            return match request {
+                AgentRequest::Unlock { passphrase } => {
+                    let passphrase = Zeroizing::new(passphrase);
+                    if lock.ct_eq(&passphrase) {
+                        state.lock = None;
+                        info!("Agent unlocked");
+                        Ok(AgentResponse::Success)
+                    } else {
+                        tokio::time::sleep(UNLOCK_FAILURE_DELAY).await;
+                        Ok(AgentResponse::Failure("Incorrect passphrase".to_string()))
+                    }
+                }
+                _ => Ok(AgentResponse::Failure("Agent is locked".to_string())),
+            };
+        }
+
+        match request {
+            AgentRequest::AddDevice { device_type, device_path, pin, confirm } => {
+                drop(state);
+                let pin = pin.map(SecretBuffer::from_vec).transpose()?;
+                let device = Self::open_device(device_type, device_path, pin, confirm).await?;
+
+                let mut state = self.state.lock().await;
+                if state.lock.is_some() {
+                    return Ok(AgentResponse::Failure("Agent was locked while the device was opened".to_string()));
+                }
+                info!("Holding {:?} device {}", device_type, device.info.device_id);
+                state.devices.retain(|held| held.info.device_id != device.info.device_id);
+                state.devices.push(device);
+                state.last_activity = Instant::now();
+                Ok(AgentResponse::Success)
+            }
+
+            AgentRequest::RemoveDevice { device_id } => {
+                let before = state.devices.len();
+                state.devices.retain(|held| held.info.device_id != device_id);
+                if state.devices.len() == before {
+                    return Ok(AgentResponse::UnknownDevice);
+                }
+                info!("Released device {}", device_id);
+                Ok(AgentResponse::Success)
+            }
+
+            AgentRequest::RemoveAll => {
+                state.devices.clear();
+                state.tickets.clear();
+                info!("Released all devices and tickets");
+                Ok(AgentResponse::Success)
+            }
+
+            AgentRequest::ListDevices => {
+                Ok(AgentResponse::Devices(state.devices.iter().map(|held| held.info.clone()).collect()))
+            }
+
+            AgentRequest::Sign { device_type, device_path, message, server } => {
+                let (info, auth) = match state.devices.iter()
+                    .find(|held| held.info.device_type == device_type && held.info.device_path == device_path)
+                {
+                    Some(held) => (held.info.clone(), held.auth.clone()),
+                    None => return Ok(AgentResponse::UnknownDevice),
+                };
+                drop(state);
+                self.sign(info, auth, &message, &server).await
+            }
+
+            // Tickets are single-use, so handing one out forgets it
+            AgentRequest::GetTicket { server } => {
+                let now = now_secs();
+                state.tickets.retain(|_, (_, expires_at)| now < *expires_at);
+                Ok(AgentResponse::Ticket(state.tickets.remove(&server).map(|(ticket, _)| ticket)))
+            }
+
+            AgentRequest::PutTicket { server, ticket, expires_at } => {
+                state.tickets.insert(server, (ticket, expires_at));
+                Ok(AgentResponse::Success)
+            }
+
+            AgentRequest::Lock { passphrase } => {
+                if passphrase.is_empty() {
+                    return Ok(AgentResponse::Failure("Empty passphrase".to_string()));
+                }
+                state.lock = Some(SecretBuffer::from_vec(passphrase)?);
+                info!("Agent locked");
+                Ok(AgentResponse::Success)
+            }
+
+            AgentRequest::Unlock { .. } => Ok(AgentResponse::Failure("Agent is not locked".to_string())),
+        }
+    }
+
+    /// Sign a challenge with a held device once the user confirms, if required
+    async fn sign(&self, info: AgentDevice, auth: DeviceHandle, message: &[u8], server: &str) -> Result<AgentResponse> {
+        if self.options.confirm || info.confirm {
+            let prompt = format!("Allow lsftp to authenticate to {} with {}?", server, info.device_id);
+            if !Self::confirm(self.options.confirm_program.as_deref(), &prompt).await? {
+                warn!("Signature for {} with {} declined", server, info.device_id);
+                return Ok(AgentResponse::Failure("Signature declined".to_string()));
+            }
+        }
+
+        let (device_id, signature, attestation) = {
+            let device = auth.lock().await;
+            let result = device.authenticate(message).await?;
+            let (device_id, signature) = match (result.success, result.device_id, result.signature) {
+                (true, Some(device_id), Some(signature)) => (device_id, signature),
+                _ => return Ok(AgentResponse::Failure(
+                    result.error.unwrap_or_else(|| "Device did not sign the challenge".to_string())
+                )),
+            };
+            (device_id, signature, device.generate_attestation(message).await?)
+        };
+
+        // The agent may have been locked, or the device released, while it signed
+        let mut state = self.state.lock().await;
+        if state.lock.is_some() {
+            return Ok(AgentResponse::Failure("Agent is locked".to_string()));
+        }
+        match state.devices.iter_mut().find(|held| Arc::ptr_eq(&held.auth, &auth)) {
+            Some(held) => held.info.last_used = Some(now_secs()),
+            None => return Ok(AgentResponse::UnknownDevice),
+        }
+        info!("Signed challenge for {} with {}", server, device_id);
+        state.last_activity = Instant::now();
+        Ok(AgentResponse::Signature { device_id, signature, attestation })
+    }
+
+    /// Open and unlock a device with one test signature, so a wrong PIN fails now
+    async fn open_device(
+        device_type: HardwareType,
+        device_path: Option<String>,
+        pin: Option<SecretBuffer>,
+        confirm: bool,
+    ) -> Result<HeldDevice> {
+        let mut auth = HardwareAuthFactory::create_with_pin_prompt(
+            device_type,
+            device_path.clone(),
+            Box::new(StoredPin(pin)),
+        ).await?;
+        auth.initialize().await?;
+
+        let result = auth.authenticate(b"lsftp-agent device unlock").await?;
+        let device_id = match (result.success, result.device_id) {
+            (true, Some(device_id)) => device_id,
+            _ => return Err(Error::HardwareAuth(
+                result.error.unwrap_or_else(|| "Device could not be unlocked".to_string())
+            )),
+        };
+
+        Ok(HeldDevice {
+            info: AgentDevice { device_type, device_path, device_id, confirm, last_used: None },
+            auth: Arc::new(Mutex::new(auth)),
+        })
+    }
+
+    /// Run the confirmation program; a missing program declines
+    async fn confirm(program: Option<&Path>, prompt: &str) -> Result<bool> {
+        let program = match program {
+            Some(program) => program.to_path_buf(),
+            None => match std::env::var_os(ASKPASS_ENV) {
+                Some(program) => PathBuf::from(program),
+                None => {
+                    warn!("Confirmation required but no --confirm-program or {} is set", ASKPASS_ENV);
+                    return Ok(false);
+                }
+            },
+        };
+
+        let status = tokio::process::Command::new(&program)
+            .arg(prompt)
+            .status()
+            .await
+            .map_err(|e| Error::Config(format!("Failed to run confirmation program {:?}: {}", program, e)))?;
+        Ok(status.success())
+    }
+
+    /// Forget devices, PINs and tickets once the agent has been idle too long
+    async fn idle_lock(self: Arc<Self>, idle: Duration) {
+        let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut state = self.state.lock().await;
+            if state.last_activity.elapsed() >= idle && (!state.devices.is_empty() || !state.tickets.is_empty()) {
+                info!("Idle for {}s; releasing {} devices and their PINs", idle.as_secs(), state.devices.len());
+                state.devices.clear();
+                state.tickets.clear();
+            }
+        }
+    }
+}
+
+/// Create the socket in a private directory owned by this user; refuses to replace a live agent
+fn bind_socket(path: &Path) -> Result<std::os::unix::net::UnixListener> {
+    if let Some(dir) = path.parent() {
+        if !dir.exists() {
+            std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        check_socket_dir(dir)?;
+    }
+
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(Error::Config(format!("An agent is already listening on {:?}", path)));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    // The socket is owner-only from the moment it exists
+    let umask = nix::sys::stat::umask(nix::sys::stat::Mode::from_bits_truncate(0o077));
+    let listener = std::os::unix::net::UnixListener::bind(path);
+    nix::sys::stat::umask(umask);
+    Ok(listener?)
+}
+
+/// Refuse a socket directory another user could have created or can write to,
+/// e.g. a pre-created `/tmp/lsftp-<uid>`
+fn check_socket_dir(dir: &Path) -> Result<()> {
+    let metadata = std::fs::symlink_metadata(dir)?;
+    let uid = nix::unistd::geteuid().as_raw();
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o777 != 0o700 {
+        return Err(Error::Config(format!(
+            "Socket directory {:?} must be a directory owned by uid {} with mode 0700", dir, uid
+        )));
+    }
+    Ok(())
+}
+
+/// Run the agent until terminated
+fn start(socket: &Path, foreground: bool, options: AgentOptions) -> Result<()> {
+    // Detaching changes to /, so relative paths would go stale
+    let socket = std::env::current_dir()?.join(socket);
+    let socket = socket.as_path();
+    let listener = bind_socket(socket)?;
+
+    println!("{}={}; export {};", agent::AGENT_SOCKET_ENV, socket.display(), agent::AGENT_SOCKET_ENV);
+    if !foreground {
+        nix::unistd::daemon(false, false)
+            .map_err(|e| Error::Config(format!("Failed to detach: {}", e)))?;
+    }
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async move {
+        listener.set_nonblocking(true)?;
+        let listener = UnixListener::from_std(listener)?;
+        let agent = Arc::new(Agent::new(options.clone()));
+        info!("LSFTP agent listening on {:?}", socket);
+
+        if let Some(idle) = options.idle_lock {
+            tokio::spawn(agent.clone().idle_lock(idle));
+        }
+
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .map_err(|e| Error::Config(format!("Failed to install SIGTERM handler: {}", e)))?;
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        let agent = agent.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = agent.serve(stream).await {
+                                error!("Agent connection error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Failed to accept agent connection: {}", e),
+                },
+                _ = terminate.recv() => break,
+                _ = tokio::signal::ctrl_c() => break,
+            }
+        }
+
+        info!("LSFTP agent stopping");
+        let _ = std::fs::remove_file(socket);
+        Ok(())
+    })
+}
+
+/// Passphrase read twice from the terminal
+fn new_passphrase() -> Result<Zeroizing<String>> {
+    let passphrase = Zeroizing::new(rpassword::prompt_password("Agent lock passphrase: ")?);
+    let again = Zeroizing::new(rpassword::prompt_password("Again: ")?);
+    if passphrase != again {
+        return Err(Error::Config("Passphrases do not match".to_string()));
+    }
+    Ok(passphrase)
+}
+
+/// Send one request to a running agent
+async fn control(socket: &Path, command: Commands) -> Result<()> {
+    let mut client = AgentClient::connect(socket).await?;
+
+    match command {
+        Commands::Add { hardware, hardware_path, confirm, no_pin } => {
+            let device_type: HardwareType = hardware.parse()?;
+            let pin = match device_type {
+                HardwareType::YubiKey | HardwareType::SmartCard if !no_pin => {
+                    let description = format!("{} {}", hardware, hardware_path.as_deref().unwrap_or_default());
+                    let pin = lsftp_core::piv::default_pin_prompt().pin(description.trim())?;
+                    Some(pin.as_slice().to_vec())
+                }
+                _ => None,
+            };
+            client.execute(&AgentRequest::AddDevice { device_type, device_path: hardware_path, pin, confirm }).await?;
+            info!("Device added to the agent");
+        }
+
+        Commands::List => {
+            let devices = client.devices().await?;
+            if devices.is_empty() {
+                println!("The agent holds no devices");
+            }
+            for device in devices {
+                println!("  {} {:?} {}{}",
+                    device.device_id,
+                    device.device_type,
+                    device.device_path.as_deref().unwrap_or("-"),
+                    if device.confirm { " [confirm]" } else { "" },
+                );
+            }
+        }
+
+        Commands::Remove { device_id, all } => {
+            let request = match device_id {
+                Some(device_id) if !all => AgentRequest::RemoveDevice { device_id },
+                _ => AgentRequest::RemoveAll,
+            };
+            client.execute(&request).await?;
+        }
+
+        Commands::Lock => {
+            let passphrase = new_passphrase()?;
+            client.execute(&AgentRequest::Lock { passphrase: passphrase.as_bytes().to_vec() }).await?;
+            info!("Agent locked");
+        }
+
+        Commands::Unlock => {
+            let passphrase = Zeroizing::new(rpassword::prompt_password("Agent lock passphrase: ")?);
+            client.execute(&AgentRequest::Unlock { passphrase: passphrase.as_bytes().to_vec() }).await?;
+            info!("Agent unlocked");
+        }
+
+        Commands::Start { .. } => unreachable!("start is handled before connecting"),
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let socket = cli.socket.clone()
+        .or_else(agent::socket_from_env)
+        .unwrap_or_else(agent::default_socket_path);
+
+    match cli.command {
+        Commands::Start { foreground, idle_lock, confirm, confirm_program, verbose } => {
+            tracing_subscriber::fmt()
+                .with_env_filter(if verbose { "lsftp_agent=debug" } else { "lsftp_agent=info" })
+                .with_writer(std::io::stderr)
+                .init();
+
+            let options = AgentOptions {
+                idle_lock: (idle_lock > 0).then_some(Duration::from_secs(idle_lock)),
+                confirm,
+                confirm_program,
+            };
+            start(&socket, foreground, options)
+        }
+        command => {
+            tracing_subscriber::fmt()
+                .with_env_filter("lsftp_agent=info")
+                .with_writer(std::io::stderr)
+                .init();
+
+            tokio::runtime::Runtime::new()?.block_on(control(&socket, command))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tickets_are_single_use() {
+        let agent = Agent::new(AgentOptions { idle_lock: None, confirm: false, confirm_program: None });
+        let server = "files.example.com:8443".to_string();
+
+        let put = AgentRequest::PutTicket { server: server.clone(), ticket: vec![1, 2, 3], expires_at: now_secs() + 60 };
+        assert!(matches!(agent.handle(put).await.unwrap(), AgentResponse::Success));
+
+        match agent.handle(AgentRequest::GetTicket { server: server.clone() }).await.unwrap() {
+            AgentResponse::Ticket(Some(ticket)) => assert_eq!(ticket, vec![1, 2, 3]),
+            other => panic!("unexpected response {:?}", other),
+        }
+        assert!(matches!(
+            agent.handle(AgentRequest::GetTicket { server: server.clone() }).await.unwrap(),
+            AgentResponse::Ticket(None)
+        ));
+
+        let expired = AgentRequest::PutTicket { server: server.clone(), ticket: vec![4], expires_at: now_secs() - 1 };
+        agent.handle(expired).await.unwrap();
+        assert!(matches!(
+            agent.handle(AgentRequest::GetTicket { server }).await.unwrap(),
+            AgentResponse::Ticket(None)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_lock_refuses_requests() {
+        let agent = Agent::new(AgentOptions { idle_lock: None, confirm: false, confirm_program: None });
+
+        let lock = AgentRequest::Lock { passphrase: b"correct horse".to_vec() };
+        assert!(matches!(agent.handle(lock).await.unwrap(), AgentResponse::Success));
+        assert!(matches!(agent.handle(AgentRequest::ListDevices).await.unwrap(), AgentResponse::Failure(_)));
+
+        let wrong = AgentRequest::Unlock { passphrase: b"battery staple".to_vec() };
+        assert!(matches!(agent.handle(wrong).await.unwrap(), AgentResponse::Failure(_)));
+
+        let unlock = AgentRequest::Unlock { passphrase: b"correct horse".to_vec() };
+        assert!(matches!(agent.handle(unlock).await.unwrap(), AgentResponse::Success));
+        assert!(matches!(agent.handle(AgentRequest::ListDevices).await.unwrap(), AgentResponse::Devices(_)));
+    }
+
+    #[test]
+    fn test_socket_needs_private_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("lsftp-agent-test-{}", std::process::id()));
+        let socket = dir.join("agent.sock");
+        let listener = bind_socket(&socket).unwrap();
+        assert_eq!(std::fs::metadata(&socket).unwrap().mode() & 0o777, 0o600);
+        drop(listener);
+
+        // A directory others can enter, as a pre-created /tmp one would be, is refused
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(bind_socket(&socket).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_device() {
+        let agent = Agent::new(AgentOptions { idle_lock: None, confirm: false, confirm_program: None });
+        let sign = AgentRequest::Sign {
+            device_type: HardwareType::YubiKey,
+            device_path: None,
+            message: vec![0; 32],
+            server: "files.example.com:8443".to_string(),
+        };
+        assert!(matches!(agent.handle(sign).await.unwrap(), AgentResponse::UnknownDevice));
+    }
+}
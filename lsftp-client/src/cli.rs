@@ -3,7 +3,10 @@
 use clap::{Parser, Subcommand};
 use crate::client::LsftpClient;
 use lsftp_core::Result;
-use std::path::PathBuf;
+use lsftp_core::crypto::{CryptoSuite, HashAlgorithm};
+use lsftp_core::encryption::SecurityLevel;
+use lsftp_core::knownhosts::{default_known_hosts_path, KnownHosts};
+use std::path::{Path, PathBuf};
 
 /// LSFTP Client - Secure File Transfer Protocol
 #[derive(Parser)]
@@ -19,18 +22,82 @@ pub struct Cli {
     #[arg(short, long, default_value = "8443")]
     pub port: u16,
 
-    /// Certificate path
-    #[arg(long)]
+    /// Client certificate chain for mutual TLS (PEM, or a single DER certificate)
+    #[arg(long, requires = "key")]
     pub cert: Option<PathBuf>,
 
-    /// Private key path
-    #[arg(long)]
+    /// Client private key for mutual TLS (PEM PKCS#8, PKCS#1 or SEC1, or DER)
+    #[arg(long, requires = "cert")]
     pub key: Option<PathBuf>,
 
-    /// Hardware device
+    /// Name the server certificate must match, also sent as SNI (defaults to --server)
+    #[arg(long)]
+    pub server_name: Option<String>,
+
+    /// CA bundle (PEM) for the server certificate instead of the system roots
+    #[arg(long)]
+    pub server_ca: Option<PathBuf>,
+
+    /// Only accept a server with this SPKI fingerprint (sha256:<hex>); repeatable
+    #[arg(long = "pin")]
+    pub pins: Vec<String>,
+
+    /// known_hosts file recording server keys (default ~/.lsftp/known_hosts)
+    #[arg(long)]
+    pub known_hosts: Option<PathBuf>,
+
+    /// Refuse servers missing from known_hosts unless their certificate chains to a trusted CA (implied by --server-ca or --pin)
+    #[arg(long)]
+    pub strict_host_key_checking: bool,
+
+    /// Hardware device (tpm, yubikey, smartcard, hsm, software)
     #[arg(long)]
     pub hardware: Option<String>,
 
+    /// Hardware device path (TPM device, YubiKey serial or PIV spec such as serial=123,slot=9c,algorithm=p384, card reader, PKCS#11 spec module=...,token=...,key=... or key file)
+    #[arg(long)]
+    pub hardware_path: Option<String>,
+
+    /// Additional factor signing the same challenge, as type or type=path (e.g. yubikey=serial=123,slot=9c); repeatable
+    #[arg(long = "factor")]
+    pub factors: Vec<String>,
+
+    /// Seconds a removed YubiKey or smart card has to be re-inserted before the session ends
+    #[arg(long, default_value_t = lsftp_core::presence::DEFAULT_UNPLUG_GRACE_SECS)]
+    pub unplug_grace: u64,
+
+    /// lsftp-agent socket signing for the devices it holds (default: $LSFTP_AUTH_SOCK)
+    #[arg(long, conflicts_with = "no_agent")]
+    pub agent: Option<PathBuf>,
+
+    /// Open the hardware devices directly even if an agent is running
+    #[arg(long)]
+    pub no_agent: bool,
+
+    /// Allow the software authenticator (insecure-dev builds only; never in production)
+    #[arg(long)]
+    pub insecure_dev_auth: bool,
+
+    /// Preferred transfer hash algorithm (blake3, sha3-256, sha256)
+    #[arg(long, default_value = "blake3")]
+    pub hash: HashAlgorithm,
+
+    /// Also compute a SHA-256 digest of every transferred file
+    #[arg(long)]
+    pub sha256_digest: bool,
+
+    /// Crypto suite strength: classical, hybrid or post-quantum; servers may require more than classical
+    #[arg(long, default_value = "hybrid")]
+    pub security_level: SecurityLevel,
+
+    /// Cap transfers at this many bytes per second (suffixes K, M, G)
+    #[arg(long, value_parser = parse_rate)]
+    pub limit_rate: Option<u64>,
+
+    /// Only cap transfers during this daily UTC window, e.g. 08:00-18:00
+    #[arg(long, requires = "limit_rate")]
+    pub limit_rate_hours: Option<String>,
+
     /// Verbose output
     #[arg(short, long)]
     pub verbose: bool,
@@ -77,12 +144,135 @@ pub enum Commands {
         path: String,
     },
 
+    /// Delete remote file
+    Delete {
+        /// Remote file path
+        #[arg(value_name = "REMOTE")]
+        remote: String,
+    },
+
+    /// Show storage used against quota
+    Quota,
+
+    /// Change bandwidth limits on the server (administrators only)
+    RateLimit {
+        /// Level to change: global, user or session
+        #[arg(value_name = "SCOPE", value_parser = ["global", "user", "session"])]
+        scope: String,
+
+        /// User ID or session ID; without one, the default for every user or session changes
+        #[arg(long)]
+        subject: Option<String>,
+
+        /// Upload rate, e.g. 10M, or unlimited
+        #[arg(long)]
+        upload: Option<String>,
+
+        /// Download rate, e.g. 10M, or unlimited
+        #[arg(long)]
+        download: Option<String>,
+
+        /// Drop the subject's override
+        #[arg(long, requires = "subject", conflicts_with_all = ["upload", "download"])]
+        reset: bool,
+    },
+
+    /// Change the minimum crypto suite on the server live (administrators only)
+    RequireEncryption {
+        /// Minimum level: classical, hybrid or post-quantum
+        #[arg(value_name = "LEVEL")]
+        minimum: SecurityLevel,
+
+        /// Path the minimum applies to; without one, every session
+        #[arg(long)]
+        path: Option<String>,
+
+        /// Classification label the minimum applies to
+        #[arg(long, conflicts_with = "path")]
+        classification: Option<String>,
+    },
+
     /// Verify file integrity
     Verify {
         /// Remote file path
         #[arg(value_name = "FILE")]
         file: String,
     },
+
+    /// Manage pinned server keys in known_hosts
+    KnownHosts {
+        #[command(subcommand)]
+        action: KnownHostsAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum KnownHostsAction {
+    /// List known hosts
+    List,
+
+    /// Pin a server key
+    Add {
+        /// Host as name:port
+        #[arg(value_name = "HOST")]
+        host: String,
+
+        /// SPKI fingerprint (sha256:<hex>) or the server certificate file
+        #[arg(value_name = "KEY")]
+        key: String,
+    },
+
+    /// Forget a host, e.g. after an expected key change
+    Remove {
+        /// Host as name:port
+        #[arg(value_name = "HOST")]
+        host: String,
+    },
+
+    /// Print the SPKI fingerprint of a certificate file
+    Fingerprint {
+        /// Certificate (PEM or DER)
+        #[arg(value_name = "CERT")]
+        cert: PathBuf,
+    },
+}
+
+fn parse_rate(s: &str) -> Result<u64> {
+    lsftp_core::ratelimit::parse_rate(s)
+}
+
+/// Run a known_hosts subcommand
+fn manage_known_hosts(path: &Path, action: KnownHostsAction) -> Result<()> {
+    let fingerprint_of = |cert: &Path| -> Result<String> {
+        let certificates = lsftp_core::transport::load_certificates(cert)?;
+        let certificate = certificates.first()
+            .ok_or_else(|| lsftp_core::error::Error::Config(format!("No certificate in {:?}", cert)))?;
+        lsftp_core::knownhosts::spki_fingerprint(&certificate.0)
+    };
+
+    let mut known_hosts = KnownHosts::load(path)?;
+    match action {
+        KnownHostsAction::List => {
+            for entry in known_hosts.entries() {
+                println!("{} {}", entry.host, entry.fingerprint);
+            }
+        }
+        KnownHostsAction::Add { host, key } => {
+            let fingerprint = if Path::new(&key).is_file() { fingerprint_of(Path::new(&key))? } else { key };
+            known_hosts.add(&host, &fingerprint)?;
+            known_hosts.save()?;
+            println!("Pinned {} {}", host, lsftp_core::knownhosts::parse_fingerprint(&fingerprint)?);
+        }
+        KnownHostsAction::Remove { host } => {
+            let removed = known_hosts.remove(&host);
+            known_hosts.save()?;
+            println!("Removed {} entries for {}", removed, host);
+        }
+        KnownHostsAction::Fingerprint { cert } => {
+            println!("{}", fingerprint_of(&cert)?);
+        }
+    }
+    Ok(())
 }
 
 /// Run CLI application
@@ -94,13 +284,46 @@ pub async fn run_cli() -> Result<()> {
         println!("Verbose logging enabled");
     }
 
+    let known_hosts = cli.known_hosts.clone().unwrap_or_else(default_known_hosts_path);
+    if let Commands::KnownHosts { action } = cli.command {
+        return manage_known_hosts(&known_hosts, action);
+    }
+
+    // Run cryptographic power-on self-tests before any traffic is sent
+    let report = lsftp_core::selftest::run_power_on_self_tests(&CryptoSuite::for_level(cli.security_level));
+    if !report.passed() {
+        for failure in report.failures() {
+            eprintln!("Self-test {} failed: {}", failure.name, failure.error.as_deref().unwrap_or("unknown error"));
+        }
+        return Err(lsftp_core::error::Error::Crypto("Cryptographic self-tests failed".to_string()));
+    }
+
+    // Development-only authenticators need an explicit opt-in
+    if cli.insecure_dev_auth {
+        lsftp_core::auth::HardwareAuthFactory::allow_insecure_dev()?;
+    }
+
     // Create client configuration
     let config = crate::client::ClientConfig {
         server_address: cli.server,
         server_port: cli.port,
         hardware_device: cli.hardware,
+        hardware_path: cli.hardware_path,
+        additional_factors: cli.factors,
+        hash_algorithm: cli.hash,
+        compliance_sha256: cli.sha256_digest,
+        crypto_suite: CryptoSuite::for_level(cli.security_level),
+        limit_rate: cli.limit_rate,
+        limit_rate_hours: cli.limit_rate_hours,
         cert_path: cli.cert,
         key_path: cli.key,
+        server_name: cli.server_name,
+        server_ca_path: cli.server_ca,
+        pinned_server_keys: cli.pins,
+        known_hosts_path: Some(known_hosts),
+        strict_host_key_checking: cli.strict_host_key_checking,
+        unplug_grace_secs: cli.unplug_grace,
+        agent_socket: if cli.no_agent { None } else { cli.agent.or_else(lsftp_core::agent::socket_from_env) },
         ..Default::default()
     };
 
@@ -138,6 +361,45 @@ pub async fn run_cli() -> Result<()> {
             }
         }
 
+        Commands::Delete { remote } => {
+            println!("Deleting {}", remote);
+            client.connect().await?;
+            client.delete_file(&remote).await?;
+            println!("Deleted {}", remote);
+        }
+
+        Commands::Quota => {
+            client.connect().await?;
+            let usage = client.quota().await?;
+            if usage.is_empty() {
+                println!("No quotas enforced");
+            }
+            for entry in usage {
+                let limit = |limit: Option<u64>| limit.map_or("-".to_string(), |limit| limit.to_string());
+                println!("{}: {} bytes (soft {}, hard {}), {} files (soft {}, hard {})",
+                    entry.subject,
+                    entry.bytes, limit(entry.limits.soft_bytes), limit(entry.limits.hard_bytes),
+                    entry.files, limit(entry.limits.soft_files), limit(entry.limits.hard_files));
+                if let Some(expires) = entry.grace_expires_at {
+                    let now = lsftp_core::now_secs();
+                    println!("  over soft quota; grace period ends in {}s", expires.saturating_sub(now));
+                }
+            }
+        }
+
+        Commands::RateLimit { scope, subject, upload, download, reset } => {
+            client.connect().await?;
+            client.set_rate_limit(&scope, subject.as_deref(), upload.as_deref(), download.as_deref(), reset).await?;
+            println!("Updated {} rate limit{}", scope, subject.map(|subject| format!(" for {}", subject)).unwrap_or_default());
+        }
+
+        Commands::RequireEncryption { minimum, path, classification } => {
+            client.connect().await?;
+            client.set_encryption_requirement(minimum, path.as_deref(), classification.as_deref()).await?;
+            let target = path.or(classification).unwrap_or_else(|| "sessions".to_string());
+            println!("{} now requires a {} crypto suite", target, minimum);
+        }
+
         Commands::Verify { file } => {
             println!("Verifying file: {}", file);
             client.connect().await?;
@@ -148,6 +410,8 @@ pub async fn run_cli() -> Result<()> {
                 println!("File integrity check failed");
             }
         }
+
+        Commands::KnownHosts { .. } => unreachable!("handled before connecting"),
     }
 
     // Disconnect
@@ -3,7 +3,20 @@
 //! This module provides the client implementation for LSFTP with
 //! hardware authentication and secure file transfer capabilities.
 
-use lsftp_core::{TransportConfig, QuicTransport, Result, Message, MessageType, protocol::{Frame, FileOpenPayload, FileDataPayload, FileClosePayload}};
+use lsftp_core::{TransportConfig, QuicTransport, Result, Message, MessageType, protocol::{Frame, FileOpenPayload, FileDataPayload, FileClosePayload, MessagePayload}};
+use lsftp_core::crypto::{HashAlgorithm, TaggedDigest, TransferHasher};
+use lsftp_core::{CryptoSuite, KeySchedule, HardwareType, SecretBuffer};
+use lsftp_core::access::{FileAction, TimeWindow};
+use lsftp_core::agent::{self, AgentClient};
+use lsftp_core::auth::{HardwareAttestation, HardwareAuthFactory};
+use lsftp_core::encryption::SecurityLevel;
+use lsftp_core::handshake;
+use lsftp_core::kem::KemKeyPair;
+use lsftp_core::keyschedule;
+use lsftp_core::mfa::FactorDevice;
+use lsftp_core::presence::{self, BoundDevice};
+use lsftp_core::protocol::{AuthResponsePayload, FactorResponse, PolicyRule, PolicyRuleType, PolicyUpdatePayload, QuotaPayload, QuotaUsage, SessionTerminatePayload};
+use lsftp_core::ratelimit::{self, TokenBucket};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use std::fs;
@@ -11,7 +24,6 @@ use std::io::{Read, Write};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use uuid::Uuid;
-use blake3::Hasher;
 
 /// Client configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,28 +34,85 @@ pub struct ClientConfig {
     pub server_port: u16,
     /// Hardware device type
     pub hardware_device: Option<String>,
+    /// Hardware device path
+    pub hardware_path: Option<String>,
+    /// Additional factors (`type` or `type=path`) answering the same challenge
+    #[serde(default)]
+    pub additional_factors: Vec<String>,
     /// Certificate path
     pub cert_path: Option<PathBuf>,
     /// Private key path
     pub key_path: Option<PathBuf>,
+    /// Name the server certificate must match; defaults to the server address
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// CA bundle for the server certificate instead of the system roots
+    #[serde(default)]
+    pub server_ca_path: Option<PathBuf>,
+    /// Pinned server SPKI fingerprints
+    #[serde(default)]
+    pub pinned_server_keys: Vec<String>,
+    /// known_hosts file; `None` disables trust on first use
+    #[serde(default)]
+    pub known_hosts_path: Option<PathBuf>,
+    /// Refuse servers missing from known_hosts unless CA-valid
+    #[serde(default)]
+    pub strict_host_key_checking: bool,
+    /// Seconds a removed YubiKey or smart card has to return before the session ends
+    #[serde(default = "default_unplug_grace_secs")]
+    pub unplug_grace_secs: u64,
+    /// lsftp-agent socket; `None` signs with the devices directly
+    #[serde(default = "agent::socket_from_env")]
+    pub agent_socket: Option<PathBuf>,
     /// Connection timeout
     pub connection_timeout: u64,
     /// Chunk size for file transfers
     pub chunk_size: usize,
+    /// Preferred transfer hash algorithm
+    pub hash_algorithm: HashAlgorithm,
+    /// Also compute a SHA-256 digest of every transferred file
+    pub compliance_sha256: bool,
+    /// Crypto suite declared to the server and used for file keys
+    #[serde(default)]
+    pub crypto_suite: CryptoSuite,
+    /// Cap on transfer bandwidth in bytes per second, both directions
+    #[serde(default)]
+    pub limit_rate: Option<u64>,
+    /// Daily UTC window (`08:00-18:00`) when `limit_rate` applies; always if unset
+    #[serde(default)]
+    pub limit_rate_hours: Option<String>,
     /// Enable verbose logging
     pub verbose: bool,
 }
 
+fn default_unplug_grace_secs() -> u64 {
+    presence::DEFAULT_UNPLUG_GRACE_SECS
+}
+
 impl Default for ClientConfig {
     fn default() -> Self {
         Self {
             server_address: "localhost".to_string(),
             server_port: 8443,
             hardware_device: None,
+            hardware_path: None,
+            additional_factors: Vec::new(),
             cert_path: None,
             key_path: None,
+            server_name: None,
+            server_ca_path: None,
+            pinned_server_keys: Vec::new(),
+            known_hosts_path: Some(lsftp_core::knownhosts::default_known_hosts_path()),
+            strict_host_key_checking: false,
+            unplug_grace_secs: default_unplug_grace_secs(),
+            agent_socket: agent::socket_from_env(),
             connection_timeout: 30,
             chunk_size: 1024 * 1024, // 1MB chunks
+            hash_algorithm: HashAlgorithm::Blake3,
+            compliance_sha256: false,
+            crypto_suite: CryptoSuite::default(),
+            limit_rate: None,
+            limit_rate_hours: None,
             verbose: false,
         }
     }
@@ -64,30 +133,117 @@ pub struct TransferStats {
     pub retries_count: u32,
 }
 
+/// Client-side bandwidth cap
+struct RateLimit {
+    bucket: TokenBucket,
+    hours: Option<TimeWindow>,
+}
+
+impl RateLimit {
+    /// Wait until `bytes` may be moved
+    async fn throttle(&mut self, bytes: usize) {
+        if self.hours.is_some_and(|hours| !hours.contains_now()) {
+            return;
+        }
+        tokio::time::sleep(self.bucket.take(bytes as u64, std::time::Instant::now())).await;
+    }
+}
+
 /// LSFTP Client
 pub struct LsftpClient {
     config: ClientConfig,
     transport: Option<QuicTransport>,
+    key_schedule: Option<KeySchedule>,
     session_id: Option<Uuid>,
+    presence_watchers: Vec<tokio::task::JoinHandle<()>>,
+    rate_limit: Option<RateLimit>,
 }
 
 impl LsftpClient {
     /// Create new client
     pub fn new(config: ClientConfig) -> Result<Self> {
+        let hours = config.limit_rate_hours.as_deref().map(str::parse::<TimeWindow>).transpose()?;
+        let rate_limit = config.limit_rate.map(|rate| RateLimit {
+            bucket: TokenBucket::new(rate, std::time::Instant::now()),
+            hours,
+        });
+
         Ok(Self {
             config,
             transport: None,
+            key_schedule: None,
             session_id: None,
+            presence_watchers: Vec::new(),
+            rate_limit,
         })
     }
 
     /// Connect to server
     pub async fn connect(&mut self) -> Result<()> {
+        // A running agent signs for the devices it holds and caches resumption tickets
+        let mut agent = self.connect_agent().await;
+        let ticket = match agent.as_mut() {
+            Some(agent) => agent.ticket(&self.server_key()).await.unwrap_or_else(|e| {
+                tracing::warn!("Failed to fetch resumption ticket from agent: {}", e);
+                None
+            }),
+            None => None,
+        };
+
+        let resuming = ticket.is_some();
+        let (transport, session_id, bound_devices) = match self.establish(agent.as_mut(), ticket).await {
+            Err(e) if resuming => {
+                tracing::warn!("Resumption ticket refused ({}); authenticating with hardware", e);
+                self.establish(agent.as_mut(), None).await?
+            }
+            result => result?,
+        };
+        self.session_id = Some(session_id);
+
+        // The session lives only as long as its removable devices stay plugged in
+        for device in bound_devices {
+            let terminator = transport.terminator()?;
+            let grace = std::time::Duration::from_secs(self.config.unplug_grace_secs);
+            self.presence_watchers.push(tokio::spawn(async move {
+                let device_id = device.device_id().to_string();
+                // A device that cannot be watched could be pulled unnoticed, so that ends the session too
+                let reason = presence::wait_for_removal(device, grace).await
+                    .unwrap_or_else(|e| format!("Cannot monitor device {} for removal: {}", device_id, e));
+                tracing::error!("{}; ending session {}", reason, session_id);
+                let payload = SessionTerminatePayload { session_id, device_id: Some(device_id), reason };
+                if let Err(e) = terminator.terminate(payload).await {
+                    tracing::warn!("Failed to notify server of session end: {}", e);
+                }
+            }));
+        }
+        
+        self.transport = Some(transport);
+        
+        if self.config.verbose {
+            tracing::info!("Connected to LSFTP server at {}:{}", 
+                self.config.server_address, self.config.server_port);
+        }
+        
+        Ok(())
+    }
+
+    /// Open the QUIC connection and authenticate it; returns the transport,
+    /// the server-assigned session ID and the removable devices used
+    async fn establish(
+        &mut self,
+        agent: Option<&mut AgentClient>,
+        ticket: Option<Vec<u8>>,
+    ) -> Result<(QuicTransport, Uuid, Vec<BoundDevice>)> {
         let transport_config = TransportConfig {
             server_address: self.config.server_address.clone(),
             server_port: self.config.server_port,
             client_cert_path: self.config.cert_path.as_ref().map(|p| p.to_string_lossy().to_string()),
             client_key_path: self.config.key_path.as_ref().map(|p| p.to_string_lossy().to_string()),
+            server_name: self.config.server_name.clone(),
+            server_ca_path: self.config.server_ca_path.as_ref().map(|p| p.to_string_lossy().to_string()),
+            pinned_server_keys: self.config.pinned_server_keys.clone(),
+            known_hosts_path: self.config.known_hosts_path.as_ref().map(|p| p.to_string_lossy().to_string()),
+            strict_host_key_checking: self.config.strict_host_key_checking,
             connection_timeout: self.config.connection_timeout,
             ..Default::default()
         };
@@ -96,23 +252,228 @@ impl LsftpClient {
         transport.initialize().await?;
         transport.connect().await?;
 
-        // Generate session ID
-        self.session_id = Some(Uuid::new_v4());
-        
-        self.transport = Some(transport);
-        
-        if self.config.verbose {
-            tracing::info!("Connected to LSFTP server at {}:{}", 
-                self.config.server_address, self.config.server_port);
+        // The server assigns the session ID in its challenge
+        let (session_id, bound_devices, kem_secret) = self.authenticate(&mut transport, agent, ticket).await?;
+
+        // Per-file keys are derived from this session's exported secret and key exchange
+        self.key_schedule = Some(KeySchedule::new(
+            keyschedule::session_secret(&transport.export_session_secret()?, &kem_secret)?,
+            self.config.crypto_suite.clone(),
+        )?);
+        Ok((transport, session_id, bound_devices))
+    }
+
+    /// Connect to the configured agent; the client works without one
+    async fn connect_agent(&self) -> Option<AgentClient> {
+        let path = self.config.agent_socket.as_ref()?;
+        match AgentClient::connect(path).await {
+            Ok(agent) => Some(agent),
+            Err(e) => {
+                tracing::warn!("Not using lsftp-agent: {}", e);
+                None
+            }
         }
-        
-        Ok(())
+    }
+
+    /// Key under which the agent caches this server's resumption tickets
+    fn server_key(&self) -> String {
+        format!("{}:{}", self.config.server_name.as_deref().unwrap_or(&self.config.server_address), self.config.server_port)
+    }
+
+    /// Answer the server's hardware challenge, with a resumption ticket if one is given;
+    /// returns the session ID, the removable devices used and the key exchange secret
+    async fn authenticate(
+        &self,
+        transport: &mut QuicTransport,
+        mut agent: Option<&mut AgentClient>,
+        ticket: Option<Vec<u8>>,
+    ) -> Result<(Uuid, Vec<BoundDevice>, SecretBuffer)> {
+        let message = transport.receive_message().await?;
+        let challenge = match message.payload {
+            Some(MessagePayload::AuthChallenge(challenge)) => challenge,
+            // A throttled or locked-out client is refused before any challenge
+            Some(MessagePayload::AuthStatus(status)) => return Err(lsftp_core::error::Error::Auth(
+                status.error.unwrap_or_else(|| "Authentication rejected".to_string())
+            )),
+            _ => return Err(lsftp_core::error::Error::Protocol(format!(
+                "Expected authentication challenge, got {:?}", message.frame.message_type
+            ))),
+        };
+
+        let binding = transport.export_auth_binding()?;
+        let signed_message = handshake::challenge_message(binding.as_slice(), &challenge);
+        let server = self.server_key();
+        let kem_key_pair = KemKeyPair::generate(self.config.crypto_suite.kem)?;
+
+        let mut bound_devices = Vec::new();
+        let resuming = ticket.is_some();
+        let response = match &self.config.hardware_device {
+            _ if ticket.is_some() => AuthResponsePayload {
+                session_id: challenge.session_id,
+                nonce: challenge.nonce,
+                device_type: None,
+                device_id: None,
+                signature: vec![],
+                attestation: None,
+                additional_factors: vec![],
+                resumption_ticket: ticket,
+                crypto_suite: self.config.crypto_suite.clone(),
+                kem_public_key: kem_key_pair.public_key().to_vec(),
+            },
+            Some(device) => {
+                let device_type: HardwareType = device.parse()?;
+                let (device_id, signature, attestation) = Self::sign_challenge(
+                    agent.as_deref_mut(), &server, device_type, self.config.hardware_path.clone(), &signed_message
+                ).await?;
+                bound_devices.extend(BoundDevice::from_device(device_type, &device_id, self.config.hardware_path.as_deref()));
+
+                let mut additional_factors = Vec::new();
+                for factor in &self.config.additional_factors {
+                    let factor: FactorDevice = factor.parse()?;
+                    let (device_id, signature, attestation) = Self::sign_challenge(
+                        agent.as_deref_mut(), &server, factor.device_type, factor.device_path.clone(), &signed_message
+                    ).await?;
+                    bound_devices.extend(BoundDevice::from_device(factor.device_type, &device_id, factor.device_path.as_deref()));
+                    if self.config.verbose {
+                        tracing::info!("Signed challenge with additional factor {}", device_id);
+                    }
+                    additional_factors.push(FactorResponse {
+                        device_type: factor.device_type,
+                        device_id,
+                        signature,
+                        attestation: Some(attestation),
+                    });
+                }
+
+                AuthResponsePayload {
+                    session_id: challenge.session_id,
+                    nonce: challenge.nonce,
+                    device_type: Some(device_type),
+                    device_id: Some(device_id),
+                    signature,
+                    attestation: Some(attestation),
+                    additional_factors,
+                    resumption_ticket: None,
+                    crypto_suite: self.config.crypto_suite.clone(),
+                    kem_public_key: kem_key_pair.public_key().to_vec(),
+                }
+            }
+            None if challenge.hardware_required => {
+                return Err(lsftp_core::error::Error::Auth(
+                    "Server requires hardware authentication; use --hardware".to_string()
+                ));
+            }
+            None if !self.config.additional_factors.is_empty() => {
+                return Err(lsftp_core::error::Error::Config(
+                    "Additional factors need a primary device; use --hardware".to_string()
+                ));
+            }
+            None => AuthResponsePayload {
+                session_id: challenge.session_id,
+                nonce: challenge.nonce,
+                device_type: None,
+                device_id: None,
+                signature: vec![],
+                attestation: None,
+                additional_factors: vec![],
+                resumption_ticket: None,
+                crypto_suite: self.config.crypto_suite.clone(),
+                kem_public_key: kem_key_pair.public_key().to_vec(),
+            },
+        };
+
+        let response_message = Message::new(MessageType::AuthResponse, Some(
+            MessagePayload::AuthResponse(response)
+        ))?;
+        transport.send_message(response_message).await?;
+
+        let message = transport.receive_message().await?;
+        match message.payload {
+            Some(MessagePayload::AuthStatus(status)) if status.authenticated => {
+                if self.config.verbose {
+                    tracing::info!("Authenticated as {}", status.user_id.as_deref().unwrap_or("anonymous"));
+                }
+                let kem_ciphertext = status.kem_ciphertext.ok_or_else(|| lsftp_core::error::Error::Protocol(
+                    "Server did not complete the key exchange".to_string()
+                ))?;
+                let kem_secret = kem_key_pair.decapsulate(&kem_ciphertext)?;
+
+                // A resumed session stays bound to the devices that authenticated the original one
+                if resuming {
+                    for device in &status.devices {
+                        let device_path = self.device_path(device.device_type)?;
+                        bound_devices.extend(BoundDevice::from_device(device.device_type, &device.device_id, device_path.as_deref()));
+                    }
+                }
+
+                if let (Some(agent), Some(ticket), Some(expires_at)) = (agent, status.resumption_ticket, status.ticket_expires_at) {
+                    if let Err(e) = agent.put_ticket(&server, ticket, expires_at).await {
+                        tracing::warn!("Failed to cache resumption ticket in agent: {}", e);
+                    }
+                }
+                Ok((challenge.session_id, bound_devices, kem_secret))
+            }
+            Some(MessagePayload::AuthStatus(status)) => Err(lsftp_core::error::Error::Auth(
+                status.error.unwrap_or_else(|| "Authentication rejected".to_string())
+            )),
+            _ => Err(lsftp_core::error::Error::Protocol(format!(
+                "Expected authentication status, got {:?}", message.frame.message_type
+            ))),
+        }
+    }
+
+    /// Configured path of the primary or additional device of a type
+    fn device_path(&self, device_type: HardwareType) -> Result<Option<String>> {
+        if let Some(device) = &self.config.hardware_device {
+            if device.parse::<HardwareType>()? == device_type {
+                return Ok(self.config.hardware_path.clone());
+            }
+        }
+        for factor in &self.config.additional_factors {
+            let factor: FactorDevice = factor.parse()?;
+            if factor.device_type == device_type {
+                return Ok(factor.device_path);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Sign the bound challenge with one device, through the agent if it holds the device;
+    /// returns its ID, signature and attestation
+    async fn sign_challenge(
+        agent: Option<&mut AgentClient>,
+        server: &str,
+        device_type: HardwareType,
+        device_path: Option<String>,
+        signed_message: &[u8],
+    ) -> Result<(String, Vec<u8>, HardwareAttestation)> {
+        if let Some(agent) = agent {
+            match agent.sign(device_type, device_path.clone(), signed_message, server).await? {
+                Some(signed) => return Ok(signed),
+                None => tracing::debug!("Agent does not hold the {:?} device; opening it directly", device_type),
+            }
+        }
+
+        let mut auth = HardwareAuthFactory::create(device_type, device_path).await?;
+        auth.initialize().await?;
+
+        let result = auth.authenticate(signed_message).await?;
+        let (device_id, signature) = match (result.success, result.device_id, result.signature) {
+            (true, Some(device_id), Some(signature)) => (device_id, signature),
+            _ => return Err(lsftp_core::error::Error::HardwareAuth(
+                result.error.unwrap_or_else(|| "Device did not sign the challenge".to_string())
+            )),
+        };
+
+        Ok((device_id, signature, auth.generate_attestation(signed_message).await?))
     }
 
     /// Upload file with progress tracking
     pub async fn upload_file(&mut self, local_path: &str, remote_path: &str) -> Result<TransferStats> {
         let transport = self.transport.as_mut()
             .ok_or_else(|| lsftp_core::error::Error::Transport("Not connected".to_string()))?;
+        let key_schedule = self.key_schedule.as_mut()
+            .ok_or_else(|| lsftp_core::error::Error::Transport("Not connected".to_string()))?;
 
         let start_time = std::time::Instant::now();
         
@@ -133,9 +494,13 @@ impl LsftpClient {
 
         // Send file open message
         let file_open_payload = FileOpenPayload {
+            file_id,
+            operation: FileAction::Write,
             path: remote_path.to_string(),
             size: file_size,
-            hash: [0u8; 32], // Will be calculated during transfer
+            hash_algorithm: self.config.hash_algorithm,
+            compliance_sha256: self.config.compliance_sha256,
+            hash: None, // Will be calculated during transfer
             permissions: 0o644,
             metadata: std::collections::HashMap::new(),
         };
@@ -145,13 +510,15 @@ impl LsftpClient {
         ))?;
 
         transport.send_message(file_open_message).await?;
+        let hash_algorithm = Self::receive_negotiated_hash(transport).await?;
+        key_schedule.open_file(file_id)?;
 
         // Upload file in chunks
         let mut buffer = vec![0u8; self.config.chunk_size];
         let mut total_bytes = 0u64;
         let mut chunks_count = 0u32;
         let mut retries_count = 0u32;
-        let mut hasher = Hasher::new();
+        let mut hasher = TransferHasher::new(hash_algorithm, self.config.compliance_sha256);
 
         loop {
             let bytes_read = file.read(&mut buffer).await
@@ -161,15 +528,23 @@ impl LsftpClient {
                 break; // End of file
             }
 
+            if let Some(rate_limit) = self.rate_limit.as_mut() {
+                rate_limit.throttle(bytes_read).await;
+            }
+
             let chunk_data = &buffer[..bytes_read];
             hasher.update(chunk_data);
 
+            // Encrypt the chunk with the per-file key
+            let encrypted = key_schedule.file_key(&file_id)?
+                .encrypt_chunk(key_schedule.crypto_suite(), chunks_count, chunk_data)?;
+
             // Create file data message
             let file_data_payload = FileDataPayload {
                 file_id,
                 chunk_index: chunks_count,
-                data: chunk_data.to_vec(),
-                chunk_hash: hasher.finalize().into(),
+                data: encrypted,
+                chunk_hash: TaggedDigest::compute(hash_algorithm, chunk_data),
                 chunk_signature: vec![], // Will be signed by server
             };
 
@@ -204,10 +579,10 @@ impl LsftpClient {
         }
 
         // Send file close message
-        let final_hash = hasher.finalize();
         let file_close_payload = FileClosePayload {
             file_id,
-            final_hash: final_hash.into(),
+            final_hash: hasher.digest(),
+            compliance_hash: hasher.compliance_digest(),
             global_signature: vec![], // Will be signed by server
             statistics: lsftp_core::protocol::TransferStatistics {
                 bytes_transferred: total_bytes,
@@ -223,6 +598,7 @@ impl LsftpClient {
         ))?;
 
         transport.send_message(file_close_message).await?;
+        key_schedule.erase_file_key(&file_id);
 
         let duration = start_time.elapsed();
         let stats = TransferStats {
@@ -248,20 +624,33 @@ impl LsftpClient {
 
     /// Download file with integrity verification
     pub async fn download_file(&mut self, remote_path: &str, local_path: &str) -> Result<TransferStats> {
+        self.receive_file(remote_path, Some(local_path)).await
+    }
+
+    /// Read a remote file, checking every chunk and the final digest, and
+    /// write it to `local_path` if given
+    async fn receive_file(&mut self, remote_path: &str, local_path: Option<&str>) -> Result<TransferStats> {
         let transport = self.transport.as_mut()
             .ok_or_else(|| lsftp_core::error::Error::Transport("Not connected".to_string()))?;
+        let key_schedule = self.key_schedule.as_mut()
+            .ok_or_else(|| lsftp_core::error::Error::Transport("Not connected".to_string()))?;
 
         let start_time = std::time::Instant::now();
         
         if self.config.verbose {
-            tracing::info!("Downloading file: {} to {}", remote_path, local_path);
+            tracing::info!("Reading file: {} to {}", remote_path, local_path.unwrap_or("nowhere"));
         }
 
         // Send file open request
+        let file_id = Uuid::new_v4();
         let file_open_payload = FileOpenPayload {
+            file_id,
+            operation: FileAction::Read,
             path: remote_path.to_string(),
             size: 0, // Will be set by server
-            hash: [0u8; 32],
+            hash_algorithm: self.config.hash_algorithm,
+            compliance_sha256: self.config.compliance_sha256,
+            hash: None,
             permissions: 0o644,
             metadata: std::collections::HashMap::new(),
         };
@@ -271,16 +660,21 @@ impl LsftpClient {
         ))?;
 
         transport.send_message(file_open_message).await?;
+        let hash_algorithm = Self::receive_negotiated_hash(transport).await?;
+        key_schedule.open_file(file_id)?;
 
         // Create local file
-        let mut file = File::create(local_path).await
-            .map_err(|e| lsftp_core::error::Error::File(format!("Failed to create file: {}", e)))?;
+        let mut file = match local_path {
+            Some(local_path) => Some(File::create(local_path).await
+                .map_err(|e| lsftp_core::error::Error::File(format!("Failed to create file: {}", e)))?),
+            None => None,
+        };
 
         // Receive file data
         let mut total_bytes = 0u64;
         let mut chunks_count = 0u32;
         let mut retries_count = 0u32;
-        let mut hasher = Hasher::new();
+        let mut hasher = TransferHasher::new(hash_algorithm, self.config.compliance_sha256);
 
         loop {
             // Receive file data message
@@ -288,31 +682,53 @@ impl LsftpClient {
             
             match message.payload {
                 Some(lsftp_core::protocol::MessagePayload::FileData(payload)) => {
-                    // Verify chunk hash
-                    let chunk_hash = blake3::hash(&payload.data);
-                    if chunk_hash.as_bytes() != &payload.chunk_hash {
+                    // Decrypt the chunk with the per-file key
+                    let data = key_schedule.file_key(&file_id)?
+                        .decrypt_chunk(key_schedule.crypto_suite(), payload.chunk_index, &payload.data)?;
+
+                    // Verify chunk hash with the negotiated algorithm
+                    let chunk_hash = TaggedDigest::compute(hash_algorithm, &data);
+                    if !chunk_hash.matches(&payload.chunk_hash) {
                         return Err(lsftp_core::error::Error::File("Chunk integrity check failed".to_string()));
                     }
 
                     // Write chunk to file
-                    file.write_all(&payload.data).await
-                        .map_err(|e| lsftp_core::error::Error::File(format!("Failed to write file: {}", e)))?;
+                    if let Some(file) = &mut file {
+                        file.write_all(&data).await
+                            .map_err(|e| lsftp_core::error::Error::File(format!("Failed to write file: {}", e)))?;
+                    }
 
-                    hasher.update(&payload.data);
-                    total_bytes += payload.data.len() as u64;
+                    hasher.update(&data);
+                    total_bytes += data.len() as u64;
                     chunks_count += 1;
 
+                    // Reading slowly holds the server back through flow control
+                    if let Some(rate_limit) = self.rate_limit.as_mut() {
+                        rate_limit.throttle(data.len()).await;
+                    }
+
                     if self.config.verbose && chunks_count % 10 == 0 {
                         tracing::info!("Download progress: {} chunks, {} bytes", chunks_count, total_bytes);
                     }
                 }
                 Some(lsftp_core::protocol::MessagePayload::FileClose(payload)) => {
+                    key_schedule.erase_file_key(&file_id);
+
                     // Verify final hash
-                    let final_hash = hasher.finalize();
-                    if final_hash.as_bytes() != &payload.final_hash {
+                    if !hasher.digest().matches(&payload.final_hash) {
                         return Err(lsftp_core::error::Error::File("File integrity check failed".to_string()));
                     }
 
+                    // Verify the SHA-256 compliance digest when one was requested
+                    if let Some(expected) = hasher.compliance_digest() {
+                        let matches = payload.compliance_hash.as_ref()
+                            .map(|actual| expected.matches(actual))
+                            .unwrap_or(false);
+                        if !matches {
+                            return Err(lsftp_core::error::Error::File("SHA-256 compliance digest check failed".to_string()));
+                        }
+                    }
+
                     if self.config.verbose {
                         tracing::info!("Download completed: {} bytes in {} chunks", 
                             total_bytes, chunks_count);
@@ -351,9 +767,13 @@ impl LsftpClient {
 
         // Send directory listing request
         let list_payload = FileOpenPayload {
+            file_id: Uuid::new_v4(),
+            operation: FileAction::List,
             path: remote_path.to_string(),
             size: 0,
-            hash: [0u8; 32],
+            hash_algorithm: self.config.hash_algorithm,
+            compliance_sha256: false,
+            hash: None,
             permissions: 0o755,
             metadata: std::collections::HashMap::new(),
         };
@@ -364,16 +784,15 @@ impl LsftpClient {
 
         transport.send_message(list_message).await?;
 
-        // Receive directory listing
-        let message = transport.receive_message().await?;
-        
-        // Parse directory listing from message payload
-        // This is a simplified implementation
-        let entries = vec![
-            "file1.txt".to_string(),
-            "file2.txt".to_string(),
-            "directory/".to_string(),
-        ];
+        // The acknowledgment maps each entry name to "file" or "dir"
+        let listing = match transport.receive_message().await?.payload {
+            Some(MessagePayload::FileOpen(ack)) => ack.metadata,
+            _ => return Err(lsftp_core::error::Error::Protocol("Expected directory listing".to_string())),
+        };
+        let mut entries: Vec<String> = listing.into_iter()
+            .map(|(name, kind)| if kind == "dir" { format!("{}/", name) } else { name })
+            .collect();
+        entries.sort();
 
         if self.config.verbose {
             tracing::info!("Directory listing: {} entries", entries.len());
@@ -382,36 +801,111 @@ impl LsftpClient {
         Ok(entries)
     }
 
-    /// Verify file integrity
-    pub async fn verify_file(&mut self, remote_path: &str) -> Result<bool> {
+    /// Delete a remote file
+    pub async fn delete_file(&mut self, remote_path: &str) -> Result<()> {
         let transport = self.transport.as_mut()
             .ok_or_else(|| lsftp_core::error::Error::Transport("Not connected".to_string()))?;
 
         if self.config.verbose {
-            tracing::info!("Verifying file: {}", remote_path);
+            tracing::info!("Deleting file: {}", remote_path);
         }
 
-        // Send file verification request
-        let verify_payload = FileOpenPayload {
+        let delete_payload = FileOpenPayload {
+            file_id: Uuid::new_v4(),
+            operation: FileAction::Delete,
             path: remote_path.to_string(),
             size: 0,
-            hash: [0u8; 32],
-            permissions: 0o644,
+            hash_algorithm: self.config.hash_algorithm,
+            compliance_sha256: false,
+            hash: None,
+            permissions: 0,
             metadata: std::collections::HashMap::new(),
         };
 
-        let verify_message = Message::new(MessageType::FileOpen, Some(
-            lsftp_core::protocol::MessagePayload::FileOpen(verify_payload)
+        let delete_message = Message::new(MessageType::FileOpen, Some(
+            lsftp_core::protocol::MessagePayload::FileOpen(delete_payload)
         ))?;
 
-        transport.send_message(verify_message).await?;
+        transport.send_message(delete_message).await?;
+        Self::receive_negotiated_hash(transport).await?;
+        Ok(())
+    }
 
-        // Receive verification result
-        let message = transport.receive_message().await?;
-        
-        // Parse verification result
-        // This is a simplified implementation
-        let is_valid = true;
+    /// Storage used by the authenticated user and each of their groups, with
+    /// their limits; empty when the server does not enforce quotas
+    pub async fn quota(&mut self) -> Result<Vec<QuotaUsage>> {
+        let session_id = self.session_id
+            .ok_or_else(|| lsftp_core::error::Error::Transport("Not connected".to_string()))?;
+        let transport = self.transport.as_mut()
+            .ok_or_else(|| lsftp_core::error::Error::Transport("Not connected".to_string()))?;
+
+        let request = Message::new(MessageType::Quota, Some(
+            MessagePayload::Quota(QuotaPayload { session_id, usage: Vec::new() })
+        ))?;
+        transport.send_message(request).await?;
+
+        match transport.receive_message().await?.payload {
+            Some(MessagePayload::Quota(report)) => Ok(report.usage),
+            _ => Err(lsftp_core::error::Error::Protocol("Expected quota report".to_string())),
+        }
+    }
+
+    /// Change bandwidth limits on the server live; needs the server's
+    /// administrator role. Each rule is a scope (`global`, `user` or
+    /// `session`), an optional subject and rates such as `10M` or `unlimited`
+    pub async fn set_rate_limit(&mut self, scope: &str, subject: Option<&str>, upload: Option<&str>, download: Option<&str>, reset: bool) -> Result<()> {
+        // Check rates here so a typo fails before it reaches the server
+        for rate in [upload, download].into_iter().flatten().filter(|rate| *rate != "unlimited") {
+            ratelimit::parse_rate(rate)?;
+        }
+        let parameters = [("scope", Some(scope)), ("subject", subject), ("upload", upload), ("download", download), ("reset", reset.then_some("true"))];
+        self.send_policy_rule(format!("rate-limit-{}", scope), PolicyRuleType::RateLimit, &parameters).await
+    }
+
+    /// Change the minimum security level on the server live; needs the
+    /// server's administrator role. Without a path or classification the
+    /// session minimum changes
+    pub async fn set_encryption_requirement(&mut self, minimum: SecurityLevel, path: Option<&str>, classification: Option<&str>) -> Result<()> {
+        let minimum = minimum.to_string();
+        let parameters = [("minimum", Some(minimum.as_str())), ("path", path), ("classification", classification)];
+        self.send_policy_rule("encryption-requirement".to_string(), PolicyRuleType::EncryptionRequirement, &parameters).await
+    }
+
+    /// Send a single-rule policy update and wait for the server's echo
+    async fn send_policy_rule(&mut self, id: String, rule_type: PolicyRuleType, parameters: &[(&str, Option<&str>)]) -> Result<()> {
+        let transport = self.transport.as_mut()
+            .ok_or_else(|| lsftp_core::error::Error::Transport("Not connected".to_string()))?;
+
+        let parameters = parameters.iter()
+            .filter_map(|(key, value)| value.map(|value| (key.to_string(), value.to_string())))
+            .collect();
+        let update = PolicyUpdatePayload {
+            policy_id: Uuid::new_v4(),
+            version: 1,
+            rules: vec![PolicyRule { id, rule_type, parameters }],
+            effective_at: lsftp_core::now_secs(),
+        };
+
+        let message = Message::new(MessageType::PolicyUpdate, Some(MessagePayload::PolicyUpdate(update)))?;
+        transport.send_message(message).await?;
+
+        match transport.receive_message().await?.payload {
+            Some(MessagePayload::PolicyUpdate(_)) => Ok(()),
+            _ => Err(lsftp_core::error::Error::Protocol("Expected policy update acknowledgment".to_string())),
+        }
+    }
+
+    /// Verify file integrity by reading it back and checking every digest
+    pub async fn verify_file(&mut self, remote_path: &str) -> Result<bool> {
+        if self.config.verbose {
+            tracing::info!("Verifying file: {}", remote_path);
+        }
+
+        let is_valid = match self.receive_file(remote_path, None).await {
+            Ok(_) => true,
+            Err(lsftp_core::error::Error::File(_)) => false,
+            Err(e) => return Err(e),
+        };
 
         if self.config.verbose {
             tracing::info!("File verification: {}", if is_valid { "PASSED" } else { "FAILED" });
@@ -420,8 +914,25 @@ impl LsftpClient {
         Ok(is_valid)
     }
 
+    /// Wait for the file open acknowledgment and return the negotiated hash algorithm
+    async fn receive_negotiated_hash(transport: &mut QuicTransport) -> Result<HashAlgorithm> {
+        match transport.receive_message().await?.payload {
+            Some(MessagePayload::FileOpen(ack)) => {
+                if let Some(warning) = ack.metadata.get("quota_warning") {
+                    tracing::warn!("Quota: {}", warning);
+                }
+                Ok(ack.hash_algorithm)
+            }
+            _ => Err(lsftp_core::error::Error::Protocol("Expected file open acknowledgment".to_string())),
+        }
+    }
+
     /// Disconnect from server
     pub async fn disconnect(&mut self) -> Result<()> {
+        for watcher in self.presence_watchers.drain(..) {
+            watcher.abort();
+        }
+        self.key_schedule = None;
         if let Some(mut transport) = self.transport.take() {
             transport.close().await?;
         }
@@ -0,0 +1,633 @@
+//! Access control for LSFTP file operations
+//!
+//! Every open, read, write, list and delete is checked against an access
+//! policy before the server touches the file system. Rules grant (or deny)
+//! actions below a path prefix to users and roles, optionally only from
+//! certain device types, source networks, time windows, file sizes and
+//! classification labels. Anything no rule allows is denied. Policies live in
+//! a TOML file:
+//!
+//! ```toml
+//! # Roles granted on top of those recorded with the user's enrolled devices
+//! [users]
+//! alice = ["finance"]
+//!
+//! # Classification of everything below a path; the longest prefix wins
+//! [labels]
+//! "/finance" = "confidential"
+//!
+//! [[rules]]
+//! id = "finance-rw"
+//! roles = ["finance"]
+//! path = "/finance"
+//! actions = ["read", "write", "list"]
+//! device_types = ["yubikey", "smartcard"]
+//! source_cidrs = ["10.0.0.0/8"]
+//! hours = "07:00-19:00"
+//! days = ["mon", "tue", "wed", "thu", "fri"]
+//! max_file_size = 10737418240
+//!
+//! [[rules]]
+//! id = "no-confidential-deletes"
+//! effect = "deny"
+//! roles = ["*"]
+//! path = "/"
+//! actions = ["delete"]
+//! classifications = ["confidential"]
+//! ```
+//!
+//! A matching deny rule wins over any allow rule. Times are UTC.
+
+use crate::auth::HardwareType;
+use crate::enrollment::EnrollmentStore;
+use crate::error::{Error, Result};
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Rule ID reported when no rule allowed an operation
+pub const DEFAULT_DENY_RULE: &str = "default-deny";
+
+/// File operation being authorized
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FileAction {
+    /// Download a file or read its contents
+    Read,
+    /// Upload or overwrite a file
+    Write,
+    /// List a directory
+    List,
+    /// Delete a file
+    Delete,
+}
+
+impl fmt::Display for FileAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FileAction::Read => "read",
+            FileAction::Write => "write",
+            FileAction::List => "list",
+            FileAction::Delete => "delete",
+        })
+    }
+}
+
+impl FromStr for FileAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "read" => Ok(FileAction::Read),
+            "write" => Ok(FileAction::Write),
+            "list" => Ok(FileAction::List),
+            "delete" => Ok(FileAction::Delete),
+            _ => Err(Error::Config(format!("Unknown file action: {}", s))),
+        }
+    }
+}
+
+/// Normalize a client path to `/a/b`, rejecting `..` components
+pub fn normalize_path(path: &str) -> Result<String> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return Err(Error::File(format!("Path may not contain '..': {}", path))),
+            component => components.push(component),
+        }
+    }
+    Ok(format!("/{}", components.join("/")))
+}
+
+/// Whether a normalized path lies at or below a normalized prefix
+pub(crate) fn is_below(path: &str, prefix: &str) -> bool {
+    prefix == "/"
+        || path == prefix
+        || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// IPv4 or IPv6 network, written `10.0.0.0/8` or a bare address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Whether the network contains an address
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let network: IpAddr = address.trim().parse()
+            .map_err(|_| Error::Config(format!("Invalid network address: {}", s)))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.trim().parse::<u8>().ok().filter(|len| *len <= max_len)
+                .ok_or_else(|| Error::Config(format!("Invalid prefix length: {}", s)))?,
+            None => max_len,
+        };
+        Ok(Self { network, prefix_len })
+    }
+}
+
+/// Daily time window in UTC, written `08:00-18:00`; may wrap past midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeWindow {
+    /// Whether a time of day falls inside the window
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// Whether the current UTC time falls inside the window
+    pub fn contains_now(&self) -> bool {
+        self.contains(Utc::now().time())
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M")
+            .map_err(|_| Error::Config(format!("Invalid time window: {}", s)));
+        let (start, end) = s.split_once('-')
+            .ok_or_else(|| Error::Config(format!("Time window must be HH:MM-HH:MM: {}", s)))?;
+        Ok(Self { start: parse(start)?, end: parse(end)? })
+    }
+}
+
+/// Whether a rule grants or refuses what it matches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    /// Allow the operation
+    #[default]
+    Allow,
+    /// Refuse the operation, whatever other rules allow
+    Deny,
+}
+
+/// One access rule
+#[derive(Debug, Clone)]
+pub struct AccessRule {
+    /// Rule ID recorded in the audit log
+    pub id: String,
+    /// Allow or deny
+    pub effect: Effect,
+    /// Users the rule applies to
+    pub users: Vec<String>,
+    /// Roles the rule applies to; `*` is any authenticated user
+    pub roles: Vec<String>,
+    /// Normalized path prefix
+    pub path: String,
+    /// Actions covered
+    pub actions: Vec<FileAction>,
+    /// Device types, at least one of which must have authenticated the session
+    pub device_types: Vec<HardwareType>,
+    /// Source networks
+    pub source_cidrs: Vec<Cidr>,
+    /// Time of day
+    pub hours: Option<TimeWindow>,
+    /// Days of the week
+    pub days: Vec<Weekday>,
+    /// Largest file the rule covers; operations without a size, such as listings, are not limited
+    pub max_file_size: Option<u64>,
+    /// Classification labels the rule covers
+    pub classifications: Vec<String>,
+}
+
+impl AccessRule {
+    fn applies_to(&self, subject: &Subject) -> bool {
+        let Some(user_id) = &subject.user_id else {
+            return false;
+        };
+        self.users.contains(user_id)
+            || self.roles.iter().any(|role| role == "*" || subject.roles.contains(role))
+    }
+
+    fn matches(&self, subject: &Subject, request: &AccessRequest<'_>, classification: Option<&str>) -> bool {
+        self.actions.contains(&request.action)
+            && is_below(request.path, &self.path)
+            && self.applies_to(subject)
+            && (self.device_types.is_empty()
+                || self.device_types.iter().any(|device_type| subject.device_types.contains(device_type)))
+            && (self.source_cidrs.is_empty()
+                || subject.source_ip.is_some_and(|ip| self.source_cidrs.iter().any(|cidr| cidr.contains(ip))))
+            && self.hours.is_none_or(|hours| hours.contains(request.at.time()))
+            && (self.days.is_empty() || self.days.contains(&request.at.weekday()))
+            && self.max_file_size.is_none_or(|max| request.size.is_none_or(|size| size <= max))
+            && (self.classifications.is_empty()
+                || classification.is_some_and(|label| self.classifications.iter().any(|l| l == label)))
+    }
+}
+
+/// Authenticated identity and the attributes rules can test
+#[derive(Debug, Clone, Default)]
+pub struct Subject {
+    /// Authenticated user; anonymous sessions match no rule
+    pub user_id: Option<String>,
+    /// Roles from the user's active enrollments and the policy
+    pub roles: Vec<String>,
+    /// Types of the devices that authenticated the session
+    pub device_types: Vec<HardwareType>,
+    /// Client address
+    pub source_ip: Option<IpAddr>,
+}
+
+impl Subject {
+    /// Subject for an authenticated session: device types come from the devices
+    /// that answered, roles from every active enrollment of the user once one of
+    /// their devices has answered; a session without a device gets no roles
+    pub fn resolve(
+        user_id: Option<String>,
+        device_ids: &[String],
+        enrollments: &EnrollmentStore,
+        source_address: Option<&str>,
+    ) -> Self {
+        let devices: Vec<_> = device_ids.iter()
+            .filter_map(|device_id| enrollments.get(device_id))
+            .filter(|device| user_id.as_deref() == Some(device.user_id.as_str()))
+            .collect();
+
+        let mut device_types: Vec<HardwareType> = devices.iter().map(|device| device.device_type).collect();
+        device_types.dedup();
+
+        let mut roles: Vec<String> = match &user_id {
+            Some(user_id) if !devices.is_empty() => enrollments.devices().into_iter()
+                .filter(|device| &device.user_id == user_id && device.is_active())
+                .flat_map(|device| device.roles.iter().cloned())
+                .collect(),
+            _ => Vec::new(),
+        };
+        roles.sort();
+        roles.dedup();
+
+        let source_ip = source_address.and_then(|address| {
+            address.parse::<std::net::SocketAddr>().map(|address| address.ip())
+                .or_else(|_| address.parse::<IpAddr>())
+                .ok()
+        });
+
+        Self { user_id, roles, device_types, source_ip }
+    }
+}
+
+/// Operation to authorize
+#[derive(Debug, Clone, Copy)]
+pub struct AccessRequest<'a> {
+    /// Action
+    pub action: FileAction,
+    /// Normalized path
+    pub path: &'a str,
+    /// File size; `None` for listings and missing files
+    pub size: Option<u64>,
+    /// Time of the request
+    pub at: DateTime<Utc>,
+}
+
+impl<'a> AccessRequest<'a> {
+    /// Request made now
+    pub fn new(action: FileAction, path: &'a str, size: Option<u64>) -> Self {
+        Self { action, path, size, at: Utc::now() }
+    }
+}
+
+/// Outcome of an access check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessDecision {
+    /// Whether the operation may proceed
+    pub allowed: bool,
+    /// Rule that decided, or `default-deny`
+    pub rule: String,
+    /// Classification label of the path
+    pub classification: Option<String>,
+}
+
+impl AccessDecision {
+    /// Error for a denied operation
+    pub fn into_result(self, request: &AccessRequest<'_>) -> Result<()> {
+        if self.allowed {
+            return Ok(());
+        }
+        Err(Error::Auth(format!(
+            "Access denied: {} {} (rule {})", request.action, request.path, self.rule
+        )))
+    }
+}
+
+/// Access rules, role assignments and path labels
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    /// Rules in file order
+    pub rules: Vec<AccessRule>,
+    /// Roles by user ID, added to enrollment roles
+    pub users: HashMap<String, Vec<String>>,
+    /// Classification labels by normalized path prefix
+    pub labels: HashMap<String, String>,
+}
+
+/// Rule as written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccessRuleFile {
+    id: String,
+    #[serde(default)]
+    effect: Effect,
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+    path: String,
+    actions: Vec<String>,
+    #[serde(default)]
+    device_types: Vec<String>,
+    #[serde(default)]
+    source_cidrs: Vec<String>,
+    hours: Option<String>,
+    #[serde(default)]
+    days: Vec<String>,
+    max_file_size: Option<u64>,
+    #[serde(default)]
+    classifications: Vec<String>,
+}
+
+/// Policy file as written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccessPolicyFile {
+    #[serde(default)]
+    users: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    #[serde(default)]
+    rules: Vec<AccessRuleFile>,
+}
+
+impl AccessPolicy {
+    /// Load a TOML policy file
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read access policy {:?}: {}", path, e)))?;
+        content.parse()
+    }
+
+    /// Add the roles the policy assigns to the subject's user
+    pub fn assign_roles(&self, subject: &mut Subject) {
+        let Some(roles) = subject.user_id.as_ref().and_then(|user_id| self.users.get(user_id)) else {
+            return;
+        };
+        subject.roles.extend(roles.iter().cloned());
+        subject.roles.sort();
+        subject.roles.dedup();
+    }
+
+    /// Classification of a normalized path: the label of its longest labelled prefix
+    pub fn classification(&self, path: &str) -> Option<&str> {
+        self.labels.iter()
+            .filter(|(prefix, _)| is_below(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, label)| label.as_str())
+    }
+
+    /// Decide a request: any matching deny rule refuses, else the first
+    /// matching allow rule permits, else the request is denied
+    pub fn evaluate(&self, subject: &Subject, request: &AccessRequest<'_>) -> AccessDecision {
+        let classification = self.classification(request.path);
+        let matching: Vec<&AccessRule> = self.rules.iter()
+            .filter(|rule| rule.matches(subject, request, classification))
+            .collect();
+
+        let decided = matching.iter().find(|rule| rule.effect == Effect::Deny)
+            .or_else(|| matching.first());
+        AccessDecision {
+            allowed: decided.is_some_and(|rule| rule.effect == Effect::Allow),
+            rule: decided.map_or_else(|| DEFAULT_DENY_RULE.to_string(), |rule| rule.id.clone()),
+            classification: classification.map(str::to_string),
+        }
+    }
+}
+
+impl FromStr for AccessPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let file: AccessPolicyFile = toml::from_str(s)
+            .map_err(|e| Error::Config(format!("Invalid access policy: {}", e)))?;
+
+        let mut rules = Vec::with_capacity(file.rules.len());
+        for rule in file.rules {
+            if rules.iter().any(|existing: &AccessRule| existing.id == rule.id) {
+                return Err(Error::Config(format!("Duplicate access rule ID: {}", rule.id)));
+            }
+            if rule.users.is_empty() && rule.roles.is_empty() {
+                return Err(Error::Config(format!("Access rule {} names no users or roles", rule.id)));
+            }
+            let days = rule.days.iter()
+                .map(|day| day.parse::<Weekday>().map_err(|_| Error::Config(format!("Invalid day in rule {}: {}", rule.id, day))))
+                .collect::<Result<Vec<_>>>()?;
+
+            rules.push(AccessRule {
+                path: normalize_path(&rule.path)
+                    .map_err(|e| Error::Config(format!("Invalid path in rule {}: {}", rule.id, e)))?,
+                effect: rule.effect,
+                users: rule.users,
+                roles: rule.roles,
+                actions: rule.actions.iter().map(|action| action.parse()).collect::<Result<_>>()?,
+                device_types: rule.device_types.iter().map(|device_type| device_type.parse()).collect::<Result<_>>()?,
+                source_cidrs: rule.source_cidrs.iter().map(|cidr| cidr.parse()).collect::<Result<_>>()?,
+                hours: rule.hours.as_deref().map(str::parse).transpose()?,
+                days,
+                max_file_size: rule.max_file_size,
+                classifications: rule.classifications,
+                id: rule.id,
+            });
+        }
+
+        let labels = file.labels.into_iter()
+            .map(|(prefix, label)| Ok((normalize_path(&prefix)?, label)))
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        Ok(Self { rules, users: file.users, labels })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const POLICY: &str = r#"
+        [users]
+        alice = ["finance"]
+
+        [labels]
+        "/finance" = "confidential"
+        "/finance/public" = "public"
+
+        [[rules]]
+        id = "finance-rw"
+        roles = ["finance"]
+        path = "/finance"
+        actions = ["read", "write", "list", "delete"]
+        device_types = ["yubikey"]
+        source_cidrs = ["10.0.0.0/8"]
+        hours = "07:00-19:00"
+        days = ["mon", "tue", "wed", "thu", "fri"]
+        max_file_size = 1000
+
+        [[rules]]
+        id = "no-confidential-deletes"
+        effect = "deny"
+        roles = ["*"]
+        path = "/"
+        actions = ["delete"]
+        classifications = ["confidential"]
+
+        [[rules]]
+        id = "bob-reports"
+        users = ["bob"]
+        path = "reports/"
+        actions = ["list"]
+    "#;
+
+    fn alice() -> Subject {
+        let mut subject = Subject {
+            user_id: Some("alice".to_string()),
+            roles: vec![],
+            device_types: vec![HardwareType::YubiKey],
+            source_ip: Some("10.1.2.3".parse().unwrap()),
+        };
+        POLICY.parse::<AccessPolicy>().unwrap().assign_roles(&mut subject);
+        subject
+    }
+
+    fn request(action: FileAction, path: &str, size: Option<u64>) -> AccessRequest<'_> {
+        // Wednesday, 10:00 UTC
+        AccessRequest { action, path, size, at: Utc.with_ymd_and_hms(2026, 3, 4, 10, 0, 0).unwrap() }
+    }
+
+    #[test]
+    fn test_grant_and_default_deny() {
+        let policy: AccessPolicy = POLICY.parse().unwrap();
+        let subject = alice();
+        assert_eq!(subject.roles, vec!["finance".to_string()]);
+
+        let decision = policy.evaluate(&subject, &request(FileAction::Write, "/finance/q1.xlsx", Some(100)));
+        assert!(decision.allowed);
+        assert_eq!(decision.rule, "finance-rw");
+        assert_eq!(decision.classification.as_deref(), Some("confidential"));
+
+        // Outside the granted prefix, including a look-alike sibling
+        let decision = policy.evaluate(&subject, &request(FileAction::Read, "/financeX/q1.xlsx", Some(100)));
+        assert!(!decision.allowed);
+        assert_eq!(decision.rule, DEFAULT_DENY_RULE);
+
+        // Bob only lists reports; anonymous sessions get nothing
+        let bob = Subject { user_id: Some("bob".to_string()), ..Default::default() };
+        assert!(policy.evaluate(&bob, &request(FileAction::List, "/reports/2026", None)).allowed);
+        assert!(!policy.evaluate(&bob, &request(FileAction::Read, "/reports/2026/a.pdf", Some(1))).allowed);
+        let anonymous = Subject { user_id: None, ..alice() };
+        assert!(!policy.evaluate(&anonymous, &request(FileAction::List, "/finance", None)).allowed);
+    }
+
+    #[test]
+    fn test_attribute_conditions() {
+        let policy: AccessPolicy = POLICY.parse().unwrap();
+        let subject = alice();
+        let write = |size| request(FileAction::Write, "/finance/q1.xlsx", size);
+
+        assert!(!policy.evaluate(&subject, &write(Some(1001))).allowed);
+        assert!(policy.evaluate(&subject, &request(FileAction::List, "/finance", None)).allowed);
+
+        let tpm_only = Subject { device_types: vec![HardwareType::Tpm], ..alice() };
+        assert!(!policy.evaluate(&tpm_only, &write(Some(1))).allowed);
+
+        let remote = Subject { source_ip: Some("192.168.1.5".parse().unwrap()), ..alice() };
+        assert!(!policy.evaluate(&remote, &write(Some(1))).allowed);
+
+        let mut late = write(Some(1));
+        late.at = Utc.with_ymd_and_hms(2026, 3, 4, 19, 0, 0).unwrap();
+        assert!(!policy.evaluate(&subject, &late).allowed);
+
+        let mut weekend = write(Some(1));
+        weekend.at = Utc.with_ymd_and_hms(2026, 3, 7, 10, 0, 0).unwrap();
+        assert!(!policy.evaluate(&subject, &weekend).allowed);
+    }
+
+    #[test]
+    fn test_deny_overrides_allow() {
+        let policy: AccessPolicy = POLICY.parse().unwrap();
+        let subject = alice();
+
+        let decision = policy.evaluate(&subject, &request(FileAction::Delete, "/finance/q1.xlsx", Some(1)));
+        assert!(!decision.allowed);
+        assert_eq!(decision.rule, "no-confidential-deletes");
+
+        // The longer label prefix makes this path public, so the deny rule does not match
+        let decision = policy.evaluate(&subject, &request(FileAction::Delete, "/finance/public/memo.txt", Some(1)));
+        assert!(decision.allowed);
+        assert_eq!(decision.classification.as_deref(), Some("public"));
+    }
+
+    #[test]
+    fn test_paths_and_networks() {
+        assert_eq!(normalize_path("a//b/./c/").unwrap(), "/a/b/c");
+        assert_eq!(normalize_path("").unwrap(), "/");
+        assert!(normalize_path("a/../../etc/passwd").is_err());
+
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains("10.255.0.1".parse().unwrap()));
+        assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::1".parse().unwrap()));
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains("2001:db8::1".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+
+        let night: TimeWindow = "22:00-06:00".parse().unwrap();
+        assert!(night.contains(NaiveTime::from_hms_opt(23, 0, 0).unwrap()));
+        assert!(night.contains(NaiveTime::from_hms_opt(5, 59, 0).unwrap()));
+        assert!(!night.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn test_invalid_policies() {
+        assert!("[[rules]]\nid = \"a\"\npath = \"/\"\nactions = [\"read\"]".parse::<AccessPolicy>().is_err());
+        assert!("[[rules]]\nid = \"a\"\nroles = [\"*\"]\npath = \"/\"\nactions = [\"chmod\"]".parse::<AccessPolicy>().is_err());
+        assert!("[[rules]]\nid = \"a\"\nroles = [\"*\"]\npath = \"/\"\nactions = [\"read\"]\nhours = \"9-5\"".parse::<AccessPolicy>().is_err());
+        assert!("[[rules]]\nid = \"a\"\nroles = [\"*\"]\npath = \"/\"\nactions = [\"read\"]\n[[rules]]\nid = \"a\"\nroles = [\"*\"]\npath = \"/\"\nactions = [\"read\"]".parse::<AccessPolicy>().is_err());
+    }
+}
@@ -0,0 +1,273 @@
+//! LSFTP authentication agent protocol
+//!
+//! `lsftp-agent` keeps hardware devices open and unlocked between client
+//! invocations, much like ssh-agent keeps keys. Clients find it through the
+//! `LSFTP_AUTH_SOCK` environment variable and ask it to sign their bound
+//! challenges and to store the resumption tickets servers hand out.
+//!
+//! Requests and responses are postcard-encoded and prefixed with their
+//! length as a big-endian u32. The agent only answers peers running as its
+//! own user, and clients only talk to an agent running as theirs.
+
+use crate::auth::{HardwareAttestation, HardwareType};
+use crate::error::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+/// Environment variable naming the agent socket
+pub const AGENT_SOCKET_ENV: &str = "LSFTP_AUTH_SOCK";
+
+/// Largest request or response accepted on the socket
+pub const MAX_AGENT_MESSAGE: usize = 1024 * 1024;
+
+/// Agent socket named by `LSFTP_AUTH_SOCK`
+pub fn socket_from_env() -> Option<PathBuf> {
+    std::env::var_os(AGENT_SOCKET_ENV).filter(|path| !path.is_empty()).map(PathBuf::from)
+}
+
+/// Default socket: `$XDG_RUNTIME_DIR/lsftp/agent.sock`, else a per-user directory under /tmp
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("lsftp").join("agent.sock"),
+        _ => PathBuf::from(format!("/tmp/lsftp-{}", nix::unistd::geteuid())).join("agent.sock"),
+    }
+}
+
+/// Request to the agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AgentRequest {
+    /// Open a device and keep it unlocked; the PIN, if any, is kept for later signatures
+    AddDevice {
+        device_type: HardwareType,
+        device_path: Option<String>,
+        pin: Option<Vec<u8>>,
+        confirm: bool,
+    },
+    /// Close one device
+    RemoveDevice { device_id: String },
+    /// Close every device and forget every ticket
+    RemoveAll,
+    /// Describe the held devices
+    ListDevices,
+    /// Sign a bound challenge for `server` with a held device
+    Sign {
+        device_type: HardwareType,
+        device_path: Option<String>,
+        message: Vec<u8>,
+        server: String,
+    },
+    /// Resumption ticket cached for a server
+    GetTicket { server: String },
+    /// Cache a resumption ticket for a server
+    PutTicket { server: String, ticket: Vec<u8>, expires_at: u64 },
+    /// Refuse all requests until unlocked with the same passphrase
+    Lock { passphrase: Vec<u8> },
+    /// Lift a lock
+    Unlock { passphrase: Vec<u8> },
+}
+
+/// Device held by the agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentDevice {
+    /// Device type
+    pub device_type: HardwareType,
+    /// Device path or spec it was added with
+    pub device_path: Option<String>,
+    /// Device identifier
+    pub device_id: String,
+    /// Whether each signature needs confirmation
+    pub confirm: bool,
+    /// Last signature (seconds since the epoch)
+    pub last_used: Option<u64>,
+}
+
+/// Agent answer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AgentResponse {
+    /// Request carried out
+    Success,
+    /// Request refused or failed
+    Failure(String),
+    /// Held devices
+    Devices(Vec<AgentDevice>),
+    /// Challenge signature
+    Signature {
+        device_id: String,
+        signature: Vec<u8>,
+        attestation: HardwareAttestation,
+    },
+    /// The agent does not hold the requested device
+    UnknownDevice,
+    /// Cached resumption ticket, if any
+    Ticket(Option<Vec<u8>>),
+}
+
+/// Read one length-prefixed message
+pub async fn read_frame<R, T>(reader: &mut R) -> Result<T>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let length = reader.read_u32().await? as usize;
+    if length > MAX_AGENT_MESSAGE {
+        return Err(Error::Protocol(format!("Agent message of {} bytes exceeds the limit", length)));
+    }
+    let mut buffer = zeroize::Zeroizing::new(vec![0u8; length]);
+    reader.read_exact(&mut buffer).await?;
+    Ok(postcard::from_bytes(&buffer)?)
+}
+
+/// Write one length-prefixed message
+pub async fn write_frame<W, T>(writer: &mut W, message: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let buffer = zeroize::Zeroizing::new(postcard::to_allocvec(message)?);
+    if buffer.len() > MAX_AGENT_MESSAGE {
+        return Err(Error::Protocol(format!("Agent message of {} bytes exceeds the limit", buffer.len())));
+    }
+    writer.write_u32(buffer.len() as u32).await?;
+    writer.write_all(&buffer).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Connection to a running agent
+pub struct AgentClient {
+    stream: UnixStream,
+}
+
+impl AgentClient {
+    /// Connect to the agent socket; nothing is sent unless the agent runs as this user
+    pub async fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path).await
+            .map_err(|e| Error::Transport(format!("Failed to connect to agent at {:?}: {}", path, e)))?;
+
+        // Another local user may have planted a socket to collect PINs or answer signatures
+        let peer = stream.peer_cred()
+            .map_err(|e| Error::Transport(format!("Failed to identify agent at {:?}: {}", path, e)))?;
+        let uid = nix::unistd::geteuid().as_raw();
+        if peer.uid() != uid {
+            return Err(Error::Transport(format!(
+                "Agent at {:?} runs as uid {}, not uid {}", path, peer.uid(), uid
+            )));
+        }
+
+        Ok(Self { stream })
+    }
+
+    /// Send a request and wait for the answer
+    pub async fn request(&mut self, request: &AgentRequest) -> Result<AgentResponse> {
+        write_frame(&mut self.stream, request).await?;
+        read_frame(&mut self.stream).await
+    }
+
+    /// Send a request that only succeeds or fails
+    pub async fn execute(&mut self, request: &AgentRequest) -> Result<()> {
+        match self.request(request).await? {
+            AgentResponse::Success => Ok(()),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    /// Devices held by the agent
+    pub async fn devices(&mut self) -> Result<Vec<AgentDevice>> {
+        match self.request(&AgentRequest::ListDevices).await? {
+            AgentResponse::Devices(devices) => Ok(devices),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    /// Sign with a held device; `None` if the agent does not hold it
+    pub async fn sign(
+        &mut self,
+        device_type: HardwareType,
+        device_path: Option<String>,
+        message: &[u8],
+        server: &str,
+    ) -> Result<Option<(String, Vec<u8>, HardwareAttestation)>> {
+        let request = AgentRequest::Sign {
+            device_type,
+            device_path,
+            message: message.to_vec(),
+            server: server.to_string(),
+        };
+        match self.request(&request).await? {
+            AgentResponse::Signature { device_id, signature, attestation } => Ok(Some((device_id, signature, attestation))),
+            AgentResponse::UnknownDevice => Ok(None),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    /// Resumption ticket cached for a server
+    pub async fn ticket(&mut self, server: &str) -> Result<Option<Vec<u8>>> {
+        match self.request(&AgentRequest::GetTicket { server: server.to_string() }).await? {
+            AgentResponse::Ticket(ticket) => Ok(ticket),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    /// Cache a resumption ticket for a server
+    pub async fn put_ticket(&mut self, server: &str, ticket: Vec<u8>, expires_at: u64) -> Result<()> {
+        self.execute(&AgentRequest::PutTicket { server: server.to_string(), ticket, expires_at }).await
+    }
+
+    fn unexpected(response: AgentResponse) -> Error {
+        match response {
+            AgentResponse::Failure(reason) => Error::HardwareAuth(format!("Agent refused: {}", reason)),
+            AgentResponse::UnknownDevice => Error::HardwareAuth("Agent does not hold that device".to_string()),
+            other => Error::Protocol(format!("Unexpected agent response: {:?}", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let (mut client, mut agent) = UnixStream::pair().unwrap();
+        let request = AgentRequest::Sign {
+            device_type: HardwareType::YubiKey,
+            device_path: Some("slot=9a".to_string()),
+            message: vec![1, 2, 3],
+            server: "files.example.com:8443".to_string(),
+        };
+
+        write_frame(&mut client, &request).await.unwrap();
+        match read_frame(&mut agent).await.unwrap() {
+            AgentRequest::Sign { device_type, device_path, message, server } => {
+                assert_eq!(device_type, HardwareType::YubiKey);
+                assert_eq!(device_path.as_deref(), Some("slot=9a"));
+                assert_eq!(message, vec![1, 2, 3]);
+                assert_eq!(server, "files.example.com:8443");
+            }
+            other => panic!("unexpected request {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_own_agent() {
+        let dir = std::env::temp_dir().join(format!("lsftp-agent-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("agent.sock");
+        let _listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        assert!(AgentClient::connect(&path).await.is_ok());
+        assert!(AgentClient::connect(&dir.join("missing.sock")).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_rejected() {
+        let (mut client, mut agent) = UnixStream::pair().unwrap();
+        client.write_u32(MAX_AGENT_MESSAGE as u32 + 1).await.unwrap();
+        assert!(read_frame::<_, AgentRequest>(&mut agent).await.is_err());
+    }
+}
@@ -3,8 +3,12 @@
 //! This module provides structured logging, audit trails, and
 //! compliance features for LSFTP operations.
 
+use crate::access::{AccessDecision, FileAction};
+use crate::auth::AuthResult;
 use crate::error::Result;
-use crate::crypto::CryptoOperations;
+use crate::crypto::{CryptoOperations, TaggedDigest};
+use crate::encryption::EncryptionViolation;
+use crate::protocol::PolicyUpdatePayload;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -36,6 +40,8 @@ pub enum AuditAction {
     FileDownload,
     /// File deletion
     FileDelete,
+    /// Directory listing
+    DirectoryList,
     /// Policy change
     PolicyChange,
     /// Session start
@@ -48,6 +54,8 @@ pub enum AuditAction {
     SecurityEvent,
     /// System event
     SystemEvent,
+    /// Key backup, recovery or rotation
+    KeyManagement,
 }
 
 /// Audit result
@@ -79,7 +87,7 @@ pub struct AuditEvent {
     /// File path (if applicable)
     pub file_path: Option<String>,
     /// File hash (if applicable)
-    pub file_hash: Option<[u8; 32]>,
+    pub file_hash: Option<TaggedDigest>,
     /// Source IP address
     pub source_ip: Option<String>,
     /// Session ID
@@ -139,7 +147,7 @@ impl AuditEvent {
     }
 
     /// Set file hash
-    pub fn with_file_hash(mut self, file_hash: [u8; 32]) -> Self {
+    pub fn with_file_hash(mut self, file_hash: TaggedDigest) -> Self {
         self.file_hash = Some(file_hash);
         self
     }
@@ -292,6 +300,7 @@ impl AuditLogger {
             "source_ip": event.source_ip,
             "hardware_id": event.hardware_id,
             "file_path": event.file_path,
+            "file_hash": event.file_hash.as_ref().map(|hash| format!("{:?}:{}", hash.algorithm, hash.to_hex())),
             "bytes_transferred": event.bytes_transferred,
             "duration_ms": event.duration_ms,
             "result": format!("{:?}", event.result),
@@ -315,6 +324,7 @@ impl AuditLogger {
                 match event.action {
                     AuditAction::SecurityEvent => Severity::Warning,
                     AuditAction::Authentication => Severity::Info,
+                    AuditAction::KeyManagement => Severity::Info,
                     _ => Severity::Debug,
                 }
             }
@@ -322,6 +332,7 @@ impl AuditLogger {
                 match event.action {
                     AuditAction::SecurityEvent => Severity::Critical,
                     AuditAction::Authentication => Severity::Error,
+                    AuditAction::KeyManagement => Severity::Error,
                     _ => Severity::Warning,
                 }
             }
@@ -416,8 +427,9 @@ impl AuditLogger {
             data.extend_from_slice(file_path.as_bytes());
         }
         
-        if let Some(file_hash) = event.file_hash {
-            data.extend_from_slice(&file_hash);
+        if let Some(ref file_hash) = event.file_hash {
+            data.push(file_hash.algorithm as u8);
+            data.extend_from_slice(&file_hash.value);
         }
         
         Ok(data)
@@ -478,12 +490,175 @@ impl SecurityLogger {
         self.audit_logger.log_event(event).await
     }
 
+    /// Log an authentication outcome with its metadata, e.g. the satisfied factors
+    pub async fn log_auth_result(&self, result: &AuthResult, source_ip: Option<String>) -> Result<()> {
+        let mut event = AuditEvent::new(
+            AuditAction::Authentication,
+            if result.success { AuditResult::Success } else { AuditResult::Failure }
+        )
+        .with_user_id(result.user_id.clone().unwrap_or_else(|| "unknown".to_string()))
+        .with_hardware_id(result.device_id.clone().unwrap_or_else(|| "unknown".to_string()))
+        .with_source_ip(source_ip.unwrap_or_else(|| "unknown".to_string()))
+        .with_error_code(if result.success { "none" } else { "AUTH_FAILED" }.to_string());
+
+        event.metadata.extend(result.metadata.clone());
+        self.audit_logger.log_event(event).await
+    }
+
+    /// Log a session ended by the client, e.g. after its bound device was removed
+    pub async fn log_session_end(
+        &self,
+        session_id: Uuid,
+        user_id: Option<String>,
+        hardware_id: Option<String>,
+        reason: &str,
+    ) -> Result<()> {
+        let event = AuditEvent::new(AuditAction::SessionEnd, AuditResult::Success)
+            .with_session_id(session_id)
+            .with_user_id(user_id.unwrap_or_else(|| "unknown".to_string()))
+            .with_hardware_id(hardware_id.unwrap_or_else(|| "unknown".to_string()))
+            .with_metadata("reason".to_string(), reason.to_string());
+
+        self.log_security_event(event).await
+    }
+
+    /// Log a source IP or device locked out after repeated authentication failures
+    pub async fn log_lockout(&self, subject: &str, failures: u32, locked_until: u64) -> Result<()> {
+        let event = AuditEvent::new(AuditAction::SecurityEvent, AuditResult::Failure)
+            .with_error_code("AUTH_LOCKOUT".to_string())
+            .with_metadata("lockout_subject".to_string(), subject.to_string())
+            .with_metadata("failures".to_string(), failures.to_string())
+            .with_metadata("locked_until".to_string(), locked_until.to_string());
+
+        self.log_security_event(event).await
+    }
+
+    /// Log lockouts cleared by an administrator
+    pub async fn log_lockout_cleared(&self, subject: &str, cleared: usize) -> Result<()> {
+        let event = AuditEvent::new(AuditAction::SecurityEvent, AuditResult::Success)
+            .with_metadata("operation".to_string(), "lockout_clear".to_string())
+            .with_metadata("lockout_subject".to_string(), subject.to_string())
+            .with_metadata("cleared".to_string(), cleared.to_string());
+
+        self.log_security_event(event).await
+    }
+
+    /// Log an access control decision with the rule that made it
+    pub async fn log_access_decision(
+        &self,
+        session_id: Uuid,
+        user_id: Option<String>,
+        action: FileAction,
+        path: &str,
+        decision: &AccessDecision,
+    ) -> Result<()> {
+        let audit_action = match action {
+            FileAction::Read => AuditAction::FileDownload,
+            FileAction::Write => AuditAction::FileUpload,
+            FileAction::List => AuditAction::DirectoryList,
+            FileAction::Delete => AuditAction::FileDelete,
+        };
+        let mut event = AuditEvent::new(
+            audit_action,
+            if decision.allowed { AuditResult::Success } else { AuditResult::Denied }
+        )
+        .with_session_id(session_id)
+        .with_user_id(user_id.unwrap_or_else(|| "unknown".to_string()))
+        .with_file_path(path.to_string())
+        .with_metadata("access_rule".to_string(), decision.rule.clone());
+
+        if let Some(classification) = &decision.classification {
+            event = event.with_metadata("classification".to_string(), classification.clone());
+        }
+        if !decision.allowed {
+            event = event.with_error_code("ACCESS_DENIED".to_string());
+        }
+
+        self.log_security_event(event).await
+    }
+
+    /// Log a live policy update with the rules it carried; updates from
+    /// non-administrators or with invalid rules are logged as denied
+    pub async fn log_policy_update(
+        &self,
+        session_id: Uuid,
+        user_id: Option<String>,
+        update: &PolicyUpdatePayload,
+        applied: bool,
+    ) -> Result<()> {
+        let rules: Vec<&str> = update.rules.iter().map(|rule| rule.id.as_str()).collect();
+        let mut event = AuditEvent::new(
+            AuditAction::PolicyChange,
+            if applied { AuditResult::Success } else { AuditResult::Denied }
+        )
+        .with_session_id(session_id)
+        .with_user_id(user_id.unwrap_or_else(|| "unknown".to_string()))
+        .with_metadata("policy_id".to_string(), update.policy_id.to_string())
+        .with_metadata("policy_version".to_string(), update.version.to_string())
+        .with_metadata("policy_rules".to_string(), rules.join(","));
+
+        if !applied {
+            event = event.with_error_code("POLICY_UPDATE_REJECTED".to_string());
+        }
+
+        self.log_security_event(event).await
+    }
+
+    /// Log a session or frame refused for falling short of the encryption requirements
+    pub async fn log_encryption_violation(
+        &self,
+        session_id: Uuid,
+        user_id: Option<String>,
+        violation: &EncryptionViolation,
+    ) -> Result<()> {
+        let mut event = AuditEvent::new(AuditAction::SecurityEvent, AuditResult::Denied)
+            .with_session_id(session_id)
+            .with_user_id(user_id.unwrap_or_else(|| "unknown".to_string()))
+            .with_error_code(violation.code().to_string())
+            .with_metadata("violation".to_string(), violation.to_string());
+
+        if let EncryptionViolation::WeakSuite { offered, required, path } = violation {
+            event = event
+                .with_metadata("offered_level".to_string(), offered.to_string())
+                .with_metadata("required_level".to_string(), required.to_string());
+            if let Some(path) = path {
+                event = event.with_file_path(path.clone());
+            }
+        }
+
+        self.log_security_event(event).await
+    }
+
+    /// Log key management operation
+    pub async fn log_key_management(
+        &self,
+        operation: &str,
+        key_fingerprint: Option<String>,
+        details: HashMap<String, String>,
+        success: bool,
+        error_code: Option<String>,
+    ) -> Result<()> {
+        let mut event = AuditEvent::new(
+            AuditAction::KeyManagement,
+            if success { AuditResult::Success } else { AuditResult::Failure }
+        )
+        .with_metadata("operation".to_string(), operation.to_string())
+        .with_metadata("key_fingerprint".to_string(), key_fingerprint.unwrap_or_else(|| "unknown".to_string()));
+
+        event.metadata.extend(details);
+        if let Some(error_code) = error_code {
+            event = event.with_error_code(error_code);
+        }
+
+        self.log_security_event(event).await
+    }
+
     /// Log file transfer
     pub async fn log_file_transfer(
         &self,
         user_id: Option<String>,
         file_path: Option<String>,
-        file_hash: Option<[u8; 32]>,
+        file_hash: Option<TaggedDigest>,
         bytes_transferred: u64,
         duration_ms: u64,
         action: AuditAction,
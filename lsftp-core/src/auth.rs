@@ -9,6 +9,8 @@ use std::collections::HashMap;
 use std::path::Path;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 // Hardware security modules enabled for Linux
 
@@ -23,14 +25,35 @@ pub enum HardwareType {
     SmartCard,
     /// Hardware Security Module
     Hsm,
+    /// Software-emulated authenticator (insecure, development and CI only)
+    Software,
 }
 
+impl FromStr for HardwareType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "tpm" => Ok(HardwareType::Tpm),
+            "yubikey" => Ok(HardwareType::YubiKey),
+            "smartcard" => Ok(HardwareType::SmartCard),
+            "hsm" => Ok(HardwareType::Hsm),
+            "software" => Ok(HardwareType::Software),
+            _ => Err(Error::Config(format!("Unknown hardware type: {}", s))),
+        }
+    }
+}
+
+/// Set once the process has explicitly opted into development-only backends
+static INSECURE_DEV_ALLOWED: AtomicBool = AtomicBool::new(false);
+
 /// Hardware authentication result
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthResult {
     /// Authentication successful
     pub success: bool,
-    /// User identifier
+    /// User identifier claimed by the device (smart cards report their certificate identity);
+    /// the server never trusts it and resolves the user from the enrollment registry
     pub user_id: Option<String>,
     /// Hardware device identifier
     pub device_id: Option<String>,
@@ -38,6 +61,8 @@ pub struct AuthResult {
     pub timestamp: u64,
     /// Additional metadata
     pub metadata: HashMap<String, String>,
+    /// Signature over the challenge, verifiable with the enrolled device key
+    pub signature: Option<Vec<u8>>,
     /// Error message if authentication failed
     pub error: Option<String>,
 }
@@ -66,8 +91,8 @@ pub trait HardwareAuth {
     /// Perform hardware authentication
     async fn authenticate(&self, challenge: &[u8]) -> Result<AuthResult>;
     
-    /// Generate attestation data
-    async fn generate_attestation(&self) -> Result<HardwareAttestation>;
+    /// Generate attestation data, qualified with the verifier's nonce
+    async fn generate_attestation(&self, nonce: &[u8]) -> Result<HardwareAttestation>;
     
     /// Verify attestation data
     async fn verify_attestation(&self, attestation: &HardwareAttestation) -> Result<bool>;
@@ -96,22 +121,39 @@ pub struct DeviceInfo {
 }
 
 /// TPM 2.0 implementation using tss-esapi
+///
+/// Authentication and quotes use one persistent attestation key, created on
+/// first use at the configured handle (see `tpmkey`).
 pub struct TpmAuth {
     device_path: String,
+    ak_config: crate::tpmkey::TpmAkConfig,
     device_info: Option<DeviceInfo>,
-    tpm_context: Option<tss_esapi::Context>,
+    tpm_context: Option<std::sync::Mutex<tss_esapi::Context>>,
+    attestation_key: Option<crate::tpmkey::AttestationKey>,
 }
 
 impl TpmAuth {
     pub fn new(device_path: String) -> Self {
+        Self::with_ak_config(device_path, crate::tpmkey::TpmAkConfig::default())
+    }
+
+    /// TPM authenticator using the AK at a specific handle
+    pub fn with_ak_config(device_path: String, ak_config: crate::tpmkey::TpmAkConfig) -> Self {
         Self {
             device_path,
+            ak_config,
             device_info: None,
             tpm_context: None,
+            attestation_key: None,
         }
     }
 
-    async fn get_tpm_info(&self) -> Result<DeviceInfo> {
+    /// The attestation key, for enrollment
+    pub fn attestation_key(&self) -> Option<&crate::tpmkey::AttestationKey> {
+        self.attestation_key.as_ref()
+    }
+
+    async fn get_tpm_info(&self, device_id: String, algorithm: crate::tpmkey::TpmKeyAlgorithm) -> Result<DeviceInfo> {
         // Check if TPM device exists
         if !Path::new(&self.device_path).exists() {
             return Err(Error::HardwareAuth(format!("TPM device not found at {}", self.device_path)));
@@ -127,25 +169,31 @@ impl TpmAuth {
 
         Ok(DeviceInfo {
             device_type: HardwareType::Tpm,
-            device_id: "tpm2.0".to_string(),
+            device_id,
             manufacturer,
             model: "TPM 2.0".to_string(),
             firmware_version: "1.0".to_string(),
             supported_algorithms: vec![
                 "SHA256".to_string(),
-                "RSA2048".to_string(),
-                "ECC_P256".to_string(),
-                "AES256".to_string(),
+                match algorithm {
+                    crate::tpmkey::TpmKeyAlgorithm::Rsa2048 => "RSA2048".to_string(),
+                    crate::tpmkey::TpmKeyAlgorithm::EccP256 => "ECC_P256".to_string(),
+                },
             ],
             capabilities: vec![
                 "PCR".to_string(),
                 "Attestation".to_string(),
                 "Sealing".to_string(),
-                "KeyGeneration".to_string(),
+                "PersistentAttestationKey".to_string(),
             ],
         })
     }
 
+    /// Device information for detection, without loading or creating the AK
+    async fn probe(&self) -> Result<DeviceInfo> {
+        self.get_tpm_info(self.device_path.clone(), self.ak_config.algorithm).await
+    }
+
     async fn initialize_tpm_context(&mut self) -> Result<()> {
         // Initialize TPM context using tss-esapi
         let tcti = tss_esapi::tcti_ldr::TctiNameConf::from_environment_var()
@@ -154,9 +202,20 @@ impl TpmAuth {
         let context = tss_esapi::Context::new(tcti)
             .map_err(|e| Error::HardwareAuth(format!("Failed to create TPM context: {}", e)))?;
         
-        self.tpm_context = Some(context);
+        self.tpm_context = Some(std::sync::Mutex::new(context));
         Ok(())
     }
+
+    /// Context and AK, or an error before `initialize`
+    fn context_and_key(&self) -> Result<(std::sync::MutexGuard<'_, tss_esapi::Context>, &crate::tpmkey::AttestationKey)> {
+        let context = self.tpm_context.as_ref()
+            .ok_or_else(|| Error::HardwareAuth("TPM context not initialized".to_string()))?
+            .lock()
+            .map_err(|_| Error::HardwareAuth("TPM context poisoned".to_string()))?;
+        let key = self.attestation_key.as_ref()
+            .ok_or_else(|| Error::HardwareAuth("TPM attestation key not loaded".to_string()))?;
+        Ok((context, key))
+    }
 }
 
 #[async_trait::async_trait]
@@ -164,131 +223,83 @@ impl HardwareAuth for TpmAuth {
     async fn initialize(&mut self) -> Result<()> {
         // Initialize TPM context
         self.initialize_tpm_context().await?;
-        
+
+        // Load the persistent AK, creating it on first use
+        let key = {
+            let context = self.tpm_context.as_mut()
+                .ok_or_else(|| Error::HardwareAuth("TPM context not initialized".to_string()))?
+                .get_mut()
+                .map_err(|_| Error::HardwareAuth("TPM context poisoned".to_string()))?;
+            crate::tpmkey::AttestationKey::load_or_create(context, &self.ak_config)?
+        };
+
         // Get device information
-        self.device_info = Some(self.get_tpm_info().await?);
-        
-        // Start TPM if needed
-        if let Some(context) = &mut self.tpm_context {
-            context.startup(tss_esapi::structures::StartupType::Clear)
-                .map_err(|e| Error::HardwareAuth(format!("Failed to startup TPM: {}", e)))?;
-        }
-        
+        self.device_info = Some(self.get_tpm_info(key.device_id(), key.algorithm).await?);
+        self.attestation_key = Some(key);
+
         Ok(())
     }
 
     async fn authenticate(&self, challenge: &[u8]) -> Result<AuthResult> {
-        let context = self.tpm_context.as_ref()
-            .ok_or_else(|| Error::HardwareAuth("TPM context not initialized".to_string()))?;
-
-        // Create a signing key for authentication
-        let key_handle = context.create_primary(
-            tss_esapi::structures::Auth::Exclusive,
-            tss_esapi::structures::Hierarchy::Endorsement,
-            tss_esapi::structures::PublicBuilder::new()
-                .public_algorithm(tss_esapi::structures::PublicAlgorithm::Rsa)
-                .name_hashing_algorithm(tss_esapi::structures::HashingAlgorithm::Sha256)
-                .object_attributes(tss_esapi::structures::ObjectAttributes::default())
-                .parameters(tss_esapi::structures::PublicRsaParametersBuilder::new()
-                    .scheme(tss_esapi::structures::PublicRsaParameters::default())
-                    .key_bits(2048)
-                    .exponent(0)
-                    .symmetric(tss_esapi::structures::PublicRsaParameters::default())
-                    .build()
-                    .map_err(|e| Error::HardwareAuth(format!("Failed to build RSA parameters: {}", e)))?)
-                .unique_identifier(tss_esapi::structures::PublicRsaParameters::default())
-                .build()
-                .map_err(|e| Error::HardwareAuth(format!("Failed to build public: {}", e)))?,
-            tss_esapi::structures::Digest::try_from(challenge)
-                .map_err(|e| Error::HardwareAuth(format!("Failed to create digest: {}", e)))?,
-            None,
-        ).map_err(|e| Error::HardwareAuth(format!("Failed to create primary key: {}", e)))?;
-
-        // Sign the challenge
-        let signature = context.sign(
-            key_handle,
-            tss_esapi::structures::Digest::try_from(challenge)
-                .map_err(|e| Error::HardwareAuth(format!("Failed to create digest: {}", e)))?,
-            tss_esapi::structures::SignatureScheme::RsaSsa(tss_esapi::structures::HashingAlgorithm::Sha256),
-            None,
-        ).map_err(|e| Error::HardwareAuth(format!("Failed to sign challenge: {}", e)))?;
-
-        let device_id = self.device_info.as_ref()
-            .map(|info| info.device_id.clone())
-            .unwrap_or_else(|| "tpm2.0".to_string());
+        let (mut context, key) = self.context_and_key()?;
+        let signature = key.sign(&mut context, challenge)?;
 
         Ok(AuthResult {
             success: true,
-            user_id: Some("tpm_user".to_string()),
-            device_id: Some(device_id),
+            user_id: None,
+            device_id: Some(key.device_id()),
             timestamp: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
             metadata: {
                 let mut map = HashMap::new();
-                map.insert("signature_algorithm".to_string(), "RSA-SHA256".to_string());
-                map.insert("key_size".to_string(), "2048".to_string());
+                map.insert("signature_algorithm".to_string(), format!("{:?}", key.algorithm.device_key_algorithm()));
+                map.insert("ak_handle".to_string(), format!("{:#010x}", self.ak_config.handle));
+                map.insert("public_key".to_string(), hex::encode(&key.public_key));
                 map
             },
+            signature: Some(signature),
             error: None,
         })
     }
 
-    async fn generate_attestation(&self) -> Result<HardwareAttestation> {
-        let context = self.tpm_context.as_ref()
-            .ok_or_else(|| Error::HardwareAuth("TPM context not initialized".to_string()))?;
-
-        // Create attestation key
-        let attestation_key = context.create_primary(
-            tss_esapi::structures::Auth::Exclusive,
-            tss_esapi::structures::Hierarchy::Endorsement,
-            tss_esapi::structures::PublicBuilder::new()
-                .public_algorithm(tss_esapi::structures::PublicAlgorithm::Rsa)
-                .name_hashing_algorithm(tss_esapi::structures::HashingAlgorithm::Sha256)
-                .object_attributes(tss_esapi::structures::ObjectAttributes::default())
-                .parameters(tss_esapi::structures::PublicRsaParametersBuilder::new()
-                    .scheme(tss_esapi::structures::PublicRsaParameters::default())
-                    .key_bits(2048)
-                    .exponent(0)
-                    .symmetric(tss_esapi::structures::PublicRsaParameters::default())
-                    .build()
-                    .map_err(|e| Error::HardwareAuth(format!("Failed to build RSA parameters: {}", e)))?)
-                .unique_identifier(tss_esapi::structures::PublicRsaParameters::default())
-                .build()
-                .map_err(|e| Error::HardwareAuth(format!("Failed to build public: {}", e)))?,
-            tss_esapi::structures::Digest::try_from(&[0u8; 32])
-                .map_err(|e| Error::HardwareAuth(format!("Failed to create digest: {}", e)))?,
-            None,
-        ).map_err(|e| Error::HardwareAuth(format!("Failed to create attestation key: {}", e)))?;
-
-        // Generate quote (attestation)
-        let quote = context.quote(
-            attestation_key,
-            tss_esapi::structures::Digest::try_from(&[0u8; 32])
-                .map_err(|e| Error::HardwareAuth(format!("Failed to create digest: {}", e)))?,
-            tss_esapi::structures::SignatureScheme::RsaSsa(tss_esapi::structures::HashingAlgorithm::Sha256),
-            None,
-        ).map_err(|e| Error::HardwareAuth(format!("Failed to generate quote: {}", e)))?;
-
-        let device_id = self.device_info.as_ref()
-            .map(|info| info.device_id.clone())
-            .unwrap_or_else(|| "tpm2.0".to_string());
+    async fn generate_attestation(&self, nonce: &[u8]) -> Result<HardwareAttestation> {
+        let (mut context, key) = self.context_and_key()?;
+
+        // Quote the SHA-256 PCR bank, qualified with the verifier's nonce
+        let pcr_slots = crate::tpmquote::QUOTE_PCRS.iter()
+            .map(|&index| tss_esapi::structures::PcrSlot::try_from(1u32 << index)
+                .map_err(|e| Error::HardwareAuth(format!("Invalid PCR slot {}: {}", index, e))))
+            .collect::<Result<Vec<_>>>()?;
+        let pcr_selection = tss_esapi::structures::PcrSelectionListBuilder::new()
+            .with_selection(tss_esapi::interface_types::algorithm::HashingAlgorithm::Sha256, &pcr_slots)
+            .build()
+            .map_err(|e| Error::HardwareAuth(format!("Failed to build PCR selection: {}", e)))?;
+
+        let (attestation_data, signature) = key.quote(&mut context, nonce, pcr_selection)?;
 
         Ok(HardwareAttestation {
             device_type: HardwareType::Tpm,
-            device_id,
-            attestation_data: quote.quoted.to_vec(),
-            signature: quote.signature.signature().to_vec(),
+            device_id: key.device_id(),
+            attestation_data,
+            signature,
             certificate_chain: vec![], // TPM certificates would be loaded here
         })
     }
 
+    /// Structural check only: a signed, TPM-generated quote over the SHA-256 PCRs.
+    /// Trust decisions (attestation key, nonce, golden PCRs) are made by the server
+    /// with `tpmquote::verify_quote` against the device's enrollment.
     async fn verify_attestation(&self, attestation: &HardwareAttestation) -> Result<bool> {
-        // Verify TPM attestation signature
-        // This would involve verifying the quote signature and checking PCR values
-        // For now, return true if attestation data is not empty
-        Ok(!attestation.attestation_data.is_empty())
+        if attestation.device_type != HardwareType::Tpm || attestation.signature.is_empty() {
+            return Ok(false);
+        }
+
+        match crate::tpmquote::Quote::parse(&attestation.attestation_data) {
+            Ok(quote) => Ok(quote.selects_sha256(&crate::tpmquote::QUOTE_PCRS)),
+            Err(_) => Ok(false),
+        }
     }
 
     async fn get_device_info(&self) -> Result<DeviceInfo> {
@@ -298,43 +309,47 @@ impl HardwareAuth for TpmAuth {
 }
 
 /// YubiKey implementation using yubikey crate
+///
+/// Signs challenges with the PIV key in the configured slot of the selected
+/// YubiKey (see `piv`); the PIN is requested through a `PinPrompt` as the
+/// key's PIN policy requires.
 pub struct YubiKeyAuth {
-    device_path: Option<String>,
+    config: crate::piv::PivConfig,
+    pin_prompt: Box<dyn crate::piv::PinPrompt>,
+    pin_verified: AtomicBool,
     device_info: Option<DeviceInfo>,
-    yubikey: Option<yubikey::YubiKey>,
+    yubikey: Option<std::sync::Mutex<yubikey::YubiKey>>,
 }
 
 impl YubiKeyAuth {
-    pub fn new(device_path: Option<String>) -> Self {
+    pub fn new(config: crate::piv::PivConfig) -> Self {
+        Self::with_pin_prompt(config, crate::piv::default_pin_prompt())
+    }
+
+    /// YubiKey authenticator with a specific PIN source
+    pub fn with_pin_prompt(config: crate::piv::PivConfig, pin_prompt: Box<dyn crate::piv::PinPrompt>) -> Self {
         Self {
-            device_path,
+            config,
+            pin_prompt,
+            pin_verified: AtomicBool::new(false),
             device_info: None,
             yubikey: None,
         }
     }
 
-    async fn get_yubikey_info(&self) -> Result<DeviceInfo> {
-        // Detect YubiKey devices
-        let devices = yubikey::YubiKey::list()
-            .map_err(|e| Error::HardwareAuth(format!("Failed to list YubiKeys: {}", e)))?;
-
-        if devices.is_empty() {
-            return Err(Error::HardwareAuth("No YubiKey devices found".to_string()));
-        }
+    fn get_yubikey_info(&self, yubikey: &yubikey::YubiKey) -> DeviceInfo {
+        let version = yubikey.version();
 
-        let device = &devices[0]; // Use first available device
-        
-        Ok(DeviceInfo {
+        DeviceInfo {
             device_type: HardwareType::YubiKey,
-            device_id: format!("yubikey-{}", device.serial()),
+            device_id: format!("yubikey-{}-{}", yubikey.serial(), self.config.slot),
             manufacturer: "Yubico".to_string(),
             model: "YubiKey".to_string(),
-            firmware_version: format!("{}.{}.{}", device.version().major, device.version().minor, device.version().build),
+            firmware_version: format!("{}.{}.{}", version.major, version.minor, version.patch),
             supported_algorithms: vec![
-                "PIV".to_string(),
-                "OpenPGP".to_string(),
-                "FIDO2".to_string(),
-                "OTP".to_string(),
+                "RSA2048".to_string(),
+                "ECC_P256".to_string(),
+                "ECC_P384".to_string(),
             ],
             capabilities: vec![
                 "Authentication".to_string(),
@@ -342,104 +357,135 @@ impl YubiKeyAuth {
                 "KeyGeneration".to_string(),
                 "Attestation".to_string(),
             ],
-        })
+        }
     }
 
-    async fn initialize_yubikey(&mut self) -> Result<()> {
-        // Connect to YubiKey
-        let devices = yubikey::YubiKey::list()
-            .map_err(|e| Error::HardwareAuth(format!("Failed to list YubiKeys: {}", e)))?;
+    fn lock_yubikey(&self) -> Result<std::sync::MutexGuard<'_, yubikey::YubiKey>> {
+        self.yubikey.as_ref()
+            .ok_or_else(|| Error::HardwareAuth("YubiKey not initialized".to_string()))?
+            .lock()
+            .map_err(|_| Error::HardwareAuth("YubiKey handle poisoned".to_string()))
+    }
 
-        if devices.is_empty() {
-            return Err(Error::HardwareAuth("No YubiKey devices found".to_string()));
-        }
+    fn device_id(&self) -> String {
+        self.device_info.as_ref()
+            .map(|info| info.device_id.clone())
+            .unwrap_or_else(|| "yubikey".to_string())
+    }
 
-        let yubikey = yubikey::YubiKey::open(&devices[0])
-            .map_err(|e| Error::HardwareAuth(format!("Failed to open YubiKey: {}", e)))?;
+    /// Verify the PIN when the key's PIN policy requires it for this signature
+    fn verify_pin(&self, yubikey: &mut yubikey::YubiKey) -> Result<()> {
+        match self.config.pin_policy {
+            crate::piv::PivPinPolicy::Never => return Ok(()),
+            crate::piv::PivPinPolicy::Once if self.pin_verified.load(Ordering::SeqCst) => return Ok(()),
+            _ => {}
+        }
 
-        self.yubikey = Some(yubikey);
+        let pin = self.pin_prompt.pin(&format!("YubiKey {}", yubikey.serial()))?;
+        yubikey.verify_pin(pin.as_slice())
+            .map_err(|e| Error::HardwareAuth(format!("YubiKey PIN verification failed: {}", e)))?;
+        self.pin_verified.store(true, Ordering::SeqCst);
         Ok(())
     }
+
+    /// Sign `message` with the slot key
+    fn sign(&self, yubikey: &mut yubikey::YubiKey, message: &[u8]) -> Result<Vec<u8>> {
+        self.verify_pin(yubikey)?;
+
+        if self.config.touch_policy != crate::piv::PivTouchPolicy::Never {
+            tracing::info!("Touch the YubiKey to confirm the signature");
+        }
+
+        let signature = yubikey::piv::sign_data(
+            yubikey,
+            &self.config.algorithm.sign_input(message),
+            self.config.algorithm.algorithm_id(),
+            self.config.slot.slot_id()?,
+        ).map_err(|e| Error::HardwareAuth(format!("Failed to sign with PIV slot {}: {}", self.config.slot, e)))?;
+
+        Ok(signature.to_vec())
+    }
+
+    /// Generate the slot key with the configured algorithm and policies,
+    /// replacing any key already in the slot; returns its public key
+    pub fn provision(&self) -> Result<Vec<u8>> {
+        let mut yubikey = self.lock_yubikey()?;
+
+        yubikey.authenticate(crate::piv::management_key()?)
+            .map_err(|e| Error::HardwareAuth(format!("PIV management key rejected: {}", e)))?;
+
+        let public_key = yubikey::piv::generate(
+            &mut yubikey,
+            self.config.slot.slot_id()?,
+            self.config.algorithm.algorithm_id(),
+            self.config.pin_policy.policy(),
+            self.config.touch_policy.policy(),
+        ).map_err(|e| Error::HardwareAuth(format!("Failed to generate key in PIV slot {}: {}", self.config.slot, e)))?;
+
+        Ok(public_key.subject_public_key.raw_bytes().to_vec())
+    }
 }
 
 #[async_trait::async_trait]
 impl HardwareAuth for YubiKeyAuth {
     async fn initialize(&mut self) -> Result<()> {
-        // Initialize YubiKey connection
-        self.initialize_yubikey().await?;
-        
+        // Open the selected YubiKey
+        let yubikey = crate::piv::open(self.config.serial)?;
+
         // Get device information
-        self.device_info = Some(self.get_yubikey_info().await?);
-        
+        self.device_info = Some(self.get_yubikey_info(&yubikey));
+        self.yubikey = Some(std::sync::Mutex::new(yubikey));
+
         Ok(())
     }
 
     async fn authenticate(&self, challenge: &[u8]) -> Result<AuthResult> {
-        let yubikey = self.yubikey.as_ref()
-            .ok_or_else(|| Error::HardwareAuth("YubiKey not initialized".to_string()))?;
-
-        // Use PIV authentication
-        let piv = yubikey.piv()
-            .map_err(|e| Error::HardwareAuth(format!("Failed to access PIV: {}", e)))?;
-
-        // Generate signature using PIV key
-        let signature = piv.sign_data(
-            yubikey::piv::Slot::Authentication,
-            challenge,
-            yubikey::piv::Algorithm::Rsa2048,
-        ).map_err(|e| Error::HardwareAuth(format!("Failed to sign with PIV: {}", e)))?;
-
-        let device_id = self.device_info.as_ref()
-            .map(|info| info.device_id.clone())
-            .unwrap_or_else(|| "yubikey".to_string());
+        let mut yubikey = self.lock_yubikey()?;
+        let signature = self.sign(&mut yubikey, challenge)?;
 
         Ok(AuthResult {
             success: true,
-            user_id: Some("yubikey_user".to_string()),
-            device_id: Some(device_id),
+            user_id: None,
+            device_id: Some(self.device_id()),
             timestamp: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
             metadata: {
                 let mut map = HashMap::new();
-                map.insert("signature_algorithm".to_string(), "RSA-SHA256".to_string());
-                map.insert("key_slot".to_string(), "Authentication".to_string());
+                map.insert("signature_algorithm".to_string(), format!("{:?}", self.config.algorithm.device_key_algorithm()));
+                map.insert("key_slot".to_string(), self.config.slot.to_string());
                 map
             },
+            signature: Some(signature),
             error: None,
         })
     }
 
-    async fn generate_attestation(&self) -> Result<HardwareAttestation> {
-        let yubikey = self.yubikey.as_ref()
-            .ok_or_else(|| Error::HardwareAuth("YubiKey not initialized".to_string()))?;
-
-        // Use FIDO2 for attestation
-        let fido2 = yubikey.fido2()
-            .map_err(|e| Error::HardwareAuth(format!("Failed to access FIDO2: {}", e)))?;
-
-        // Generate attestation data
-        let attestation_data = fido2.get_info()
-            .map_err(|e| Error::HardwareAuth(format!("Failed to get FIDO2 info: {}", e)))?;
-
-        let device_id = self.device_info.as_ref()
-            .map(|info| info.device_id.clone())
-            .unwrap_or_else(|| "yubikey".to_string());
+    /// PIV attestation: the slot's attestation certificate and the f9
+    /// certificate that issued it, with a signature over the nonce by the
+    /// attested key to prove it is live
+    async fn generate_attestation(&self, nonce: &[u8]) -> Result<HardwareAttestation> {
+        let mut yubikey = self.lock_yubikey()?;
+        let certificate_chain = crate::piv::attestation_chain(&mut yubikey, self.config.slot)?;
+        let signature = self.sign(&mut yubikey, nonce)?;
 
         Ok(HardwareAttestation {
             device_type: HardwareType::YubiKey,
-            device_id,
-            attestation_data: attestation_data.to_vec(),
-            signature: vec![], // FIDO2 attestation signature would be generated here
-            certificate_chain: vec![], // FIDO2 certificates would be included here
+            device_id: self.device_id(),
+            attestation_data: certificate_chain[0].clone(),
+            signature,
+            certificate_chain,
         })
     }
 
+    /// Structural check only: a signed slot certificate followed by its f9 issuer.
+    /// The chain is recorded with the device when it is enrolled.
     async fn verify_attestation(&self, attestation: &HardwareAttestation) -> Result<bool> {
-        // Verify YubiKey attestation
-        // This would involve verifying FIDO2 attestation data
-        Ok(!attestation.attestation_data.is_empty())
+        Ok(attestation.device_type == HardwareType::YubiKey
+            && !attestation.signature.is_empty()
+            && attestation.certificate_chain.len() == 2
+            && attestation.certificate_chain.first() == Some(&attestation.attestation_data))
     }
 
     async fn get_device_info(&self) -> Result<DeviceInfo> {
@@ -449,62 +495,69 @@ impl HardwareAuth for YubiKeyAuth {
 }
 
 /// Smart Card implementation using pcsc crate
+///
+/// Drives the card's PIV applet (see `smartcard`): the CHUID identifies the
+/// card, the PIV authentication certificate carries the key and the user
+/// identity, and challenges are signed with key 9A after PIN verification.
 pub struct SmartCardAuth {
     reader_name: String,
+    pin_prompt: Box<dyn crate::piv::PinPrompt>,
+    pin_verified: AtomicBool,
     device_info: Option<DeviceInfo>,
     context: Option<pcsc::Context>,
     card: Option<pcsc::Card>,
+    chuid: Option<crate::smartcard::Chuid>,
+    certificate: Option<Vec<u8>>,
+    certificate_info: Option<crate::smartcard::CertificateInfo>,
 }
 
 impl SmartCardAuth {
     pub fn new(reader_name: String) -> Self {
+        Self::with_pin_prompt(reader_name, crate::piv::default_pin_prompt())
+    }
+
+    /// Smart card authenticator with a specific PIN source
+    pub fn with_pin_prompt(reader_name: String, pin_prompt: Box<dyn crate::piv::PinPrompt>) -> Self {
         Self {
             reader_name,
+            pin_prompt,
+            pin_verified: AtomicBool::new(false),
             device_info: None,
             context: None,
             card: None,
+            chuid: None,
+            certificate: None,
+            certificate_info: None,
         }
     }
 
-    async fn get_smartcard_info(&self) -> Result<DeviceInfo> {
-        // List available smart card readers
-        let context = pcsc::Context::establish(pcsc::Scope::User)
-            .map_err(|e| Error::HardwareAuth(format!("Failed to establish PCSC context: {}", e)))?;
-
-        let readers = context.list_readers()
-            .map_err(|e| Error::HardwareAuth(format!("Failed to list readers: {}", e)))?;
-
-        if readers.is_empty() {
-            return Err(Error::HardwareAuth("No smart card readers found".to_string()));
-        }
-
-        // Try to connect to the specified reader
-        let reader = readers.iter().find(|r| r.to_string().contains(&self.reader_name))
-            .ok_or_else(|| Error::HardwareAuth(format!("Reader {} not found", self.reader_name)))?;
+    /// PIV authentication certificate (DER)
+    pub fn certificate(&self) -> Option<&[u8]> {
+        self.certificate.as_deref()
+    }
 
-        let card = context.connect(reader, pcsc::ShareMode::Shared, pcsc::Protocols::ANY)
-            .map_err(|e| Error::HardwareAuth(format!("Failed to connect to smart card: {}", e)))?;
+    /// Key and identity from the PIV authentication certificate
+    pub fn certificate_info(&self) -> Option<&crate::smartcard::CertificateInfo> {
+        self.certificate_info.as_ref()
+    }
 
-        // Get card information using APDU commands
-        let atr = card.status2()
-            .map_err(|e| Error::HardwareAuth(format!("Failed to get card status: {}", e)))?
-            .atr;
+    fn get_smartcard_info(&self) -> Result<DeviceInfo> {
+        let chuid = self.chuid.as_ref()
+            .ok_or_else(|| Error::HardwareAuth("Smart card not initialized".to_string()))?;
+        let certificate = self.certificate_info.as_ref()
+            .ok_or_else(|| Error::HardwareAuth("Smart card not initialized".to_string()))?;
 
         Ok(DeviceInfo {
             device_type: HardwareType::SmartCard,
-            device_id: format!("smartcard-{}", hex::encode(&atr[..8])),
-            manufacturer: "Unknown".to_string(),
-            model: "Smart Card".to_string(),
-            firmware_version: "1.0".to_string(),
-            supported_algorithms: vec![
-                "RSA".to_string(),
-                "ECC".to_string(),
-                "AES".to_string(),
-            ],
+            device_id: chuid.device_id(),
+            manufacturer: certificate.issuer_organization.clone().unwrap_or_else(|| "Unknown".to_string()),
+            model: "PIV Card".to_string(),
+            firmware_version: chuid.expiration.clone().map(|date| format!("expires {}", date)).unwrap_or_default(),
+            supported_algorithms: vec![format!("{:?}", certificate.algorithm.device_key_algorithm())],
             capabilities: vec![
                 "Authentication".to_string(),
                 "DigitalSignature".to_string(),
-                "KeyGeneration".to_string(),
+                "Certificate".to_string(),
             ],
         })
     }
@@ -514,24 +567,68 @@ impl SmartCardAuth {
         let context = pcsc::Context::establish(pcsc::Scope::User)
             .map_err(|e| Error::HardwareAuth(format!("Failed to establish PCSC context: {}", e)))?;
 
-        let readers = context.list_readers()
-            .map_err(|e| Error::HardwareAuth(format!("Failed to list readers: {}", e)))?;
+        let mut buffer = vec![0u8; context.list_readers_len()
+            .map_err(|e| Error::HardwareAuth(format!("Failed to list readers: {}", e)))?];
+        let readers: Vec<_> = context.list_readers(&mut buffer)
+            .map_err(|e| Error::HardwareAuth(format!("Failed to list readers: {}", e)))?
+            .collect();
 
         if readers.is_empty() {
             return Err(Error::HardwareAuth("No smart card readers found".to_string()));
         }
 
         // Connect to smart card
-        let reader = readers.iter().find(|r| r.to_string().contains(&self.reader_name))
+        let reader = readers.iter().find(|r| r.to_string_lossy().contains(&self.reader_name))
             .ok_or_else(|| Error::HardwareAuth(format!("Reader {} not found", self.reader_name)))?;
 
         let card = context.connect(reader, pcsc::ShareMode::Shared, pcsc::Protocols::ANY)
             .map_err(|e| Error::HardwareAuth(format!("Failed to connect to smart card: {}", e)))?;
 
+        // SELECT the PIV applet, then read the CHUID and the authentication certificate
+        use crate::smartcard::{self, Apdu};
+        smartcard::transmit(&card, &Apdu::select_piv())?;
+        let chuid = smartcard::transmit(&card, &Apdu::get_data(smartcard::OBJECT_CHUID))?;
+        let chuid = smartcard::Chuid::parse(smartcard::data_object(&chuid)?)?;
+        let certificate = smartcard::transmit(&card, &Apdu::get_data(smartcard::OBJECT_AUTHENTICATION_CERTIFICATE))?;
+        let certificate = smartcard::certificate_from_object(smartcard::data_object(&certificate)?)?;
+
+        self.certificate_info = Some(smartcard::CertificateInfo::from_der(&certificate)?);
+        self.certificate = Some(certificate);
+        self.chuid = Some(chuid);
         self.context = Some(context);
         self.card = Some(card);
         Ok(())
     }
+
+    fn device_id(&self) -> String {
+        self.device_info.as_ref()
+            .map(|info| info.device_id.clone())
+            .unwrap_or_else(|| "smartcard".to_string())
+    }
+
+    /// VERIFY the PIN once, then sign `message` with the PIV authentication key
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        use crate::smartcard::{self, Apdu};
+
+        let card = self.card.as_ref()
+            .ok_or_else(|| Error::HardwareAuth("Smart card not initialized".to_string()))?;
+        let algorithm = self.certificate_info.as_ref()
+            .ok_or_else(|| Error::HardwareAuth("Smart card not initialized".to_string()))?
+            .algorithm;
+
+        if !self.pin_verified.load(Ordering::SeqCst) {
+            let pin = self.pin_prompt.pin(&format!("smart card in {}", self.reader_name))?;
+            smartcard::transmit(card, &Apdu::verify_pin(pin.as_slice())?)?;
+            self.pin_verified.store(true, Ordering::SeqCst);
+        }
+
+        let response = smartcard::transmit(card, &Apdu::general_authenticate(
+            algorithm,
+            smartcard::KEY_AUTHENTICATION,
+            &algorithm.sign_input(message),
+        ))?;
+        smartcard::signature_from_response(&response)
+    }
 }
 
 #[async_trait::async_trait]
@@ -541,66 +638,61 @@ impl HardwareAuth for SmartCardAuth {
         self.initialize_smartcard().await?;
         
         // Get device information
-        self.device_info = Some(self.get_smartcard_info().await?);
+        self.device_info = Some(self.get_smartcard_info()?);
         
         Ok(())
     }
 
     async fn authenticate(&self, challenge: &[u8]) -> Result<AuthResult> {
-        let card = self.card.as_ref()
+        let signature = self.sign(challenge)?;
+        let certificate = self.certificate_info.as_ref()
             .ok_or_else(|| Error::HardwareAuth("Smart card not initialized".to_string()))?;
 
-        // Use PKCS#11 or APDU commands for authentication
-        // This is a simplified implementation
-        let response = card.transmit(&[0x00, 0x88, 0x00, 0x00, challenge.len() as u8])
-            .map_err(|e| Error::HardwareAuth(format!("Failed to transmit APDU: {}", e)))?;
-
-        let device_id = self.device_info.as_ref()
-            .map(|info| info.device_id.clone())
-            .unwrap_or_else(|| "smartcard".to_string());
-
         Ok(AuthResult {
-            success: response.len() > 0,
-            user_id: Some("smartcard_user".to_string()),
-            device_id: Some(device_id),
+            success: true,
+            user_id: Some(certificate.identity.clone()),
+            device_id: Some(self.device_id()),
             timestamp: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
             metadata: {
                 let mut map = HashMap::new();
-                map.insert("protocol".to_string(), "PCSC".to_string());
+                map.insert("protocol".to_string(), "PIV".to_string());
                 map.insert("reader".to_string(), self.reader_name.clone());
+                map.insert("signature_algorithm".to_string(), format!("{:?}", certificate.algorithm.device_key_algorithm()));
+                map.insert("public_key".to_string(), hex::encode(&certificate.public_key));
                 map
             },
+            signature: Some(signature),
             error: None,
         })
     }
 
-    async fn generate_attestation(&self) -> Result<HardwareAttestation> {
-        let card = self.card.as_ref()
+    /// The PIV authentication certificate, with a signature over the nonce
+    /// by its key; the server validates the certificate against its CA bundle
+    async fn generate_attestation(&self, nonce: &[u8]) -> Result<HardwareAttestation> {
+        let certificate = self.certificate.clone()
             .ok_or_else(|| Error::HardwareAuth("Smart card not initialized".to_string()))?;
-
-        // Generate attestation data using smart card
-        let attestation_data = card.transmit(&[0x00, 0xCB, 0x00, 0x00, 0x00])
-            .map_err(|e| Error::HardwareAuth(format!("Failed to generate attestation: {}", e)))?;
-
-        let device_id = self.device_info.as_ref()
-            .map(|info| info.device_id.clone())
-            .unwrap_or_else(|| "smartcard".to_string());
+        let signature = self.sign(nonce)?;
 
         Ok(HardwareAttestation {
             device_type: HardwareType::SmartCard,
-            device_id,
-            attestation_data,
-            signature: vec![], // Smart card signature would be generated here
-            certificate_chain: vec![], // Smart card certificates would be included here
+            device_id: self.device_id(),
+            attestation_data: certificate.clone(),
+            signature,
+            certificate_chain: vec![certificate],
         })
     }
 
+    /// Structural check only: a signed attestation carrying a usable PIV certificate
     async fn verify_attestation(&self, attestation: &HardwareAttestation) -> Result<bool> {
-        // Verify smart card attestation
-        Ok(!attestation.attestation_data.is_empty())
+        if attestation.device_type != HardwareType::SmartCard || attestation.signature.is_empty() {
+            return Ok(false);
+        }
+
+        Ok(attestation.certificate_chain.first()
+            .map_or(false, |certificate| crate::smartcard::CertificateInfo::from_der(certificate).is_ok()))
     }
 
     async fn get_device_info(&self) -> Result<DeviceInfo> {
@@ -617,24 +709,85 @@ impl HardwareAuthFactory {
     pub async fn create(
         hardware_type: HardwareType,
         device_path: Option<String>,
+    ) -> Result<Box<dyn HardwareAuth + Send + Sync>> {
+        Self::create_with_pin_prompt(hardware_type, device_path, crate::piv::default_pin_prompt()).await
+    }
+
+    /// Create hardware authentication instance whose PIV PIN comes from `pin_prompt`
+    pub async fn create_with_pin_prompt(
+        hardware_type: HardwareType,
+        device_path: Option<String>,
+        pin_prompt: Box<dyn crate::piv::PinPrompt>,
     ) -> Result<Box<dyn HardwareAuth + Send + Sync>> {
         match hardware_type {
             HardwareType::Tpm => {
                 let path = device_path.unwrap_or_else(|| "/dev/tpmrm0".to_string());
-                Ok(Box::new(TpmAuth::new(path)))
+                Ok(Box::new(TpmAuth::with_ak_config(path, crate::tpmkey::TpmAkConfig::from_env()?)))
             }
             HardwareType::YubiKey => {
-                Ok(Box::new(YubiKeyAuth::new(device_path)))
+                let config = device_path.as_deref().unwrap_or_default().parse()?;
+                Ok(Box::new(YubiKeyAuth::with_pin_prompt(config, pin_prompt)))
             }
             HardwareType::SmartCard => {
                 let reader = device_path.unwrap_or_else(|| "0".to_string());
-                Ok(Box::new(SmartCardAuth::new(reader)))
+                Ok(Box::new(SmartCardAuth::with_pin_prompt(reader, pin_prompt)))
             }
             HardwareType::Hsm => {
-                // HSM implementation would go here
-                Err(Error::HardwareAuth("HSM support not yet implemented".to_string()))
+                let config = device_path.as_deref().unwrap_or_default().parse()?;
+                Ok(Box::new(crate::pkcs11::HsmAuth::new(config)))
             }
+            HardwareType::Software => Self::create_software(device_path),
+        }
+    }
+
+    /// Allow development-only authenticators for this process
+    pub fn allow_insecure_dev() -> Result<()> {
+        if !cfg!(feature = "insecure-dev") {
+            return Err(Error::Config("This build does not include the insecure-dev feature".to_string()));
+        }
+
+        tracing::warn!("INSECURE development authenticators enabled; never use this in production");
+        INSECURE_DEV_ALLOWED.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Whether development-only authenticators were allowed for this process
+    pub fn insecure_dev_allowed() -> bool {
+        INSECURE_DEV_ALLOWED.load(Ordering::SeqCst)
+    }
+
+    /// Check the configured hardware types; production configuration refuses the software authenticator
+    pub fn validate_config(supported_hardware: &[HardwareType], insecure_dev: bool) -> Result<()> {
+        if insecure_dev && !cfg!(feature = "insecure-dev") {
+            return Err(Error::Config("insecure_dev is set but this build does not include the insecure-dev feature".to_string()));
+        }
+
+        if supported_hardware.contains(&HardwareType::Software) && !insecure_dev {
+            return Err(Error::Config("The software authenticator requires insecure_dev = true and must not be used in production".to_string()));
         }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "insecure-dev")]
+    fn create_software(device_path: Option<String>) -> Result<Box<dyn HardwareAuth + Send + Sync>> {
+        if !Self::insecure_dev_allowed() {
+            return Err(Error::Config("Software authenticator requires an explicit insecure-dev opt-in".to_string()));
+        }
+
+        let key_path = device_path.unwrap_or_else(|| crate::softauth::DEFAULT_KEY_PATH.to_string());
+        let passphrase = std::env::var(crate::softauth::PASSPHRASE_ENV)
+            .map_err(|_| Error::Config(format!("{} is not set", crate::softauth::PASSPHRASE_ENV)))?;
+
+        Ok(Box::new(crate::softauth::SoftwareAuth::new(
+            key_path,
+            crate::secmem::SecretBuffer::from_vec(passphrase.into_bytes())?,
+        )))
+    }
+
+    #[cfg(not(feature = "insecure-dev"))]
+    fn create_software(_device_path: Option<String>) -> Result<Box<dyn HardwareAuth + Send + Sync>> {
+        Err(Error::Config("Software authenticator requires a build with the insecure-dev feature".to_string()))
     }
 
     /// Detect available hardware devices
@@ -644,28 +797,29 @@ impl HardwareAuthFactory {
         // Detect TPM devices
         if Path::new("/dev/tpmrm0").exists() {
             let tpm_auth = TpmAuth::new("/dev/tpmrm0".to_string());
-            if let Ok(info) = tpm_auth.get_tpm_info().await {
+            if let Ok(info) = tpm_auth.probe().await {
                 devices.push(info);
             }
         }
 
         // Detect YubiKey devices
-        if let Ok(yubikey_list) = yubikey::YubiKey::list() {
-            if !yubikey_list.is_empty() {
-                let yubikey_auth = YubiKeyAuth::new(None);
-                if let Ok(info) = yubikey_auth.get_yubikey_info().await {
-                    devices.push(info);
-                }
+        if let Ok(yubikeys) = crate::piv::list_devices() {
+            let yubikey_auth = YubiKeyAuth::new(crate::piv::PivConfig::default());
+            for yubikey in &yubikeys {
+                devices.push(yubikey_auth.get_yubikey_info(yubikey));
             }
         }
 
-        // Detect smart card readers
+        // Detect PIV smart cards
         if let Ok(context) = pcsc::Context::establish(pcsc::Scope::User) {
-            if let Ok(readers) = context.list_readers() {
+            let mut buffer = vec![0u8; context.list_readers_len().unwrap_or(0)];
+            if let Ok(readers) = context.list_readers(&mut buffer) {
                 for reader in readers {
-                    let smartcard_auth = SmartCardAuth::new(reader.to_string());
-                    if let Ok(info) = smartcard_auth.get_smartcard_info().await {
-                        devices.push(info);
+                    let mut smartcard_auth = SmartCardAuth::new(reader.to_string_lossy().into_owned());
+                    if smartcard_auth.initialize().await.is_ok() {
+                        if let Ok(info) = smartcard_auth.get_device_info().await {
+                            devices.push(info);
+                        }
                     }
                 }
             }
@@ -693,4 +847,18 @@ mod tests {
         let deserialized: HardwareType = serde_json::from_str(&serialized).unwrap();
         assert_eq!(tpm, deserialized);
     }
+
+    #[test]
+    fn test_production_config_refuses_software_auth() {
+        let hardware: Vec<HardwareType> = ["tpm", "software"].iter()
+            .map(|name| name.parse().unwrap())
+            .collect();
+
+        assert!(HardwareAuthFactory::validate_config(&hardware, false).is_err());
+        assert!(HardwareAuthFactory::validate_config(&hardware[..1], false).is_ok());
+        assert_eq!(
+            HardwareAuthFactory::validate_config(&hardware, true).is_ok(),
+            cfg!(feature = "insecure-dev")
+        );
+    }
 }
@@ -3,12 +3,12 @@
 //! This module provides post-quantum and hybrid cryptographic algorithms
 //! as specified in the LSFTP protocol specification for Linux systems.
 
+use crate::encryption::SecurityLevel;
 use crate::error::{Error, Result};
+use crate::secmem::SecretBuffer;
 use serde::{Deserialize, Serialize};
 use ring::signature::KeyPair;
 use std::time::SystemTime;
-use libc;
-use zeroize::Zeroize;
 
 /// Supported key exchange algorithms
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -36,6 +36,28 @@ pub enum SignatureAlgorithm {
     MlDsa87,
 }
 
+impl KemAlgorithm {
+    /// Protection against quantum attacks
+    pub fn security_level(&self) -> SecurityLevel {
+        match self {
+            KemAlgorithm::EcdheP256 => SecurityLevel::Classical,
+            KemAlgorithm::HybridEcdheP256MlKem768 => SecurityLevel::Hybrid,
+            KemAlgorithm::MlKem768 | KemAlgorithm::MlKem1024 => SecurityLevel::PostQuantum,
+        }
+    }
+}
+
+impl SignatureAlgorithm {
+    /// Protection against quantum attacks
+    pub fn security_level(&self) -> SecurityLevel {
+        match self {
+            SignatureAlgorithm::Ed25519 => SecurityLevel::Classical,
+            SignatureAlgorithm::HybridEd25519MlDsa65 => SecurityLevel::Hybrid,
+            SignatureAlgorithm::MlDsa65 | SignatureAlgorithm::MlDsa87 => SecurityLevel::PostQuantum,
+        }
+    }
+}
+
 /// Supported AEAD algorithms
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum AeadAlgorithm {
@@ -52,6 +74,155 @@ pub enum HashAlgorithm {
     Blake3,
     /// SHA3-256 (NIST standard)
     Sha3256,
+    /// SHA-256 (FIPS 180-4, for SHA-2-only compliance systems)
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Digest length in bytes
+    pub fn digest_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Blake3 | HashAlgorithm::Sha3256 | HashAlgorithm::Sha256 => 32,
+        }
+    }
+}
+
+impl std::str::FromStr for HashAlgorithm {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "sha3-256" | "sha3256" => Ok(HashAlgorithm::Sha3256),
+            "sha256" | "sha-256" => Ok(HashAlgorithm::Sha256),
+            _ => Err(Error::InvalidInput(format!("Unknown hash algorithm: {}", value))),
+        }
+    }
+}
+
+/// Digest tagged with the algorithm that produced it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaggedDigest {
+    /// Hash algorithm
+    pub algorithm: HashAlgorithm,
+    /// Digest bytes
+    pub value: Vec<u8>,
+}
+
+impl TaggedDigest {
+    /// Compute a one-shot digest
+    pub fn compute(algorithm: HashAlgorithm, data: &[u8]) -> Self {
+        let mut hasher = StreamingHasher::new(algorithm);
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// Constant-time comparison; digests of different algorithms never match
+    pub fn matches(&self, other: &TaggedDigest) -> bool {
+        self.algorithm == other.algorithm
+            && bool::from(subtle::ConstantTimeEq::ct_eq(self.value.as_slice(), other.value.as_slice()))
+    }
+
+    /// Hex encoding of the digest bytes
+    pub fn to_hex(&self) -> String {
+        hex::encode(&self.value)
+    }
+}
+
+/// Incremental hasher for any supported hash algorithm
+#[derive(Clone)]
+pub enum StreamingHasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha3256(sha3::Sha3_256),
+    Sha256(sha2::Sha256),
+}
+
+impl StreamingHasher {
+    /// Create new hasher
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        use sha2::Digest;
+        match algorithm {
+            HashAlgorithm::Blake3 => StreamingHasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Sha3256 => StreamingHasher::Sha3256(sha3::Sha3_256::new()),
+            HashAlgorithm::Sha256 => StreamingHasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    /// Hash algorithm
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            StreamingHasher::Blake3(_) => HashAlgorithm::Blake3,
+            StreamingHasher::Sha3256(_) => HashAlgorithm::Sha3256,
+            StreamingHasher::Sha256(_) => HashAlgorithm::Sha256,
+        }
+    }
+
+    /// Feed data into the hasher
+    pub fn update(&mut self, data: &[u8]) {
+        use sha2::Digest;
+        match self {
+            StreamingHasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            StreamingHasher::Sha3256(hasher) => hasher.update(data),
+            StreamingHasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Digest of everything fed so far; the hasher can keep absorbing data
+    pub fn finalize(&self) -> TaggedDigest {
+        use sha2::Digest;
+        let value = match self {
+            StreamingHasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            StreamingHasher::Sha3256(hasher) => hasher.clone().finalize().to_vec(),
+            StreamingHasher::Sha256(hasher) => hasher.clone().finalize().to_vec(),
+        };
+
+        TaggedDigest {
+            algorithm: self.algorithm(),
+            value,
+        }
+    }
+}
+
+/// Whole-file hasher for transfers: the negotiated digest plus an optional SHA-256
+#[derive(Clone)]
+pub struct TransferHasher {
+    primary: StreamingHasher,
+    compliance: Option<StreamingHasher>,
+}
+
+impl TransferHasher {
+    /// Create new transfer hasher
+    pub fn new(algorithm: HashAlgorithm, compliance_sha256: bool) -> Self {
+        Self {
+            primary: StreamingHasher::new(algorithm),
+            compliance: compliance_sha256.then(|| StreamingHasher::new(HashAlgorithm::Sha256)),
+        }
+    }
+
+    /// Negotiated hash algorithm
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.primary.algorithm()
+    }
+
+    /// Feed file data into every digest
+    pub fn update(&mut self, data: &[u8]) {
+        self.primary.update(data);
+        if let Some(compliance) = &mut self.compliance {
+            compliance.update(data);
+        }
+    }
+
+    /// Negotiated digest
+    pub fn digest(&self) -> TaggedDigest {
+        self.primary.finalize()
+    }
+
+    /// Additional SHA-256 digest, if requested
+    pub fn compliance_digest(&self) -> Option<TaggedDigest> {
+        self.compliance.as_ref().map(|hasher| hasher.finalize())
+    }
 }
 
 /// Cryptographic suite configuration
@@ -76,10 +247,29 @@ impl Default for CryptoSuite {
     }
 }
 
+impl CryptoSuite {
+    /// Default suite at a security level
+    pub fn for_level(level: SecurityLevel) -> Self {
+        let (kem, signature) = match level {
+            SecurityLevel::Classical => (KemAlgorithm::EcdheP256, SignatureAlgorithm::Ed25519),
+            SecurityLevel::Hybrid => (KemAlgorithm::HybridEcdheP256MlKem768, SignatureAlgorithm::HybridEd25519MlDsa65),
+            SecurityLevel::PostQuantum => (KemAlgorithm::MlKem768, SignatureAlgorithm::MlDsa65),
+        };
+        Self { kem, signature, ..Self::default() }
+    }
+
+    /// Level of the suite's key exchange, the part a session actually runs;
+    /// session signatures come from the enrolled devices, not the suite
+    pub fn security_level(&self) -> SecurityLevel {
+        self.kem.security_level()
+    }
+}
+
 /// Key exchange result
 #[derive(Debug)]
 pub struct KeyExchange {
-    pub shared_secret: Vec<u8>,
+    pub shared_secret: SecretBuffer,
+    /// Encapsulation the peer decapsulates to reach the same secret
     pub public_key: Vec<u8>,
     pub algorithm: KemAlgorithm,
 }
@@ -92,27 +282,23 @@ pub struct Signature {
     pub algorithm: SignatureAlgorithm,
 }
 
-/// Secure private key storage with automatic zeroization
-#[derive(Zeroize)]
-#[zeroize(drop)]
+/// Secure private key storage in locked, guard-paged memory
 pub struct PrivateKey {
     pub algorithm: KemAlgorithm,
-    pub key_material: Vec<u8>,
+    pub key_material: SecretBuffer,
     pub created_at: SystemTime,
 }
 
 impl PrivateKey {
     pub fn new(algorithm: KemAlgorithm, material: Vec<u8>) -> Result<Self> {
-        // Lock memory pages to prevent swap (Linux only)
-        unsafe {
-            if libc::mlock(material.as_ptr() as *const _, material.len()) != 0 {
-                return Err(Error::System("Failed to lock memory pages".to_string()));
-            }
-        }
-        
+        // Move material into locked memory; the source vector is zeroized
+        Self::from_secret(algorithm, SecretBuffer::from_vec(material)?)
+    }
+
+    pub fn from_secret(algorithm: KemAlgorithm, key_material: SecretBuffer) -> Result<Self> {
         Ok(Self {
             algorithm,
-            key_material: material,
+            key_material,
             created_at: SystemTime::now(),
         })
     }
@@ -151,12 +337,14 @@ impl PrivateKey {
             }
             KemAlgorithm::HybridEcdheP256MlKem768 => {
                 // Generate hybrid key (classical + post-quantum)
-                let classical_key = Self::generate(KemAlgorithm::EcdheP256)?.key_material;
-                let pq_key = Self::generate(KemAlgorithm::MlKem768)?.key_material;
-                // Combine both keys
-                let mut hybrid_key = classical_key;
-                hybrid_key.extend_from_slice(&pq_key);
-                hybrid_key
+                let classical_key = Self::generate(KemAlgorithm::EcdheP256)?;
+                let pq_key = Self::generate(KemAlgorithm::MlKem768)?;
+                // Combine both keys without leaving unlocked copies behind
+                let hybrid_key = SecretBuffer::concat(&[
+                    classical_key.key_material.as_slice(),
+                    pq_key.key_material.as_slice(),
+                ])?;
+                return Self::from_secret(algorithm, hybrid_key);
             }
         };
 
@@ -175,58 +363,14 @@ pub trait CryptoOperations {
 }
 
 impl CryptoOperations for CryptoSuite {
+    /// Encapsulate to the peer's public key with the session key exchange in `kem`
     fn perform_key_exchange(&self, peer_public_key: &[u8]) -> Result<KeyExchange> {
-        match self.kem {
-            KemAlgorithm::EcdheP256 => {
-                // Classical ECDH key exchange
-                let rng = ring::rand::SystemRandom::new();
-                let private_key = ring::agreement::EphemeralPrivateKey::generate(
-                    &ring::agreement::X25519,
-                    &rng,
-                )?;
-                
-                let public_key = private_key.compute_public_key()?;
-                let shared_secret = ring::agreement::agree_ephemeral(
-                    private_key,
-                    &ring::agreement::UnparsedPublicKey::new(
-                        &ring::agreement::X25519,
-                        peer_public_key,
-                    ),
-                    |key_material| Ok::<Vec<u8>, ring::error::Unspecified>(key_material.to_vec()),
-                )??;
-
-                Ok(KeyExchange {
-                    shared_secret,
-                    public_key: public_key.as_ref().to_vec(),
-                    algorithm: self.kem,
-                })
-            }
-            KemAlgorithm::HybridEcdheP256MlKem768 => {
-                // Hybrid key exchange: classical + post-quantum
-                let classical_ke = self.perform_classical_key_exchange(peer_public_key)?;
-                let pq_ke = self.perform_post_quantum_key_exchange(peer_public_key)?;
-                
-                // Combine both shared secrets
-                let mut hybrid_secret = classical_ke.shared_secret;
-                hybrid_secret.extend_from_slice(&pq_ke.shared_secret);
-                
-                // Combine public keys
-                let mut hybrid_public_key = classical_ke.public_key;
-                hybrid_public_key.extend_from_slice(&pq_ke.public_key);
-                
-                Ok(KeyExchange {
-                    shared_secret: hybrid_secret,
-                    public_key: hybrid_public_key,
-                    algorithm: self.kem,
-                })
-            }
-            KemAlgorithm::MlKem768 => {
-                self.perform_post_quantum_key_exchange(peer_public_key)
-            }
-            KemAlgorithm::MlKem1024 => {
-                self.perform_post_quantum_key_exchange(peer_public_key)
-            }
-        }
+        let (ciphertext, shared_secret) = crate::kem::encapsulate(self.kem, peer_public_key)?;
+        Ok(KeyExchange {
+            shared_secret,
+            public_key: ciphertext,
+            algorithm: self.kem,
+        })
     }
 
     fn sign(&self, message: &[u8]) -> Result<Signature> {
@@ -391,61 +535,17 @@ impl CryptoOperations for CryptoSuite {
                 hasher.update(data);
                 Ok(hasher.finalize().to_vec())
             }
+            HashAlgorithm::Sha256 => {
+                use sha2::{Digest, Sha256};
+                let mut hasher = Sha256::new();
+                hasher.update(data);
+                Ok(hasher.finalize().to_vec())
+            }
         }
     }
 }
 
 impl CryptoSuite {
-    /// Perform classical key exchange (ECDH P-256)
-    fn perform_classical_key_exchange(&self, peer_public_key: &[u8]) -> Result<KeyExchange> {
-        let rng = ring::rand::SystemRandom::new();
-        let private_key = ring::agreement::EphemeralPrivateKey::generate(
-            &ring::agreement::X25519,
-            &rng,
-        )?;
-        
-        let public_key = private_key.compute_public_key()?;
-        let shared_secret = ring::agreement::agree_ephemeral(
-            private_key,
-            &ring::agreement::UnparsedPublicKey::new(
-                &ring::agreement::X25519,
-                peer_public_key,
-            ),
-            |key_material| Ok::<Vec<u8>, ring::error::Unspecified>(key_material.to_vec()),
-        )??;
-
-        Ok(KeyExchange {
-            shared_secret,
-            public_key: public_key.as_ref().to_vec(),
-            algorithm: KemAlgorithm::EcdheP256,
-        })
-    }
-
-    /// Perform post-quantum key exchange (ML-KEM)
-    fn perform_post_quantum_key_exchange(&self, peer_public_key: &[u8]) -> Result<KeyExchange> {
-        let kem = match self.kem {
-            KemAlgorithm::MlKem768 => oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber768),
-            KemAlgorithm::MlKem1024 => oqs::kem::Kem::new(oqs::kem::Algorithm::Kyber1024),
-            _ => return Err(Error::Crypto("Invalid algorithm for post-quantum key exchange".to_string())),
-        }.map_err(|e| Error::Crypto(format!("Failed to initialize KEM: {}", e)))?;
-
-        // Generate keypair
-        let (public_key, secret_key) = kem.keypair().map_err(|e| {
-            Error::Crypto(format!("Failed to generate keypair: {}", e))
-        })?;
-
-        // Encapsulate to peer's public key
-        let (ciphertext, shared_secret) = kem.encaps(&public_key).map_err(|e| {
-            Error::Crypto(format!("Failed to encapsulate: {}", e))
-        })?;
-
-        Ok(KeyExchange {
-            shared_secret: shared_secret.into_vec(),
-            public_key: public_key.into_vec(),
-            algorithm: self.kem,
-        })
-    }
-
     /// Perform classical signature (Ed25519)
     fn perform_classical_signature(&self, message: &[u8]) -> Result<Signature> {
         let rng = ring::rand::SystemRandom::new();
@@ -463,7 +563,8 @@ impl CryptoSuite {
     /// Perform post-quantum signature (ML-DSA)
     fn perform_post_quantum_signature(&self, message: &[u8]) -> Result<Signature> {
         let sig = match self.signature {
-            SignatureAlgorithm::MlDsa65 => oqs::sig::Sig::new(oqs::sig::Algorithm::Dilithium3),
+            // The post-quantum half of a hybrid signature is ML-DSA-65
+            SignatureAlgorithm::MlDsa65 | SignatureAlgorithm::HybridEd25519MlDsa65 => oqs::sig::Sig::new(oqs::sig::Algorithm::Dilithium3),
             SignatureAlgorithm::MlDsa87 => oqs::sig::Sig::new(oqs::sig::Algorithm::Dilithium5),
             _ => return Err(Error::Crypto("Invalid algorithm for post-quantum signature".to_string())),
         }.map_err(|e| Error::Crypto(format!("Failed to initialize signature: {}", e)))?;
@@ -497,7 +598,8 @@ impl CryptoSuite {
     /// Verify post-quantum signature (ML-DSA)
     fn verify_post_quantum_signature(&self, message: &[u8], signature: &[u8], public_key: &[u8]) -> Result<bool> {
         let sig = match self.signature {
-            SignatureAlgorithm::MlDsa65 => oqs::sig::Sig::new(oqs::sig::Algorithm::Dilithium3),
+            // The post-quantum half of a hybrid signature is ML-DSA-65
+            SignatureAlgorithm::MlDsa65 | SignatureAlgorithm::HybridEd25519MlDsa65 => oqs::sig::Sig::new(oqs::sig::Algorithm::Dilithium3),
             SignatureAlgorithm::MlDsa87 => oqs::sig::Sig::new(oqs::sig::Algorithm::Dilithium5),
             _ => return Err(Error::Crypto("Invalid algorithm for post-quantum signature verification".to_string())),
         }.map_err(|e| Error::Crypto(format!("Failed to initialize signature verification: {}", e)))?;
@@ -527,6 +629,14 @@ mod tests {
         assert_eq!(suite.hash, HashAlgorithm::Blake3);
     }
 
+    #[test]
+    fn test_key_exchange_matches_session_kem() {
+        let suite = CryptoSuite::default();
+        let client = crate::kem::KemKeyPair::generate(suite.kem).unwrap();
+        let exchange = suite.perform_key_exchange(client.public_key()).unwrap();
+        assert_eq!(client.decapsulate(&exchange.public_key).unwrap().as_slice(), exchange.shared_secret.as_slice());
+    }
+
     #[test]
     fn test_hash_operations() {
         let suite = CryptoSuite::default();
@@ -536,6 +646,36 @@ mod tests {
         assert_eq!(hash.len(), 32); // BLAKE3 output size
     }
 
+    #[test]
+    fn test_tagged_digests() {
+        let data = b"abc";
+        for algorithm in [HashAlgorithm::Blake3, HashAlgorithm::Sha3256, HashAlgorithm::Sha256] {
+            let suite = CryptoSuite { hash: algorithm, ..Default::default() };
+            let digest = TaggedDigest::compute(algorithm, data);
+            assert_eq!(digest.value, suite.hash(data).unwrap());
+            assert_eq!(digest.value.len(), algorithm.digest_len());
+        }
+
+        let blake3 = TaggedDigest::compute(HashAlgorithm::Blake3, data);
+        let sha3 = TaggedDigest::compute(HashAlgorithm::Sha3256, data);
+        assert!(blake3.matches(&blake3.clone()));
+        assert!(!blake3.matches(&sha3));
+    }
+
+    #[test]
+    fn test_transfer_hasher_streaming() {
+        let mut hasher = TransferHasher::new(HashAlgorithm::Sha3256, true);
+        hasher.update(b"ab");
+        hasher.update(b"c");
+
+        assert!(hasher.digest().matches(&TaggedDigest::compute(HashAlgorithm::Sha3256, b"abc")));
+        assert_eq!(
+            hasher.compliance_digest().unwrap().to_hex(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(TransferHasher::new(HashAlgorithm::Blake3, false).compliance_digest().is_none());
+    }
+
     #[test]
     fn test_encrypt_decrypt() {
         let suite = CryptoSuite::default();
@@ -0,0 +1,61 @@
+//! DER encoding of device public keys for LSFTP
+//!
+//! TPM and PKCS#11 backends hand out RSA keys as a bare modulus and
+//! exponent; the enrollment registry stores them as a PKCS#1
+//! `RSAPublicKey`, the form ring verifies against.
+
+/// DER `RSAPublicKey` (PKCS#1) from unsigned big-endian modulus and exponent
+pub fn rsa_public_key_der(modulus: &[u8], exponent: &[u8]) -> Vec<u8> {
+    let mut body = der_integer(modulus);
+    body.extend_from_slice(&der_integer(exponent));
+    der_tlv(0x30, &body)
+}
+
+/// DER INTEGER from unsigned big-endian bytes
+fn der_integer(value: &[u8]) -> Vec<u8> {
+    let value = match value.iter().position(|&b| b != 0) {
+        Some(start) => &value[start..],
+        None => &[0u8][..],
+    };
+
+    let mut content = Vec::with_capacity(value.len() + 1);
+    if value[0] & 0x80 != 0 {
+        content.push(0);
+    }
+    content.extend_from_slice(value);
+    der_tlv(0x02, &content)
+}
+
+fn der_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    match content.len() {
+        len @ 0..=0x7f => out.push(len as u8),
+        len @ 0x80..=0xff => out.extend_from_slice(&[0x81, len as u8]),
+        len => {
+            out.push(0x82);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rsa_public_key_der() {
+        // Small modulus with the high bit set gets a leading zero
+        assert_eq!(
+            rsa_public_key_der(&[0x00, 0xc5, 0x01], &65537u32.to_be_bytes()),
+            vec![0x30, 0x0a, 0x02, 0x03, 0x00, 0xc5, 0x01, 0x02, 0x03, 0x01, 0x00, 0x01],
+        );
+
+        let der = rsa_public_key_der(&[0xff; 256], &[0x01, 0x00, 0x01]);
+        assert_eq!(&der[..9], &[0x30, 0x82, 0x01, 0x0a, 0x02, 0x82, 0x01, 0x01, 0x00]);
+        assert_eq!(der.len(), 4 + 0x010a);
+
+        assert_eq!(der_integer(&[0x00, 0x00]), vec![0x02, 0x01, 0x00]);
+    }
+}
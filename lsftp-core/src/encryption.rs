@@ -0,0 +1,376 @@
+//! Encryption requirements for LSFTP
+//!
+//! Clients declare the crypto suite of their session when they answer the
+//! authentication challenge, along with a public key for the suite's KEM. A
+//! suite is as strong as that key exchange, the part the session actually
+//! runs: classical, hybrid or post-quantum. The server refuses sessions below
+//! the policy's minimum and runs the declared key exchange (see `kem`); its
+//! secret keys the session, so a suite cannot be claimed without being used. The server also refuses
+//! operations on paths or classification labels (from the access policy) that
+//! demand more than the session offers. Frames must always carry the
+//! encrypted flag. Requirements live in a TOML file:
+//!
+//! ```toml
+//! # Every session
+//! minimum = "hybrid"
+//!
+//! [[paths]]
+//! path = "/secret"
+//! minimum = "hybrid"
+//!
+//! [[paths]]
+//! path = "/topsecret"
+//! minimum = "post-quantum"
+//!
+//! [classifications]
+//! top-secret = "post-quantum"
+//! ```
+//!
+//! Administrators tighten or relax requirements live with
+//! `EncryptionRequirement` policy rules carrying `minimum` and at most one
+//! of `path` or `classification`.
+
+use crate::access;
+use crate::crypto::{CryptoSuite, KemAlgorithm};
+use crate::error::{Error, Result};
+use crate::kem;
+use crate::protocol::{Frame, MessageType, PolicyRule, PolicyRuleType};
+use crate::secmem::SecretBuffer;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Protection against quantum attacks, weakest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SecurityLevel {
+    /// Classical algorithms only
+    Classical,
+    /// Classical and post-quantum combined
+    Hybrid,
+    /// Post-quantum algorithms only
+    PostQuantum,
+}
+
+impl fmt::Display for SecurityLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecurityLevel::Classical => write!(f, "classical"),
+            SecurityLevel::Hybrid => write!(f, "hybrid"),
+            SecurityLevel::PostQuantum => write!(f, "post-quantum"),
+        }
+    }
+}
+
+impl FromStr for SecurityLevel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "classical" => Ok(SecurityLevel::Classical),
+            "hybrid" => Ok(SecurityLevel::Hybrid),
+            "post-quantum" | "pq" => Ok(SecurityLevel::PostQuantum),
+            _ => Err(Error::Config(format!("Unknown security level: {}", s))),
+        }
+    }
+}
+
+/// Session or frame refused by the encryption requirements
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncryptionViolation {
+    /// Session suite below what the session or a path requires
+    WeakSuite {
+        /// Level of the session's suite
+        offered: SecurityLevel,
+        /// Level required
+        required: SecurityLevel,
+        /// Path that raised the requirement, if any
+        path: Option<String>,
+    },
+    /// Key exchange of the declared suite could not be carried out
+    KeyExchange {
+        /// Declared KEM
+        kem: KemAlgorithm,
+        /// Why the client's public key was refused
+        reason: String,
+    },
+    /// Frame without the encrypted flag
+    UnencryptedFrame {
+        /// Type of the offending frame
+        message_type: MessageType,
+    },
+}
+
+impl EncryptionViolation {
+    /// Error code recorded in the audit log
+    pub fn code(&self) -> &'static str {
+        match self {
+            EncryptionViolation::WeakSuite { .. } => "WEAK_CRYPTO_SUITE",
+            EncryptionViolation::KeyExchange { .. } => "KEY_EXCHANGE_FAILED",
+            EncryptionViolation::UnencryptedFrame { .. } => "UNENCRYPTED_FRAME",
+        }
+    }
+}
+
+impl fmt::Display for EncryptionViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionViolation::WeakSuite { offered, required, path: Some(path) } => {
+                write!(f, "{} requires a {} crypto suite, session uses {}", path, required, offered)
+            }
+            EncryptionViolation::WeakSuite { offered, required, path: None } => {
+                write!(f, "Sessions require a {} crypto suite, client offered {}", required, offered)
+            }
+            EncryptionViolation::KeyExchange { kem, reason } => {
+                write!(f, "Declared {:?} key exchange failed: {}", kem, reason)
+            }
+            EncryptionViolation::UnencryptedFrame { message_type } => {
+                write!(f, "{:?} frame does not have the encrypted flag set", message_type)
+            }
+        }
+    }
+}
+
+impl From<EncryptionViolation> for Error {
+    fn from(violation: EncryptionViolation) -> Self {
+        Error::Crypto(format!("{}: {}", violation.code(), violation))
+    }
+}
+
+/// Refuse frames sent without the encrypted flag
+pub fn check_frame(frame: &Frame) -> std::result::Result<(), EncryptionViolation> {
+    if !frame.flags.encrypted {
+        return Err(EncryptionViolation::UnencryptedFrame { message_type: frame.message_type });
+    }
+    Ok(())
+}
+
+/// Path requirement as written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PathRequirementFile {
+    path: String,
+    minimum: String,
+}
+
+/// Requirements as written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EncryptionPolicyFile {
+    minimum: Option<String>,
+    #[serde(default)]
+    paths: Vec<PathRequirementFile>,
+    #[serde(default)]
+    classifications: HashMap<String, String>,
+}
+
+/// Minimum security levels for sessions, paths and classification labels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionPolicy {
+    /// Floor for every session
+    pub minimum: SecurityLevel,
+    /// Floors by normalized path prefix
+    pub paths: BTreeMap<String, SecurityLevel>,
+    /// Floors by classification label
+    pub classifications: HashMap<String, SecurityLevel>,
+}
+
+impl Default for EncryptionPolicy {
+    /// Classical-only sessions are refused unless a policy allows them
+    fn default() -> Self {
+        Self {
+            minimum: SecurityLevel::Hybrid,
+            paths: BTreeMap::new(),
+            classifications: HashMap::new(),
+        }
+    }
+}
+
+impl EncryptionPolicy {
+    /// Load a TOML requirements file
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read encryption policy {:?}: {}", path, e)))?;
+        content.parse()
+    }
+
+    /// Level required to touch a path with the given classification
+    pub fn required(&self, path: &str, classification: Option<&str>) -> SecurityLevel {
+        let by_path = self.paths.iter()
+            .filter(|(prefix, _)| access::is_below(path, prefix))
+            .map(|(_, level)| *level);
+        let by_label = classification.and_then(|label| self.classifications.get(label)).copied();
+        by_path.chain(by_label).fold(self.minimum, SecurityLevel::max)
+    }
+
+    /// Refuse suites below the session minimum
+    pub fn check_session(&self, suite: &CryptoSuite) -> std::result::Result<(), EncryptionViolation> {
+        let offered = suite.security_level();
+        if offered < self.minimum {
+            return Err(EncryptionViolation::WeakSuite { offered, required: self.minimum, path: None });
+        }
+        Ok(())
+    }
+
+    /// Refuse suites below the session minimum, then run the suite's key
+    /// exchange against the client's public key; returns the ciphertext for
+    /// the client and the shared secret for the session key schedule
+    pub fn negotiate(&self, suite: &CryptoSuite, client_public_key: &[u8]) -> std::result::Result<(Vec<u8>, SecretBuffer), EncryptionViolation> {
+        self.check_session(suite)?;
+        kem::encapsulate(suite.kem, client_public_key)
+            .map_err(|e| EncryptionViolation::KeyExchange { kem: suite.kem, reason: e.to_string() })
+    }
+
+    /// Refuse operations on paths that require more than the session's suite
+    pub fn check_path(&self, suite: &CryptoSuite, path: &str, classification: Option<&str>) -> std::result::Result<(), EncryptionViolation> {
+        let offered = suite.security_level();
+        let required = self.required(path, classification);
+        if offered < required {
+            return Err(EncryptionViolation::WeakSuite { offered, required, path: Some(path.to_string()) });
+        }
+        Ok(())
+    }
+
+    /// Apply a live `EncryptionRequirement` policy rule
+    pub fn apply_rule(&mut self, rule: &PolicyRule) -> Result<()> {
+        if rule.rule_type != PolicyRuleType::EncryptionRequirement {
+            return Err(Error::Config(format!("Rule {} is not an encryption requirement", rule.id)));
+        }
+        let minimum: SecurityLevel = rule.parameters.get("minimum")
+            .ok_or_else(|| Error::Config(format!("Rule {} has no minimum", rule.id)))?
+            .parse()?;
+
+        match (rule.parameters.get("path"), rule.parameters.get("classification")) {
+            (None, None) => self.minimum = minimum,
+            (Some(path), None) => {
+                self.paths.insert(access::normalize_path(path)?, minimum);
+            }
+            (None, Some(label)) => {
+                self.classifications.insert(label.clone(), minimum);
+            }
+            (Some(_), Some(_)) => {
+                return Err(Error::Config(format!("Rule {} names both a path and a classification", rule.id)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let file: EncryptionPolicyFile = toml::from_str(s)
+            .map_err(|e| Error::Config(format!("Invalid encryption policy: {}", e)))?;
+
+        let mut policy = Self::default();
+        if let Some(minimum) = &file.minimum {
+            policy.minimum = minimum.parse()?;
+        }
+        for requirement in file.paths {
+            let path = access::normalize_path(&requirement.path)?;
+            if policy.paths.insert(path, requirement.minimum.parse()?).is_some() {
+                return Err(Error::Config(format!("Duplicate encryption requirement for {}", requirement.path)));
+            }
+        }
+        for (label, minimum) in file.classifications {
+            policy.classifications.insert(label, minimum.parse()?);
+        }
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{KemAlgorithm, SignatureAlgorithm};
+
+    const POLICY: &str = r#"
+        minimum = "classical"
+
+        [[paths]]
+        path = "/secret"
+        minimum = "hybrid"
+
+        [[paths]]
+        path = "/secret/topsecret/"
+        minimum = "pq"
+
+        [classifications]
+        restricted = "post-quantum"
+    "#;
+
+    #[test]
+    fn test_suite_levels() {
+        assert_eq!(CryptoSuite::default().security_level(), SecurityLevel::Hybrid);
+        assert_eq!(CryptoSuite::for_level(SecurityLevel::PostQuantum).security_level(), SecurityLevel::PostQuantum);
+
+        // The level is that of the key exchange the session runs; the suite's signature is not used
+        let mixed = CryptoSuite { kem: KemAlgorithm::MlKem1024, signature: SignatureAlgorithm::Ed25519, ..CryptoSuite::default() };
+        assert_eq!(mixed.security_level(), SecurityLevel::PostQuantum);
+        let classical = CryptoSuite { kem: KemAlgorithm::EcdheP256, signature: SignatureAlgorithm::MlDsa87, ..CryptoSuite::default() };
+        assert_eq!(classical.security_level(), SecurityLevel::Classical);
+    }
+
+    #[test]
+    fn test_path_requirements() {
+        let policy: EncryptionPolicy = POLICY.parse().unwrap();
+        let classical = CryptoSuite::for_level(SecurityLevel::Classical);
+        let hybrid = CryptoSuite::default();
+
+        assert!(policy.check_session(&classical).is_ok());
+        assert!(policy.check_path(&classical, "/public/readme", None).is_ok());
+        assert_eq!(policy.required("/secret/plans", None), SecurityLevel::Hybrid);
+        assert_eq!(policy.required("/secretive", None), SecurityLevel::Classical);
+        assert_eq!(policy.required("/secret/topsecret/x", None), SecurityLevel::PostQuantum);
+        assert_eq!(policy.required("/public/x", Some("restricted")), SecurityLevel::PostQuantum);
+
+        let violation = policy.check_path(&hybrid, "/secret/topsecret/x", None).unwrap_err();
+        assert_eq!(violation.code(), "WEAK_CRYPTO_SUITE");
+        assert!(Error::from(violation).to_string().contains("WEAK_CRYPTO_SUITE"));
+        assert!(policy.check_path(&hybrid, "/secret/plans", None).is_ok());
+    }
+
+    #[test]
+    fn test_claimed_suite_must_be_negotiated() {
+        let policy: EncryptionPolicy = "minimum = \"post-quantum\"".parse().unwrap();
+        let post_quantum = CryptoSuite::for_level(SecurityLevel::PostQuantum);
+
+        // Claiming a post-quantum suite over a classical key exchange is refused
+        let classical = kem::KemKeyPair::generate(KemAlgorithm::EcdheP256).unwrap();
+        let violation = policy.negotiate(&post_quantum, classical.public_key()).unwrap_err();
+        assert_eq!(violation.code(), "KEY_EXCHANGE_FAILED");
+
+        // Running the declared KEM gives both sides the same session secret
+        let client = kem::KemKeyPair::generate(post_quantum.kem).unwrap();
+        let (ciphertext, server_secret) = policy.negotiate(&post_quantum, client.public_key()).unwrap();
+        assert_eq!(client.decapsulate(&ciphertext).unwrap().as_slice(), server_secret.as_slice());
+
+        // A weak suite is refused before any key exchange
+        let hybrid = kem::KemKeyPair::generate(KemAlgorithm::HybridEcdheP256MlKem768).unwrap();
+        let violation = policy.negotiate(&CryptoSuite::default(), hybrid.public_key()).unwrap_err();
+        assert_eq!(violation.code(), "WEAK_CRYPTO_SUITE");
+    }
+
+    #[test]
+    fn test_defaults_and_live_rules() {
+        let mut policy = EncryptionPolicy::default();
+        assert!(policy.check_session(&CryptoSuite::for_level(SecurityLevel::Classical)).is_err());
+
+        let rule = |parameters: &[(&str, &str)]| PolicyRule {
+            id: "test".to_string(),
+            rule_type: PolicyRuleType::EncryptionRequirement,
+            parameters: parameters.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+        };
+        policy.apply_rule(&rule(&[("minimum", "pq"), ("path", "vault//")])).unwrap();
+        assert_eq!(policy.required("/vault/key", None), SecurityLevel::PostQuantum);
+        policy.apply_rule(&rule(&[("minimum", "classical")])).unwrap();
+        assert_eq!(policy.minimum, SecurityLevel::Classical);
+
+        assert!(policy.apply_rule(&rule(&[("minimum", "strong")])).is_err());
+        assert!(policy.apply_rule(&rule(&[("path", "/x")])).is_err());
+        assert!(policy.apply_rule(&rule(&[("minimum", "pq"), ("path", "/x"), ("classification", "y")])).is_err());
+        assert!("[[paths]]\npath = \"/a\"\nminimum = \"pq\"\n[[paths]]\npath = \"/a/\"\nminimum = \"hybrid\"".parse::<EncryptionPolicy>().is_err());
+    }
+}
@@ -0,0 +1,388 @@
+//! Enrolled hardware devices for LSFTP
+//!
+//! The server only accepts challenge responses signed by a device key that
+//! was enrolled beforehand. Enrollments are kept in a JSON file mapping each
+//! device ID to its public key, attestation chain, owning user, roles and
+//! status. The owning user is the identity a session authenticates as.
+
+use crate::auth::HardwareType;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Default enrollment file
+pub const DEFAULT_ENROLLMENT_PATH: &str = "/etc/lsftp/devices.json";
+
+/// Signature algorithm of an enrolled device key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKeyAlgorithm {
+    /// Ed25519 over the challenge
+    Ed25519,
+    /// ECDSA P-256 with SHA-256, ASN.1 DER signature
+    EcdsaP256Sha256,
+    /// ECDSA P-256 with SHA-256, fixed-width `r || s` signature (TPM)
+    EcdsaP256Sha256Fixed,
+    /// ECDSA P-384 with SHA-384, ASN.1 DER signature (YubiKey PIV)
+    EcdsaP384Sha384,
+    /// ECDSA P-384 with SHA-384, fixed-width `r || s` signature (PKCS#11)
+    EcdsaP384Sha384Fixed,
+    /// RSASSA-PKCS1-v1_5 with SHA-256, 2048 bits or more
+    RsaPkcs1Sha256,
+}
+
+impl std::str::FromStr for DeviceKeyAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "ed25519" => Ok(DeviceKeyAlgorithm::Ed25519),
+            "ecdsa-p256-sha256" | "ecdsa_p256_sha256" => Ok(DeviceKeyAlgorithm::EcdsaP256Sha256),
+            "ecdsa-p256-sha256-fixed" | "ecdsa_p256_sha256_fixed" => Ok(DeviceKeyAlgorithm::EcdsaP256Sha256Fixed),
+            "ecdsa-p384-sha384" | "ecdsa_p384_sha384" => Ok(DeviceKeyAlgorithm::EcdsaP384Sha384),
+            "ecdsa-p384-sha384-fixed" | "ecdsa_p384_sha384_fixed" => Ok(DeviceKeyAlgorithm::EcdsaP384Sha384Fixed),
+            "rsa-pkcs1-sha256" | "rsa_pkcs1_sha256" => Ok(DeviceKeyAlgorithm::RsaPkcs1Sha256),
+            other => Err(Error::Config(format!("Unknown device key algorithm: {}", other))),
+        }
+    }
+}
+
+impl DeviceKeyAlgorithm {
+    /// Verify a signature over `message` with a public key in this algorithm
+    pub fn verify(&self, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        let algorithm: &dyn ring::signature::VerificationAlgorithm = match self {
+            DeviceKeyAlgorithm::Ed25519 => &ring::signature::ED25519,
+            DeviceKeyAlgorithm::EcdsaP256Sha256 => &ring::signature::ECDSA_P256_SHA256_ASN1,
+            DeviceKeyAlgorithm::EcdsaP256Sha256Fixed => &ring::signature::ECDSA_P256_SHA256_FIXED,
+            DeviceKeyAlgorithm::EcdsaP384Sha384 => &ring::signature::ECDSA_P384_SHA384_ASN1,
+            DeviceKeyAlgorithm::EcdsaP384Sha384Fixed => &ring::signature::ECDSA_P384_SHA384_FIXED,
+            DeviceKeyAlgorithm::RsaPkcs1Sha256 => &ring::signature::RSA_PKCS1_2048_8192_SHA256,
+        };
+
+        ring::signature::UnparsedPublicKey::new(algorithm, public_key)
+            .verify(message, signature)
+            .is_ok()
+    }
+}
+
+/// Enrollment status of a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceStatus {
+    /// Device may authenticate
+    #[default]
+    Active,
+    /// Temporarily barred; can be resumed
+    Suspended,
+    /// Permanently barred; the device ID cannot be enrolled again
+    Revoked,
+}
+
+impl std::fmt::Display for DeviceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceStatus::Active => write!(f, "active"),
+            DeviceStatus::Suspended => write!(f, "suspended"),
+            DeviceStatus::Revoked => write!(f, "revoked"),
+        }
+    }
+}
+
+/// Attestation key of an enrolled device, e.g. a TPM AK
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttestationKey {
+    /// Signature algorithm
+    pub algorithm: DeviceKeyAlgorithm,
+    /// Public key (hex)
+    pub public_key: String,
+}
+
+impl AttestationKey {
+    /// Decoded public key
+    pub fn public_key_bytes(&self) -> Result<Vec<u8>> {
+        hex::decode(&self.public_key)
+            .map_err(|e| Error::Config(format!("Invalid attestation key: {}", e)))
+    }
+}
+
+/// Enrolled device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnrolledDevice {
+    /// Device identifier as reported by the device
+    pub device_id: String,
+    /// Device type
+    pub device_type: HardwareType,
+    /// Signature algorithm
+    pub algorithm: DeviceKeyAlgorithm,
+    /// Public key (hex)
+    pub public_key: String,
+    /// Attestation certificate chain captured at enrollment (hex, leaf first)
+    #[serde(default)]
+    pub attestation_chain: Vec<String>,
+    /// Key that signs the device's attestations
+    #[serde(default)]
+    pub attestation_key: Option<AttestationKey>,
+    /// Host class selecting the golden PCR policy (TPM devices)
+    #[serde(default)]
+    pub host_class: Option<String>,
+    /// User the device belongs to
+    pub user_id: String,
+    /// Roles granted to sessions authenticated with this device
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Enrollment time (seconds since the epoch)
+    pub enrolled_at: u64,
+    /// Enrollment status
+    #[serde(default)]
+    pub status: DeviceStatus,
+    /// Last status change (seconds since the epoch)
+    #[serde(default)]
+    pub status_changed_at: Option<u64>,
+    /// Reason for the last status change
+    #[serde(default)]
+    pub status_reason: Option<String>,
+}
+
+impl EnrolledDevice {
+    /// Verify a challenge signature made by this device
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<bool> {
+        let public_key = hex::decode(&self.public_key)
+            .map_err(|e| Error::Config(format!("Invalid public key for {}: {}", self.device_id, e)))?;
+        Ok(self.algorithm.verify(&public_key, message, signature))
+    }
+
+    /// Whether the device may authenticate
+    pub fn is_active(&self) -> bool {
+        self.status == DeviceStatus::Active
+    }
+}
+
+/// Enrolled devices by device ID
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EnrollmentStore {
+    devices: HashMap<String, EnrolledDevice>,
+}
+
+impl EnrollmentStore {
+    /// Load enrollments; a missing file is an empty store
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    /// Save enrollments atomically, readable by the owner only
+    pub fn save(&self, path: &Path) -> Result<()> {
+        crate::fsutil::write_private(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    /// Look up an enrolled device, whatever its status
+    pub fn get(&self, device_id: &str) -> Option<&EnrolledDevice> {
+        self.devices.get(device_id)
+    }
+
+    /// Look up a device that may authenticate
+    pub fn active(&self, device_id: &str) -> Result<&EnrolledDevice> {
+        let device = self.get(device_id)
+            .ok_or_else(|| Error::Auth(format!("Device {} is not enrolled", device_id)))?;
+
+        match device.status {
+            DeviceStatus::Active => Ok(device),
+            status => Err(Error::Auth(format!("Device {} is {}", device_id, status))),
+        }
+    }
+
+    /// Resolve the user a device belongs to
+    pub fn resolve_user(&self, device_id: &str) -> Result<&str> {
+        Ok(self.active(device_id)?.user_id.as_str())
+    }
+
+    /// Add or replace an enrollment
+    pub fn insert(&mut self, device: EnrolledDevice) {
+        self.devices.insert(device.device_id.clone(), device);
+    }
+
+    /// Enroll a new device; an existing or revoked device ID is refused
+    pub fn enroll(&mut self, device: EnrolledDevice) -> Result<()> {
+        if let Some(existing) = self.devices.get(&device.device_id) {
+            return Err(Error::Config(format!(
+                "Device {} is already enrolled ({})", existing.device_id, existing.status
+            )));
+        }
+
+        let public_key = hex::decode(&device.public_key)
+            .map_err(|e| Error::Config(format!("Invalid public key for {}: {}", device.device_id, e)))?;
+        if public_key.is_empty() {
+            return Err(Error::Config(format!("Empty public key for {}", device.device_id)));
+        }
+
+        self.insert(device);
+        Ok(())
+    }
+
+    /// Change a device's status; revocation is final
+    pub fn set_status(&mut self, device_id: &str, status: DeviceStatus, reason: Option<String>) -> Result<&EnrolledDevice> {
+        let device = self.devices.get_mut(device_id)
+            .ok_or_else(|| Error::Config(format!("Device {} is not enrolled", device_id)))?;
+
+        if device.status == DeviceStatus::Revoked {
+            return Err(Error::Config(format!("Device {} is revoked", device_id)));
+        }
+
+        device.status = status;
+        device.status_changed_at = Some(crate::now_secs());
+        device.status_reason = reason;
+        Ok(device)
+    }
+
+    /// Refuse active software authenticator enrollments unless insecure-dev is allowed
+    pub fn check_insecure_dev(&self, insecure_dev: bool) -> Result<()> {
+        let software: Vec<&str> = self.devices()
+            .into_iter()
+            .filter(|device| device.is_active() && device.device_type == HardwareType::Software)
+            .map(|device| device.device_id.as_str())
+            .collect();
+
+        if !software.is_empty() && !insecure_dev {
+            return Err(Error::Config(format!(
+                "Software authenticator devices are enrolled but insecure-dev is off: {}", software.join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    /// Refuse active smart card enrollments when no CA bundle checks their certificates
+    pub fn check_smartcard_ca(&self, has_ca: bool) -> Result<()> {
+        let smartcards: Vec<&str> = self.devices()
+            .into_iter()
+            .filter(|device| device.is_active() && device.device_type == HardwareType::SmartCard)
+            .map(|device| device.device_id.as_str())
+            .collect();
+
+        if !smartcards.is_empty() && !has_ca {
+            return Err(Error::Config(format!(
+                "Smart card devices are enrolled but no smart card CA is configured: {}", smartcards.join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    /// Enrolled devices ordered by user, then device ID
+    pub fn devices(&self) -> Vec<&EnrolledDevice> {
+        let mut devices: Vec<_> = self.devices.values().collect();
+        devices.sort_by(|a, b| (&a.user_id, &a.device_id).cmp(&(&b.user_id, &b.device_id)));
+        devices
+    }
+
+    /// Number of enrolled devices
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Whether no device is enrolled
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+}
+
+/// Enrollment records for tests across the crate
+#[cfg(test)]
+impl EnrolledDevice {
+    /// Active device of a user without roles or attestation and with a placeholder key
+    pub(crate) fn fixture(device_id: &str, device_type: HardwareType, user_id: &str) -> Self {
+        Self {
+            device_id: device_id.to_string(),
+            device_type,
+            algorithm: DeviceKeyAlgorithm::EcdsaP256Sha256,
+            public_key: "04aa".to_string(),
+            attestation_chain: vec![],
+            attestation_key: None,
+            host_class: None,
+            user_id: user_id.to_string(),
+            roles: vec![],
+            enrolled_at: 0,
+            status: DeviceStatus::Active,
+            status_changed_at: None,
+            status_reason: None,
+        }
+    }
+
+    /// Verify signatures with an Ed25519 key
+    pub(crate) fn with_ed25519_key(mut self, key: &ring::signature::Ed25519KeyPair) -> Self {
+        use ring::signature::KeyPair;
+        self.algorithm = DeviceKeyAlgorithm::Ed25519;
+        self.public_key = hex::encode(key.public_key().as_ref());
+        self
+    }
+
+    /// Grant roles to the device's sessions
+    pub(crate) fn with_roles(mut self, roles: &[&str]) -> Self {
+        self.roles = roles.iter().map(|role| role.to_string()).collect();
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(device_id: &str) -> EnrolledDevice {
+        EnrolledDevice::fixture(device_id, HardwareType::YubiKey, "alice").with_roles(&["upload"])
+    }
+
+    #[test]
+    fn test_status_lifecycle() {
+        let mut store = EnrollmentStore::default();
+        store.enroll(device("yk-1")).unwrap();
+        assert!(store.enroll(device("yk-1")).is_err());
+        assert_eq!(store.resolve_user("yk-1").unwrap(), "alice");
+
+        store.set_status("yk-1", DeviceStatus::Suspended, Some("lost".to_string())).unwrap();
+        assert!(store.resolve_user("yk-1").is_err());
+
+        store.set_status("yk-1", DeviceStatus::Active, None).unwrap();
+        assert!(store.active("yk-1").is_ok());
+
+        store.set_status("yk-1", DeviceStatus::Revoked, None).unwrap();
+        assert!(store.active("yk-1").is_err());
+        assert!(store.set_status("yk-1", DeviceStatus::Active, None).is_err());
+        assert!(store.enroll(device("yk-1")).is_err());
+    }
+
+    #[test]
+    fn test_active_software_devices_need_insecure_dev() {
+        let mut store = EnrollmentStore::default();
+        let mut software = device("soft-1");
+        software.device_type = HardwareType::Software;
+        store.enroll(software).unwrap();
+
+        assert!(store.check_insecure_dev(false).is_err());
+        assert!(store.check_insecure_dev(true).is_ok());
+
+        store.set_status("soft-1", DeviceStatus::Revoked, None).unwrap();
+        assert!(store.check_insecure_dev(false).is_ok());
+    }
+
+    #[test]
+    fn test_active_smart_cards_need_a_ca() {
+        let mut store = EnrollmentStore::default();
+        store.enroll(EnrolledDevice::fixture("card-1", HardwareType::SmartCard, "alice")).unwrap();
+
+        assert!(store.check_smartcard_ca(false).is_err());
+        assert!(store.check_smartcard_ca(true).is_ok());
+
+        store.set_status("card-1", DeviceStatus::Suspended, None).unwrap();
+        assert!(store.check_smartcard_ca(false).is_ok());
+    }
+
+    #[test]
+    fn test_legacy_entries_default_to_active() {
+        let json = r#"{"devices":{"tpm-1":{"device_id":"tpm-1","device_type":"Tpm","algorithm":"rsa_pkcs1_sha256","public_key":"00","user_id":"bob","enrolled_at":0}}}"#;
+        let store: EnrollmentStore = serde_json::from_str(json).unwrap();
+        assert_eq!(store.resolve_user("tpm-1").unwrap(), "bob");
+    }
+}
@@ -0,0 +1,121 @@
+//! State file helpers for LSFTP
+//!
+//! Registries that the server reads while running are replaced atomically.
+//! The new contents are written to a private temporary file next to the
+//! target, then renamed over it, so readers see the old file or the new one
+//! and never a partial write.
+//!
+//! Files changed by more than one process take a [`StateLock`] around their
+//! read-modify-write. The lock lives in a separate `.lock` file, because the
+//! rename replaces the state file's inode on every save.
+
+use crate::error::Result;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Temporary file written before the rename
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    PathBuf::from(temporary)
+}
+
+/// Atomically replace `path` with `contents`, readable by the owner only
+pub fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // A leftover from a crash may have looser permissions; start afresh
+    let temporary = temporary_path(path);
+    if temporary.exists() {
+        std::fs::remove_file(&temporary)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Advisory exclusive lock on a state file, released when dropped
+pub struct StateLock {
+    _file: std::fs::File,
+}
+
+impl StateLock {
+    /// Block until no other process holds the lock for `path`
+    pub fn acquire(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(0o600)
+            .open(PathBuf::from(lock_path))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_write_private_replaces_file() {
+        let path = std::env::temp_dir().join(format!("lsftp-state-{}.json", Uuid::new_v4()));
+        std::fs::write(&path, b"old contents, longer than the new ones").unwrap();
+
+        write_private(&path, b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!temporary_path(&path).exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_state_lock_excludes_other_holders() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let path = std::env::temp_dir().join(format!("lsftp-state-{}.json", Uuid::new_v4()));
+        let lock = StateLock::acquire(&path).unwrap();
+
+        let acquired = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let (path, acquired) = (path.clone(), acquired.clone());
+            std::thread::spawn(move || {
+                let _lock = StateLock::acquire(&path).unwrap();
+                acquired.store(true, Ordering::SeqCst);
+            })
+        };
+
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!acquired.load(Ordering::SeqCst));
+        drop(lock);
+        waiter.join().unwrap();
+        assert!(acquired.load(Ordering::SeqCst));
+
+        let mut lock_path = path.into_os_string();
+        lock_path.push(".lock");
+        std::fs::remove_file(lock_path).unwrap();
+    }
+}
@@ -0,0 +1,332 @@
+//! Hardware challenge-response handshake for LSFTP
+//!
+//! After the QUIC/TLS handshake the server sends a fresh random challenge.
+//! Both sides bind it to the TLS session through an exporter value, so a
+//! signature cannot be replayed on another connection. The client signs
+//! the bound challenge with its hardware device; the server verifies the
+//! signature against the enrolled device key before the session is Ready.
+//! TPM clients must also present a quote over the same bound challenge, signed
+//! by their enrolled attestation key; with a PCR policy configured its boot
+//! state must match their host class.
+//! With a smart card CA bundle configured, smart cards must present a PIV
+//! certificate that chains to it, carries the enrolled key and names the
+//! enrolled user. Additional factors for multi-factor policies sign the same
+//! bound challenge and are checked the same way.
+
+use crate::auth::{HardwareAttestation, HardwareAuthFactory, HardwareType};
+use crate::enrollment::{EnrolledDevice, EnrollmentStore};
+use crate::error::{Error, Result};
+use crate::now_secs;
+use crate::protocol::{AuthChallengePayload, AuthResponsePayload, FactorResponse};
+use crate::smartcard::CaBundle;
+use crate::tpmquote::{self, PcrPolicy, DEFAULT_HOST_CLASS};
+use ring::rand::SecureRandom;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+/// TLS exporter label for the authentication channel binding
+pub const AUTH_EXPORTER_LABEL: &[u8] = b"EXPORTER-lsftp-auth-v1";
+
+/// Channel binding length in bytes
+pub const CHANNEL_BINDING_LEN: usize = 32;
+
+/// Domain separator for signed challenges
+const CHALLENGE_CONTEXT: &[u8] = b"LSFTP-AUTH-CHALLENGE-v1";
+
+/// Issue a fresh challenge for a session
+pub fn issue_challenge(session_id: Uuid, hardware_required: bool) -> Result<AuthChallengePayload> {
+    let mut nonce = [0u8; 32];
+    ring::rand::SystemRandom::new().fill(&mut nonce)?;
+
+    Ok(AuthChallengePayload {
+        session_id,
+        nonce,
+        issued_at: now_secs(),
+        hardware_required,
+    })
+}
+
+/// Message the device signs: SHA-256 over the challenge and channel binding
+pub fn challenge_message(channel_binding: &[u8], challenge: &AuthChallengePayload) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(CHALLENGE_CONTEXT);
+    hasher.update(channel_binding);
+    hasher.update(challenge.session_id.as_bytes());
+    hasher.update(challenge.nonce);
+    hasher.update(challenge.issued_at.to_be_bytes());
+    hasher.update([challenge.hardware_required as u8]);
+    hasher.finalize().into()
+}
+
+/// Verify a challenge response; returns the enrolled device, if one answered
+pub fn verify_response<'a>(
+    enrollments: &'a EnrollmentStore,
+    channel_binding: &[u8],
+    challenge: &AuthChallengePayload,
+    response: &AuthResponsePayload,
+    pcr_policy: Option<&PcrPolicy>,
+    smartcard_ca: Option<&CaBundle>,
+) -> Result<Option<&'a EnrolledDevice>> {
+    let nonce_matches: bool = response.nonce[..].ct_eq(&challenge.nonce[..]).into();
+    if !nonce_matches || response.session_id != challenge.session_id {
+        return Err(Error::Auth("Response does not answer this challenge".to_string()));
+    }
+
+    if now_secs().saturating_sub(challenge.issued_at) > crate::HARDWARE_AUTH_TIMEOUT_SECS {
+        return Err(Error::Auth("Challenge expired".to_string()));
+    }
+
+    let device_id = match &response.device_id {
+        Some(device_id) => device_id,
+        None if challenge.hardware_required => {
+            return Err(Error::Auth("Hardware authentication required".to_string()));
+        }
+        None => return Ok(None),
+    };
+
+    let message = challenge_message(channel_binding, challenge);
+    let device = verify_device(
+        enrollments, &message, response.device_type, device_id, &response.signature,
+        response.attestation.as_ref(), pcr_policy, smartcard_ca,
+    )?;
+
+    Ok(Some(device))
+}
+
+/// Verify an additional factor's signature over the bound challenge message
+pub fn verify_factor<'a>(
+    enrollments: &'a EnrollmentStore,
+    message: &[u8],
+    factor: &FactorResponse,
+    pcr_policy: Option<&PcrPolicy>,
+    smartcard_ca: Option<&CaBundle>,
+) -> Result<&'a EnrolledDevice> {
+    verify_device(
+        enrollments, message, Some(factor.device_type), &factor.device_id, &factor.signature,
+        factor.attestation.as_ref(), pcr_policy, smartcard_ca,
+    )
+}
+
+/// Check one device's signature, attestation and policies against its enrollment
+#[allow(clippy::too_many_arguments)]
+fn verify_device<'a>(
+    enrollments: &'a EnrollmentStore,
+    message: &[u8],
+    device_type: Option<HardwareType>,
+    device_id: &str,
+    signature: &[u8],
+    attestation: Option<&HardwareAttestation>,
+    pcr_policy: Option<&PcrPolicy>,
+    smartcard_ca: Option<&CaBundle>,
+) -> Result<&'a EnrolledDevice> {
+    let device = enrollments.active(device_id)?;
+
+    // The software authenticator is never trusted like hardware without the explicit opt-in
+    if device.device_type == HardwareType::Software && !HardwareAuthFactory::insecure_dev_allowed() {
+        return Err(Error::Auth(format!("Device {} is a software authenticator and insecure-dev is off", device_id)));
+    }
+
+    if device_type != Some(device.device_type) {
+        return Err(Error::Auth(format!("Device {} reported an unexpected type", device_id)));
+    }
+
+    if let Some(attestation) = attestation {
+        if attestation.device_id != device_id || attestation.device_type != device.device_type {
+            return Err(Error::Auth(format!("Attestation does not belong to device {}", device_id)));
+        }
+    }
+
+    if !device.verify(message, signature)? {
+        return Err(Error::Auth(format!("Invalid challenge signature from device {}", device_id)));
+    }
+
+    if device.device_type == HardwareType::Tpm {
+        verify_boot_state(device, attestation, message, pcr_policy)?;
+    }
+
+    if let (Some(ca), HardwareType::SmartCard) = (smartcard_ca, device.device_type) {
+        verify_certificate(device, attestation, ca)?;
+    }
+
+    Ok(device)
+}
+
+/// Check a TPM device's quote over the bound challenge and, with a PCR policy,
+/// its boot state against its host class
+fn verify_boot_state(
+    device: &EnrolledDevice,
+    attestation: Option<&HardwareAttestation>,
+    message: &[u8],
+    policy: Option<&PcrPolicy>,
+) -> Result<()> {
+    let attestation = attestation
+        .ok_or_else(|| Error::Auth(format!("Device {} sent no TPM quote", device.device_id)))?;
+    let attestation_key = device.attestation_key.as_ref()
+        .ok_or_else(|| Error::Auth(format!("Device {} has no enrolled attestation key", device.device_id)))?;
+
+    let host_class = device.host_class.as_deref().unwrap_or(DEFAULT_HOST_CLASS);
+    let baseline = tpmquote::verify_quote(
+        &attestation.attestation_data,
+        &attestation.signature,
+        attestation_key.algorithm,
+        &attestation_key.public_key_bytes()?,
+        message,
+        policy.map(|policy| policy.class(host_class)).transpose()?,
+    ).map_err(|e| Error::Auth(format!("Device {} failed boot attestation: {}", device.device_id, e)))?;
+
+    if let Some(baseline) = baseline {
+        tracing::debug!("Device {} matches {} baseline {}", device.device_id, host_class, baseline);
+    }
+    Ok(())
+}
+
+/// Check a smart card's PIV certificate against the CA bundle and its enrollment
+fn verify_certificate(device: &EnrolledDevice, attestation: Option<&HardwareAttestation>, ca: &CaBundle) -> Result<()> {
+    let chain = attestation
+        .map(|attestation| attestation.certificate_chain.as_slice())
+        .unwrap_or_default();
+    let (certificate, intermediates) = chain.split_first()
+        .ok_or_else(|| Error::Auth(format!("Device {} sent no certificate", device.device_id)))?;
+
+    let info = ca.validate(certificate, intermediates)
+        .map_err(|e| Error::Auth(format!("Device {} certificate rejected: {}", device.device_id, e)))?;
+
+    if hex::encode(&info.public_key) != device.public_key {
+        return Err(Error::Auth(format!("Certificate of device {} does not hold the enrolled key", device.device_id)));
+    }
+    if info.identity != device.user_id {
+        return Err(Error::Auth(format!(
+            "Certificate of device {} names {}, enrolled for {}", device.device_id, info.identity, device.user_id
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::HardwareType;
+
+    fn enrolled_device() -> (ring::signature::Ed25519KeyPair, EnrollmentStore) {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let key = ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+
+        let mut store = EnrollmentStore::default();
+        store.insert(EnrolledDevice::fixture("yubikey-test", HardwareType::YubiKey, "alice").with_ed25519_key(&key));
+        (key, store)
+    }
+
+    fn response(challenge: &AuthChallengePayload, signature: Vec<u8>) -> AuthResponsePayload {
+        AuthResponsePayload {
+            session_id: challenge.session_id,
+            nonce: challenge.nonce,
+            device_type: Some(HardwareType::YubiKey),
+            device_id: Some("yubikey-test".to_string()),
+            signature,
+            attestation: None,
+            additional_factors: vec![],
+            resumption_ticket: None,
+            crypto_suite: crate::crypto::CryptoSuite::default(),
+            kem_public_key: vec![],
+        }
+    }
+
+    #[test]
+    fn test_valid_response_accepted() {
+        let (key, store) = enrolled_device();
+        let binding = [7u8; CHANNEL_BINDING_LEN];
+        let challenge = issue_challenge(Uuid::new_v4(), true).unwrap();
+
+        let signature = key.sign(&challenge_message(&binding, &challenge)).as_ref().to_vec();
+        let device = verify_response(&store, &binding, &challenge, &response(&challenge, signature), None, None).unwrap();
+        assert_eq!(device.unwrap().user_id, "alice");
+    }
+
+    #[test]
+    fn test_response_bound_to_channel() {
+        let (key, store) = enrolled_device();
+        let challenge = issue_challenge(Uuid::new_v4(), true).unwrap();
+
+        // A signature made on another TLS session does not verify here
+        let signature = key.sign(&challenge_message(&[1u8; CHANNEL_BINDING_LEN], &challenge)).as_ref().to_vec();
+        assert!(verify_response(&store, &[2u8; CHANNEL_BINDING_LEN], &challenge, &response(&challenge, signature), None, None).is_err());
+    }
+
+    #[test]
+    fn test_unenrolled_and_missing_devices_rejected() {
+        let (key, store) = enrolled_device();
+        let binding = [7u8; CHANNEL_BINDING_LEN];
+        let challenge = issue_challenge(Uuid::new_v4(), true).unwrap();
+        let signature = key.sign(&challenge_message(&binding, &challenge)).as_ref().to_vec();
+
+        let mut unknown = response(&challenge, signature);
+        unknown.device_id = Some("yubikey-other".to_string());
+        assert!(verify_response(&store, &binding, &challenge, &unknown, None, None).is_err());
+
+        let mut anonymous = unknown.clone();
+        anonymous.device_id = None;
+        assert!(verify_response(&store, &binding, &challenge, &anonymous, None, None).is_err());
+
+        // Without the hardware requirement an anonymous response is accepted
+        let optional = issue_challenge(Uuid::new_v4(), false).unwrap();
+        anonymous.session_id = optional.session_id;
+        anonymous.nonce = optional.nonce;
+        assert!(verify_response(&store, &binding, &optional, &anonymous, None, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_software_device_needs_insecure_dev() {
+        let (key, mut store) = enrolled_device();
+        let mut device = store.get("yubikey-test").unwrap().clone();
+        device.device_type = HardwareType::Software;
+        store.insert(device);
+
+        let binding = [7u8; CHANNEL_BINDING_LEN];
+        let challenge = issue_challenge(Uuid::new_v4(), true).unwrap();
+        let signature = key.sign(&challenge_message(&binding, &challenge)).as_ref().to_vec();
+        let mut software_response = response(&challenge, signature);
+        software_response.device_type = Some(HardwareType::Software);
+
+        // No test enables insecure-dev, so a valid signature is still refused
+        assert!(verify_response(&store, &binding, &challenge, &software_response, None, None).is_err());
+    }
+
+    #[test]
+    fn test_tpm_device_needs_quote() {
+        let (key, mut store) = enrolled_device();
+        let mut device = store.get("yubikey-test").unwrap().clone();
+        device.device_type = HardwareType::Tpm;
+        store.insert(device);
+
+        let binding = [7u8; CHANNEL_BINDING_LEN];
+        let challenge = issue_challenge(Uuid::new_v4(), true).unwrap();
+        let signature = key.sign(&challenge_message(&binding, &challenge)).as_ref().to_vec();
+        let mut tpm_response = response(&challenge, signature);
+        tpm_response.device_type = Some(HardwareType::Tpm);
+
+        // A valid challenge signature without a quote is refused, with or without a PCR policy
+        assert!(verify_response(&store, &binding, &challenge, &tpm_response, None, None).is_err());
+        assert!(verify_response(&store, &binding, &challenge, &tpm_response, Some(&PcrPolicy::default()), None).is_err());
+    }
+
+    #[test]
+    fn test_smartcard_needs_certificate_under_ca() {
+        let (key, mut store) = enrolled_device();
+        let mut device = store.get("yubikey-test").unwrap().clone();
+        device.device_type = HardwareType::SmartCard;
+        store.insert(device);
+
+        let binding = [7u8; CHANNEL_BINDING_LEN];
+        let challenge = issue_challenge(Uuid::new_v4(), true).unwrap();
+        let signature = key.sign(&challenge_message(&binding, &challenge)).as_ref().to_vec();
+        let mut card_response = response(&challenge, signature);
+        card_response.device_type = Some(HardwareType::SmartCard);
+
+        let ca = CaBundle::from_der(vec![vec![0x30, 0x00]]);
+        assert!(verify_response(&store, &binding, &challenge, &card_response, None, None).is_ok());
+        assert!(verify_response(&store, &binding, &challenge, &card_response, None, Some(&ca)).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Session key exchange for LSFTP
+//!
+//! The TLS 1.3 handshake under QUIC only offers classical key exchange
+//! groups, so the key exchange of a session's declared crypto suite runs
+//! inside the authentication exchange. The client sends a fresh public key
+//! for the suite's KEM with its authentication response. The server
+//! encapsulates to it and returns the ciphertext with the authentication
+//! status. Both sides mix the shared secret into the session key schedule, so
+//! a client that declares a suite without running its KEM cannot derive any
+//! file key.
+//!
+//! Hybrid keys and ciphertexts are the X25519 part followed by the ML-KEM-768
+//! part; the shared secret is both secrets concatenated.
+
+use crate::crypto::KemAlgorithm;
+use crate::error::{Error, Result};
+use crate::secmem::SecretBuffer;
+use ring::agreement::{agree_ephemeral, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+
+/// X25519 public key length in bytes
+const X25519_KEY_LEN: usize = 32;
+
+/// ML-KEM parameter set of a suite's KEM, if it has one
+fn ml_kem(algorithm: KemAlgorithm) -> Result<Option<oqs::kem::Kem>> {
+    let parameters = match algorithm {
+        KemAlgorithm::EcdheP256 => return Ok(None),
+        KemAlgorithm::HybridEcdheP256MlKem768 | KemAlgorithm::MlKem768 => oqs::kem::Algorithm::Kyber768,
+        KemAlgorithm::MlKem1024 => oqs::kem::Algorithm::Kyber1024,
+    };
+    oqs::kem::Kem::new(parameters)
+        .map(Some)
+        .map_err(|e| Error::Crypto(format!("Failed to initialize KEM: {}", e)))
+}
+
+/// Split a key or ciphertext into its X25519 and ML-KEM parts
+fn split(algorithm: KemAlgorithm, data: &[u8]) -> Result<(&[u8], &[u8])> {
+    match algorithm {
+        KemAlgorithm::EcdheP256 if data.len() == X25519_KEY_LEN => Ok((data, &[])),
+        KemAlgorithm::HybridEcdheP256MlKem768 if data.len() > X25519_KEY_LEN => Ok(data.split_at(X25519_KEY_LEN)),
+        KemAlgorithm::MlKem768 | KemAlgorithm::MlKem1024 => Ok((&[], data)),
+        _ => Err(Error::Crypto(format!("Key exchange data does not match {:?}", algorithm))),
+    }
+}
+
+/// Client half of a session key exchange
+pub struct KemKeyPair {
+    algorithm: KemAlgorithm,
+    public_key: Vec<u8>,
+    classical: Option<EphemeralPrivateKey>,
+    post_quantum: Option<SecretBuffer>,
+}
+
+impl KemKeyPair {
+    /// Fresh key pair for a suite's KEM
+    pub fn generate(algorithm: KemAlgorithm) -> Result<Self> {
+        let mut public_key = Vec::new();
+
+        let classical = match algorithm {
+            KemAlgorithm::EcdheP256 | KemAlgorithm::HybridEcdheP256MlKem768 => {
+                let private_key = EphemeralPrivateKey::generate(&X25519, &ring::rand::SystemRandom::new())?;
+                public_key.extend_from_slice(private_key.compute_public_key()?.as_ref());
+                Some(private_key)
+            }
+            KemAlgorithm::MlKem768 | KemAlgorithm::MlKem1024 => None,
+        };
+
+        let post_quantum = match ml_kem(algorithm)? {
+            Some(kem) => {
+                let (kem_public, kem_secret) = kem.keypair()
+                    .map_err(|e| Error::Crypto(format!("Failed to generate KEM keypair: {}", e)))?;
+                public_key.extend_from_slice(kem_public.as_ref());
+                Some(SecretBuffer::from_vec(kem_secret.into_vec())?)
+            }
+            None => None,
+        };
+
+        Ok(Self { algorithm, public_key, classical, post_quantum })
+    }
+
+    /// KEM this key pair belongs to
+    pub fn algorithm(&self) -> KemAlgorithm {
+        self.algorithm
+    }
+
+    /// Public key sent to the server
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Recover the shared secret from the server's ciphertext
+    pub fn decapsulate(self, ciphertext: &[u8]) -> Result<SecretBuffer> {
+        let (classical_part, post_quantum_part) = split(self.algorithm, ciphertext)?;
+        let mut secret = Vec::new();
+
+        if let Some(private_key) = self.classical {
+            secret.extend(agree_ephemeral(
+                private_key,
+                &UnparsedPublicKey::new(&X25519, classical_part),
+                |material| material.to_vec(),
+            )?);
+        }
+
+        if let (Some(kem), Some(kem_secret)) = (ml_kem(self.algorithm)?, &self.post_quantum) {
+            let invalid = || Error::Crypto(format!("Malformed {:?} ciphertext", self.algorithm));
+            let kem_secret = kem.secret_key_from_bytes(kem_secret.as_slice()).ok_or_else(invalid)?;
+            let kem_ciphertext = kem.ciphertext_from_bytes(post_quantum_part).ok_or_else(invalid)?;
+            let shared = kem.decapsulate(kem_secret, kem_ciphertext)
+                .map_err(|e| Error::Crypto(format!("Failed to decapsulate: {}", e)))?;
+            secret.extend_from_slice(shared.as_ref());
+        }
+
+        SecretBuffer::from_vec(secret)
+    }
+}
+
+/// Server half of a session key exchange: encapsulate to the client's public
+/// key; returns the ciphertext and the shared secret
+pub fn encapsulate(algorithm: KemAlgorithm, public_key: &[u8]) -> Result<(Vec<u8>, SecretBuffer)> {
+    let (classical_part, post_quantum_part) = split(algorithm, public_key)?;
+    let mut ciphertext = Vec::new();
+    let mut secret = Vec::new();
+
+    if !classical_part.is_empty() {
+        let private_key = EphemeralPrivateKey::generate(&X25519, &ring::rand::SystemRandom::new())?;
+        ciphertext.extend_from_slice(private_key.compute_public_key()?.as_ref());
+        secret.extend(agree_ephemeral(
+            private_key,
+            &UnparsedPublicKey::new(&X25519, classical_part),
+            |material| material.to_vec(),
+        )?);
+    }
+
+    if let Some(kem) = ml_kem(algorithm)? {
+        let kem_public = kem.public_key_from_bytes(post_quantum_part)
+            .ok_or_else(|| Error::Crypto(format!("Malformed {:?} public key", algorithm)))?;
+        let (kem_ciphertext, shared) = kem.encapsulate(kem_public)
+            .map_err(|e| Error::Crypto(format!("Failed to encapsulate: {}", e)))?;
+        ciphertext.extend_from_slice(kem_ciphertext.as_ref());
+        secret.extend_from_slice(shared.as_ref());
+    }
+
+    Ok((ciphertext, SecretBuffer::from_vec(secret)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [KemAlgorithm; 4] = [
+        KemAlgorithm::EcdheP256,
+        KemAlgorithm::HybridEcdheP256MlKem768,
+        KemAlgorithm::MlKem768,
+        KemAlgorithm::MlKem1024,
+    ];
+
+    #[test]
+    fn test_round_trips() {
+        for algorithm in ALGORITHMS {
+            let client = KemKeyPair::generate(algorithm).unwrap();
+            let (ciphertext, server_secret) = encapsulate(algorithm, client.public_key()).unwrap();
+            let client_secret = client.decapsulate(&ciphertext).unwrap();
+            assert_eq!(client_secret.as_slice(), server_secret.as_slice(), "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_key_must_match_declared_kem() {
+        // A classical key cannot pass for a post-quantum or hybrid one, nor the reverse
+        let classical = KemKeyPair::generate(KemAlgorithm::EcdheP256).unwrap();
+        assert!(encapsulate(KemAlgorithm::MlKem768, classical.public_key()).is_err());
+        assert!(encapsulate(KemAlgorithm::HybridEcdheP256MlKem768, classical.public_key()).is_err());
+
+        let post_quantum = KemKeyPair::generate(KemAlgorithm::MlKem768).unwrap();
+        assert!(encapsulate(KemAlgorithm::EcdheP256, post_quantum.public_key()).is_err());
+        assert!(encapsulate(KemAlgorithm::MlKem1024, post_quantum.public_key()).is_err());
+    }
+}
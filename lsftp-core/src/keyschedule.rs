@@ -0,0 +1,264 @@
+//! Session key schedule for LSFTP
+//!
+//! Every session derives a master secret from the QUIC/TLS 1.3 exporter and
+//! the shared secret of the declared suite's key exchange (see `kem`).
+//! Each file transferred in the session gets its own key, derived with
+//! HKDF-SHA256 from that secret and the file ID. File keys live in
+//! `SecretBuffer`s and are erased on `FileClose`, so one file's key reveals
+//! nothing about the keys of other files in the same session.
+
+use crate::crypto::{CryptoOperations, CryptoSuite};
+use crate::error::{Error, Result};
+use crate::secmem::SecretBuffer;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+use zeroize::Zeroize;
+
+/// TLS exporter label for the session master secret
+pub const SESSION_EXPORTER_LABEL: &[u8] = b"EXPORTER-lsftp-session-v1";
+
+/// Session master secret length in bytes
+pub const SESSION_SECRET_LEN: usize = 32;
+
+/// AEAD key length for file keys in bytes
+pub const FILE_KEY_LEN: usize = 32;
+
+/// HKDF salt for the session key schedule
+const KEY_SCHEDULE_SALT: &[u8] = b"lsftp key schedule v1";
+
+/// HKDF info prefix for per-file keys
+const FILE_KEY_INFO: &[u8] = b"lsftp file key";
+
+/// HKDF info for the session master secret
+const SESSION_SECRET_INFO: &[u8] = b"lsftp session secret";
+
+/// Output length marker for ring's HKDF
+struct KeyLength(usize);
+
+impl ring::hkdf::KeyType for KeyLength {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Session master secret: HKDF-SHA256 keyed by the TLS exporter over the
+/// KEM shared secret, so both must be known to derive any file key
+pub fn session_secret(exporter_secret: &SecretBuffer, kem_secret: &SecretBuffer) -> Result<SecretBuffer> {
+    let salt = ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, exporter_secret.as_slice());
+    let prk = salt.extract(kem_secret.as_slice());
+
+    let mut secret = SecretBuffer::new(SESSION_SECRET_LEN)?;
+    prk.expand(&[SESSION_SECRET_INFO], KeyLength(SESSION_SECRET_LEN))?
+        .fill(secret.as_mut_slice())?;
+
+    Ok(secret)
+}
+
+/// Per-file ephemeral key
+pub struct FileKey {
+    file_id: Uuid,
+    key: SecretBuffer,
+}
+
+impl FileKey {
+    /// File this key belongs to
+    pub fn file_id(&self) -> Uuid {
+        self.file_id
+    }
+
+    /// Encrypt one chunk of this file
+    pub fn encrypt_chunk(&self, crypto_suite: &CryptoSuite, chunk_index: u32, plaintext: &[u8]) -> Result<Vec<u8>> {
+        crypto_suite.encrypt(plaintext, self.key.as_slice(), &Self::chunk_nonce(chunk_index))
+    }
+
+    /// Decrypt one chunk of this file
+    pub fn decrypt_chunk(&self, crypto_suite: &CryptoSuite, chunk_index: u32, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        crypto_suite.decrypt(ciphertext, self.key.as_slice(), &Self::chunk_nonce(chunk_index))
+    }
+
+    /// Nonce for a chunk; unique because every file has its own key
+    fn chunk_nonce(chunk_index: u32) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[8..].copy_from_slice(&chunk_index.to_be_bytes());
+        nonce
+    }
+
+    /// Overwrite the key material in place
+    fn erase(&mut self) {
+        self.key.as_mut_slice().zeroize();
+    }
+}
+
+/// Key schedule for one session
+pub struct KeySchedule {
+    session_secret: SecretBuffer,
+    crypto_suite: CryptoSuite,
+    file_keys: HashMap<Uuid, FileKey>,
+    erased: HashSet<Uuid>,
+}
+
+impl KeySchedule {
+    /// Create key schedule from the exported session secret
+    pub fn new(session_secret: SecretBuffer, crypto_suite: CryptoSuite) -> Result<Self> {
+        if session_secret.len() < SESSION_SECRET_LEN {
+            return Err(Error::Crypto("Session secret too short".to_string()));
+        }
+
+        Ok(Self {
+            session_secret,
+            crypto_suite,
+            file_keys: HashMap::new(),
+            erased: HashSet::new(),
+        })
+    }
+
+    /// Crypto suite used for file encryption
+    pub fn crypto_suite(&self) -> &CryptoSuite {
+        &self.crypto_suite
+    }
+
+    /// Derive and hold the key for a newly opened file
+    pub fn open_file(&mut self, file_id: Uuid) -> Result<&FileKey> {
+        // A closed file's key must never come back within the session
+        if self.erased.contains(&file_id) || self.file_keys.contains_key(&file_id) {
+            return Err(Error::Crypto(format!("File key for {} already issued", file_id)));
+        }
+
+        let key = self.derive_file_key(file_id)?;
+        Ok(self.file_keys.entry(file_id).or_insert(FileKey { file_id, key }))
+    }
+
+    /// Key of an open file
+    pub fn file_key(&self, file_id: &Uuid) -> Result<&FileKey> {
+        self.file_keys.get(file_id)
+            .ok_or_else(|| Error::Crypto(format!("No active file key for {}", file_id)))
+    }
+
+    /// Erase a file's key on close
+    pub fn erase_file_key(&mut self, file_id: &Uuid) -> bool {
+        self.erased.insert(*file_id);
+        match self.file_keys.remove(file_id) {
+            Some(mut file_key) => {
+                file_key.erase();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Number of file keys currently held
+    pub fn active_file_keys(&self) -> usize {
+        self.file_keys.len()
+    }
+
+    /// HKDF-SHA256(session secret, "lsftp file key" || file ID)
+    fn derive_file_key(&self, file_id: Uuid) -> Result<SecretBuffer> {
+        let salt = ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, KEY_SCHEDULE_SALT);
+        let prk = salt.extract(self.session_secret.as_slice());
+        let info = [FILE_KEY_INFO, file_id.as_bytes().as_slice()];
+
+        let mut key = SecretBuffer::new(FILE_KEY_LEN)?;
+        prk.expand(&info, KeyLength(FILE_KEY_LEN))?
+            .fill(key.as_mut_slice())?;
+
+        Ok(key)
+    }
+}
+
+impl Drop for KeySchedule {
+    fn drop(&mut self) {
+        for file_key in self.file_keys.values_mut() {
+            file_key.erase();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(secret_byte: u8) -> KeySchedule {
+        let secret = SecretBuffer::from_slice(&[secret_byte; SESSION_SECRET_LEN]).unwrap();
+        KeySchedule::new(secret, CryptoSuite::default()).unwrap()
+    }
+
+    #[test]
+    fn test_file_keys_differ() {
+        let mut keys = schedule(7);
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+
+        let first_key = keys.open_file(first).unwrap().key.as_slice().to_vec();
+        let second_key = keys.open_file(second).unwrap().key.as_slice().to_vec();
+        assert_ne!(first_key, second_key);
+
+        // The same file ID in another session yields another key
+        let mut other_session = schedule(8);
+        let other_key = other_session.open_file(first).unwrap().key.as_slice().to_vec();
+        assert_ne!(first_key, other_key);
+    }
+
+    #[test]
+    fn test_chunk_encryption_is_file_bound() {
+        let mut keys = schedule(7);
+        let suite = keys.crypto_suite().clone();
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        keys.open_file(first).unwrap();
+        keys.open_file(second).unwrap();
+
+        let ciphertext = keys.file_key(&first).unwrap().encrypt_chunk(&suite, 0, b"chunk").unwrap();
+        assert_eq!(keys.file_key(&first).unwrap().decrypt_chunk(&suite, 0, &ciphertext).unwrap(), b"chunk");
+        assert!(keys.file_key(&second).unwrap().decrypt_chunk(&suite, 0, &ciphertext).is_err());
+        assert!(keys.file_key(&first).unwrap().decrypt_chunk(&suite, 1, &ciphertext).is_err());
+    }
+
+    #[test]
+    fn test_session_secret_needs_kem_secret() {
+        let exporter = SecretBuffer::from_slice(&[7; SESSION_SECRET_LEN]).unwrap();
+        let kem_secret = SecretBuffer::from_slice(&[9; 64]).unwrap();
+        let other_kem_secret = SecretBuffer::from_slice(&[10; 64]).unwrap();
+
+        let mut negotiated = KeySchedule::new(session_secret(&exporter, &kem_secret).unwrap(), CryptoSuite::default()).unwrap();
+        let mut exporter_only = KeySchedule::new(exporter, CryptoSuite::default()).unwrap();
+        let suite = negotiated.crypto_suite().clone();
+        let file_id = Uuid::new_v4();
+
+        // A peer that skipped the key exchange, or ran another one, cannot read the file
+        let ciphertext = negotiated.open_file(file_id).unwrap().encrypt_chunk(&suite, 0, b"chunk").unwrap();
+        assert!(exporter_only.open_file(file_id).unwrap().decrypt_chunk(&suite, 0, &ciphertext).is_err());
+        let exporter = SecretBuffer::from_slice(&[7; SESSION_SECRET_LEN]).unwrap();
+        let mut mismatched = KeySchedule::new(session_secret(&exporter, &other_kem_secret).unwrap(), CryptoSuite::default()).unwrap();
+        assert!(mismatched.open_file(file_id).unwrap().decrypt_chunk(&suite, 0, &ciphertext).is_err());
+    }
+
+    #[test]
+    fn test_file_key_erasure() {
+        let mut keys = schedule(7);
+        let file_id = Uuid::new_v4();
+        keys.open_file(file_id).unwrap();
+        assert_eq!(keys.active_file_keys(), 1);
+
+        assert!(keys.erase_file_key(&file_id));
+        assert_eq!(keys.active_file_keys(), 0);
+        assert!(keys.file_key(&file_id).is_err());
+
+        // Erased keys cannot be re-derived within the session
+        assert!(keys.open_file(file_id).is_err());
+    }
+
+    #[test]
+    fn test_file_key_zeroized_on_erase() {
+        let mut keys = schedule(7);
+        let file_id = Uuid::new_v4();
+        let mut file_key = FileKey {
+            file_id,
+            key: keys.derive_file_key(file_id).unwrap(),
+        };
+        assert!(file_key.key.as_slice().iter().any(|&b| b != 0));
+
+        file_key.erase();
+        assert!(file_key.key.as_slice().iter().all(|&b| b == 0));
+        keys.erase_file_key(&file_id);
+    }
+}
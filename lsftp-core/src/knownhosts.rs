@@ -0,0 +1,372 @@
+//! Server identity verification for LSFTP clients
+//!
+//! A server certificate is trusted in one of three ways, checked in order:
+//!
+//! 1. Pinned SPKI fingerprints: when any are configured, the server key must
+//!    be one of them and nothing else is consulted.
+//! 2. The known_hosts file: a host recorded there must present the recorded
+//!    key. A different key aborts the connection with a loud warning, as it
+//!    may mean the connection is being intercepted.
+//! 3. The CA bundle (or the system roots): a certificate that chains to it
+//!    and matches the server name is accepted and, in trust-on-first-use
+//!    mode, recorded. In trust-on-first-use mode an unknown host whose
+//!    certificate does not chain is also recorded, with a warning; in strict
+//!    mode it is refused.
+//!
+//! Entries are `host:port sha256:<hex>`, one per line, `#` starts a comment.
+
+use crate::error::{Error, Result};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use x509_parser::prelude::*;
+
+/// Fingerprint prefix
+const FINGERPRINT_PREFIX: &str = "sha256:";
+
+/// Default known_hosts file, under the user's home directory
+pub fn default_known_hosts_path() -> PathBuf {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join(".lsftp")
+        .join("known_hosts")
+}
+
+/// SHA-256 fingerprint of a certificate's SubjectPublicKeyInfo, as `sha256:<hex>`
+pub fn spki_fingerprint(certificate: &[u8]) -> Result<String> {
+    let (_, certificate) = X509Certificate::from_der(certificate)
+        .map_err(|e| Error::Config(format!("Invalid certificate: {}", e)))?;
+    Ok(format!("{}{}", FINGERPRINT_PREFIX, hex::encode(Sha256::digest(certificate.public_key().raw))))
+}
+
+/// Normalize a fingerprint written with or without the prefix or colons
+pub fn parse_fingerprint(fingerprint: &str) -> Result<String> {
+    let hex: String = fingerprint.strip_prefix(FINGERPRINT_PREFIX)
+        .unwrap_or(fingerprint)
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::Config(format!("Invalid SPKI fingerprint: {}", fingerprint)));
+    }
+    Ok(format!("{}{}", FINGERPRINT_PREFIX, hex))
+}
+
+/// What to do with hosts missing from known_hosts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HostKeyChecking {
+    /// Record new hosts on first connection
+    #[default]
+    TrustOnFirstUse,
+    /// Never record hosts; unknown hosts need a CA-valid certificate
+    Strict,
+}
+
+impl FromStr for HostKeyChecking {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "tofu" | "accept-new" => Ok(HostKeyChecking::TrustOnFirstUse),
+            "strict" | "yes" => Ok(HostKeyChecking::Strict),
+            _ => Err(Error::Config(format!("Unknown host key checking mode: {}", s))),
+        }
+    }
+}
+
+/// Known host entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownHost {
+    /// `host:port`
+    pub host: String,
+    /// `sha256:<hex>` SPKI fingerprint
+    pub fingerprint: String,
+}
+
+/// known_hosts file
+#[derive(Debug, Clone, Default)]
+pub struct KnownHosts {
+    path: PathBuf,
+    entries: Vec<KnownHost>,
+}
+
+impl KnownHosts {
+    /// Load a known_hosts file; a missing file is empty
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(Error::Config(format!("Failed to read known hosts {:?}: {}", path, e))),
+        };
+
+        let entries = content.lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next(), fields.next()) {
+                    (Some(host), Some(fingerprint), None) => Ok(KnownHost {
+                        host: host.to_string(),
+                        fingerprint: parse_fingerprint(fingerprint)?,
+                    }),
+                    _ => Err(Error::Config(format!("Malformed known hosts line in {:?}: {}", path, line))),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { path: path.to_path_buf(), entries })
+    }
+
+    /// Write the file back atomically, creating its directory
+    pub fn save(&self) -> Result<()> {
+        let content: String = self.entries.iter()
+            .map(|entry| format!("{} {}\n", entry.host, entry.fingerprint))
+            .collect();
+        crate::fsutil::write_private(&self.path, content.as_bytes())
+            .map_err(|e| Error::Config(format!("Failed to write known hosts {:?}: {}", self.path, e)))
+    }
+
+    /// All entries
+    pub fn entries(&self) -> &[KnownHost] {
+        &self.entries
+    }
+
+    /// Fingerprints recorded for a host
+    pub fn fingerprints(&self, host: &str) -> Vec<&str> {
+        self.entries.iter()
+            .filter(|entry| entry.host == host)
+            .map(|entry| entry.fingerprint.as_str())
+            .collect()
+    }
+
+    /// Record a fingerprint for a host
+    pub fn add(&mut self, host: &str, fingerprint: &str) -> Result<()> {
+        let fingerprint = parse_fingerprint(fingerprint)?;
+        if !self.fingerprints(host).contains(&fingerprint.as_str()) {
+            self.entries.push(KnownHost { host: host.to_string(), fingerprint });
+        }
+        Ok(())
+    }
+
+    /// Forget a host; returns the number of removed entries
+    pub fn remove(&mut self, host: &str) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.host != host);
+        before - self.entries.len()
+    }
+}
+
+/// Server certificate verifier combining pins, known_hosts and a CA bundle
+pub struct ServerTrust {
+    host: String,
+    pins: Vec<String>,
+    known_hosts: Option<Mutex<KnownHosts>>,
+    checking: HostKeyChecking,
+    webpki: WebPkiVerifier,
+}
+
+impl ServerTrust {
+    /// Trust for `host` (written `name:port` in known_hosts) under a root store
+    pub fn new(host: String, roots: RootCertStore) -> Self {
+        Self {
+            host,
+            pins: Vec::new(),
+            known_hosts: None,
+            checking: HostKeyChecking::default(),
+            webpki: WebPkiVerifier::new(roots, None),
+        }
+    }
+
+    /// Only accept these SPKI fingerprints
+    pub fn with_pins(mut self, pins: &[String]) -> Result<Self> {
+        self.pins = pins.iter().map(|pin| parse_fingerprint(pin)).collect::<Result<_>>()?;
+        Ok(self)
+    }
+
+    /// Check and record hosts in a known_hosts file
+    pub fn with_known_hosts(mut self, known_hosts: KnownHosts, checking: HostKeyChecking) -> Self {
+        self.known_hosts = Some(Mutex::new(known_hosts));
+        self.checking = checking;
+        self
+    }
+
+    /// Decide on a certificate given its fingerprint and CA validation result
+    fn check(&self, fingerprint: &str, ca_result: std::result::Result<(), String>) -> Result<()> {
+        if !self.pins.is_empty() {
+            return if self.pins.iter().any(|pin| pin == fingerprint) {
+                Ok(())
+            } else {
+                Err(Error::Transport(format!("Server {} key {} matches no pinned key", self.host, fingerprint)))
+            };
+        }
+
+        let known_hosts = match &self.known_hosts {
+            Some(known_hosts) => known_hosts,
+            None => return ca_result.map_err(|e| Error::Transport(format!("Server {} certificate rejected: {}", self.host, e))),
+        };
+        let mut known_hosts = known_hosts.lock()
+            .map_err(|_| Error::Transport("Known hosts lock poisoned".to_string()))?;
+
+        let recorded = known_hosts.fingerprints(&self.host);
+        if recorded.contains(&fingerprint) {
+            return Ok(());
+        }
+        if !recorded.is_empty() {
+            tracing::error!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+            tracing::error!("@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @");
+            tracing::error!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+            tracing::error!("Someone could be intercepting this connection, or the host key was replaced.");
+            tracing::error!("Host {} presented {}, known_hosts records {}", self.host, fingerprint, recorded.join(", "));
+            tracing::error!("If the change is expected, run: lsftp-client known-hosts remove {}", self.host);
+            return Err(Error::Transport(format!("Host key for {} has changed", self.host)));
+        }
+
+        match (ca_result, self.checking) {
+            (Ok(()), HostKeyChecking::Strict) => return Ok(()),
+            (Err(e), HostKeyChecking::Strict) => {
+                return Err(Error::Transport(format!(
+                    "Host {} is not in known_hosts and its certificate is not trusted: {}", self.host, e
+                )));
+            }
+            (Ok(()), HostKeyChecking::TrustOnFirstUse) => {
+                tracing::info!("Recording CA-verified host {} ({})", self.host, fingerprint);
+            }
+            (Err(e), HostKeyChecking::TrustOnFirstUse) => {
+                tracing::warn!("Trusting unknown host {} on first use: {} ({})", self.host, fingerprint, e);
+            }
+        }
+
+        known_hosts.add(&self.host, fingerprint)?;
+        known_hosts.save()
+    }
+}
+
+impl ServerCertVerifier for ServerTrust {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = spki_fingerprint(&end_entity.0)
+            .map_err(|e| rustls::Error::General(e.to_string()))?;
+        let ca_result = self.webpki
+            .verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+
+        self.check(&fingerprint, ca_result)
+            .map(|_| ServerCertVerified::assertion())
+            .map_err(|e| rustls::Error::General(e.to_string()))
+    }
+}
+
+/// Root store from a PEM CA bundle, or the system roots
+pub fn root_store(ca_bundle: Option<&Path>) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    let certificates = match ca_bundle {
+        Some(path) => crate::transport::load_certificates(path)?,
+        None => rustls_native_certs::load_native_certs()
+            .map_err(|e| Error::Config(format!("Failed to load system roots: {}", e)))?
+            .into_iter()
+            .map(|certificate| Certificate(certificate.0))
+            .collect(),
+    };
+
+    for certificate in &certificates {
+        if let Err(e) = roots.add(certificate) {
+            tracing::debug!("Skipping unusable root certificate: {}", e);
+        }
+    }
+    if ca_bundle.is_some() && roots.is_empty() {
+        return Err(Error::Config("CA bundle holds no usable certificates".to_string()));
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    const FP_A: &str = "sha256:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    const FP_B: &str = "sha256:bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("lsftp-known-hosts-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_fingerprint_parsing() {
+        assert_eq!(parse_fingerprint(&FP_A.to_uppercase().replace("SHA256:", "")).unwrap(), FP_A);
+        assert!(parse_fingerprint("sha256:abcd").is_err());
+        assert!(spki_fingerprint(&[0x30, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_known_hosts_round_trip() {
+        let path = temp_path();
+        let mut known_hosts = KnownHosts::load(&path).unwrap();
+        assert!(known_hosts.entries().is_empty());
+
+        known_hosts.add("files.example.com:8443", FP_A).unwrap();
+        known_hosts.add("files.example.com:8443", FP_A).unwrap();
+        known_hosts.add("backup.example.com:8443", FP_B).unwrap();
+        known_hosts.save().unwrap();
+
+        let mut reloaded = KnownHosts::load(&path).unwrap();
+        assert_eq!(reloaded.entries().len(), 2);
+        assert_eq!(reloaded.fingerprints("files.example.com:8443"), vec![FP_A]);
+        assert_eq!(reloaded.remove("files.example.com:8443"), 1);
+        assert!(reloaded.fingerprints("files.example.com:8443").is_empty());
+
+        std::fs::write(&path, "files.example.com:8443\n").unwrap();
+        assert!(KnownHosts::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_pins_override_everything() {
+        let trust = ServerTrust::new("files.example.com:8443".to_string(), RootCertStore::empty())
+            .with_pins(&[FP_A.to_string()])
+            .unwrap();
+        assert!(trust.check(FP_A, Err("unknown issuer".to_string())).is_ok());
+        assert!(trust.check(FP_B, Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_trust_on_first_use_and_key_change() {
+        let path = temp_path();
+        let trust = ServerTrust::new("files.example.com:8443".to_string(), RootCertStore::empty())
+            .with_known_hosts(KnownHosts::load(&path).unwrap(), HostKeyChecking::TrustOnFirstUse);
+
+        // First contact records the key, later contacts must present it
+        assert!(trust.check(FP_A, Err("unknown issuer".to_string())).is_ok());
+        assert_eq!(KnownHosts::load(&path).unwrap().fingerprints("files.example.com:8443"), vec![FP_A]);
+        assert!(trust.check(FP_A, Err("unknown issuer".to_string())).is_ok());
+        assert!(trust.check(FP_B, Ok(())).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_strict_mode_refuses_unknown_hosts() {
+        let path = temp_path();
+        let trust = ServerTrust::new("files.example.com:8443".to_string(), RootCertStore::empty())
+            .with_known_hosts(KnownHosts::load(&path).unwrap(), HostKeyChecking::Strict);
+
+        assert!(trust.check(FP_A, Err("unknown issuer".to_string())).is_err());
+        assert!(trust.check(FP_A, Ok(())).is_ok());
+        // Strict mode never writes the file
+        assert!(!path.exists());
+    }
+}
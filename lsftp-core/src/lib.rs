@@ -10,14 +10,46 @@ pub mod auth;
 pub mod transport;
 pub mod audit;
 pub mod error;
+pub mod selftest;
+pub mod secmem;
+pub mod keyschedule;
+pub mod kem;
+pub mod shamir;
+pub mod der;
+pub mod enrollment;
+pub mod fsutil;
+pub mod handshake;
+pub mod tpmquote;
+pub mod tpmkey;
+pub mod tpmseal;
+pub mod piv;
+pub mod pkcs11;
+pub mod smartcard;
+pub mod mfa;
+pub mod mtls;
+pub mod knownhosts;
+pub mod presence;
+pub mod lockout;
+pub mod resumption;
+pub mod agent;
+pub mod access;
+pub mod vfs;
+pub mod quota;
+pub mod ratelimit;
+pub mod encryption;
+#[cfg(feature = "insecure-dev")]
+pub mod softauth;
 
 // Re-export commonly used types
 pub use error::{Error, Result};
 pub use protocol::{Message, MessageType, Frame};
 pub use auth::{HardwareAuth, AuthResult, HardwareType};
 pub use crypto::{CryptoSuite, KeyExchange, Signature};
+pub use secmem::SecretBuffer;
+pub use keyschedule::{KeySchedule, FileKey};
 pub use transport::{TransportConfig, QuicTransport, QuicServerTransport};
 pub use audit::{AuditEvent, AuditLogger, SecurityLogger};
+pub use selftest::{SelfTest, SelfTestReport, ModuleState};
 
 /// LSFTP Protocol Version (V1.0 as specified)
 pub const PROTOCOL_VERSION: u8 = 1;
@@ -40,6 +72,14 @@ pub const KEY_ROTATION_INTERVAL_SECS: u64 = 300;
 /// Hardware authentication timeout in seconds
 pub const HARDWARE_AUTH_TIMEOUT_SECS: u64 = 30;
 
+/// Seconds since the Unix epoch
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
 /// Maximum concurrent connections per server
 pub const MAX_CONCURRENT_CONNECTIONS: u32 = 1000;
 
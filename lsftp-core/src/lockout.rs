@@ -0,0 +1,399 @@
+//! Authentication throttling for LSFTP
+//!
+//! Failed authentications are counted per source IP and per device. Each
+//! failure doubles the time before the next attempt is accepted, and once a
+//! subject reaches the failure limit it is locked out for a fixed period. A
+//! success clears the subject's record.
+//!
+//! The records live in a JSON state file shared with `lsftp-tools`; the
+//! server re-reads it whenever it changes on disk, so clearing a lockout with
+//! the tools takes effect on the next attempt. Both take the file's
+//! [`StateLock`](crate::fsutil::StateLock) while they change it, so neither
+//! overwrites the other's update.
+
+use crate::error::{Error, Result};
+use crate::now_secs;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Default location of the lockout state file
+pub const DEFAULT_LOCKOUT_STATE_PATH: &str = "/var/lib/lsftp/auth-lockouts.json";
+
+/// Throttle key for a source address; the port is ignored
+pub fn ip_key(address: &str) -> String {
+    let ip = address.parse::<std::net::SocketAddr>()
+        .map(|address| address.ip().to_string())
+        .unwrap_or_else(|_| address.to_string());
+    format!("ip:{}", ip)
+}
+
+/// Throttle key for a hardware device
+pub fn device_key(device_id: &str) -> String {
+    format!("device:{}", device_id)
+}
+
+/// Failure limits and backoff timing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    /// Failures before a subject is locked out
+    pub max_failures: u32,
+    /// Wait after the first failure; doubles with each further failure
+    pub base_backoff_secs: u64,
+    /// Upper bound on the backoff wait
+    pub max_backoff_secs: u64,
+    /// Length of a lockout
+    pub lockout_secs: u64,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            base_backoff_secs: 1,
+            max_backoff_secs: 60,
+            lockout_secs: 900,
+        }
+    }
+}
+
+impl LockoutPolicy {
+    /// Wait required after `failures` consecutive failures
+    pub fn backoff_secs(&self, failures: u32) -> u64 {
+        if failures == 0 {
+            return 0;
+        }
+        let doubled = self.base_backoff_secs.saturating_mul(1u64 << (failures - 1).min(32));
+        doubled.min(self.max_backoff_secs)
+    }
+}
+
+/// Failures recorded for one IP or device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailureRecord {
+    /// Consecutive failures
+    pub failures: u32,
+    /// Unix time of the last failure
+    pub last_failure: u64,
+    /// Unix time the lockout ends, if locked out
+    pub locked_until: Option<u64>,
+}
+
+impl FailureRecord {
+    /// Whether the subject is locked out at `now`
+    pub fn is_locked(&self, now: u64) -> bool {
+        self.locked_until.is_some_and(|until| now < until)
+    }
+}
+
+/// Failure records keyed by `ip:<address>` or `device:<id>`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LockoutStore {
+    records: BTreeMap<String, FailureRecord>,
+}
+
+impl LockoutStore {
+    /// Load the state file; a missing file has no records
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    /// Save the state file atomically, so the server never reads a torn write
+    pub fn save(&self, path: &Path) -> Result<()> {
+        crate::fsutil::write_private(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    /// All records
+    pub fn records(&self) -> impl Iterator<Item = (&String, &FailureRecord)> {
+        self.records.iter()
+    }
+
+    /// Record for a key
+    pub fn get(&self, key: &str) -> Option<&FailureRecord> {
+        self.records.get(key)
+    }
+
+    /// Fail if the subject is locked out or still inside its backoff window
+    pub fn check(&self, key: &str, policy: &LockoutPolicy, now: u64) -> Result<()> {
+        let record = match self.records.get(key) {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+
+        if let Some(until) = record.locked_until.filter(|until| now < *until) {
+            return Err(Error::Auth(format!("{} is locked out for another {}s", key, until - now)));
+        }
+        if record.locked_until.is_none() {
+            let retry_at = record.last_failure.saturating_add(policy.backoff_secs(record.failures));
+            if now < retry_at {
+                return Err(Error::Auth(format!(
+                    "Too many failed attempts from {}; retry in {}s", key, retry_at - now
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a failure; returns the lockout end if this failure caused a lockout
+    pub fn record_failure(&mut self, key: &str, policy: &LockoutPolicy, now: u64) -> Option<u64> {
+        let record = self.records.entry(key.to_string()).or_insert(FailureRecord {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+
+        // An expired lockout starts the count again
+        if record.locked_until.is_some_and(|until| now >= until) {
+            record.failures = 0;
+            record.locked_until = None;
+        }
+
+        record.failures = record.failures.saturating_add(1);
+        record.last_failure = now;
+        if record.locked_until.is_none() && record.failures >= policy.max_failures {
+            let until = now.saturating_add(policy.lockout_secs);
+            record.locked_until = Some(until);
+            return Some(until);
+        }
+        None
+    }
+
+    /// Forget a subject's failures after a successful authentication
+    pub fn record_success(&mut self, key: &str) -> bool {
+        self.records.remove(key).is_some()
+    }
+
+    /// Clear one key, or every record when `key` is `None`; returns the number cleared
+    pub fn clear(&mut self, key: Option<&str>) -> usize {
+        match key {
+            Some(key) => usize::from(self.records.remove(key).is_some()),
+            None => {
+                let count = self.records.len();
+                self.records.clear();
+                count
+            }
+        }
+    }
+
+    /// Drop expired lockouts and failures older than a lockout period
+    pub fn prune(&mut self, policy: &LockoutPolicy, now: u64) {
+        self.records.retain(|_, record| match record.locked_until {
+            Some(until) => now < until,
+            None => now < record.last_failure.saturating_add(policy.lockout_secs),
+        });
+    }
+}
+
+/// Lockout raised by a failed authentication
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lockout {
+    /// Locked key
+    pub key: String,
+    /// Failures that led to the lockout
+    pub failures: u32,
+    /// Unix time the lockout ends
+    pub locked_until: u64,
+}
+
+struct ThrottleState {
+    store: LockoutStore,
+    modified: Option<SystemTime>,
+}
+
+/// Shared authentication throttle used by the server
+pub struct AuthThrottle {
+    policy: LockoutPolicy,
+    path: Option<PathBuf>,
+    state: Mutex<ThrottleState>,
+}
+
+impl AuthThrottle {
+    /// Throttle whose records only live in memory
+    pub fn new(policy: LockoutPolicy) -> Self {
+        Self {
+            policy,
+            path: None,
+            state: Mutex::new(ThrottleState { store: LockoutStore::default(), modified: None }),
+        }
+    }
+
+    /// Throttle persisted to a state file
+    pub fn with_state_file(policy: LockoutPolicy, path: &Path) -> Result<Self> {
+        let store = LockoutStore::load(path)?;
+        let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        Ok(Self {
+            policy,
+            path: Some(path.to_path_buf()),
+            state: Mutex::new(ThrottleState { store, modified }),
+        })
+    }
+
+    /// Throttling policy
+    pub fn policy(&self) -> &LockoutPolicy {
+        &self.policy
+    }
+
+    /// Fail if any of the keys is locked out or backing off
+    pub fn check(&self, keys: &[String]) -> Result<()> {
+        let now = now_secs();
+        self.with_store(false, |store, policy| {
+            keys.iter().try_for_each(|key| store.check(key, policy, now))
+        })
+    }
+
+    /// Count a failure against every key; returns the lockouts it caused
+    pub fn record_failure(&self, keys: &[String]) -> Result<Vec<Lockout>> {
+        let now = now_secs();
+        self.with_store(true, |store, policy| {
+            Ok(keys.iter()
+                .filter_map(|key| store.record_failure(key, policy, now).map(|until| Lockout {
+                    key: key.clone(),
+                    failures: store.get(key).map_or(0, |record| record.failures),
+                    locked_until: until,
+                }))
+                .collect())
+        })
+    }
+
+    /// Clear the keys after a successful authentication
+    pub fn record_success(&self, keys: &[String]) -> Result<()> {
+        self.with_store(true, |store, _| {
+            for key in keys {
+                store.record_success(key);
+            }
+            Ok(())
+        })
+    }
+
+    /// Run `f` on the current records, re-reading the state file if another
+    /// process changed it and writing it back when `write` is set
+    fn with_store<T>(&self, write: bool, f: impl FnOnce(&mut LockoutStore, &LockoutPolicy) -> Result<T>) -> Result<T> {
+        let mut state = self.state.lock()
+            .map_err(|_| Error::Auth("Lockout state lock poisoned".to_string()))?;
+
+        // Hold the file lock from the re-read to the save so a concurrent clear is not lost
+        let _file_lock = match (&self.path, write) {
+            (Some(path), true) => Some(crate::fsutil::StateLock::acquire(path)?),
+            _ => None,
+        };
+
+        if let Some(path) = &self.path {
+            let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+            if modified != state.modified {
+                state.store = LockoutStore::load(path)?;
+                state.modified = modified;
+            }
+        }
+
+        let result = f(&mut state.store, &self.policy)?;
+
+        if write {
+            state.store.prune(&self.policy, now_secs());
+            if let Some(path) = &self.path {
+                state.store.save(path)?;
+                state.modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(ip_key("192.0.2.7:51234"), "ip:192.0.2.7");
+        assert_eq!(ip_key("[2001:db8::1]:8443"), "ip:2001:db8::1");
+        assert_eq!(ip_key("192.0.2.7"), "ip:192.0.2.7");
+        assert_eq!(device_key("yubikey-1-9a"), "device:yubikey-1-9a");
+    }
+
+    #[test]
+    fn test_exponential_backoff() {
+        let policy = LockoutPolicy { max_failures: 10, base_backoff_secs: 2, max_backoff_secs: 30, lockout_secs: 600 };
+        assert_eq!(policy.backoff_secs(0), 0);
+        assert_eq!(policy.backoff_secs(1), 2);
+        assert_eq!(policy.backoff_secs(3), 8);
+        assert_eq!(policy.backoff_secs(5), 30);
+        assert_eq!(policy.backoff_secs(u32::MAX), 30);
+
+        let mut store = LockoutStore::default();
+        store.record_failure("ip:192.0.2.7", &policy, 1000);
+        store.record_failure("ip:192.0.2.7", &policy, 1002);
+        assert!(store.check("ip:192.0.2.7", &policy, 1005).is_err());
+        assert!(store.check("ip:192.0.2.7", &policy, 1006).is_ok());
+        assert!(store.check("ip:198.51.100.1", &policy, 1005).is_ok());
+
+        store.record_success("ip:192.0.2.7");
+        assert!(store.check("ip:192.0.2.7", &policy, 1002).is_ok());
+    }
+
+    #[test]
+    fn test_lockout_after_max_failures() {
+        let policy = LockoutPolicy { max_failures: 3, base_backoff_secs: 1, max_backoff_secs: 4, lockout_secs: 600 };
+        let mut store = LockoutStore::default();
+
+        assert_eq!(store.record_failure("device:tpm-1", &policy, 100), None);
+        assert_eq!(store.record_failure("device:tpm-1", &policy, 110), None);
+        assert_eq!(store.record_failure("device:tpm-1", &policy, 120), Some(720));
+        // Further failures while locked do not extend or re-raise the lockout
+        assert_eq!(store.record_failure("device:tpm-1", &policy, 130), None);
+
+        assert!(store.check("device:tpm-1", &policy, 719).is_err());
+        assert!(store.check("device:tpm-1", &policy, 720).is_ok());
+
+        // The count restarts once the lockout has expired
+        assert_eq!(store.record_failure("device:tpm-1", &policy, 800), None);
+        assert_eq!(store.get("device:tpm-1").unwrap().failures, 1);
+
+        store.prune(&policy, 800 + 600);
+        assert!(store.get("device:tpm-1").is_none());
+    }
+
+    #[test]
+    fn test_clear() {
+        let policy = LockoutPolicy::default();
+        let mut store = LockoutStore::default();
+        store.record_failure("ip:192.0.2.7", &policy, 100);
+        store.record_failure("device:tpm-1", &policy, 100);
+
+        assert_eq!(store.clear(Some("ip:192.0.2.7")), 1);
+        assert_eq!(store.clear(Some("ip:192.0.2.7")), 0);
+        assert_eq!(store.clear(None), 1);
+        assert_eq!(store.records().count(), 0);
+    }
+
+    #[test]
+    fn test_throttle_sees_external_clear() {
+        let path = std::env::temp_dir().join(format!("lsftp-lockouts-{}.json", Uuid::new_v4()));
+        let policy = LockoutPolicy { max_failures: 2, base_backoff_secs: 0, max_backoff_secs: 0, lockout_secs: 600 };
+        let throttle = AuthThrottle::with_state_file(policy, &path).unwrap();
+        let keys = vec![ip_key("192.0.2.7:4000"), device_key("tpm-1")];
+
+        assert!(throttle.record_failure(&keys).unwrap().is_empty());
+        let lockouts = throttle.record_failure(&keys).unwrap();
+        assert_eq!(lockouts.len(), 2);
+        assert_eq!(lockouts[0].failures, 2);
+        assert!(throttle.check(&keys).is_err());
+
+        // An administrator clears the lockouts through the state file
+        let mut store = LockoutStore::load(&path).unwrap();
+        assert_eq!(store.clear(None), 2);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        store.save(&path).unwrap();
+        assert!(throttle.check(&keys).is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("json.lock")).unwrap();
+    }
+}
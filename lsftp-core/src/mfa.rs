@@ -0,0 +1,502 @@
+//! Multi-factor authentication policies for LSFTP
+//!
+//! A client may answer the handshake challenge with several hardware devices.
+//! Every device signs the same bound challenge and must be enrolled for the
+//! same user. The server then checks the combination of device types against
+//! the policy for that user or their roles, e.g. `tpm AND (yubikey OR smartcard)`.
+//! Policies live in a TOML file:
+//!
+//! ```toml
+//! default = "tpm"
+//!
+//! [users]
+//! alice = "tpm AND (yubikey OR smartcard)"
+//!
+//! [roles]
+//! admin = "tpm AND yubikey"
+//! ```
+
+use crate::auth::{AuthResult, HardwareType};
+use crate::enrollment::{EnrolledDevice, EnrollmentStore};
+use crate::error::{Error, Result};
+use crate::handshake;
+use crate::protocol::{AuthChallengePayload, AuthResponsePayload};
+use crate::smartcard::CaBundle;
+use crate::tpmquote::PcrPolicy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Most additional factors one response may carry: one device of each other type
+pub const MAX_ADDITIONAL_FACTORS: usize = 4;
+
+/// Combination of device types a user must present
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FactorExpr {
+    /// A device of this type
+    Device(HardwareType),
+    /// Every sub-expression
+    All(Vec<FactorExpr>),
+    /// At least one sub-expression
+    Any(Vec<FactorExpr>),
+}
+
+impl FactorExpr {
+    /// Whether the presented device types satisfy the expression
+    pub fn is_satisfied(&self, presented: &[HardwareType]) -> bool {
+        match self {
+            FactorExpr::Device(device_type) => presented.contains(device_type),
+            FactorExpr::All(exprs) => exprs.iter().all(|expr| expr.is_satisfied(presented)),
+            FactorExpr::Any(exprs) => exprs.iter().any(|expr| expr.is_satisfied(presented)),
+        }
+    }
+}
+
+impl fmt::Display for FactorExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FactorExpr::Device(device_type) => f.write_str(&format!("{:?}", device_type).to_ascii_lowercase()),
+            FactorExpr::All(exprs) => write_joined(f, exprs, "AND"),
+            FactorExpr::Any(exprs) => write_joined(f, exprs, "OR"),
+        }
+    }
+}
+
+/// Write sub-expressions joined by an operator, parenthesising compound ones
+fn write_joined(f: &mut fmt::Formatter<'_>, exprs: &[FactorExpr], op: &str) -> fmt::Result {
+    for (index, expr) in exprs.iter().enumerate() {
+        if index > 0 {
+            write!(f, " {} ", op)?;
+        }
+        match expr {
+            FactorExpr::Device(_) => write!(f, "{}", expr)?,
+            _ => write!(f, "({})", expr)?,
+        }
+    }
+    Ok(())
+}
+
+impl FromStr for FactorExpr {
+    type Err = Error;
+
+    /// Device types combined with `AND`, `OR` and parentheses; `AND` binds tighter
+    fn from_str(s: &str) -> Result<Self> {
+        let tokens = tokenize(s);
+        let mut parser = Parser { tokens: &tokens, position: 0 };
+        let expr = parser.any()?;
+        if parser.position != tokens.len() {
+            return Err(Error::Config(format!("Unexpected '{}' in factor policy: {}", tokens[parser.position], s)));
+        }
+        Ok(expr)
+    }
+}
+
+fn tokenize(s: &str) -> Vec<String> {
+    s.replace('(', " ( ")
+        .replace(')', " ) ")
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Recursive-descent parser over policy tokens
+struct Parser<'a> {
+    tokens: &'a [String],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        self.tokens.get(self.position).is_some_and(|token| token.eq_ignore_ascii_case(keyword))
+    }
+
+    fn any(&mut self) -> Result<FactorExpr> {
+        let mut exprs = vec![self.all()?];
+        while self.peek_keyword("or") {
+            self.position += 1;
+            exprs.push(self.all()?);
+        }
+        Ok(if exprs.len() == 1 { exprs.remove(0) } else { FactorExpr::Any(exprs) })
+    }
+
+    fn all(&mut self) -> Result<FactorExpr> {
+        let mut exprs = vec![self.factor()?];
+        while self.peek_keyword("and") {
+            self.position += 1;
+            exprs.push(self.factor()?);
+        }
+        Ok(if exprs.len() == 1 { exprs.remove(0) } else { FactorExpr::All(exprs) })
+    }
+
+    fn factor(&mut self) -> Result<FactorExpr> {
+        let token = self.tokens.get(self.position)
+            .ok_or_else(|| Error::Config("Factor policy ends unexpectedly".to_string()))?;
+        self.position += 1;
+
+        if token == "(" {
+            let expr = self.any()?;
+            if self.tokens.get(self.position).map(String::as_str) != Some(")") {
+                return Err(Error::Config("Unbalanced parentheses in factor policy".to_string()));
+            }
+            self.position += 1;
+            return Ok(expr);
+        }
+
+        Ok(FactorExpr::Device(token.parse()?))
+    }
+}
+
+/// Factor policies per user and per role
+#[derive(Debug, Clone, Default)]
+pub struct MfaPolicy {
+    /// Policy for users without a user or role policy
+    pub default: Option<FactorExpr>,
+    /// Policies by user ID
+    pub users: HashMap<String, FactorExpr>,
+    /// Policies by role
+    pub roles: HashMap<String, FactorExpr>,
+}
+
+/// Policy file as written
+#[derive(Deserialize)]
+struct MfaPolicyFile {
+    default: Option<String>,
+    #[serde(default)]
+    users: HashMap<String, String>,
+    #[serde(default)]
+    roles: HashMap<String, String>,
+}
+
+impl MfaPolicy {
+    /// Load a TOML policy file
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read MFA policy {:?}: {}", path, e)))?;
+        content.parse()
+    }
+
+    /// Requirement for a user: their own policy, else every policy of their
+    /// roles, else the default
+    pub fn required(&self, user_id: &str, roles: &[String]) -> Option<FactorExpr> {
+        if let Some(expr) = self.users.get(user_id) {
+            return Some(expr.clone());
+        }
+
+        let mut role_exprs: Vec<_> = roles.iter().filter_map(|role| self.roles.get(role)).cloned().collect();
+        match role_exprs.len() {
+            0 => self.default.clone(),
+            1 => role_exprs.pop(),
+            _ => Some(FactorExpr::All(role_exprs)),
+        }
+    }
+
+    /// Whether the policy requires nothing of anyone
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.users.is_empty() && self.roles.is_empty()
+    }
+}
+
+impl FromStr for MfaPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let file: MfaPolicyFile = toml::from_str(s)
+            .map_err(|e| Error::Config(format!("Invalid MFA policy: {}", e)))?;
+        let parse_all = |entries: HashMap<String, String>| entries.into_iter()
+            .map(|(name, expr)| Ok((name, expr.parse()?)))
+            .collect::<Result<HashMap<_, _>>>();
+
+        Ok(Self {
+            default: file.default.as_deref().map(str::parse).transpose()?,
+            users: parse_all(file.users)?,
+            roles: parse_all(file.roles)?,
+        })
+    }
+}
+
+/// Device and path of an additional client factor, written `type` or `type=path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FactorDevice {
+    pub device_type: HardwareType,
+    pub device_path: Option<String>,
+}
+
+impl FromStr for FactorDevice {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (device_type, device_path) = match s.split_once('=') {
+            Some((device_type, path)) => (device_type, Some(path.to_string())),
+            None => (s, None),
+        };
+        Ok(Self { device_type: device_type.parse()?, device_path })
+    }
+}
+
+/// Devices that answered a challenge, all enrolled for one user
+#[derive(Debug)]
+pub struct VerifiedFactors<'a> {
+    /// Authenticated user
+    pub user_id: String,
+    /// Verified devices, primary first
+    pub devices: Vec<&'a EnrolledDevice>,
+    /// Requirement that was enforced, if any
+    pub requirement: Option<FactorExpr>,
+}
+
+impl VerifiedFactors<'_> {
+    /// Device types presented
+    pub fn satisfied(&self) -> Vec<HardwareType> {
+        self.devices.iter().map(|device| device.device_type).collect()
+    }
+
+    /// Authentication result recording the satisfied factors, for audit
+    pub fn auth_result(&self) -> AuthResult {
+        let factors = self.devices.iter()
+            .map(|device| format!("{}:{}", FactorExpr::Device(device.device_type), device.device_id))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let mut metadata = HashMap::new();
+        metadata.insert("factors".to_string(), factors);
+        metadata.insert("factor_count".to_string(), self.devices.len().to_string());
+        metadata.insert("mfa_policy".to_string(), self.requirement.as_ref()
+            .map_or_else(|| "none".to_string(), |expr| expr.to_string()));
+
+        AuthResult {
+            success: true,
+            user_id: Some(self.user_id.clone()),
+            device_id: self.devices.first().map(|device| device.device_id.clone()),
+            timestamp: crate::now_secs(),
+            metadata,
+            signature: None,
+            error: None,
+        }
+    }
+}
+
+/// Verify the primary response and every additional factor, then enforce the
+/// user's factor policy; `None` for an accepted anonymous session, which a
+/// policy with any requirement refuses since no user can be checked against it
+pub fn verify_factors<'a>(
+    enrollments: &'a EnrollmentStore,
+    channel_binding: &[u8],
+    challenge: &AuthChallengePayload,
+    response: &AuthResponsePayload,
+    pcr_policy: Option<&PcrPolicy>,
+    smartcard_ca: Option<&CaBundle>,
+    policy: &MfaPolicy,
+) -> Result<Option<VerifiedFactors<'a>>> {
+    if response.additional_factors.len() > MAX_ADDITIONAL_FACTORS {
+        return Err(Error::Auth(format!(
+            "{} additional factors sent, at most {} accepted", response.additional_factors.len(), MAX_ADDITIONAL_FACTORS
+        )));
+    }
+
+    let primary = match handshake::verify_response(enrollments, channel_binding, challenge, response, pcr_policy, smartcard_ca)? {
+        Some(device) => device,
+        None if response.additional_factors.is_empty() && policy.is_empty() => return Ok(None),
+        None if response.additional_factors.is_empty() => {
+            return Err(Error::Auth("The factor policy requires a hardware device".to_string()));
+        }
+        None => return Err(Error::Auth("Additional factors without a primary device".to_string())),
+    };
+
+    let message = handshake::challenge_message(channel_binding, challenge);
+    let mut devices = vec![primary];
+    for factor in &response.additional_factors {
+        let device = handshake::verify_factor(enrollments, &message, factor, pcr_policy, smartcard_ca)?;
+        if device.user_id != primary.user_id {
+            return Err(Error::Auth(format!("Device {} is not enrolled for {}", device.device_id, primary.user_id)));
+        }
+        if devices.iter().any(|d| d.device_id == device.device_id) {
+            return Err(Error::Auth(format!("Device {} answered more than once", device.device_id)));
+        }
+        devices.push(device);
+    }
+
+    // Roles come from every active enrollment of the user, not only the devices presented
+    let mut roles: Vec<String> = enrollments.devices().into_iter()
+        .filter(|device| device.user_id == primary.user_id && device.is_active())
+        .flat_map(|device| device.roles.iter().cloned())
+        .collect();
+    roles.sort();
+    roles.dedup();
+
+    let verified = VerifiedFactors {
+        user_id: primary.user_id.clone(),
+        devices,
+        requirement: policy.required(&primary.user_id, &roles),
+    };
+
+    if let Some(requirement) = &verified.requirement {
+        if !requirement.is_satisfied(&verified.satisfied()) {
+            return Err(Error::Auth(format!("User {} must authenticate with {}", verified.user_id, requirement)));
+        }
+    }
+
+    Ok(Some(verified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::FactorResponse;
+    use ring::signature::Ed25519KeyPair;
+
+    fn enroll(store: &mut EnrollmentStore, device_id: &str, device_type: HardwareType, user_id: &str) -> Ed25519KeyPair {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+
+        store.insert(EnrolledDevice::fixture(device_id, device_type, user_id).with_ed25519_key(&key).with_roles(&["admin"]));
+        key
+    }
+
+    #[test]
+    fn test_expression_parsing() {
+        let expr: FactorExpr = "tpm AND (yubikey OR smartcard)".parse().unwrap();
+        assert_eq!(expr, FactorExpr::All(vec![
+            FactorExpr::Device(HardwareType::Tpm),
+            FactorExpr::Any(vec![
+                FactorExpr::Device(HardwareType::YubiKey),
+                FactorExpr::Device(HardwareType::SmartCard),
+            ]),
+        ]));
+        assert_eq!(expr.to_string(), "tpm AND (yubikey OR smartcard)");
+
+        // AND binds tighter than OR
+        let expr: FactorExpr = "tpm and yubikey or hsm".parse().unwrap();
+        assert!(matches!(expr, FactorExpr::Any(ref exprs) if exprs.len() == 2));
+
+        assert!("tpm AND".parse::<FactorExpr>().is_err());
+        assert!("(tpm OR yubikey".parse::<FactorExpr>().is_err());
+        assert!("tpm yubikey".parse::<FactorExpr>().is_err());
+        assert!("fingerprint".parse::<FactorExpr>().is_err());
+    }
+
+    #[test]
+    fn test_expression_evaluation() {
+        let expr: FactorExpr = "tpm AND (yubikey OR smartcard)".parse().unwrap();
+        assert!(expr.is_satisfied(&[HardwareType::Tpm, HardwareType::SmartCard]));
+        assert!(expr.is_satisfied(&[HardwareType::YubiKey, HardwareType::Tpm]));
+        assert!(!expr.is_satisfied(&[HardwareType::Tpm]));
+        assert!(!expr.is_satisfied(&[HardwareType::YubiKey, HardwareType::SmartCard]));
+    }
+
+    #[test]
+    fn test_policy_resolution() {
+        let policy: MfaPolicy = r#"
+            default = "tpm"
+
+            [users]
+            alice = "tpm AND yubikey"
+
+            [roles]
+            admin = "tpm AND smartcard"
+            auditor = "yubikey OR smartcard"
+        "#.parse().unwrap();
+
+        let admin = vec!["admin".to_string()];
+        assert_eq!(policy.required("alice", &admin).unwrap().to_string(), "tpm AND yubikey");
+        assert_eq!(policy.required("bob", &admin).unwrap().to_string(), "tpm AND smartcard");
+        assert_eq!(policy.required("carol", &[]).unwrap().to_string(), "tpm");
+
+        let both = vec!["admin".to_string(), "auditor".to_string()];
+        let required = policy.required("dave", &both).unwrap();
+        assert!(required.is_satisfied(&[HardwareType::Tpm, HardwareType::SmartCard]));
+        assert!(!required.is_satisfied(&[HardwareType::Tpm, HardwareType::YubiKey]));
+
+        assert!(MfaPolicy::default().required("alice", &admin).is_none());
+        assert!("[users]\nalice = \"tpm AND\"".parse::<MfaPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_factor_device_spec() {
+        let factor: FactorDevice = "yubikey=serial=42,slot=9c".parse().unwrap();
+        assert_eq!(factor.device_type, HardwareType::YubiKey);
+        assert_eq!(factor.device_path.as_deref(), Some("serial=42,slot=9c"));
+
+        assert_eq!("tpm".parse::<FactorDevice>().unwrap().device_path, None);
+        assert!("retina".parse::<FactorDevice>().is_err());
+    }
+
+    #[test]
+    fn test_factors_verified_against_policy() {
+        let mut store = EnrollmentStore::default();
+        let hsm = enroll(&mut store, "hsm-1", HardwareType::Hsm, "alice");
+        let yubikey = enroll(&mut store, "yubikey-1", HardwareType::YubiKey, "alice");
+        let other = enroll(&mut store, "yubikey-2", HardwareType::YubiKey, "bob");
+
+        let binding = [7u8; handshake::CHANNEL_BINDING_LEN];
+        let challenge = handshake::issue_challenge(uuid::Uuid::new_v4(), true).unwrap();
+        let message = handshake::challenge_message(&binding, &challenge);
+        let factor = |device_id: &str, key: &Ed25519KeyPair| FactorResponse {
+            device_type: HardwareType::YubiKey,
+            device_id: device_id.to_string(),
+            signature: key.sign(&message).as_ref().to_vec(),
+            attestation: None,
+        };
+
+        let mut response = AuthResponsePayload {
+            session_id: challenge.session_id,
+            nonce: challenge.nonce,
+            device_type: Some(HardwareType::Hsm),
+            device_id: Some("hsm-1".to_string()),
+            signature: hsm.sign(&message).as_ref().to_vec(),
+            attestation: None,
+            additional_factors: vec![],
+            resumption_ticket: None,
+            crypto_suite: crate::crypto::CryptoSuite::default(),
+            kem_public_key: vec![],
+        };
+
+        let policy: MfaPolicy = "[roles]\nadmin = \"hsm AND (yubikey OR smartcard)\"".parse().unwrap();
+        assert!(verify_factors(&store, &binding, &challenge, &response, None, None, &policy).is_err());
+
+        response.additional_factors.push(factor("yubikey-1", &yubikey));
+        let verified = verify_factors(&store, &binding, &challenge, &response, None, None, &policy).unwrap().unwrap();
+        let result = verified.auth_result();
+        assert_eq!(result.user_id.as_deref(), Some("alice"));
+        assert_eq!(result.metadata["factors"], "hsm:hsm-1,yubikey:yubikey-1");
+        assert_eq!(result.metadata["mfa_policy"], "hsm AND (yubikey OR smartcard)");
+
+        // Factors enrolled for another user or repeated are rejected
+        response.additional_factors = vec![factor("yubikey-2", &other)];
+        assert!(verify_factors(&store, &binding, &challenge, &response, None, None, &policy).is_err());
+        response.additional_factors = vec![factor("yubikey-1", &yubikey), factor("yubikey-1", &yubikey)];
+        assert!(verify_factors(&store, &binding, &challenge, &response, None, None, &policy).is_err());
+
+        // More factors than device types are refused before any is checked
+        response.additional_factors = vec![factor("yubikey-1", &yubikey); MAX_ADDITIONAL_FACTORS + 1];
+        assert!(verify_factors(&store, &binding, &challenge, &response, None, None, &policy).is_err());
+    }
+
+    #[test]
+    fn test_anonymous_session_needs_empty_policy() {
+        let store = EnrollmentStore::default();
+        let binding = [7u8; handshake::CHANNEL_BINDING_LEN];
+        let challenge = handshake::issue_challenge(uuid::Uuid::new_v4(), false).unwrap();
+        let response = AuthResponsePayload {
+            session_id: challenge.session_id,
+            nonce: challenge.nonce,
+            device_type: None,
+            device_id: None,
+            signature: vec![],
+            attestation: None,
+            additional_factors: vec![],
+            resumption_ticket: None,
+            crypto_suite: crate::crypto::CryptoSuite::default(),
+            kem_public_key: vec![],
+        };
+
+        let none = MfaPolicy::default();
+        assert!(verify_factors(&store, &binding, &challenge, &response, None, None, &none).unwrap().is_none());
+
+        // A requirement for one role still leaves nobody to check it against
+        let policy: MfaPolicy = "[roles]\nadmin = \"tpm\"".parse().unwrap();
+        assert!(verify_factors(&store, &binding, &challenge, &response, None, None, &policy).is_err());
+    }
+}
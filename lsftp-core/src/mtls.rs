@@ -0,0 +1,353 @@
+//! Mutual TLS client authentication for LSFTP
+//!
+//! The server can require every QUIC client to present a certificate. A
+//! certificate is accepted when it chains to the configured CA bundle, every
+//! certificate in the chain is within its validity period, it carries the
+//! clientAuth extended key usage and neither it nor any intermediate is on
+//! the local revocation list. The certificate's subject is then mapped to an LSFTP user,
+//! which the server compares with the user resolved by hardware authentication.
+//!
+//! Serials are only unique per issuer, so the revocation list is a text file
+//! with one hexadecimal serial (colons allowed) followed by its issuer's DN per
+//! line, `#` starting a comment:
+//!
+//! ```text
+//! 01:02:03 CN=Example Issuing CA,O=Example Corp
+//! ```
+//!
+//! It is re-read on `reload_revocations` so revoking a certificate needs no
+//! restart. The optional subject map is a TOML table from subject DN, UPN or
+//! common name to user ID:
+//!
+//! ```toml
+//! "CN=alice,O=Example Corp" = "alice"
+//! "bob@example.com" = "bob"
+//! ```
+
+use crate::error::{Error, Result};
+use crate::smartcard::{self, CaBundle};
+use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use rustls::{Certificate, DistinguishedName};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::SystemTime;
+use x509_parser::objects::{oid2abbrev, oid_registry};
+use x509_parser::prelude::*;
+
+/// Certificates revoked locally, by issuer DN and serial
+#[derive(Debug, Default)]
+pub struct RevocationList {
+    path: Option<PathBuf>,
+    serials: RwLock<HashSet<(String, String)>>,
+}
+
+impl RevocationList {
+    /// Load a revocation list file
+    pub fn load(path: &Path) -> Result<Self> {
+        let list = Self { path: Some(path.to_path_buf()), serials: RwLock::default() };
+        list.reload()?;
+        Ok(list)
+    }
+
+    /// Re-read the file; the previous list stays in force if it cannot be read
+    pub fn reload(&self) -> Result<usize> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read revocation list {:?}: {}", path, e)))?;
+
+        let serials: HashSet<(String, String)> = content.lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let (serial, issuer) = line.split_once(char::is_whitespace)
+                    .ok_or_else(|| Error::Config(format!("Revocation entry without an issuer: {}", line)))?;
+                Ok((normalize_subject(issuer.trim())?, normalize_serial(serial)?))
+            })
+            .collect::<Result<_>>()?;
+
+        let count = serials.len();
+        *self.serials.write().map_err(|_| Error::Config("Revocation list lock poisoned".to_string()))? = serials;
+        Ok(count)
+    }
+
+    /// Whether the certificate with this issuer and serial, in any hex notation, is revoked
+    pub fn is_revoked(&self, issuer: &str, serial: &str) -> bool {
+        let key = match (normalize_subject(issuer), normalize_serial(serial)) {
+            (Ok(issuer), Ok(serial)) => (issuer, serial),
+            _ => return false,
+        };
+        // Fail closed if another thread panicked while reloading
+        self.serials.read().map_or(true, |serials| serials.contains(&key))
+    }
+
+    /// Whether a parsed certificate is revoked
+    fn contains(&self, certificate: &X509Certificate<'_>) -> bool {
+        let key = (name_key(certificate.issuer()), normalize_serial(&certificate.tbs_certificate.serial.to_str_radix(16)));
+        match key {
+            (issuer, Ok(serial)) => self.serials.read().map_or(true, |serials| serials.contains(&(issuer, serial))),
+            (_, Err(_)) => false,
+        }
+    }
+
+    /// Number of revoked serials
+    pub fn len(&self) -> usize {
+        self.serials.read().map_or(0, |serials| serials.len())
+    }
+
+    /// Whether nothing is revoked
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Lowercase hex without separators or leading zeros
+fn normalize_serial(serial: &str) -> Result<String> {
+    let hex: String = serial.chars().filter(|c| *c != ':').collect::<String>().to_ascii_lowercase();
+    if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::Config(format!("Invalid certificate serial: {}", serial)));
+    }
+    let trimmed = hex.trim_start_matches('0');
+    Ok(if trimmed.is_empty() { "0".to_string() } else { trimmed.to_string() })
+}
+
+/// Comparable form of a certificate name: its RDNs in order, each attribute
+/// as `TYPE=value` with separators in the value escaped
+fn name_key(name: &X509Name<'_>) -> String {
+    name.iter()
+        .map(|rdn| rdn.iter()
+            .map(|attribute| {
+                let attribute_type = oid2abbrev(attribute.attr_type(), oid_registry())
+                    .map(str::to_ascii_uppercase)
+                    .unwrap_or_else(|_| attribute.attr_type().to_id_string());
+                let value = match attribute.as_str() {
+                    Ok(value) => escape_value(value),
+                    Err(_) => format!("#{}", hex::encode(attribute.as_slice())),
+                };
+                format!("{}={}", attribute_type, value)
+            })
+            .collect::<Vec<_>>()
+            .join("+"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Comparable form of a DN written in a configuration file, matching `name_key`;
+/// backslash escapes and quoted values may contain separators
+fn normalize_subject(subject: &str) -> Result<String> {
+    let invalid = || Error::Config(format!("Invalid distinguished name: {}", subject));
+    let mut key = String::new();
+    let (mut attribute_type, mut current) = (None::<String>, String::new());
+    let (mut chars, mut quoted) = (subject.chars(), false);
+
+    loop {
+        let c = chars.next();
+        match c {
+            Some('\\') => current.push(chars.next().ok_or_else(invalid)?),
+            Some('"') => quoted = !quoted,
+            Some('=') if !quoted && attribute_type.is_none() => {
+                attribute_type = Some(current.trim().to_ascii_uppercase());
+                current.clear();
+            }
+            Some(',' | '+') | None if !quoted => {
+                let attribute_type = attribute_type.take().filter(|t| !t.is_empty()).ok_or_else(invalid)?;
+                key.push_str(&format!("{}={}", attribute_type, escape_value(current.trim())));
+                current.clear();
+                match c {
+                    Some(separator) => key.push(separator),
+                    None => return Ok(key),
+                }
+            }
+            Some(c) => current.push(c),
+            None => return Err(invalid()),
+        }
+    }
+}
+
+/// Escape the characters that separate attributes and RDNs
+fn escape_value(value: &str) -> String {
+    value.chars().fold(String::new(), |mut escaped, c| {
+        if matches!(c, ',' | '+' | '\\' | '"' | '=' | ';' | '<' | '>') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        escaped
+    })
+}
+
+/// Client certificate requirements for mutual TLS
+#[derive(Debug)]
+pub struct ClientCertPolicy {
+    ca: CaBundle,
+    root_subjects: Vec<DistinguishedName>,
+    subjects: Option<HashMap<String, String>>,
+    revocations: RevocationList,
+}
+
+impl ClientCertPolicy {
+    /// Trust client certificates issued under a CA bundle
+    pub fn new(ca: CaBundle) -> Result<Self> {
+        let root_subjects = ca.subjects()?.into_iter().map(DistinguishedName::from).collect();
+        Ok(Self { ca, root_subjects, subjects: None, revocations: RevocationList::default() })
+    }
+
+    /// Only accept certificates listed in a subject map file
+    pub fn with_subject_map(mut self, path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read subject map {:?}: {}", path, e)))?;
+        let subjects: HashMap<String, String> = toml::from_str(&content)
+            .map_err(|e| Error::Config(format!("Invalid subject map {:?}: {}", path, e)))?;
+        self.subjects = Some(subjects.into_iter()
+            .map(|(subject, user)| Ok((normalize_subject(&subject)?, user)))
+            .collect::<Result<_>>()?);
+        Ok(self)
+    }
+
+    /// Reject certificates whose serials are on a revocation list file
+    pub fn with_revocation_list(mut self, path: &Path) -> Result<Self> {
+        self.revocations = RevocationList::load(path)?;
+        Ok(self)
+    }
+
+    /// Number of trusted CAs
+    pub fn ca_count(&self) -> usize {
+        self.ca.len()
+    }
+
+    /// Re-read the revocation list; returns the number of revoked certificates
+    pub fn reload_revocations(&self) -> Result<usize> {
+        self.revocations.reload()
+    }
+
+    /// Validate a client certificate chain; returns the LSFTP user it maps to
+    pub fn verify(&self, end_entity: &[u8], intermediates: &[Vec<u8>]) -> Result<String> {
+        self.ca.verify_chain(end_entity, intermediates)?;
+
+        let (_, certificate) = X509Certificate::from_der(end_entity)
+            .map_err(|e| Error::Auth(format!("Invalid client certificate: {}", e)))?;
+        let subject = certificate.subject().to_string();
+
+        let client_auth = match certificate.extended_key_usage() {
+            Ok(Some(eku)) => eku.value.client_auth,
+            _ => false,
+        };
+        if !client_auth {
+            return Err(Error::Auth(format!("Certificate {} is not valid for client authentication", subject)));
+        }
+
+        // An intermediate revoked by its issuer takes everything below it along
+        for der in std::iter::once(end_entity).chain(intermediates.iter().map(Vec::as_slice)) {
+            let (_, revoked) = X509Certificate::from_der(der)
+                .map_err(|e| Error::Auth(format!("Invalid certificate in client chain: {}", e)))?;
+            if self.revocations.contains(&revoked) {
+                return Err(Error::Auth(format!(
+                    "Certificate {} (serial {}, issuer {}) is revoked",
+                    revoked.subject(), revoked.tbs_certificate.serial.to_str_radix(16), revoked.issuer()
+                )));
+            }
+        }
+
+        let identity = smartcard::certificate_identity(&certificate)
+            .map_err(|e| Error::Auth(e.to_string()))?;
+        match &self.subjects {
+            None => Ok(identity),
+            Some(subjects) => subjects.get(&name_key(certificate.subject()))
+                .or_else(|| subjects.get(&identity))
+                .cloned()
+                .ok_or_else(|| Error::Auth(format!("Certificate {} is not mapped to a user", subject))),
+        }
+    }
+}
+
+impl ClientCertVerifier for ClientCertPolicy {
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &self.root_subjects
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> std::result::Result<ClientCertVerified, rustls::Error> {
+        let intermediates: Vec<Vec<u8>> = intermediates.iter().map(|certificate| certificate.0.clone()).collect();
+        match self.verify(&end_entity.0, &intermediates) {
+            Ok(user) => {
+                tracing::debug!("Client certificate accepted for {}", user);
+                Ok(ClientCertVerified::assertion())
+            }
+            Err(e) => {
+                tracing::warn!("Client certificate rejected: {}", e);
+                Err(rustls::Error::General(e.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_serial_normalization() {
+        assert_eq!(normalize_serial("00:1A:2b").unwrap(), "1a2b");
+        assert_eq!(normalize_serial("1a2b").unwrap(), "1a2b");
+        assert_eq!(normalize_serial("00").unwrap(), "0");
+        assert!(normalize_serial("xyz").is_err());
+        assert!(normalize_serial("").is_err());
+    }
+
+    #[test]
+    fn test_revocation_list_reload() {
+        let path = std::env::temp_dir().join(format!("lsftp-crl-{}.txt", Uuid::new_v4()));
+        std::fs::write(&path, "# revoked laptops\n01:02:03 CN=Issuing CA,O=Example\n0a0b CN=Issuing CA, O=Example # lost\n").unwrap();
+
+        let list = RevocationList::load(&path).unwrap();
+        assert_eq!(list.len(), 2);
+        assert!(list.is_revoked("CN=Issuing CA,O=Example", "010203"));
+        assert!(list.is_revoked("CN = Issuing CA, O = Example", "00:0A:0B"));
+        assert!(!list.is_revoked("CN=Issuing CA,O=Example", "ff"));
+
+        // The same serial from another issuer is a different certificate
+        assert!(!list.is_revoked("CN=Other CA,O=Example", "010203"));
+
+        std::fs::write(&path, "ff CN=Issuing CA,O=Example\n").unwrap();
+        assert_eq!(list.reload().unwrap(), 1);
+        assert!(list.is_revoked("CN=Issuing CA,O=Example", "ff"));
+        assert!(!list.is_revoked("CN=Issuing CA,O=Example", "010203"));
+
+        // A broken file keeps the previous list in force, as does a serial without an issuer
+        std::fs::write(&path, "not-a-serial CN=Issuing CA\n").unwrap();
+        assert!(list.reload().is_err());
+        std::fs::write(&path, "ff\n").unwrap();
+        assert!(list.reload().is_err());
+        assert!(list.is_revoked("CN=Issuing CA,O=Example", "ff"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_subject_normalization() {
+        assert_eq!(normalize_subject("cn = alice, O = Example Corp").unwrap(), "CN=alice,O=Example Corp");
+        assert_eq!(normalize_subject("CN=alice,O=Example Corp").unwrap(), "CN=alice,O=Example Corp");
+
+        // Escaped and quoted separators stay inside the value
+        assert_eq!(normalize_subject("CN=alice,O=Example\\, Inc").unwrap(), "CN=alice,O=Example\\, Inc");
+        assert_eq!(normalize_subject("CN=alice,O=\"Example, Inc\"").unwrap(), "CN=alice,O=Example\\, Inc");
+        assert_eq!(normalize_subject("CN=alice+UID=42,O=Example").unwrap(), "CN=alice+UID=42,O=Example");
+
+        assert!(normalize_subject("alice").is_err());
+        assert!(normalize_subject("CN=\"alice").is_err());
+    }
+
+    #[test]
+    fn test_untrusted_certificate_rejected() {
+        let policy = ClientCertPolicy::new(CaBundle::from_der(vec![])).unwrap();
+        assert!(policy.verify(&[0x30, 0x00], &[]).is_err());
+        assert!(policy.client_auth_root_subjects().is_empty());
+    }
+}
@@ -0,0 +1,446 @@
+//! YubiKey PIV configuration for LSFTP
+//!
+//! Selects the YubiKey (by serial), the PIV slot and key algorithm used for
+//! authentication, and the PIN and touch policies applied when the key is
+//! provisioned. PIN entry goes through a pluggable [`PinPrompt`].
+
+use crate::enrollment::DeviceKeyAlgorithm;
+use crate::error::{Error, Result};
+use crate::secmem::SecretBuffer;
+use crate::smartcard::{self, CaBundle, CertificateInfo};
+use std::fmt;
+use std::str::FromStr;
+
+/// Environment variable holding the PIV PIN for unattended use
+pub const PIN_ENV: &str = "LSFTP_PIV_PIN";
+
+/// Environment variable holding the PIV management key (hex) for provisioning
+pub const MGM_KEY_ENV: &str = "LSFTP_PIV_MGM_KEY";
+
+/// PIV authentication slot
+pub const SLOT_AUTHENTICATION: u8 = 0x9a;
+
+/// PIV digital signature slot
+pub const SLOT_SIGNATURE: u8 = 0x9c;
+
+/// Slot holding the YubiKey attestation key and certificate
+pub const SLOT_ATTESTATION: u8 = 0xf9;
+
+/// Default Yubico PIV root CA bundle that YubiKey attestations must chain to
+pub const DEFAULT_ATTESTATION_ROOT_PATH: &str = "/etc/lsftp/yubico-piv-ca.pem";
+
+/// PIV data object holding the slot f9 attestation certificate
+const ATTESTATION_OBJECT_ID: u32 = 0x5f_ff01;
+
+/// First and last retired key management slots
+const RETIRED_SLOTS: std::ops::RangeInclusive<u8> = 0x82..=0x95;
+
+/// SHA-256 `DigestInfo` prefix for PKCS#1 v1.5 signatures
+const SHA256_DIGEST_INFO: [u8; 19] = [
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00, 0x04, 0x20,
+];
+
+/// PIV key slot usable for LSFTP authentication: 9a, 9c or a retired slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PivSlot(u8);
+
+impl PivSlot {
+    /// Slot from its PIV key reference
+    pub fn new(slot: u8) -> Result<Self> {
+        if slot == SLOT_AUTHENTICATION || slot == SLOT_SIGNATURE || RETIRED_SLOTS.contains(&slot) {
+            Ok(Self(slot))
+        } else {
+            Err(Error::Config(format!("PIV slot {:02x} cannot hold an LSFTP key (use 9a, 9c or 82-95)", slot)))
+        }
+    }
+
+    /// PIV key reference
+    pub fn id(&self) -> u8 {
+        self.0
+    }
+
+    /// Slot identifier for the yubikey crate
+    pub fn slot_id(&self) -> Result<yubikey::piv::SlotId> {
+        yubikey::piv::SlotId::try_from(self.0)
+            .map_err(|e| Error::HardwareAuth(format!("Invalid PIV slot {}: {}", self, e)))
+    }
+}
+
+impl Default for PivSlot {
+    fn default() -> Self {
+        Self(SLOT_AUTHENTICATION)
+    }
+}
+
+impl fmt::Display for PivSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}", self.0)
+    }
+}
+
+impl FromStr for PivSlot {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let slot = u8::from_str_radix(s.trim_start_matches("0x"), 16)
+            .map_err(|_| Error::Config(format!("Invalid PIV slot: {}", s)))?;
+        Self::new(slot)
+    }
+}
+
+/// PIV key algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PivAlgorithm {
+    /// RSA-2048, PKCS#1 v1.5 with SHA-256
+    Rsa2048,
+    /// ECC P-256, ECDSA with SHA-256
+    #[default]
+    EccP256,
+    /// ECC P-384, ECDSA with SHA-384
+    EccP384,
+}
+
+impl PivAlgorithm {
+    /// Algorithm identifier for the yubikey crate
+    pub fn algorithm_id(&self) -> yubikey::piv::AlgorithmId {
+        match self {
+            PivAlgorithm::Rsa2048 => yubikey::piv::AlgorithmId::Rsa2048,
+            PivAlgorithm::EccP256 => yubikey::piv::AlgorithmId::EccP256,
+            PivAlgorithm::EccP384 => yubikey::piv::AlgorithmId::EccP384,
+        }
+    }
+
+    /// PIV cryptographic algorithm reference (SP 800-78)
+    pub fn reference(&self) -> u8 {
+        match self {
+            PivAlgorithm::Rsa2048 => 0x07,
+            PivAlgorithm::EccP256 => 0x11,
+            PivAlgorithm::EccP384 => 0x14,
+        }
+    }
+
+    /// Algorithm the server verifies signatures with
+    pub fn device_key_algorithm(&self) -> DeviceKeyAlgorithm {
+        match self {
+            PivAlgorithm::Rsa2048 => DeviceKeyAlgorithm::RsaPkcs1Sha256,
+            PivAlgorithm::EccP256 => DeviceKeyAlgorithm::EcdsaP256Sha256,
+            PivAlgorithm::EccP384 => DeviceKeyAlgorithm::EcdsaP384Sha384,
+        }
+    }
+
+    /// Input for the PIV raw sign operation: a digest for ECDSA, a padded block for RSA
+    pub fn sign_input(&self, message: &[u8]) -> Vec<u8> {
+        match self {
+            PivAlgorithm::Rsa2048 => pkcs1_v15_sha256(message, 256),
+            PivAlgorithm::EccP256 => ring::digest::digest(&ring::digest::SHA256, message).as_ref().to_vec(),
+            PivAlgorithm::EccP384 => ring::digest::digest(&ring::digest::SHA384, message).as_ref().to_vec(),
+        }
+    }
+}
+
+impl FromStr for PivAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "rsa2048" | "rsa" => Ok(PivAlgorithm::Rsa2048),
+            "p256" | "eccp256" | "ecc-p256" => Ok(PivAlgorithm::EccP256),
+            "p384" | "eccp384" | "ecc-p384" => Ok(PivAlgorithm::EccP384),
+            other => Err(Error::Config(format!("Unknown PIV algorithm: {}", other))),
+        }
+    }
+}
+
+/// When the key requires the PIN
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PivPinPolicy {
+    /// Never
+    Never,
+    /// Once per session
+    #[default]
+    Once,
+    /// For every signature
+    Always,
+}
+
+impl PivPinPolicy {
+    /// Policy for the yubikey crate
+    pub fn policy(&self) -> yubikey::PinPolicy {
+        match self {
+            PivPinPolicy::Never => yubikey::PinPolicy::Never,
+            PivPinPolicy::Once => yubikey::PinPolicy::Once,
+            PivPinPolicy::Always => yubikey::PinPolicy::Always,
+        }
+    }
+}
+
+impl FromStr for PivPinPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(PivPinPolicy::Never),
+            "once" => Ok(PivPinPolicy::Once),
+            "always" => Ok(PivPinPolicy::Always),
+            other => Err(Error::Config(format!("Unknown PIN policy: {}", other))),
+        }
+    }
+}
+
+/// When the key requires a touch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PivTouchPolicy {
+    /// Never
+    Never,
+    /// For every signature
+    Always,
+    /// Once every 15 seconds
+    #[default]
+    Cached,
+}
+
+impl PivTouchPolicy {
+    /// Policy for the yubikey crate
+    pub fn policy(&self) -> yubikey::TouchPolicy {
+        match self {
+            PivTouchPolicy::Never => yubikey::TouchPolicy::Never,
+            PivTouchPolicy::Always => yubikey::TouchPolicy::Always,
+            PivTouchPolicy::Cached => yubikey::TouchPolicy::Cached,
+        }
+    }
+}
+
+impl FromStr for PivTouchPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(PivTouchPolicy::Never),
+            "always" => Ok(PivTouchPolicy::Always),
+            "cached" => Ok(PivTouchPolicy::Cached),
+            other => Err(Error::Config(format!("Unknown touch policy: {}", other))),
+        }
+    }
+}
+
+/// YubiKey PIV selection and policies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PivConfig {
+    /// Serial of the YubiKey to use; the only one present if unset
+    pub serial: Option<u32>,
+    /// Key slot
+    pub slot: PivSlot,
+    /// Key algorithm
+    pub algorithm: PivAlgorithm,
+    /// PIN policy applied at provisioning
+    pub pin_policy: PivPinPolicy,
+    /// Touch policy applied at provisioning
+    pub touch_policy: PivTouchPolicy,
+}
+
+impl FromStr for PivConfig {
+    type Err = Error;
+
+    /// A bare serial (`12345678`) or `serial=12345678,slot=9c,algorithm=p384`
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        if s.is_empty() {
+            return Ok(config);
+        }
+
+        if let Ok(serial) = s.parse::<u32>() {
+            config.serial = Some(serial);
+            return Ok(config);
+        }
+
+        for option in s.split(',') {
+            let (key, value) = option.split_once('=')
+                .ok_or_else(|| Error::Config(format!("Invalid PIV option: {}", option)))?;
+            match key.trim() {
+                "serial" => config.serial = Some(value.trim().parse()
+                    .map_err(|_| Error::Config(format!("Invalid YubiKey serial: {}", value)))?),
+                "slot" => config.slot = value.trim().parse()?,
+                "algorithm" => config.algorithm = value.trim().parse()?,
+                "pin_policy" | "pin-policy" => config.pin_policy = value.trim().parse()?,
+                "touch_policy" | "touch-policy" => config.touch_policy = value.trim().parse()?,
+                other => return Err(Error::Config(format!("Unknown PIV option: {}", other))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Source of the PIV PIN
+pub trait PinPrompt: Send + Sync {
+    /// PIN for the described device, e.g. `YubiKey 12345678`
+    fn pin(&self, device: &str) -> Result<SecretBuffer>;
+}
+
+/// PIN from `LSFTP_PIV_PIN`, for unattended clients
+pub struct EnvPinPrompt;
+
+impl PinPrompt for EnvPinPrompt {
+    fn pin(&self, _device: &str) -> Result<SecretBuffer> {
+        let pin = zeroize::Zeroizing::new(std::env::var(PIN_ENV)
+            .map_err(|_| Error::HardwareAuth(format!("{} is not set", PIN_ENV)))?);
+        SecretBuffer::from_slice(pin.as_bytes())
+    }
+}
+
+/// PIN typed on the terminal without echo
+pub struct TerminalPinPrompt;
+
+impl PinPrompt for TerminalPinPrompt {
+    fn pin(&self, device: &str) -> Result<SecretBuffer> {
+        let pin = zeroize::Zeroizing::new(rpassword::prompt_password(format!("PIN for {}: ", device))?);
+        SecretBuffer::from_slice(pin.as_bytes())
+    }
+}
+
+/// `LSFTP_PIV_PIN` when set, the terminal otherwise
+pub fn default_pin_prompt() -> Box<dyn PinPrompt> {
+    if std::env::var_os(PIN_ENV).is_some() {
+        Box::new(EnvPinPrompt)
+    } else {
+        Box::new(TerminalPinPrompt)
+    }
+}
+
+/// Every YubiKey attached to the system
+pub fn list_devices() -> Result<Vec<yubikey::YubiKey>> {
+    let mut readers = yubikey::reader::Context::open()
+        .map_err(|e| Error::HardwareAuth(format!("Failed to open PC/SC context: {}", e)))?;
+    let readers = readers.iter()
+        .map_err(|e| Error::HardwareAuth(format!("Failed to list YubiKeys: {}", e)))?;
+    Ok(readers.filter_map(|reader| reader.open().ok()).collect())
+}
+
+/// Open the YubiKey with this serial, or the only one present
+pub fn open(serial: Option<u32>) -> Result<yubikey::YubiKey> {
+    if let Some(serial) = serial {
+        return yubikey::YubiKey::open_by_serial(yubikey::Serial::from(serial))
+            .map_err(|e| Error::HardwareAuth(format!("Failed to open YubiKey {}: {}", serial, e)));
+    }
+
+    let mut devices = list_devices()?;
+    match devices.len() {
+        0 => Err(Error::HardwareAuth("No YubiKey devices found".to_string())),
+        1 => Ok(devices.remove(0)),
+        n => Err(Error::HardwareAuth(format!("{} YubiKeys present; select one by serial", n))),
+    }
+}
+
+/// Management key from `LSFTP_PIV_MGM_KEY`, the factory default otherwise
+pub fn management_key() -> Result<yubikey::MgmKey> {
+    match std::env::var(MGM_KEY_ENV) {
+        Ok(key) => {
+            let key = zeroize::Zeroizing::new(hex::decode(key.trim())
+                .map_err(|_| Error::Config(format!("{} is not valid hex", MGM_KEY_ENV)))?);
+            yubikey::MgmKey::from_bytes(key.as_slice())
+                .map_err(|e| Error::Config(format!("Invalid PIV management key: {}", e)))
+        }
+        Err(_) => Ok(yubikey::MgmKey::default()),
+    }
+}
+
+/// DER attestation certificates for the key in `slot`: the slot certificate
+/// issued by the f9 attestation key, then the f9 certificate itself
+pub fn attestation_chain(yubikey: &mut yubikey::YubiKey, slot: PivSlot) -> Result<Vec<Vec<u8>>> {
+    let slot_certificate = yubikey::piv::attest(yubikey, slot.slot_id()?)
+        .map_err(|e| Error::HardwareAuth(format!("Failed to attest PIV slot {}: {}", slot, e)))?;
+    let object = yubikey.fetch_object(ATTESTATION_OBJECT_ID)
+        .map_err(|e| Error::HardwareAuth(format!("Failed to read PIV slot {:02x} certificate: {}", SLOT_ATTESTATION, e)))?;
+
+    Ok(vec![slot_certificate.to_vec(), smartcard::certificate_from_object(&object)?])
+}
+
+/// Check an attestation chain from [`attestation_chain`]: the slot certificate must
+/// be signed by the f9 certificate, which must chain to a root in `roots` through
+/// any further intermediates; returns the attested slot key
+pub fn verify_attestation_chain(chain: &[Vec<u8>], roots: &CaBundle) -> Result<CertificateInfo> {
+    use x509_parser::prelude::{FromDer, X509Certificate};
+
+    let [slot_certificate, attestation_certificate, intermediates @ ..] = chain else {
+        return Err(Error::Auth("YubiKey attestation needs the slot and f9 certificates".to_string()));
+    };
+    let parse = |der: &[u8]| X509Certificate::from_der(der)
+        .map(|(_, certificate)| certificate)
+        .map_err(|e| Error::Auth(format!("Invalid attestation certificate: {}", e)));
+    let slot = parse(slot_certificate)?;
+    let attestation = parse(attestation_certificate)?;
+
+    if slot.issuer().as_raw() != attestation.subject().as_raw()
+        || slot.verify_signature(Some(attestation.public_key())).is_err()
+    {
+        return Err(Error::Auth(format!(
+            "Slot attestation certificate is not signed by the slot {:02x} attestation key", SLOT_ATTESTATION
+        )));
+    }
+    if !slot.validity().is_valid() {
+        return Err(Error::Auth("Slot attestation certificate is expired or not yet valid".to_string()));
+    }
+
+    roots.verify_chain(attestation_certificate, intermediates)?;
+    CertificateInfo::from_der(slot_certificate).map_err(|e| Error::Auth(e.to_string()))
+}
+
+/// EMSA-PKCS1-v1_5 encoding of SHA-256(message) for a modulus of `len` bytes
+pub fn pkcs1_v15_sha256(message: &[u8], len: usize) -> Vec<u8> {
+    let digest = ring::digest::digest(&ring::digest::SHA256, message);
+    let t_len = SHA256_DIGEST_INFO.len() + digest.as_ref().len();
+
+    let mut block = vec![0x00, 0x01];
+    block.resize(len - t_len - 1, 0xff);
+    block.push(0x00);
+    block.extend_from_slice(&SHA256_DIGEST_INFO);
+    block.extend_from_slice(digest.as_ref());
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slot_parsing() {
+        assert_eq!("9a".parse::<PivSlot>().unwrap().id(), SLOT_AUTHENTICATION);
+        assert_eq!("9C".parse::<PivSlot>().unwrap().id(), SLOT_SIGNATURE);
+        assert_eq!("0x95".parse::<PivSlot>().unwrap().id(), 0x95);
+        assert!("9d".parse::<PivSlot>().is_err());
+        assert!("f9".parse::<PivSlot>().is_err());
+        assert!("zz".parse::<PivSlot>().is_err());
+    }
+
+    #[test]
+    fn test_config_spec() {
+        assert_eq!("12345678".parse::<PivConfig>().unwrap().serial, Some(12345678));
+
+        let config: PivConfig = "serial=42,slot=82,algorithm=p384,pin-policy=always,touch-policy=never".parse().unwrap();
+        assert_eq!(config.serial, Some(42));
+        assert_eq!(config.slot.id(), 0x82);
+        assert_eq!(config.algorithm, PivAlgorithm::EccP384);
+        assert_eq!(config.pin_policy, PivPinPolicy::Always);
+        assert_eq!(config.touch_policy, PivTouchPolicy::Never);
+
+        assert!("slot=9a,colour=blue".parse::<PivConfig>().is_err());
+        assert_eq!("".parse::<PivConfig>().unwrap(), PivConfig::default());
+    }
+
+    #[test]
+    fn test_attestation_chain_needs_slot_and_f9_certificates() {
+        let roots = CaBundle::from_der(vec![vec![0x30, 0x00]]);
+        assert!(verify_attestation_chain(&[], &roots).is_err());
+        assert!(verify_attestation_chain(&[vec![0x30, 0x00]], &roots).is_err());
+        assert!(verify_attestation_chain(&[vec![0x30, 0x00], vec![0x30, 0x00]], &roots).is_err());
+    }
+
+    #[test]
+    fn test_pkcs1_v15_encoding() {
+        let block = pkcs1_v15_sha256(b"challenge", 256);
+        assert_eq!(block.len(), 256);
+        assert_eq!(&block[..2], &[0x00, 0x01]);
+        assert_eq!(block[256 - 52], 0x00);
+        assert!(block[2..256 - 52].iter().all(|&b| b == 0xff));
+        assert_eq!(&block[256 - 51..256 - 32], &SHA256_DIGEST_INFO);
+    }
+}
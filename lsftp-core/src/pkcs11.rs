@@ -0,0 +1,649 @@
+//! PKCS#11 hardware security module backend for LSFTP
+//!
+//! Loads a PKCS#11 module (SoftHSMv2, or the vendor module of a network HSM),
+//! selects the token and key pair by label and signs challenges, attestation
+//! statements and file digests inside the token. The private key never leaves
+//! the HSM.
+
+use crate::auth::{AuthResult, DeviceInfo, HardwareAttestation, HardwareAuth, HardwareType};
+use crate::crypto::TaggedDigest;
+use crate::der::rsa_public_key_der;
+use crate::enrollment::DeviceKeyAlgorithm;
+use crate::error::{Error, Result};
+use crate::secmem::SecretBuffer;
+use cryptoki::context::{CInitializeArgs, Pkcs11};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle};
+use cryptoki::session::{Session, UserType};
+use cryptoki::types::AuthPin;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// SoftHSMv2 module on Debian and Ubuntu
+pub const DEFAULT_MODULE_PATH: &str = "/usr/lib/softhsm/libsofthsm2.so";
+
+/// Default token label
+pub const DEFAULT_TOKEN_LABEL: &str = "lsftp";
+
+/// Default key pair label
+pub const DEFAULT_KEY_LABEL: &str = "lsftp-auth";
+
+/// Environment variable holding the token user PIN
+pub const PIN_ENV: &str = "LSFTP_PKCS11_PIN";
+
+/// Domain separator for attestation statements
+const ATTESTATION_CONTEXT: &[u8] = b"LSFTP-PKCS11-ATTESTATION-v1";
+
+/// Domain separator for file signatures
+const FILE_SIGNATURE_CONTEXT: &[u8] = b"LSFTP-FILE-SIGNATURE-v1";
+
+/// DER object identifier of the P-256 curve
+const OID_P256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+/// DER object identifier of the P-384 curve
+const OID_P384: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+
+/// Token, key and module selection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pkcs11Config {
+    /// PKCS#11 module to load
+    pub module: PathBuf,
+    /// Label of the token holding the key
+    pub token_label: String,
+    /// Label of the key pair
+    pub key_label: String,
+}
+
+impl Default for Pkcs11Config {
+    fn default() -> Self {
+        Self {
+            module: PathBuf::from(DEFAULT_MODULE_PATH),
+            token_label: DEFAULT_TOKEN_LABEL.to_string(),
+            key_label: DEFAULT_KEY_LABEL.to_string(),
+        }
+    }
+}
+
+impl FromStr for Pkcs11Config {
+    type Err = Error;
+
+    /// `module=/usr/lib/softhsm/libsofthsm2.so,token=lsftp,key=lsftp-auth`; omitted fields keep their defaults
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        if s.is_empty() {
+            return Ok(config);
+        }
+
+        for option in s.split(',') {
+            let (key, value) = option.split_once('=')
+                .ok_or_else(|| Error::Config(format!("Invalid PKCS#11 option: {}", option)))?;
+            match key.trim() {
+                "module" => config.module = PathBuf::from(value.trim()),
+                "token" => config.token_label = value.trim().to_string(),
+                "key" => config.key_label = value.trim().to_string(),
+                other => return Err(Error::Config(format!("Unknown PKCS#11 option: {}", other))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Algorithm of the HSM key pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HsmKeyAlgorithm {
+    /// RSA, PKCS#1 v1.5 with SHA-256
+    Rsa,
+    /// ECC P-256, ECDSA with SHA-256
+    EccP256,
+    /// ECC P-384, ECDSA with SHA-384
+    EccP384,
+}
+
+impl HsmKeyAlgorithm {
+    /// Algorithm the server verifies signatures with
+    pub fn device_key_algorithm(&self) -> DeviceKeyAlgorithm {
+        match self {
+            HsmKeyAlgorithm::Rsa => DeviceKeyAlgorithm::RsaPkcs1Sha256,
+            HsmKeyAlgorithm::EccP256 => DeviceKeyAlgorithm::EcdsaP256Sha256Fixed,
+            HsmKeyAlgorithm::EccP384 => DeviceKeyAlgorithm::EcdsaP384Sha384Fixed,
+        }
+    }
+
+    /// Signing mechanism and its input; ECDSA signs a digest computed here
+    fn mechanism_and_input(&self, message: &[u8]) -> (Mechanism<'static>, Vec<u8>) {
+        match self {
+            HsmKeyAlgorithm::Rsa => (Mechanism::Sha256RsaPkcs, message.to_vec()),
+            HsmKeyAlgorithm::EccP256 => (Mechanism::Ecdsa, ring::digest::digest(&ring::digest::SHA256, message).as_ref().to_vec()),
+            HsmKeyAlgorithm::EccP384 => (Mechanism::Ecdsa, ring::digest::digest(&ring::digest::SHA384, message).as_ref().to_vec()),
+        }
+    }
+
+    /// Length of an uncompressed point on this key's curve
+    fn ec_point_len(&self) -> Option<usize> {
+        match self {
+            HsmKeyAlgorithm::Rsa => None,
+            HsmKeyAlgorithm::EccP256 => Some(65),
+            HsmKeyAlgorithm::EccP384 => Some(97),
+        }
+    }
+
+    /// Curve of an EC key from its `CKA_EC_PARAMS`
+    fn from_ec_params(params: &[u8]) -> Result<Self> {
+        match params {
+            OID_P256 => Ok(HsmKeyAlgorithm::EccP256),
+            OID_P384 => Ok(HsmKeyAlgorithm::EccP384),
+            _ => Err(Error::HardwareAuth("Unsupported EC curve on HSM key".to_string())),
+        }
+    }
+}
+
+impl FromStr for HsmKeyAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "rsa" | "rsa2048" => Ok(HsmKeyAlgorithm::Rsa),
+            "p256" | "ecc" | "eccp256" => Ok(HsmKeyAlgorithm::EccP256),
+            "p384" | "eccp384" => Ok(HsmKeyAlgorithm::EccP384),
+            other => Err(Error::Config(format!("Unknown HSM key algorithm: {}", other))),
+        }
+    }
+}
+
+/// Handles of the selected key pair
+struct HsmKey {
+    private_key: ObjectHandle,
+    algorithm: HsmKeyAlgorithm,
+    public_key: Vec<u8>,
+    certificate: Option<Vec<u8>>,
+    never_extractable: bool,
+    local: bool,
+}
+
+impl HsmKey {
+    fn device_id(&self) -> String {
+        let digest = Sha256::digest(&self.public_key);
+        format!("hsm-{}", hex::encode(&digest[..8]))
+    }
+}
+
+/// PKCS#11 HSM authenticator
+pub struct HsmAuth {
+    config: Pkcs11Config,
+    session: Option<Mutex<Session>>,
+    key: Option<HsmKey>,
+    device_info: Option<DeviceInfo>,
+}
+
+impl HsmAuth {
+    pub fn new(config: Pkcs11Config) -> Self {
+        Self {
+            config,
+            session: None,
+            key: None,
+            device_info: None,
+        }
+    }
+
+    /// Generate the key pair on the token; refuses to replace an existing key with the same label
+    pub fn provision(config: &Pkcs11Config, algorithm: HsmKeyAlgorithm) -> Result<Vec<u8>> {
+        let (pkcs11, slot) = open_token(config)?;
+        let session = pkcs11.open_rw_session(slot).map_err(pkcs11_error("open session"))?;
+        login(&session)?;
+
+        if !find(&session, ObjectClass::PRIVATE_KEY, &config.key_label)?.is_empty() {
+            return Err(Error::HardwareAuth(format!("Token already holds a key labelled {}", config.key_label)));
+        }
+
+        let label = Attribute::Label(config.key_label.as_bytes().to_vec());
+        let mut public_template = vec![Attribute::Token(true), Attribute::Verify(true), label.clone()];
+        let private_template = vec![
+            Attribute::Token(true),
+            Attribute::Private(true),
+            Attribute::Sensitive(true),
+            Attribute::Extractable(false),
+            Attribute::Sign(true),
+            label,
+        ];
+        let mechanism = match algorithm {
+            HsmKeyAlgorithm::Rsa => {
+                public_template.push(Attribute::ModulusBits(2048.into()));
+                public_template.push(Attribute::PublicExponent(vec![0x01, 0x00, 0x01]));
+                Mechanism::RsaPkcsKeyPairGen
+            }
+            HsmKeyAlgorithm::EccP256 => {
+                public_template.push(Attribute::EcParams(OID_P256.to_vec()));
+                Mechanism::EccKeyPairGen
+            }
+            HsmKeyAlgorithm::EccP384 => {
+                public_template.push(Attribute::EcParams(OID_P384.to_vec()));
+                Mechanism::EccKeyPairGen
+            }
+        };
+
+        let (public_key, _) = session.generate_key_pair(&mechanism, &public_template, &private_template)
+            .map_err(pkcs11_error("generate key pair"))?;
+        let (_, public_key) = read_public_key(&session, public_key)?;
+        Ok(public_key)
+    }
+
+    /// Public key in the form the enrollment registry expects
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        Ok(self.session_and_key()?.1.public_key.clone())
+    }
+
+    /// Algorithm of the loaded key pair
+    pub fn algorithm(&self) -> Result<HsmKeyAlgorithm> {
+        Ok(self.session_and_key()?.1.algorithm)
+    }
+
+    /// Sign `message` with the HSM key
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let (session, key) = self.session_and_key()?;
+        let (mechanism, input) = key.algorithm.mechanism_and_input(message);
+        session.sign(&mechanism, key.private_key, &input)
+            .map_err(pkcs11_error("sign"))
+    }
+
+    /// Sign a transferred file's final digest
+    pub fn sign_file(&self, file_id: &uuid::Uuid, digest: &TaggedDigest) -> Result<Vec<u8>> {
+        self.sign(&file_signature_message(file_id, digest)?)
+    }
+
+    fn session_and_key(&self) -> Result<(std::sync::MutexGuard<'_, Session>, &HsmKey)> {
+        let session = self.session.as_ref()
+            .ok_or_else(|| Error::HardwareAuth("HSM session not open".to_string()))?
+            .lock()
+            .map_err(|_| Error::HardwareAuth("HSM session poisoned".to_string()))?;
+        let key = self.key.as_ref()
+            .ok_or_else(|| Error::HardwareAuth("HSM key not loaded".to_string()))?;
+        Ok((session, key))
+    }
+
+    /// Check an attestation statement made over `nonce` by this HSM's key
+    pub fn verify_attestation_nonce(&self, attestation: &HardwareAttestation, nonce: &[u8]) -> Result<bool> {
+        let (_, key) = self.session_and_key()?;
+        Self::check_attestation(key, attestation, nonce)
+    }
+
+    /// The statement must be exactly this key's statement for `nonce`, signed by
+    /// the key, which was generated on the token and never left it
+    fn check_attestation(key: &HsmKey, attestation: &HardwareAttestation, nonce: &[u8]) -> Result<bool> {
+        let expected = Self::attestation_statement(&key.device_id(), key, nonce);
+
+        if attestation.device_type != HardwareType::Hsm
+            || attestation.attestation_data != expected
+            || !key.local
+            || !key.never_extractable
+        {
+            return Ok(false);
+        }
+
+        Ok(key.algorithm.device_key_algorithm().verify(&key.public_key, &attestation.attestation_data, &attestation.signature))
+    }
+
+    /// Statement signed by the HSM key for attestation
+    fn attestation_statement(device_id: &str, key: &HsmKey, nonce: &[u8]) -> Vec<u8> {
+        let mut data = ATTESTATION_CONTEXT.to_vec();
+        data.extend_from_slice(&(device_id.len() as u32).to_be_bytes());
+        data.extend_from_slice(device_id.as_bytes());
+        data.push(key.algorithm as u8);
+        data.push(key.local as u8);
+        data.push(key.never_extractable as u8);
+        data.extend_from_slice(&(key.public_key.len() as u32).to_be_bytes());
+        data.extend_from_slice(&key.public_key);
+        data.extend_from_slice(nonce);
+        data
+    }
+}
+
+#[async_trait::async_trait]
+impl HardwareAuth for HsmAuth {
+    async fn initialize(&mut self) -> Result<()> {
+        let (pkcs11, slot) = open_token(&self.config)?;
+        let token = pkcs11.get_token_info(slot).map_err(pkcs11_error("read token info"))?;
+
+        let session = pkcs11.open_ro_session(slot).map_err(pkcs11_error("open session"))?;
+        login(&session)?;
+
+        let private_key = find_one(&session, ObjectClass::PRIVATE_KEY, &self.config.key_label)?;
+        let public_key = find_one(&session, ObjectClass::PUBLIC_KEY, &self.config.key_label)?;
+        let (algorithm, public_key) = read_public_key(&session, public_key)?;
+
+        let flags = session.get_attributes(private_key, &[AttributeType::Local, AttributeType::NeverExtractable])
+            .map_err(pkcs11_error("read key attributes"))?;
+        let flag = |wanted: AttributeType| flags.iter().any(|attribute| matches!(
+            (attribute, wanted),
+            (Attribute::Local(true), AttributeType::Local) | (Attribute::NeverExtractable(true), AttributeType::NeverExtractable)
+        ));
+
+        // A certificate stored under the same label is offered as the attestation chain
+        let certificate = match find(&session, ObjectClass::CERTIFICATE, &self.config.key_label)?.first() {
+            Some(&handle) => session.get_attributes(handle, &[AttributeType::Value])
+                .map_err(pkcs11_error("read certificate"))?
+                .into_iter()
+                .find_map(|attribute| match attribute {
+                    Attribute::Value(value) => Some(value),
+                    _ => None,
+                }),
+            None => None,
+        };
+
+        let key = HsmKey {
+            private_key,
+            algorithm,
+            public_key,
+            certificate,
+            never_extractable: flag(AttributeType::NeverExtractable),
+            local: flag(AttributeType::Local),
+        };
+
+        self.device_info = Some(DeviceInfo {
+            device_type: HardwareType::Hsm,
+            device_id: key.device_id(),
+            manufacturer: token.manufacturer_id().to_string(),
+            model: token.model().to_string(),
+            firmware_version: format!("{}.{}", token.firmware_version().major(), token.firmware_version().minor()),
+            supported_algorithms: vec![format!("{:?}", algorithm.device_key_algorithm())],
+            capabilities: vec![
+                "Authentication".to_string(),
+                "DigitalSignature".to_string(),
+                "Attestation".to_string(),
+                "FileSignature".to_string(),
+            ],
+        });
+        self.key = Some(key);
+        self.session = Some(Mutex::new(session));
+
+        Ok(())
+    }
+
+    async fn authenticate(&self, challenge: &[u8]) -> Result<AuthResult> {
+        let signature = self.sign(challenge)?;
+        let (_, key) = self.session_and_key()?;
+
+        Ok(AuthResult {
+            success: true,
+            user_id: None,
+            device_id: Some(key.device_id()),
+            timestamp: crate::now_secs(),
+            metadata: {
+                let mut map = HashMap::new();
+                map.insert("signature_algorithm".to_string(), format!("{:?}", key.algorithm.device_key_algorithm()));
+                map.insert("key_label".to_string(), self.config.key_label.clone());
+                map.insert("public_key".to_string(), hex::encode(&key.public_key));
+                map
+            },
+            signature: Some(signature),
+            error: None,
+        })
+    }
+
+    /// PKCS#11 has no standard attestation; the HSM key signs a statement of
+    /// its public key and the token's `CKA_LOCAL` and `CKA_NEVER_EXTRACTABLE`
+    /// flags, qualified with the nonce
+    async fn generate_attestation(&self, nonce: &[u8]) -> Result<HardwareAttestation> {
+        let (device_id, attestation_data, certificate_chain) = {
+            let (_, key) = self.session_and_key()?;
+            let device_id = key.device_id();
+            let data = Self::attestation_statement(&device_id, key, nonce);
+            (device_id, data, key.certificate.iter().cloned().collect())
+        };
+        let signature = self.sign(&attestation_data)?;
+
+        Ok(HardwareAttestation {
+            device_type: HardwareType::Hsm,
+            device_id,
+            attestation_data,
+            signature,
+            certificate_chain,
+        })
+    }
+
+    /// Checks the statement was signed by this HSM's key and that the key was
+    /// generated on the token and never left it. The trait passes no nonce, so
+    /// any nonce ending the statement is accepted; a caller that issued one
+    /// checks it with [`HsmAuth::verify_attestation_nonce`]
+    async fn verify_attestation(&self, attestation: &HardwareAttestation) -> Result<bool> {
+        let (_, key) = self.session_and_key()?;
+        let prefix = Self::attestation_statement(&key.device_id(), key, &[]);
+        let nonce = attestation.attestation_data.strip_prefix(prefix.as_slice()).unwrap_or_default();
+        Self::check_attestation(key, attestation, nonce)
+    }
+
+    async fn get_device_info(&self) -> Result<DeviceInfo> {
+        self.device_info.clone()
+            .ok_or_else(|| Error::HardwareAuth("Device info not available".to_string()))
+    }
+}
+
+/// Message signed for a transferred file: its ID and tagged final digest
+pub fn file_signature_message(file_id: &uuid::Uuid, digest: &TaggedDigest) -> Result<Vec<u8>> {
+    let mut message = FILE_SIGNATURE_CONTEXT.to_vec();
+    message.extend_from_slice(file_id.as_bytes());
+    message.extend_from_slice(&postcard::to_allocvec(digest)?);
+    Ok(message)
+}
+
+/// User PIN from `LSFTP_PKCS11_PIN`, the terminal otherwise
+fn user_pin() -> Result<SecretBuffer> {
+    let pin = match std::env::var(PIN_ENV) {
+        Ok(pin) => zeroize::Zeroizing::new(pin),
+        Err(_) => zeroize::Zeroizing::new(rpassword::prompt_password("HSM user PIN: ")?),
+    };
+    SecretBuffer::from_slice(pin.as_bytes())
+}
+
+/// Load the module and find the slot holding the configured token
+fn open_token(config: &Pkcs11Config) -> Result<(Pkcs11, cryptoki::slot::Slot)> {
+    let pkcs11 = Pkcs11::new(&config.module)
+        .map_err(|e| Error::HardwareAuth(format!("Failed to load PKCS#11 module {:?}: {}", config.module, e)))?;
+    pkcs11.initialize(CInitializeArgs::OsThreads)
+        .map_err(pkcs11_error("initialize module"))?;
+
+    for slot in pkcs11.get_slots_with_token().map_err(pkcs11_error("list slots"))? {
+        let token = pkcs11.get_token_info(slot).map_err(pkcs11_error("read token info"))?;
+        if token.label() == config.token_label {
+            return Ok((pkcs11, slot));
+        }
+    }
+
+    Err(Error::HardwareAuth(format!("No PKCS#11 token labelled {}", config.token_label)))
+}
+
+/// Log in as the token user
+fn login(session: &Session) -> Result<()> {
+    let pin = user_pin()?;
+    let pin = String::from_utf8(pin.as_slice().to_vec())
+        .map_err(|_| Error::HardwareAuth("HSM PIN is not valid UTF-8".to_string()))?;
+    session.login(UserType::User, Some(&AuthPin::new(pin)))
+        .map_err(pkcs11_error("log in"))
+}
+
+fn find(session: &Session, class: ObjectClass, label: &str) -> Result<Vec<ObjectHandle>> {
+    session.find_objects(&[Attribute::Class(class), Attribute::Label(label.as_bytes().to_vec())])
+        .map_err(pkcs11_error("find objects"))
+}
+
+fn find_one(session: &Session, class: ObjectClass, label: &str) -> Result<ObjectHandle> {
+    match find(session, class, label)?.as_slice() {
+        [handle] => Ok(*handle),
+        [] => Err(Error::HardwareAuth(format!("No {} labelled {} on the token", class, label))),
+        _ => Err(Error::HardwareAuth(format!("Several objects of class {} labelled {}", class, label))),
+    }
+}
+
+/// Algorithm and verifiable public key of a public key object
+fn read_public_key(session: &Session, handle: ObjectHandle) -> Result<(HsmKeyAlgorithm, Vec<u8>)> {
+    let attributes = session.get_attributes(handle, &[
+        AttributeType::KeyType,
+        AttributeType::Modulus,
+        AttributeType::PublicExponent,
+        AttributeType::EcParams,
+        AttributeType::EcPoint,
+    ]).map_err(pkcs11_error("read public key"))?;
+
+    let mut key_type = None;
+    let (mut modulus, mut exponent, mut params, mut point) = (None, None, None, None);
+    for attribute in attributes {
+        match attribute {
+            Attribute::KeyType(value) => key_type = Some(value),
+            Attribute::Modulus(value) => modulus = Some(value),
+            Attribute::PublicExponent(value) => exponent = Some(value),
+            Attribute::EcParams(value) => params = Some(value),
+            Attribute::EcPoint(value) => point = Some(value),
+            _ => {}
+        }
+    }
+
+    let missing = || Error::HardwareAuth("HSM public key attributes incomplete".to_string());
+    match key_type.ok_or_else(missing)? {
+        KeyType::RSA => Ok((
+            HsmKeyAlgorithm::Rsa,
+            rsa_public_key_der(&modulus.ok_or_else(missing)?, &exponent.ok_or_else(missing)?),
+        )),
+        KeyType::EC => {
+            let algorithm = HsmKeyAlgorithm::from_ec_params(&params.ok_or_else(missing)?)?;
+            Ok((algorithm, ec_point(&point.ok_or_else(missing)?, algorithm)?))
+        }
+        other => Err(Error::HardwareAuth(format!("Unsupported HSM key type {}", other))),
+    }
+}
+
+/// Uncompressed EC point from `CKA_EC_POINT`, which modules return DER-wrapped or raw;
+/// the curve's point length decides, since a raw point can look like an OCTET STRING
+pub fn ec_point(value: &[u8], algorithm: HsmKeyAlgorithm) -> Result<Vec<u8>> {
+    let point_len = algorithm.ec_point_len()
+        .ok_or_else(|| Error::HardwareAuth("RSA keys have no EC point".to_string()))?;
+
+    let point = match value {
+        raw if raw.len() == point_len => raw,
+        [0x04, len, point @ ..] if *len as usize == point_len && point.len() == point_len => point,
+        [0x04, 0x81, len, point @ ..] if *len as usize == point_len && point.len() == point_len => point,
+        _ => return Err(Error::HardwareAuth("Malformed EC point".to_string())),
+    };
+
+    match point.first() {
+        Some(0x04) => Ok(point.to_vec()),
+        _ => Err(Error::HardwareAuth("EC point is not uncompressed".to_string())),
+    }
+}
+
+fn pkcs11_error(operation: &'static str) -> impl Fn(cryptoki::error::Error) -> Error {
+    move |e| Error::HardwareAuth(format!("PKCS#11 {} failed: {}", operation, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_spec() {
+        assert_eq!("".parse::<Pkcs11Config>().unwrap(), Pkcs11Config::default());
+
+        let config: Pkcs11Config = "module=/opt/hsm/lib/libcs_pkcs11_R3.so,token=dc1,key=lsftp-server".parse().unwrap();
+        assert_eq!(config.module, PathBuf::from("/opt/hsm/lib/libcs_pkcs11_R3.so"));
+        assert_eq!(config.token_label, "dc1");
+        assert_eq!(config.key_label, "lsftp-server");
+
+        assert!("token".parse::<Pkcs11Config>().is_err());
+        assert!("slot=1".parse::<Pkcs11Config>().is_err());
+    }
+
+    #[test]
+    fn test_ec_point_unwrapping() {
+        let point: Vec<u8> = std::iter::once(0x04).chain(std::iter::repeat(0xab).take(64)).collect();
+
+        let mut wrapped = vec![0x04, 0x41];
+        wrapped.extend_from_slice(&point);
+        assert_eq!(ec_point(&wrapped, HsmKeyAlgorithm::EccP256).unwrap(), point);
+        assert_eq!(ec_point(&point, HsmKeyAlgorithm::EccP256).unwrap(), point);
+
+        let point_384: Vec<u8> = std::iter::once(0x04).chain(std::iter::repeat(0xcd).take(96)).collect();
+        let mut wrapped = vec![0x04, 0x61];
+        wrapped.extend_from_slice(&point_384);
+        assert_eq!(ec_point(&wrapped, HsmKeyAlgorithm::EccP384).unwrap(), point_384);
+        assert_eq!(ec_point(&point_384, HsmKeyAlgorithm::EccP384).unwrap(), point_384);
+
+        // A raw P-256 point whose X starts 3f 04 looks like a 63-byte OCTET STRING
+        let mut lookalike = vec![0x04, 0x3f, 0x04];
+        lookalike.resize(65, 0xab);
+        assert_eq!(ec_point(&lookalike, HsmKeyAlgorithm::EccP256).unwrap(), lookalike);
+
+        assert!(ec_point(&point, HsmKeyAlgorithm::EccP384).is_err());
+        assert!(ec_point(&point, HsmKeyAlgorithm::Rsa).is_err());
+        assert!(ec_point(&[0x30, 0x00], HsmKeyAlgorithm::EccP256).is_err());
+    }
+
+    #[test]
+    fn test_file_signature_message_binds_file_and_algorithm() {
+        let file_id = uuid::Uuid::new_v4();
+        let digest = TaggedDigest::compute(crate::crypto::HashAlgorithm::Blake3, b"data");
+        let sha3 = TaggedDigest::compute(crate::crypto::HashAlgorithm::Sha3256, b"data");
+
+        let message = file_signature_message(&file_id, &digest).unwrap();
+        assert!(message.starts_with(FILE_SIGNATURE_CONTEXT));
+        assert_ne!(message, file_signature_message(&uuid::Uuid::new_v4(), &digest).unwrap());
+        assert_ne!(message, file_signature_message(&file_id, &sha3).unwrap());
+    }
+
+    /// End to end against SoftHSMv2: set `LSFTP_TEST_PKCS11_MODULE` to the
+    /// module path and `SOFTHSM2_CONF` to a config whose token directory is
+    /// writable, then run with `--ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn test_softhsm_end_to_end() {
+        let module = std::env::var("LSFTP_TEST_PKCS11_MODULE").unwrap_or_else(|_| DEFAULT_MODULE_PATH.to_string());
+        let pin = "123456";
+        std::env::set_var(PIN_ENV, pin);
+
+        // Initialise a fresh token in the first free slot
+        let token_label = format!("lsftp-test-{}", &uuid::Uuid::new_v4().simple().to_string()[..8]);
+        {
+            let pkcs11 = Pkcs11::new(&module).unwrap();
+            pkcs11.initialize(CInitializeArgs::OsThreads).unwrap();
+            let slot = pkcs11.get_all_slots().unwrap().into_iter()
+                .find(|&slot| pkcs11.get_token_info(slot).map(|info| !info.token_initialized()).unwrap_or(false))
+                .unwrap();
+            let so_pin = AuthPin::new("12345678".to_string());
+            pkcs11.init_token(slot, &so_pin, &token_label).unwrap();
+            let session = pkcs11.open_rw_session(slot).unwrap();
+            session.login(UserType::So, Some(&so_pin)).unwrap();
+            session.init_pin(&AuthPin::new(pin.to_string())).unwrap();
+        }
+
+        for algorithm in [HsmKeyAlgorithm::EccP256, HsmKeyAlgorithm::EccP384, HsmKeyAlgorithm::Rsa] {
+            let config = Pkcs11Config {
+                module: PathBuf::from(&module),
+                token_label: token_label.clone(),
+                key_label: format!("{:?}", algorithm),
+            };
+            let public_key = HsmAuth::provision(&config, algorithm).unwrap();
+            assert!(HsmAuth::provision(&config, algorithm).is_err());
+
+            let mut auth = HsmAuth::new(config);
+            auth.initialize().await.unwrap();
+            assert_eq!(auth.public_key().unwrap(), public_key);
+
+            let result = auth.authenticate(b"challenge").await.unwrap();
+            assert!(algorithm.device_key_algorithm().verify(&public_key, b"challenge", &result.signature.unwrap()));
+
+            let attestation = auth.generate_attestation(b"nonce").await.unwrap();
+            assert!(auth.verify_attestation(&attestation).await.unwrap());
+            assert!(auth.verify_attestation_nonce(&attestation, b"nonce").unwrap());
+            assert!(!auth.verify_attestation_nonce(&attestation, b"other").unwrap());
+            let mut tampered = attestation.clone();
+            tampered.attestation_data.push(0);
+            assert!(!auth.verify_attestation(&tampered).await.unwrap());
+
+            let file_id = uuid::Uuid::new_v4();
+            let digest = TaggedDigest::compute(crate::crypto::HashAlgorithm::Blake3, b"file");
+            let signature = auth.sign_file(&file_id, &digest).unwrap();
+            let message = file_signature_message(&file_id, &digest).unwrap();
+            assert!(algorithm.device_key_algorithm().verify(&public_key, &message, &signature));
+        }
+    }
+}
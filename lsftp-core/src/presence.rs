@@ -0,0 +1,245 @@
+//! Hardware presence monitoring for LSFTP sessions
+//!
+//! A session is bound to the devices that answered its challenge. The client
+//! watches every removable device among them: YubiKeys through udev events
+//! on the hidraw subsystem, smart cards through PC/SC `SCardGetStatusChange`
+//! on their reader. When a device disappears it has a grace period to come
+//! back; if it does not, or a different card is inserted, the client ends the
+//! session with a SessionTerminate message and closes the connection.
+
+use crate::auth::HardwareType;
+use crate::error::{Error, Result};
+use std::ffi::CString;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Default time a removed device has to be re-inserted
+pub const DEFAULT_UNPLUG_GRACE_SECS: u64 = 10;
+
+/// How often watcher threads check whether the session is still interested
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Removable device a session is bound to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BoundDevice {
+    /// YubiKey, identified by serial
+    YubiKey { device_id: String, serial: u32 },
+    /// PIV smart card in a PC/SC reader
+    SmartCard { device_id: String, reader: String },
+}
+
+impl BoundDevice {
+    /// Device to watch for an authenticated device; `None` for devices that cannot be unplugged
+    pub fn from_device(device_type: HardwareType, device_id: &str, device_path: Option<&str>) -> Option<Self> {
+        match device_type {
+            // Device IDs are `yubikey-<serial>-<slot>`
+            HardwareType::YubiKey => device_id.strip_prefix("yubikey-")
+                .and_then(|rest| rest.split('-').next())
+                .and_then(|serial| serial.parse().ok())
+                .map(|serial| BoundDevice::YubiKey { device_id: device_id.to_string(), serial }),
+            HardwareType::SmartCard => Some(BoundDevice::SmartCard {
+                device_id: device_id.to_string(),
+                reader: device_path.unwrap_or("0").to_string(),
+            }),
+            HardwareType::Tpm | HardwareType::Hsm | HardwareType::Software => None,
+        }
+    }
+
+    /// Device identifier
+    pub fn device_id(&self) -> &str {
+        match self {
+            BoundDevice::YubiKey { device_id, .. } | BoundDevice::SmartCard { device_id, .. } => device_id,
+        }
+    }
+
+    /// Report presence changes until the receiver is dropped; runs on a blocking thread
+    fn watch(&self, presence: mpsc::Sender<bool>) -> Result<()> {
+        match self {
+            BoundDevice::YubiKey { serial, .. } => watch_yubikey(*serial, presence),
+            BoundDevice::SmartCard { device_id, reader } => watch_reader(device_id, reader, presence),
+        }
+    }
+}
+
+/// Watch hidraw add/remove events and re-check the YubiKey on each
+fn watch_yubikey(serial: u32, presence: mpsc::Sender<bool>) -> Result<()> {
+    let socket = udev::MonitorBuilder::new()
+        .and_then(|builder| builder.match_subsystem("hidraw"))
+        .and_then(|builder| builder.listen())
+        .map_err(|e| Error::HardwareAuth(format!("Failed to monitor udev: {}", e)))?;
+    let is_present = || yubikey::YubiKey::open_by_serial(yubikey::Serial::from(serial)).is_ok();
+
+    let mut present = is_present();
+    if presence.blocking_send(present).is_err() {
+        return Ok(());
+    }
+
+    while !presence.is_closed() {
+        let mut fds = [libc::pollfd { fd: socket.as_raw_fd(), events: libc::POLLIN, revents: 0 }];
+        // SAFETY: one valid pollfd for the lifetime of the call
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), 1, POLL_INTERVAL.as_millis() as libc::c_int) };
+        if ready <= 0 {
+            continue;
+        }
+
+        // Drain the queued events, then ask the device itself
+        let changed = socket.iter().count() > 0;
+        if changed && is_present() != present {
+            present = !present;
+            if presence.blocking_send(present).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Watch a reader with SCardGetStatusChange; a re-inserted card must be the same card
+fn watch_reader(device_id: &str, reader: &str, presence: mpsc::Sender<bool>) -> Result<()> {
+    let context = pcsc::Context::establish(pcsc::Scope::User)
+        .map_err(|e| Error::HardwareAuth(format!("Failed to establish PCSC context: {}", e)))?;
+    let mut buffer = vec![0u8; context.list_readers_len()
+        .map_err(|e| Error::HardwareAuth(format!("Failed to list readers: {}", e)))?];
+    let reader_name: CString = context.list_readers(&mut buffer)
+        .map_err(|e| Error::HardwareAuth(format!("Failed to list readers: {}", e)))?
+        .find(|name| name.to_string_lossy().contains(reader))
+        .map(CString::from)
+        .ok_or_else(|| Error::HardwareAuth(format!("Reader {} not found", reader)))?;
+
+    let mut states = [pcsc::ReaderState::new(reader_name.clone(), pcsc::State::UNAWARE)];
+    let mut present = None;
+    while !presence.is_closed() {
+        match context.get_status_change(Some(POLL_INTERVAL), &mut states) {
+            Ok(()) => {}
+            Err(pcsc::Error::Timeout) => continue,
+            // A removed reader takes the card with it
+            Err(pcsc::Error::UnknownReader) | Err(pcsc::Error::ReaderUnavailable) => {
+                states[0] = pcsc::ReaderState::new(reader_name.clone(), pcsc::State::UNAWARE);
+                if present != Some(false) {
+                    present = Some(false);
+                    let _ = presence.blocking_send(false);
+                }
+                std::thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => return Err(Error::HardwareAuth(format!("Reader {} status failed: {}", reader, e))),
+        }
+
+        let state = states[0].event_state();
+        states[0].sync_current_state();
+        let card_present = state.contains(pcsc::State::PRESENT)
+            && !state.intersects(pcsc::State::EMPTY | pcsc::State::UNAVAILABLE | pcsc::State::UNKNOWN);
+        let now_present = card_present && card_device_id(&context, &reader_name).as_deref() == Some(device_id);
+
+        if present != Some(now_present) {
+            present = Some(now_present);
+            if presence.blocking_send(now_present).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Device ID from the CHUID of the card in a reader
+fn card_device_id(context: &pcsc::Context, reader: &CString) -> Option<String> {
+    use crate::smartcard::{self, Apdu};
+
+    let card = context.connect(reader, pcsc::ShareMode::Shared, pcsc::Protocols::ANY).ok()?;
+    smartcard::transmit(&card, &Apdu::select_piv()).ok()?;
+    let chuid = smartcard::transmit(&card, &Apdu::get_data(smartcard::OBJECT_CHUID)).ok()?;
+    let chuid = smartcard::Chuid::parse(smartcard::data_object(&chuid).ok()?).ok()?;
+    Some(chuid.device_id())
+}
+
+/// Resolve once the device has been gone for longer than `grace`; the
+/// returned string describes the removal. Fails if the device cannot be watched.
+pub async fn wait_for_removal(device: BoundDevice, grace: Duration) -> Result<String> {
+    let (sender, presence) = mpsc::channel(8);
+    let watched = device.clone();
+    let watcher = tokio::task::spawn_blocking(move || watched.watch(sender));
+
+    let removal = removal_after_grace(device.device_id(), presence, grace).await;
+    if removal.is_err() {
+        if let Ok(Err(e)) = watcher.await {
+            return Err(e);
+        }
+    }
+    removal
+}
+
+/// Follow presence reports until the device has been gone for longer than
+/// `grace`; fails once the reports stop
+async fn removal_after_grace(device_id: &str, mut presence: mpsc::Receiver<bool>, grace: Duration) -> Result<String> {
+    loop {
+        match presence.recv().await {
+            Some(true) => continue,
+            Some(false) => {
+                tracing::warn!("Device {} removed; waiting {}s for it to return", device_id, grace.as_secs());
+                let returned = tokio::time::timeout(grace, async {
+                    while let Some(present) = presence.recv().await {
+                        if present {
+                            return true;
+                        }
+                    }
+                    false
+                }).await;
+
+                if returned == Ok(true) {
+                    tracing::info!("Device {} re-inserted", device_id);
+                    continue;
+                }
+                return Ok(format!("Device {} removed for more than {}s", device_id, grace.as_secs()));
+            }
+            None => return Err(Error::HardwareAuth(format!("Stopped watching device {}", device_id))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bound_devices() {
+        assert_eq!(
+            BoundDevice::from_device(HardwareType::YubiKey, "yubikey-12345678-9a", None),
+            Some(BoundDevice::YubiKey { device_id: "yubikey-12345678-9a".to_string(), serial: 12345678 })
+        );
+        assert_eq!(
+            BoundDevice::from_device(HardwareType::SmartCard, "smartcard-abcd", Some("Gemalto")),
+            Some(BoundDevice::SmartCard { device_id: "smartcard-abcd".to_string(), reader: "Gemalto".to_string() })
+        );
+        assert!(BoundDevice::from_device(HardwareType::YubiKey, "yubikey", None).is_none());
+        assert!(BoundDevice::from_device(HardwareType::Tpm, "tpm-1", None).is_none());
+        assert!(BoundDevice::from_device(HardwareType::Hsm, "hsm-1", None).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_removal_grace() {
+        let grace = Duration::from_millis(200);
+
+        // Re-inserted within the grace period, then gone for good
+        let (sender, presence) = mpsc::channel(8);
+        let reports = tokio::spawn(async move {
+            sender.send(true).await.unwrap();
+            sender.send(false).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            sender.send(true).await.unwrap();
+            sender.send(false).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+        let started = std::time::Instant::now();
+        let reason = removal_after_grace("yubikey-1-9a", presence, grace).await.unwrap();
+        assert!(reason.contains("yubikey-1-9a"));
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        reports.abort();
+
+        // A watcher that stops reporting is a failure, not a removal
+        let (sender, presence) = mpsc::channel(8);
+        sender.send(true).await.unwrap();
+        drop(sender);
+        assert!(removal_after_grace("yubikey-1-9a", presence, grace).await.is_err());
+    }
+}
@@ -3,7 +3,11 @@
 //! This module defines the wire protocol format, message types,
 //! and protocol state machine for LSFTP.
 
+use crate::access::FileAction;
+use crate::auth::{HardwareAttestation, HardwareType};
+use crate::crypto::{HashAlgorithm, TaggedDigest};
 use crate::error::{Error, Result};
+use crate::quota::QuotaLimits;
 use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -25,6 +29,18 @@ pub enum MessageType {
     PolicyUpdate = 0x06,
     /// Emergency stop and revocation
     EmergencyStop = 0x07,
+    /// Hardware authentication challenge from the server
+    AuthChallenge = 0x08,
+    /// Signed challenge response from the client
+    AuthResponse = 0x09,
+    /// Authentication outcome from the server
+    AuthStatus = 0x0A,
+    /// Client ends the session, e.g. after its bound device was removed
+    SessionTerminate = 0x0B,
+    /// Storage quota usage request from the client and report from the server
+    Quota = 0x0C,
+    /// Refusal of a request, from the server
+    Error = 0x0D,
 }
 
 impl TryFrom<u8> for MessageType {
@@ -39,6 +55,12 @@ impl TryFrom<u8> for MessageType {
             0x05 => Ok(MessageType::Heartbeat),
             0x06 => Ok(MessageType::PolicyUpdate),
             0x07 => Ok(MessageType::EmergencyStop),
+            0x08 => Ok(MessageType::AuthChallenge),
+            0x09 => Ok(MessageType::AuthResponse),
+            0x0A => Ok(MessageType::AuthStatus),
+            0x0B => Ok(MessageType::SessionTerminate),
+            0x0C => Ok(MessageType::Quota),
+            0x0D => Ok(MessageType::Error),
             _ => Err(Error::Protocol(format!("Unknown message type: 0x{:02x}", value))),
         }
     }
@@ -218,15 +240,166 @@ pub struct HandshakePayload {
     pub certificate_chain: Vec<Vec<u8>>,
 }
 
+/// Authentication challenge payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthChallengePayload {
+    /// Server-assigned session ID
+    pub session_id: uuid::Uuid,
+    /// Fresh random nonce
+    pub nonce: [u8; 32],
+    /// Issue time (seconds since the epoch)
+    pub issued_at: u64,
+    /// Whether the server requires a hardware-backed response
+    pub hardware_required: bool,
+}
+
+/// Authentication response payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthResponsePayload {
+    /// Session ID from the challenge
+    pub session_id: uuid::Uuid,
+    /// Nonce from the challenge
+    pub nonce: [u8; 32],
+    /// Device type, if a hardware device answered
+    pub device_type: Option<HardwareType>,
+    /// Device identifier
+    pub device_id: Option<String>,
+    /// Signature over the transcript-bound challenge
+    pub signature: Vec<u8>,
+    /// Device attestation
+    pub attestation: Option<HardwareAttestation>,
+    /// Further devices answering the same challenge for multi-factor policies
+    pub additional_factors: Vec<FactorResponse>,
+    /// Resumption ticket from an earlier session, instead of device signatures
+    pub resumption_ticket: Option<Vec<u8>>,
+    /// Crypto suite the client uses for this session
+    pub crypto_suite: crate::crypto::CryptoSuite,
+    /// Fresh public key for the suite's KEM
+    pub kem_public_key: Vec<u8>,
+}
+
+/// Additional factor in an authentication response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FactorResponse {
+    /// Device type
+    pub device_type: HardwareType,
+    /// Device identifier
+    pub device_id: String,
+    /// Signature over the transcript-bound challenge
+    pub signature: Vec<u8>,
+    /// Device attestation
+    pub attestation: Option<HardwareAttestation>,
+}
+
+/// Authentication status payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthStatusPayload {
+    /// Session ID
+    pub session_id: uuid::Uuid,
+    /// Whether the session is authenticated
+    pub authenticated: bool,
+    /// Resolved user identifier
+    pub user_id: Option<String>,
+    /// Failure reason
+    pub error: Option<String>,
+    /// Single-use ticket for resuming without hardware on a later connection
+    pub resumption_ticket: Option<Vec<u8>>,
+    /// Expiry of the resumption ticket (seconds since the epoch)
+    pub ticket_expires_at: Option<u64>,
+    /// Encapsulation to the client's KEM public key, on success
+    pub kem_ciphertext: Option<Vec<u8>>,
+    /// Devices the session is bound to; a resumed session signed with none of them
+    pub devices: Vec<SessionDevice>,
+}
+
+/// Device that authenticated a session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionDevice {
+    /// Device type
+    pub device_type: HardwareType,
+    /// Device identifier
+    pub device_id: String,
+}
+
+/// Session termination payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionTerminatePayload {
+    /// Session ID
+    pub session_id: uuid::Uuid,
+    /// Device whose removal ended the session
+    pub device_id: Option<String>,
+    /// Reason for ending the session
+    pub reason: String,
+}
+
+/// Error code for an operation the access policy denied
+pub const ACCESS_DENIED: &str = "ACCESS_DENIED";
+
+/// Error code for an upload refused by a storage quota
+pub const QUOTA_EXCEEDED: &str = "QUOTA_EXCEEDED";
+
+/// Refusal of a request; the session stays open unless the refusal says otherwise
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorPayload {
+    /// Session ID
+    pub session_id: uuid::Uuid,
+    /// File the refused request was about
+    pub file_id: Option<uuid::Uuid>,
+    /// Machine-readable code, e.g. `ACCESS_DENIED` or `WEAK_CRYPTO_SUITE`
+    pub code: String,
+    /// Reason for the refusal
+    pub message: String,
+    /// Whether the server closes the session after this message
+    pub fatal: bool,
+}
+
+impl From<ErrorPayload> for Error {
+    fn from(payload: ErrorPayload) -> Self {
+        Error::Protocol(format!("Server refused request ({}): {}", payload.code, payload.message))
+    }
+}
+
+/// Quota message payload; empty `usage` in a request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaPayload {
+    /// Session ID
+    pub session_id: uuid::Uuid,
+    /// Usage of the user and each of their groups
+    pub usage: Vec<QuotaUsage>,
+}
+
+/// Storage used by one user or group, with its limits
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    /// `user:<id>` or `group:<role>`
+    pub subject: String,
+    /// Bytes stored
+    pub bytes: u64,
+    /// Files stored
+    pub files: u64,
+    /// Soft and hard limits
+    pub limits: QuotaLimits,
+    /// When the soft-limit grace period ends, if usage is above a soft limit
+    pub grace_expires_at: Option<u64>,
+}
+
 /// File open message payload
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileOpenPayload {
+    /// File ID chosen by the client; also selects the per-file key
+    pub file_id: uuid::Uuid,
+    /// Operation: upload, download, directory listing or deletion
+    pub operation: FileAction,
     /// File path
     pub path: String,
     /// File size
     pub size: u64,
-    /// File hash
-    pub hash: [u8; 32],
+    /// Requested transfer hash algorithm; the acknowledgment carries the negotiated one
+    pub hash_algorithm: HashAlgorithm,
+    /// Also compute a SHA-256 digest for compliance systems
+    pub compliance_sha256: bool,
+    /// File hash, if known when the file is opened
+    pub hash: Option<TaggedDigest>,
     /// File permissions
     pub permissions: u32,
     /// File metadata
@@ -243,7 +416,7 @@ pub struct FileDataPayload {
     /// Chunk data
     pub data: Vec<u8>,
     /// Chunk hash
-    pub chunk_hash: [u8; 32],
+    pub chunk_hash: TaggedDigest,
     /// Chunk signature
     pub chunk_signature: Vec<u8>,
 }
@@ -254,7 +427,9 @@ pub struct FileClosePayload {
     /// File ID
     pub file_id: uuid::Uuid,
     /// Final file hash
-    pub final_hash: [u8; 32],
+    pub final_hash: TaggedDigest,
+    /// Additional SHA-256 file hash, if requested at open
+    pub compliance_hash: Option<TaggedDigest>,
     /// Global signature
     pub global_signature: Vec<u8>,
     /// Transfer statistics
@@ -378,6 +553,18 @@ pub enum MessagePayload {
     PolicyUpdate(PolicyUpdatePayload),
     /// Emergency stop payload
     EmergencyStop(EmergencyStopPayload),
+    /// Authentication challenge payload
+    AuthChallenge(AuthChallengePayload),
+    /// Authentication response payload
+    AuthResponse(AuthResponsePayload),
+    /// Authentication status payload
+    AuthStatus(AuthStatusPayload),
+    /// Session termination payload
+    SessionTerminate(SessionTerminatePayload),
+    /// Quota payload
+    Quota(QuotaPayload),
+    /// Error payload
+    Error(ErrorPayload),
 }
 
 impl Message {
@@ -391,6 +578,12 @@ impl Message {
             Some(MessagePayload::Heartbeat(p)) => postcard::to_allocvec(p)?,
             Some(MessagePayload::PolicyUpdate(p)) => postcard::to_allocvec(p)?,
             Some(MessagePayload::EmergencyStop(p)) => postcard::to_allocvec(p)?,
+            Some(MessagePayload::AuthChallenge(p)) => postcard::to_allocvec(p)?,
+            Some(MessagePayload::AuthResponse(p)) => postcard::to_allocvec(p)?,
+            Some(MessagePayload::AuthStatus(p)) => postcard::to_allocvec(p)?,
+            Some(MessagePayload::SessionTerminate(p)) => postcard::to_allocvec(p)?,
+            Some(MessagePayload::Quota(p)) => postcard::to_allocvec(p)?,
+            Some(MessagePayload::Error(p)) => postcard::to_allocvec(p)?,
             None => Vec::new(),
         };
 
@@ -437,6 +630,30 @@ impl Message {
                 let payload: EmergencyStopPayload = postcard::from_bytes(&self.frame.payload)?;
                 Some(MessagePayload::EmergencyStop(payload))
             }
+            MessageType::AuthChallenge => {
+                let payload: AuthChallengePayload = postcard::from_bytes(&self.frame.payload)?;
+                Some(MessagePayload::AuthChallenge(payload))
+            }
+            MessageType::AuthResponse => {
+                let payload: AuthResponsePayload = postcard::from_bytes(&self.frame.payload)?;
+                Some(MessagePayload::AuthResponse(payload))
+            }
+            MessageType::AuthStatus => {
+                let payload: AuthStatusPayload = postcard::from_bytes(&self.frame.payload)?;
+                Some(MessagePayload::AuthStatus(payload))
+            }
+            MessageType::SessionTerminate => {
+                let payload: SessionTerminatePayload = postcard::from_bytes(&self.frame.payload)?;
+                Some(MessagePayload::SessionTerminate(payload))
+            }
+            MessageType::Quota => {
+                let payload: QuotaPayload = postcard::from_bytes(&self.frame.payload)?;
+                Some(MessagePayload::Quota(payload))
+            }
+            MessageType::Error => {
+                let payload: ErrorPayload = postcard::from_bytes(&self.frame.payload)?;
+                Some(MessagePayload::Error(payload))
+            }
         };
 
         Ok(())
@@ -456,6 +673,16 @@ mod tests {
         assert_eq!(MessageType::Heartbeat as u8, 0x05);
         assert_eq!(MessageType::PolicyUpdate as u8, 0x06);
         assert_eq!(MessageType::EmergencyStop as u8, 0x07);
+        assert_eq!(MessageType::AuthChallenge as u8, 0x08);
+        assert_eq!(MessageType::AuthResponse as u8, 0x09);
+        assert_eq!(MessageType::AuthStatus as u8, 0x0A);
+        assert_eq!(MessageType::try_from(0x0A).unwrap(), MessageType::AuthStatus);
+        assert_eq!(MessageType::SessionTerminate as u8, 0x0B);
+        assert_eq!(MessageType::try_from(0x0B).unwrap(), MessageType::SessionTerminate);
+        assert_eq!(MessageType::Quota as u8, 0x0C);
+        assert_eq!(MessageType::try_from(0x0C).unwrap(), MessageType::Quota);
+        assert_eq!(MessageType::Error as u8, 0x0D);
+        assert_eq!(MessageType::try_from(0x0D).unwrap(), MessageType::Error);
     }
 
     #[test]
@@ -478,6 +705,36 @@ mod tests {
         assert_eq!(flags.high_priority, flags_back.high_priority);
     }
 
+    #[test]
+    fn test_file_close_digest_roundtrip() {
+        let payload = FileClosePayload {
+            file_id: uuid::Uuid::new_v4(),
+            final_hash: TaggedDigest::compute(HashAlgorithm::Sha3256, b"file"),
+            compliance_hash: Some(TaggedDigest::compute(HashAlgorithm::Sha256, b"file")),
+            global_signature: vec![],
+            statistics: TransferStatistics {
+                bytes_transferred: 4,
+                duration_ms: 1,
+                throughput_bps: 4000,
+                chunks_count: 1,
+                retries_count: 0,
+            },
+        };
+
+        let message = Message::new(MessageType::FileClose, Some(MessagePayload::FileClose(payload.clone()))).unwrap();
+        let mut parsed = Message::new(MessageType::FileClose, None).unwrap();
+        parsed.frame = message.frame;
+        parsed.parse_payload().unwrap();
+
+        match parsed.payload {
+            Some(MessagePayload::FileClose(decoded)) => {
+                assert!(decoded.final_hash.matches(&payload.final_hash));
+                assert_eq!(decoded.compliance_hash, payload.compliance_hash);
+            }
+            _ => panic!("Expected file close payload"),
+        }
+    }
+
     #[test]
     fn test_frame_serialization() {
         let frame = Frame::new(MessageType::Handshake, b"test payload".to_vec());
@@ -0,0 +1,718 @@
+//! Storage quotas for LSFTP
+//!
+//! Every upload is charged to the uploading user and to each of their groups
+//! (their roles). Users and groups may have soft and hard limits on bytes and
+//! on file count. A hard limit is never exceeded. A soft limit may be exceeded
+//! for a grace period, after which uploads are refused until usage drops back
+//! below it. Policies live in a TOML file:
+//!
+//! ```toml
+//! grace_secs = 604800
+//!
+//! # Users without their own entry
+//! [default]
+//! soft_bytes = 10737418240
+//! hard_bytes = 16106127360
+//!
+//! [users.alice]
+//! hard_bytes = 107374182400
+//!
+//! [groups.finance]
+//! soft_files = 100000
+//! hard_files = 120000
+//! ```
+//!
+//! Usage is kept in a JSON ledger, shared with `lsftp-tools`, that records
+//! every stored file with the subjects it was charged to. Totals therefore
+//! survive restarts, and deleting or overwriting a file credits whoever
+//! uploaded it. Uploads in flight hold a reservation, so parallel uploads
+//! cannot overrun a limit together.
+//!
+//! Each change is appended to a journal next to the ledger instead of
+//! rewriting it, so an upload costs the same however many files are stored.
+//! The journal is folded into the ledger once it has grown as long as the
+//! ledger itself.
+
+use crate::access::Subject;
+use crate::error::{Error, Result};
+use crate::now_secs;
+use crate::protocol::QuotaUsage;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Default location of the usage ledger
+pub const DEFAULT_QUOTA_STATE_PATH: &str = "/var/lib/lsftp/quota-usage.json";
+
+/// Default time usage may stay above a soft limit (7 days)
+pub const DEFAULT_GRACE_SECS: u64 = 7 * 24 * 3600;
+
+/// Journal entries kept before compaction, however small the ledger
+const MIN_COMPACTION_ENTRIES: usize = 1024;
+
+/// Journal of changes to the ledger at `path`
+fn journal_path(path: &Path) -> PathBuf {
+    path.with_extension("journal")
+}
+
+/// Ledger key for a user
+pub fn user_key(user_id: &str) -> String {
+    format!("user:{}", user_id)
+}
+
+/// Ledger key for a group
+pub fn group_key(group: &str) -> String {
+    format!("group:{}", group)
+}
+
+/// Ledger keys an upload by the subject is charged to: the user, then their groups
+pub fn subject_keys(subject: &Subject) -> Vec<String> {
+    subject.user_id.as_deref().map(user_key).into_iter()
+        .chain(subject.roles.iter().map(|role| group_key(role)))
+        .collect()
+}
+
+/// Soft and hard limits on bytes and file count; unset limits do not apply
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaLimits {
+    /// Bytes that may be exceeded for the grace period
+    pub soft_bytes: Option<u64>,
+    /// Bytes that are never exceeded
+    pub hard_bytes: Option<u64>,
+    /// Files that may be exceeded for the grace period
+    pub soft_files: Option<u64>,
+    /// Files that are never exceeded
+    pub hard_files: Option<u64>,
+}
+
+impl QuotaLimits {
+    fn over_soft(&self, bytes: u64, files: u64) -> bool {
+        self.soft_bytes.is_some_and(|limit| bytes > limit) || self.soft_files.is_some_and(|limit| files > limit)
+    }
+}
+
+/// Limits per user and group
+#[derive(Debug, Clone)]
+pub struct QuotaPolicy {
+    /// Time usage may stay above a soft limit
+    pub grace_secs: u64,
+    /// Limits for users without their own entry
+    pub default: QuotaLimits,
+    /// Limits by user ID
+    pub users: HashMap<String, QuotaLimits>,
+    /// Limits by group (role)
+    pub groups: HashMap<String, QuotaLimits>,
+}
+
+impl Default for QuotaPolicy {
+    fn default() -> Self {
+        Self {
+            grace_secs: DEFAULT_GRACE_SECS,
+            default: QuotaLimits::default(),
+            users: HashMap::new(),
+            groups: HashMap::new(),
+        }
+    }
+}
+
+/// Policy file as written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QuotaPolicyFile {
+    grace_secs: Option<u64>,
+    #[serde(default)]
+    default: QuotaLimits,
+    #[serde(default)]
+    users: HashMap<String, QuotaLimits>,
+    #[serde(default)]
+    groups: HashMap<String, QuotaLimits>,
+}
+
+impl QuotaPolicy {
+    /// Load a TOML policy file
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read quota policy {:?}: {}", path, e)))?;
+        content.parse()
+    }
+
+    /// Limits for a ledger key; groups without an entry are unlimited
+    pub fn limits(&self, key: &str) -> QuotaLimits {
+        if let Some(user_id) = key.strip_prefix("user:") {
+            return self.users.get(user_id).copied().unwrap_or(self.default);
+        }
+        key.strip_prefix("group:")
+            .and_then(|group| self.groups.get(group))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for QuotaPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let file: QuotaPolicyFile = toml::from_str(s)
+            .map_err(|e| Error::Config(format!("Invalid quota policy: {}", e)))?;
+
+        let all = std::iter::once(("default", &file.default))
+            .chain(file.users.iter().map(|(name, limits)| (name.as_str(), limits)))
+            .chain(file.groups.iter().map(|(name, limits)| (name.as_str(), limits)));
+        for (name, limits) in all {
+            let inverted = |soft: Option<u64>, hard: Option<u64>| soft.zip(hard).is_some_and(|(soft, hard)| soft > hard);
+            if inverted(limits.soft_bytes, limits.hard_bytes) || inverted(limits.soft_files, limits.hard_files) {
+                return Err(Error::Config(format!("Soft quota above hard quota for {}", name)));
+            }
+        }
+
+        Ok(Self {
+            grace_secs: file.grace_secs.unwrap_or(DEFAULT_GRACE_SECS),
+            default: file.default,
+            users: file.users,
+            groups: file.groups,
+        })
+    }
+}
+
+/// Usage charged to one user or group
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Bytes stored
+    pub bytes: u64,
+    /// Files stored
+    pub files: u64,
+    /// When usage first went above a soft limit, if it still is
+    pub soft_exceeded_since: Option<u64>,
+}
+
+/// File recorded in the ledger
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredFile {
+    /// Ledger keys the file is charged to
+    pub charged_to: Vec<String>,
+    /// Size in bytes
+    pub bytes: u64,
+}
+
+/// Usage totals and the files behind them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QuotaLedger {
+    usage: BTreeMap<String, Usage>,
+    files: BTreeMap<String, StoredFile>,
+}
+
+impl QuotaLedger {
+    /// Load the ledger with its journal replayed; missing files are empty
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self::load_with_journal(path)?.0)
+    }
+
+    /// Load the ledger and replay its journal; returns the number of entries replayed
+    fn load_with_journal(path: &Path) -> Result<(Self, usize)> {
+        let mut ledger: Self = match std::fs::read_to_string(path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let journal = match std::fs::read_to_string(journal_path(path)) {
+            Ok(journal) => journal,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((ledger, 0)),
+            Err(e) => return Err(e.into()),
+        };
+        let mut replayed = 0;
+        for line in journal.lines().filter(|line| !line.is_empty()) {
+            // Only the last entry can be torn, by a crash while it was appended
+            match serde_json::from_str::<JournalEntry>(line) {
+                Ok(entry) => ledger.apply(entry),
+                Err(_) => break,
+            }
+            replayed += 1;
+        }
+        Ok((ledger, replayed))
+    }
+
+    /// Save the ledger, replacing the old file atomically so a crash never leaves it half-written
+    pub fn save(&self, path: &Path) -> Result<()> {
+        crate::fsutil::write_private(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    /// Usage of every user and group
+    pub fn usage(&self) -> impl Iterator<Item = (&String, &Usage)> {
+        self.usage.iter()
+    }
+
+    /// Usage of one ledger key
+    pub fn get(&self, key: &str) -> Usage {
+        self.usage.get(key).copied().unwrap_or_default()
+    }
+
+    /// Recorded file at a server path
+    pub fn file(&self, path: &str) -> Option<&StoredFile> {
+        self.files.get(path)
+    }
+
+    /// Record a stored file, replacing and crediting any earlier file at the same path
+    pub fn record(&mut self, path: &str, charged_to: Vec<String>, bytes: u64, policy: &QuotaPolicy, now: u64) {
+        self.remove(path, policy, now);
+        for key in &charged_to {
+            let usage = self.usage.entry(key.clone()).or_default();
+            usage.bytes = usage.bytes.saturating_add(bytes);
+            usage.files = usage.files.saturating_add(1);
+        }
+        self.refresh_grace(&charged_to, policy, now);
+        self.files.insert(path.to_string(), StoredFile { charged_to, bytes });
+    }
+
+    /// Forget a deleted file and credit its subjects
+    pub fn remove(&mut self, path: &str, policy: &QuotaPolicy, now: u64) -> Option<StoredFile> {
+        let stored = self.files.remove(path)?;
+        for key in &stored.charged_to {
+            if let Some(usage) = self.usage.get_mut(key) {
+                usage.bytes = usage.bytes.saturating_sub(stored.bytes);
+                usage.files = usage.files.saturating_sub(1);
+            }
+        }
+        self.refresh_grace(&stored.charged_to, policy, now);
+        self.usage.retain(|_, usage| usage.bytes > 0 || usage.files > 0);
+        Some(stored)
+    }
+
+    /// Current state of a file and the subjects it touched, to be journaled
+    fn journal_entry(&self, path: &str, keys: &[String]) -> JournalEntry {
+        JournalEntry {
+            path: path.to_string(),
+            file: self.files.get(path).cloned(),
+            usage: keys.iter().map(|key| (key.clone(), self.get(key))).collect(),
+        }
+    }
+
+    /// Replay a journaled change
+    fn apply(&mut self, entry: JournalEntry) {
+        match entry.file {
+            Some(file) => self.files.insert(entry.path, file),
+            None => self.files.remove(&entry.path),
+        };
+        for (key, usage) in entry.usage {
+            if usage.bytes > 0 || usage.files > 0 {
+                self.usage.insert(key, usage);
+            } else {
+                self.usage.remove(&key);
+            }
+        }
+    }
+
+    /// Start the grace period of subjects that went above a soft limit, end it for those back below
+    fn refresh_grace(&mut self, keys: &[String], policy: &QuotaPolicy, now: u64) {
+        for key in keys {
+            if let Some(usage) = self.usage.get_mut(key) {
+                if policy.limits(key).over_soft(usage.bytes, usage.files) {
+                    usage.soft_exceeded_since.get_or_insert(now);
+                } else {
+                    usage.soft_exceeded_since = None;
+                }
+            }
+        }
+    }
+}
+
+/// Change to the ledger as journaled: the resulting state of one file and of
+/// the subjects it is or was charged to, so replaying an entry twice is harmless
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JournalEntry {
+    path: String,
+    file: Option<StoredFile>,
+    usage: Vec<(String, Usage)>,
+}
+
+/// Append-only journal of ledger changes
+struct Journal {
+    file: File,
+    entries: usize,
+}
+
+impl Journal {
+    fn open(path: &Path, entries: usize) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(journal_path(path))?;
+        Ok(Self { file, entries })
+    }
+
+    /// Append one entry and flush it to disk
+    fn append(&mut self, entry: &JournalEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Empty the journal once its entries are in the saved ledger
+    fn truncate(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.entries = 0;
+        Ok(())
+    }
+}
+
+/// Space held for an upload in flight
+#[derive(Debug, Clone)]
+struct Reservation {
+    path: String,
+    charged_to: Vec<String>,
+    bytes: u64,
+}
+
+struct QuotaState {
+    ledger: QuotaLedger,
+    journal: Option<Journal>,
+    /// Keyed by session and file ID; file IDs are chosen by clients and only unique within a session
+    reservations: HashMap<(Uuid, Uuid), Reservation>,
+}
+
+impl QuotaState {
+    /// Refuse usage that would cross a hard limit or a soft limit past its grace
+    /// period; returns warnings for soft limits still within their grace
+    fn check(&self, policy: &QuotaPolicy, upload: (Uuid, Uuid), path: &str, charged_to: &[String], bytes: u64, now: u64) -> Result<Vec<String>> {
+        let replaced = self.ledger.file(path);
+        let mut warnings = Vec::new();
+
+        for key in charged_to {
+            let limits = policy.limits(key);
+            let usage = self.ledger.get(key);
+            let pending = self.reservations.iter()
+                .filter(|(id, reservation)| **id != upload && reservation.charged_to.contains(key));
+            let (pending_bytes, pending_files) = pending.fold((0u64, 0u64), |(bytes, files), (_, reservation)| {
+                (bytes.saturating_add(reservation.bytes), files + 1)
+            });
+            let (replaced_bytes, replaced_files) = match replaced {
+                Some(stored) if stored.charged_to.contains(key) => (stored.bytes, 1),
+                _ => (0, 0),
+            };
+
+            let total_bytes = (usage.bytes + pending_bytes).saturating_sub(replaced_bytes).saturating_add(bytes);
+            let total_files = (usage.files + pending_files + 1).saturating_sub(replaced_files);
+
+            if let Some(limit) = limits.hard_bytes.filter(|limit| total_bytes > *limit) {
+                return Err(Error::File(format!("Quota exceeded for {}: {} bytes over the hard limit of {}", key, total_bytes, limit)));
+            }
+            if let Some(limit) = limits.hard_files.filter(|limit| total_files > *limit) {
+                return Err(Error::File(format!("Quota exceeded for {}: {} files over the hard limit of {}", key, total_files, limit)));
+            }
+            if limits.over_soft(total_bytes, total_files) {
+                match usage.soft_exceeded_since.map(|since| since.saturating_add(policy.grace_secs)) {
+                    Some(expired) if now >= expired => {
+                        return Err(Error::File(format!("Quota exceeded for {}: soft limit grace period has expired", key)));
+                    }
+                    Some(expires) => warnings.push(format!("{} is over its soft quota; grace period ends in {}s", key, expires - now)),
+                    None => warnings.push(format!("{} is over its soft quota; grace period of {}s starts now", key, policy.grace_secs)),
+                }
+            }
+        }
+        Ok(warnings)
+    }
+}
+
+/// Quota enforcement shared by the server's sessions
+pub struct QuotaManager {
+    policy: QuotaPolicy,
+    path: PathBuf,
+    state: Mutex<QuotaState>,
+}
+
+impl QuotaManager {
+    /// Manager persisting usage to a ledger file and its journal
+    pub fn new(policy: QuotaPolicy, path: &Path) -> Result<Self> {
+        let (ledger, replayed) = QuotaLedger::load_with_journal(path)?;
+        Ok(Self {
+            policy,
+            path: path.to_path_buf(),
+            state: Mutex::new(QuotaState {
+                ledger,
+                journal: Some(Journal::open(path, replayed)?),
+                reservations: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Quota policy
+    pub fn policy(&self) -> &QuotaPolicy {
+        &self.policy
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, QuotaState>> {
+        self.state.lock().map_err(|_| Error::File("Quota state lock poisoned".to_string()))
+    }
+
+    /// Reserve the declared size of an upload; returns soft-limit warnings
+    pub fn reserve(&self, session_id: Uuid, file_id: Uuid, path: &Path, charged_to: Vec<String>, bytes: u64) -> Result<Vec<String>> {
+        let path = path.to_string_lossy().to_string();
+        let upload = (session_id, file_id);
+        let mut state = self.lock()?;
+        if state.reservations.contains_key(&upload) {
+            return Err(Error::File(format!("Upload {} is already in progress", file_id)));
+        }
+        let warnings = state.check(&self.policy, upload, &path, &charged_to, bytes, now_secs())?;
+        state.reservations.insert(upload, Reservation { path, charged_to, bytes });
+        Ok(warnings)
+    }
+
+    /// Check an upload again as data arrives; the reservation grows once data
+    /// passes the declared size
+    pub fn extend(&self, session_id: Uuid, file_id: Uuid, bytes: u64) -> Result<()> {
+        let upload = (session_id, file_id);
+        let mut state = self.lock()?;
+        let reservation = state.reservations.get(&upload).cloned()
+            .ok_or_else(|| Error::File("No quota reservation for this upload".to_string()))?;
+        if bytes <= reservation.bytes {
+            return Ok(());
+        }
+
+        state.check(&self.policy, upload, &reservation.path, &reservation.charged_to, bytes, now_secs())?;
+        if let Some(reservation) = state.reservations.get_mut(&upload) {
+            reservation.bytes = bytes;
+        }
+        Ok(())
+    }
+
+    /// Charge a completed upload and journal the change; blocks on disk I/O
+    pub fn commit(&self, session_id: Uuid, file_id: Uuid, bytes: u64) -> Result<()> {
+        let mut state = self.lock()?;
+        let reservation = state.reservations.remove(&(session_id, file_id))
+            .ok_or_else(|| Error::File("No quota reservation for this upload".to_string()))?;
+
+        // Subjects charged for a replaced file are credited, so they are journaled too
+        let mut keys = reservation.charged_to.clone();
+        if let Some(replaced) = state.ledger.file(&reservation.path) {
+            keys.extend(replaced.charged_to.iter().filter(|key| !reservation.charged_to.contains(key)).cloned());
+        }
+        state.ledger.record(&reservation.path, reservation.charged_to, bytes, &self.policy, now_secs());
+        let entry = state.ledger.journal_entry(&reservation.path, &keys);
+        self.persist(&mut state, &entry)
+    }
+
+    /// Drop the reservation of an upload that wrote nothing
+    pub fn release(&self, session_id: Uuid, file_id: Uuid) {
+        if let Ok(mut state) = self.state.lock() {
+            state.reservations.remove(&(session_id, file_id));
+        }
+    }
+
+    /// Credit a deleted file and journal the change; blocks on disk I/O
+    pub fn remove_file(&self, path: &Path) -> Result<()> {
+        let path = path.to_string_lossy();
+        let mut state = self.lock()?;
+        let Some(removed) = state.ledger.remove(&path, &self.policy, now_secs()) else {
+            return Ok(());
+        };
+        let entry = state.ledger.journal_entry(&path, &removed.charged_to);
+        self.persist(&mut state, &entry)
+    }
+
+    /// Append a change to the journal, folding the journal into the ledger once
+    /// it is as long as the ledger
+    fn persist(&self, state: &mut QuotaState, entry: &JournalEntry) -> Result<()> {
+        // After a failed append the journal may end in a torn entry; start afresh from a full save
+        let Some(mut journal) = state.journal.take() else {
+            state.ledger.save(&self.path)?;
+            let mut journal = Journal::open(&self.path, 0)?;
+            journal.truncate()?;
+            state.journal = Some(journal);
+            return Ok(());
+        };
+        journal.append(entry)?;
+
+        // Entries are idempotent, so a crash between the save and the truncation loses nothing
+        let compacted = if journal.entries >= state.ledger.files.len().max(MIN_COMPACTION_ENTRIES) {
+            state.ledger.save(&self.path).and_then(|()| journal.truncate())
+        } else {
+            Ok(())
+        };
+        state.journal = Some(journal);
+        compacted
+    }
+
+    /// Drop the reservations of a session that ended, whether or not its uploads finished
+    pub fn release_session(&self, session_id: Uuid) {
+        if let Ok(mut state) = self.state.lock() {
+            state.reservations.retain(|(session, _), _| *session != session_id);
+        }
+    }
+
+    /// Usage and limits of the given ledger keys
+    pub fn report(&self, keys: &[String]) -> Result<Vec<QuotaUsage>> {
+        let state = self.lock()?;
+        Ok(keys.iter().map(|key| usage_report(key, state.ledger.get(key), &self.policy)).collect())
+    }
+}
+
+/// Usage of one ledger key with its limits, as reported to clients and administrators
+pub fn usage_report(key: &str, usage: Usage, policy: &QuotaPolicy) -> QuotaUsage {
+    QuotaUsage {
+        subject: key.to_string(),
+        bytes: usage.bytes,
+        files: usage.files,
+        limits: policy.limits(key),
+        grace_expires_at: usage.soft_exceeded_since.map(|since| since.saturating_add(policy.grace_secs)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = r#"
+        grace_secs = 100
+
+        [default]
+        soft_bytes = 1000
+        hard_bytes = 2000
+
+        [groups.finance]
+        hard_files = 2
+    "#;
+
+    fn keys() -> Vec<String> {
+        vec![user_key("alice"), group_key("finance")]
+    }
+
+    fn manager(dir: &Path) -> QuotaManager {
+        QuotaManager::new(POLICY.parse().unwrap(), &dir.join("quota.json")).unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lsftp-quota-{}-{}", name, Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_hard_limits_and_reservations() {
+        let dir = temp_dir("hard");
+        let quota = manager(&dir);
+        let session = Uuid::new_v4();
+
+        assert!(quota.reserve(session, Uuid::new_v4(), Path::new("/h/a"), keys(), 2001).is_err());
+
+        // A pending upload counts against a parallel one
+        let first = Uuid::new_v4();
+        assert!(quota.reserve(session, first, Path::new("/h/a"), keys(), 900).unwrap().is_empty());
+        let second = Uuid::new_v4();
+        assert!(quota.reserve(session, second, Path::new("/h/b"), keys(), 1200).is_err());
+
+        // Streaming past the declared size is checked again
+        assert!(quota.extend(session, first, 950).is_ok());
+        assert!(quota.extend(session, first, 2100).is_err());
+        quota.commit(session, first, 950).unwrap();
+
+        // The group allows two files
+        let third = Uuid::new_v4();
+        quota.reserve(session, third, Path::new("/h/b"), keys(), 10).unwrap();
+        quota.commit(session, third, 10).unwrap();
+        assert!(quota.reserve(session, Uuid::new_v4(), Path::new("/h/c"), keys(), 10).is_err());
+
+        // Overwriting a file replaces its usage instead of adding to it
+        let overwrite = Uuid::new_v4();
+        quota.reserve(session, overwrite, Path::new("/h/a"), keys(), 1500).unwrap();
+        quota.commit(session, overwrite, 1500).unwrap();
+        let report = quota.report(&keys()).unwrap();
+        assert_eq!((report[0].bytes, report[0].files), (1510, 2));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_ids_are_per_session() {
+        let dir = temp_dir("ids");
+        let quota = manager(&dir);
+        let (first, second, file_id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        quota.reserve(first, file_id, Path::new("/h/a"), keys(), 10).unwrap();
+        assert!(quota.reserve(first, file_id, Path::new("/h/b"), keys(), 10).is_err());
+
+        // Another session reusing the ID neither replaces nor finishes the first upload
+        quota.reserve(second, file_id, Path::new("/h/b"), keys(), 20).unwrap();
+        quota.commit(second, file_id, 20).unwrap();
+        quota.commit(first, file_id, 10).unwrap();
+        let report = quota.report(&keys()).unwrap();
+        assert_eq!((report[0].bytes, report[0].files), (30, 2));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_usage_survives_restart() {
+        let dir = temp_dir("restart");
+        let session = Uuid::new_v4();
+        {
+            let quota = manager(&dir);
+            let file_id = Uuid::new_v4();
+            quota.reserve(session, file_id, Path::new("/h/a"), keys(), 500).unwrap();
+            quota.commit(session, file_id, 500).unwrap();
+
+            // An unfinished upload is not charged
+            quota.reserve(session, Uuid::new_v4(), Path::new("/h/b"), keys(), 500).unwrap();
+            quota.release_session(session);
+        }
+
+        let quota = manager(&dir);
+        let report = quota.report(&keys()).unwrap();
+        assert_eq!((report[0].bytes, report[0].files), (500, 1));
+        assert_eq!(report[0].limits.hard_bytes, Some(2000));
+
+        // Readers of the ledger see journaled changes that are not compacted yet
+        assert!(!dir.join("quota.json").exists());
+        let ledger = QuotaLedger::load(&dir.join("quota.json")).unwrap();
+        assert_eq!(ledger.get(&group_key("finance")).bytes, 500);
+        assert_eq!((report[1].bytes, report[1].files), (500, 1));
+
+        quota.remove_file(Path::new("/h/a")).unwrap();
+        let quota = manager(&dir);
+        assert_eq!(quota.report(&keys()).unwrap()[0].bytes, 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_soft_limit_grace_period() {
+        let policy: QuotaPolicy = POLICY.parse().unwrap();
+        let mut ledger = QuotaLedger::default();
+        ledger.record("/h/a", vec![user_key("alice")], 1200, &policy, 1000);
+        assert_eq!(ledger.get(&user_key("alice")).soft_exceeded_since, Some(1000));
+
+        let state = QuotaState { ledger, journal: None, reservations: HashMap::new() };
+        let warnings = state.check(&policy, (Uuid::new_v4(), Uuid::new_v4()), "/h/b", &[user_key("alice")], 10, 1050).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(state.check(&policy, (Uuid::new_v4(), Uuid::new_v4()), "/h/b", &[user_key("alice")], 10, 1100).is_err());
+
+        // Dropping back below the soft limit ends the grace period
+        let mut ledger = state.ledger;
+        ledger.remove("/h/a", &policy, 1200);
+        assert_eq!(ledger.get(&user_key("alice")), Usage::default());
+    }
+
+    #[test]
+    fn test_invalid_policies() {
+        assert!("[default]\nsoft_bytes = 10\nhard_bytes = 5".parse::<QuotaPolicy>().is_err());
+        assert!("[users.alice]\nhard_gigabytes = 5".parse::<QuotaPolicy>().is_err());
+        let policy: QuotaPolicy = "[users.alice]\nhard_files = 5".parse().unwrap();
+        assert_eq!(policy.grace_secs, DEFAULT_GRACE_SECS);
+        assert_eq!(policy.limits(&user_key("bob")), QuotaLimits::default());
+        assert_eq!(policy.limits(&group_key("ops")), QuotaLimits::default());
+    }
+}
@@ -0,0 +1,589 @@
+//! Bandwidth limits for LSFTP
+//!
+//! Transfers are throttled by token buckets at three levels: the whole
+//! server, each user across all of their sessions, and each session. Uploads
+//! and downloads have separate buckets. A chunk is charged to every bucket
+//! that applies and waits for the slowest. Rates are bytes per second, with
+//! an optional `K`, `M` or `G` suffix (powers of 1024). Limits live in a TOML
+//! file:
+//!
+//! ```toml
+//! [global]
+//! upload = "1G"
+//! download = "2G"
+//!
+//! # Each user, unless listed below
+//! [user]
+//! upload = "100M"
+//!
+//! [session]
+//! download = "50M"
+//!
+//! [users.backup]
+//! upload = "20M"
+//! ```
+//!
+//! Administrators adjust limits live with `RateLimit` policy rules. A rule's
+//! `scope` is `global`, `user` or `session`. Its `subject` names a user ID or
+//! session ID; without one, the rule changes the default for every user or
+//! session. `upload` and `download` take a rate or `unlimited`, and
+//! `reset = "true"` drops a user's or session's override.
+
+use crate::error::{Error, Result};
+use crate::protocol::{PolicyRule, PolicyRuleType};
+use crate::MAX_TRANSFER_RATE;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Direction of a transfer, as seen from the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Client to server
+    Upload,
+    /// Server to client
+    Download,
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Upload => write!(f, "upload"),
+            Direction::Download => write!(f, "download"),
+        }
+    }
+}
+
+/// Parse a rate in bytes per second, such as `1048576`, `512K` or `10M`
+pub fn parse_rate(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1u64 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    let rate = digits.parse::<u64>().ok()
+        .and_then(|value| value.checked_mul(multiplier))
+        .ok_or_else(|| Error::Config(format!("Invalid rate: {}", s)))?;
+    check_rate(rate)
+}
+
+fn check_rate(rate: u64) -> Result<u64> {
+    if rate == 0 || rate > MAX_TRANSFER_RATE {
+        return Err(Error::Config(format!("Rate must be between 1 and {} bytes per second", MAX_TRANSFER_RATE)));
+    }
+    Ok(rate)
+}
+
+/// Upload and download limits in bytes per second; `None` is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rate {
+    /// Client to server
+    pub upload: Option<u64>,
+    /// Server to client
+    pub download: Option<u64>,
+}
+
+impl Rate {
+    /// Both directions limited to `rate`
+    pub fn symmetric(rate: u64) -> Self {
+        Self { upload: Some(rate), download: Some(rate) }
+    }
+
+    /// Limit in one direction
+    pub fn get(&self, direction: Direction) -> Option<u64> {
+        match direction {
+            Direction::Upload => self.upload,
+            Direction::Download => self.download,
+        }
+    }
+
+    /// Apply the `upload` and `download` parameters of a policy rule; absent ones are kept
+    fn apply(&mut self, parameters: &HashMap<String, String>) -> Result<()> {
+        let parse = |value: &str| match value {
+            "unlimited" => Ok(None),
+            value => parse_rate(value).map(Some),
+        };
+        let upload = parameters.get("upload").map(|value| parse(value)).transpose()?;
+        let download = parameters.get("download").map(|value| parse(value)).transpose()?;
+        if let Some(upload) = upload {
+            self.upload = upload;
+        }
+        if let Some(download) = download {
+            self.download = download;
+        }
+        Ok(())
+    }
+}
+
+/// Rate as written: a number of bytes per second or a string with a suffix
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RateValue {
+    Bytes(u64),
+    Text(String),
+}
+
+impl RateValue {
+    fn parse(&self) -> Result<u64> {
+        match self {
+            RateValue::Bytes(rate) => check_rate(*rate),
+            RateValue::Text(rate) => parse_rate(rate),
+        }
+    }
+}
+
+/// Limits of one level as written
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RateFile {
+    upload: Option<RateValue>,
+    download: Option<RateValue>,
+}
+
+impl RateFile {
+    fn parse(&self) -> Result<Rate> {
+        Ok(Rate {
+            upload: self.upload.as_ref().map(RateValue::parse).transpose()?,
+            download: self.download.as_ref().map(RateValue::parse).transpose()?,
+        })
+    }
+}
+
+/// Limit file as written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitFile {
+    #[serde(default)]
+    global: RateFile,
+    #[serde(default)]
+    user: RateFile,
+    #[serde(default)]
+    session: RateFile,
+    #[serde(default)]
+    users: HashMap<String, RateFile>,
+}
+
+/// Configured limits at each level
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitPolicy {
+    /// Whole server
+    pub global: Rate,
+    /// Each user without their own entry, across all of their sessions
+    pub user: Rate,
+    /// Each session
+    pub session: Rate,
+    /// Per-user limits replacing `user`
+    pub users: HashMap<String, Rate>,
+}
+
+impl Default for RateLimitPolicy {
+    fn default() -> Self {
+        Self {
+            global: Rate::symmetric(MAX_TRANSFER_RATE),
+            user: Rate::default(),
+            session: Rate::default(),
+            users: HashMap::new(),
+        }
+    }
+}
+
+impl RateLimitPolicy {
+    /// Load a TOML limit file
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read rate limits {:?}: {}", path, e)))?;
+        content.parse()
+    }
+
+    /// Limits of one user
+    pub fn user_rate(&self, user_id: &str) -> Rate {
+        self.users.get(user_id).copied().unwrap_or(self.user)
+    }
+}
+
+impl FromStr for RateLimitPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let file: RateLimitFile = toml::from_str(s)
+            .map_err(|e| Error::Config(format!("Invalid rate limits: {}", e)))?;
+
+        // The server never exceeds the protocol maximum, even when the file sets no global limit
+        let mut global = file.global.parse()?;
+        global.upload.get_or_insert(MAX_TRANSFER_RATE);
+        global.download.get_or_insert(MAX_TRANSFER_RATE);
+
+        Ok(Self {
+            global,
+            user: file.user.parse()?,
+            session: file.session.parse()?,
+            users: file.users.iter()
+                .map(|(user_id, rate)| Ok((user_id.clone(), rate.parse()?)))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// Token bucket holding up to one second of traffic; a large chunk may take
+/// it into debt, which the caller repays by waiting
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Full bucket refilling at `rate` bytes per second
+    pub fn new(rate: u64, now: Instant) -> Self {
+        Self { rate, tokens: rate as f64, last: now }
+    }
+
+    /// Bytes per second
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+    }
+
+    /// Change the rate without granting a fresh burst
+    pub fn set_rate(&mut self, rate: u64, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    /// Charge `bytes`; returns how long to wait before sending them
+    pub fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+/// Upload and download buckets of one level
+#[derive(Debug, Default)]
+struct Buckets {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl Buckets {
+    /// Create, retune or drop buckets to match a rate
+    fn sync(&mut self, rate: Rate, now: Instant) {
+        for (bucket, rate) in [(&mut self.upload, rate.upload), (&mut self.download, rate.download)] {
+            match (bucket.as_mut(), rate) {
+                (Some(existing), Some(rate)) => existing.set_rate(rate, now),
+                (None, Some(rate)) => *bucket = Some(TokenBucket::new(rate, now)),
+                (_, None) => *bucket = None,
+            }
+        }
+    }
+
+    fn take(&mut self, direction: Direction, bytes: u64, now: Instant) -> Duration {
+        let bucket = match direction {
+            Direction::Upload => self.upload.as_mut(),
+            Direction::Download => self.download.as_mut(),
+        };
+        bucket.map_or(Duration::ZERO, |bucket| bucket.take(bytes, now))
+    }
+}
+
+struct SessionBuckets {
+    user_id: Option<String>,
+    buckets: Buckets,
+}
+
+struct LimiterState {
+    policy: RateLimitPolicy,
+    session_overrides: HashMap<Uuid, Rate>,
+    global: Buckets,
+    users: HashMap<String, Buckets>,
+    sessions: HashMap<Uuid, SessionBuckets>,
+}
+
+impl LimiterState {
+    fn session_rate(&self, session_id: &Uuid) -> Rate {
+        self.session_overrides.get(session_id).copied().unwrap_or(self.policy.session)
+    }
+
+    /// Bring every bucket in line with the current limits
+    fn resync(&mut self, now: Instant) {
+        self.global.sync(self.policy.global, now);
+        for (user_id, buckets) in &mut self.users {
+            buckets.sync(self.policy.user_rate(user_id), now);
+        }
+        let rates: Vec<(Uuid, Rate)> = self.sessions.keys().map(|id| (*id, self.session_rate(id))).collect();
+        for (session_id, rate) in rates {
+            if let Some(session) = self.sessions.get_mut(&session_id) {
+                session.buckets.sync(rate, now);
+            }
+        }
+    }
+}
+
+/// Bandwidth limits shared by the server's sessions
+pub struct RateLimiter {
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    /// Limiter enforcing a policy
+    pub fn new(policy: RateLimitPolicy) -> Self {
+        let mut global = Buckets::default();
+        global.sync(policy.global, Instant::now());
+        Self {
+            state: Mutex::new(LimiterState {
+                policy,
+                session_overrides: HashMap::new(),
+                global,
+                users: HashMap::new(),
+                sessions: HashMap::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LimiterState> {
+        // Bucket state is always consistent, so a poisoned lock is still usable
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Current limits, including live overrides
+    pub fn policy(&self) -> RateLimitPolicy {
+        self.lock().policy.clone()
+    }
+
+    /// Start metering an authenticated session
+    pub fn open_session(&self, session_id: Uuid, user_id: Option<&str>) {
+        let now = Instant::now();
+        let mut state = self.lock();
+        let mut buckets = Buckets::default();
+        buckets.sync(state.session_rate(&session_id), now);
+        if let Some(user_id) = user_id {
+            let rate = state.policy.user_rate(user_id);
+            state.users.entry(user_id.to_string()).or_default().sync(rate, now);
+        }
+        state.sessions.insert(session_id, SessionBuckets { user_id: user_id.map(str::to_string), buckets });
+    }
+
+    /// Stop metering a session; the user's buckets go with their last session
+    pub fn close_session(&self, session_id: Uuid) {
+        let mut state = self.lock();
+        state.session_overrides.remove(&session_id);
+        let Some(closed) = state.sessions.remove(&session_id) else {
+            return;
+        };
+        if let Some(user_id) = closed.user_id {
+            if !state.sessions.values().any(|session| session.user_id.as_deref() == Some(user_id.as_str())) {
+                state.users.remove(&user_id);
+            }
+        }
+    }
+
+    /// Charge a chunk to the global, user and session buckets; returns how long
+    /// to wait before moving it
+    pub fn delay(&self, session_id: Uuid, direction: Direction, bytes: u64) -> Duration {
+        self.delay_at(session_id, direction, bytes, Instant::now())
+    }
+
+    fn delay_at(&self, session_id: Uuid, direction: Direction, bytes: u64, now: Instant) -> Duration {
+        let mut state = self.lock();
+        let state = &mut *state;
+        let mut wait = state.global.take(direction, bytes, now);
+        if let Some(session) = state.sessions.get_mut(&session_id) {
+            wait = wait.max(session.buckets.take(direction, bytes, now));
+            if let Some(user) = session.user_id.as_ref().and_then(|user_id| state.users.get_mut(user_id)) {
+                wait = wait.max(user.take(direction, bytes, now));
+            }
+        }
+        wait
+    }
+
+    /// Apply a live `RateLimit` policy rule
+    pub fn apply_rule(&self, rule: &PolicyRule) -> Result<()> {
+        self.apply_rules(std::slice::from_ref(rule))
+    }
+
+    /// Apply live `RateLimit` policy rules in order; if any rule is invalid,
+    /// none of them take effect
+    pub fn apply_rules(&self, rules: &[PolicyRule]) -> Result<()> {
+        let mut state = self.lock();
+        let mut policy = state.policy.clone();
+        let mut session_overrides = state.session_overrides.clone();
+        for rule in rules {
+            Self::stage_rule(&mut policy, &mut session_overrides, rule)?;
+        }
+
+        state.policy = policy;
+        state.session_overrides = session_overrides;
+        state.resync(Instant::now());
+        Ok(())
+    }
+
+    fn stage_rule(policy: &mut RateLimitPolicy, session_overrides: &mut HashMap<Uuid, Rate>, rule: &PolicyRule) -> Result<()> {
+        if rule.rule_type != PolicyRuleType::RateLimit {
+            return Err(Error::Config(format!("Rule {} is not a rate limit", rule.id)));
+        }
+        let parameters = &rule.parameters;
+        let subject = parameters.get("subject").map(String::as_str);
+        let reset = parameters.get("reset").is_some_and(|reset| reset == "true");
+
+        match (parameters.get("scope").map(String::as_str), subject) {
+            (Some("global"), None) => {
+                policy.global.apply(parameters)?;
+                policy.global.upload.get_or_insert(MAX_TRANSFER_RATE);
+                policy.global.download.get_or_insert(MAX_TRANSFER_RATE);
+            }
+            (Some("user"), None) => policy.user.apply(parameters)?,
+            (Some("user"), Some(user_id)) if reset => {
+                policy.users.remove(user_id);
+            }
+            (Some("user"), Some(user_id)) => {
+                let mut rate = policy.user_rate(user_id);
+                rate.apply(parameters)?;
+                policy.users.insert(user_id.to_string(), rate);
+            }
+            (Some("session"), None) => policy.session.apply(parameters)?,
+            (Some("session"), Some(session_id)) => {
+                let session_id = Uuid::parse_str(session_id)
+                    .map_err(|_| Error::Config(format!("Invalid session ID in rule {}: {}", rule.id, session_id)))?;
+                if reset {
+                    session_overrides.remove(&session_id);
+                } else {
+                    let mut rate = session_overrides.get(&session_id).copied().unwrap_or(policy.session);
+                    rate.apply(parameters)?;
+                    session_overrides.insert(session_id, rate);
+                }
+            }
+            _ => return Err(Error::Config(format!(
+                "Rule {} needs scope global, user or session, with a subject only for user or session", rule.id
+            ))),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1 << 20;
+
+    fn rule(parameters: &[(&str, &str)]) -> PolicyRule {
+        PolicyRule {
+            id: "test".to_string(),
+            rule_type: PolicyRuleType::RateLimit,
+            parameters: parameters.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_rates() {
+        assert_eq!(parse_rate("1048576").unwrap(), MIB);
+        assert_eq!(parse_rate("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse_rate("10m").unwrap(), 10 * MIB);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("20G").is_err());
+
+        let policy: RateLimitPolicy = "[user]\nupload = \"1M\"\n[users.backup]\ndownload = 2048".parse().unwrap();
+        assert_eq!(policy.global, Rate::symmetric(MAX_TRANSFER_RATE));
+        assert_eq!(policy.user_rate("alice"), Rate { upload: Some(MIB), download: None });
+        assert_eq!(policy.user_rate("backup"), Rate { upload: None, download: Some(2048) });
+        assert!("[user]\nupload = \"1M\"\nburst = 5".parse::<RateLimitPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(MIB, start);
+
+        // One second of burst, then a debt repaid at the rate
+        assert_eq!(bucket.take(MIB, start), Duration::ZERO);
+        assert_eq!(bucket.take(MIB / 2, start), Duration::from_millis(500));
+        assert_eq!(bucket.take(MIB / 2, start + Duration::from_millis(500)), Duration::from_millis(500));
+
+        // Idle time refills no more than one second's worth
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.take(MIB, later), Duration::ZERO);
+        assert!(bucket.take(1, later) > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_levels_and_directions() {
+        let policy: RateLimitPolicy = "[user]\nupload = \"1M\"\n[session]\nupload = \"4M\"".parse().unwrap();
+        let limiter = RateLimiter::new(policy);
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        limiter.open_session(first, Some("alice"));
+        limiter.open_session(second, Some("alice"));
+        let now = Instant::now();
+
+        // Both sessions draw on the same per-user bucket
+        assert_eq!(limiter.delay_at(first, Direction::Upload, MIB, now), Duration::ZERO);
+        assert_eq!(limiter.delay_at(second, Direction::Upload, MIB, now), Duration::from_secs(1));
+
+        // Downloads are not limited below the global maximum
+        assert_eq!(limiter.delay_at(first, Direction::Download, 100 * MIB, now), Duration::ZERO);
+
+        limiter.close_session(first);
+        limiter.close_session(second);
+        assert!(limiter.lock().users.is_empty());
+    }
+
+    #[test]
+    fn test_live_overrides() {
+        let limiter = RateLimiter::new(RateLimitPolicy::default());
+        let session = Uuid::new_v4();
+        limiter.open_session(session, Some("backup"));
+
+        limiter.apply_rule(&rule(&[("scope", "user"), ("subject", "backup"), ("upload", "1M")])).unwrap();
+        assert_eq!(limiter.policy().user_rate("backup").upload, Some(MIB));
+        let now = Instant::now();
+        assert!(limiter.delay_at(session, Direction::Upload, 3 * MIB, now) >= Duration::from_secs(1));
+
+        limiter.apply_rule(&rule(&[("scope", "user"), ("subject", "backup"), ("reset", "true")])).unwrap();
+        assert_eq!(limiter.policy().user_rate("backup"), Rate::default());
+
+        limiter.apply_rule(&rule(&[("scope", "session"), ("subject", &session.to_string()), ("download", "512K")])).unwrap();
+        assert_eq!(limiter.lock().session_rate(&session).download, Some(512 * 1024));
+
+        // The global limit can be lowered but never lifted past the protocol maximum
+        limiter.apply_rule(&rule(&[("scope", "global"), ("upload", "unlimited")])).unwrap();
+        assert_eq!(limiter.policy().global.upload, Some(MAX_TRANSFER_RATE));
+
+        assert!(limiter.apply_rule(&rule(&[("scope", "global"), ("subject", "backup")])).is_err());
+        assert!(limiter.apply_rule(&rule(&[("scope", "session"), ("subject", "nope")])).is_err());
+        assert!(limiter.apply_rule(&PolicyRule { rule_type: PolicyRuleType::AccessControl, ..rule(&[]) }).is_err());
+    }
+
+    #[test]
+    fn test_rejected_batch_changes_nothing() {
+        let limiter = RateLimiter::new(RateLimitPolicy::default());
+        let before = limiter.policy();
+
+        // A bad download rate rejects its own valid upload half and every earlier rule
+        let rules = [
+            rule(&[("scope", "user"), ("upload", "1M")]),
+            rule(&[("scope", "session"), ("upload", "2M"), ("download", "fast")]),
+        ];
+        assert!(limiter.apply_rules(&rules).is_err());
+        assert_eq!(limiter.policy(), before);
+
+        limiter.apply_rules(&rules[..1]).unwrap();
+        assert_eq!(limiter.policy().user.upload, Some(MIB));
+    }
+}
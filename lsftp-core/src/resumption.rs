@@ -0,0 +1,263 @@
+//! Session resumption tickets for LSFTP
+//!
+//! After a successful hardware authentication the server can hand the client
+//! a ticket naming the authenticated user, devices and factors, sealed with a
+//! key that only exists in the server's memory. Presenting the ticket on a new
+//! connection within its lifetime authenticates without another signature, so
+//! batch jobs driven through `lsftp-agent` do not need a touch per connection.
+//!
+//! Tickets are single-use: every resumption returns a fresh ticket and the old
+//! one is refused. The fresh ticket keeps the time of the original hardware
+//! authentication, so a chain of resumptions never outlives one lifetime from
+//! the last touch. A ticket is also refused once any of its devices is no
+//! longer active in the enrollment registry, and restarting the server
+//! invalidates every outstanding ticket.
+
+use crate::auth::AuthResult;
+use crate::crypto::{CryptoOperations, CryptoSuite};
+use crate::enrollment::EnrollmentStore;
+use crate::error::{Error, Result};
+use crate::now_secs;
+use crate::secmem::SecretBuffer;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+const TICKET_KEY_LEN: usize = 32;
+const TICKET_NONCE_LEN: usize = 12;
+
+/// Sealed contents of a ticket
+#[derive(Debug, Serialize, Deserialize)]
+struct TicketContents {
+    ticket_id: Uuid,
+    user_id: String,
+    device_ids: Vec<String>,
+    metadata: HashMap<String, String>,
+    authenticated_at: u64,
+    expires_at: u64,
+}
+
+/// Ticket handed to the client
+#[derive(Debug, Clone)]
+pub struct IssuedTicket {
+    /// Opaque ticket bytes
+    pub ticket: Vec<u8>,
+    /// Expiry (seconds since the epoch)
+    pub expires_at: u64,
+}
+
+/// Session resumed from a ticket
+#[derive(Debug, Clone)]
+pub struct ResumedSession {
+    /// Authentication result of the original session, marked as resumed
+    pub result: AuthResult,
+    /// Devices that authenticated the original session
+    pub device_ids: Vec<String>,
+    /// Time of the original hardware authentication (seconds since the epoch)
+    pub authenticated_at: u64,
+}
+
+/// Issues and redeems resumption tickets
+pub struct TicketIssuer {
+    key: SecretBuffer,
+    lifetime: Duration,
+    crypto_suite: CryptoSuite,
+    redeemed: Mutex<HashMap<Uuid, u64>>,
+}
+
+impl TicketIssuer {
+    /// Issuer with a fresh random key and the given ticket lifetime
+    pub fn new(lifetime: Duration) -> Result<Self> {
+        let mut key = SecretBuffer::new(TICKET_KEY_LEN)?;
+        SystemRandom::new().fill(key.as_mut_slice())
+            .map_err(|_| Error::Crypto("Failed to generate ticket key".to_string()))?;
+
+        Ok(Self {
+            key,
+            lifetime,
+            crypto_suite: CryptoSuite::default(),
+            redeemed: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Ticket lifetime
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Seal a ticket for a hardware authentication made at `authenticated_at`;
+    /// it expires one lifetime after that, however often it is resumed
+    pub fn issue(&self, result: &AuthResult, device_ids: &[String], authenticated_at: u64) -> Result<IssuedTicket> {
+        let user_id = result.user_id.clone()
+            .ok_or_else(|| Error::Auth("Cannot issue a resumption ticket without a user".to_string()))?;
+        if device_ids.is_empty() {
+            return Err(Error::Auth("Cannot issue a resumption ticket without devices".to_string()));
+        }
+
+        let expires_at = authenticated_at.saturating_add(self.lifetime.as_secs());
+        if now_secs() >= expires_at {
+            return Err(Error::Auth("Hardware authentication is older than the ticket lifetime".to_string()));
+        }
+        let mut metadata = result.metadata.clone();
+        metadata.remove("resumed");
+        let contents = TicketContents {
+            ticket_id: Uuid::new_v4(),
+            user_id,
+            device_ids: device_ids.to_vec(),
+            metadata,
+            authenticated_at,
+            expires_at,
+        };
+
+        let mut nonce = [0u8; TICKET_NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce)
+            .map_err(|_| Error::Crypto("Failed to generate ticket nonce".to_string()))?;
+
+        let mut ticket = nonce.to_vec();
+        ticket.extend(self.crypto_suite.encrypt(&postcard::to_allocvec(&contents)?, self.key.as_slice(), &nonce)?);
+        Ok(IssuedTicket { ticket, expires_at })
+    }
+
+    /// Open a ticket, refusing expired, reused and tampered tickets and
+    /// tickets whose devices are no longer active
+    pub fn redeem(&self, ticket: &[u8], enrollments: &EnrollmentStore) -> Result<ResumedSession> {
+        if ticket.len() <= TICKET_NONCE_LEN {
+            return Err(Error::Auth("Malformed resumption ticket".to_string()));
+        }
+        let (nonce, ciphertext) = ticket.split_at(TICKET_NONCE_LEN);
+        let plaintext = self.crypto_suite.decrypt(ciphertext, self.key.as_slice(), nonce)
+            .map_err(|_| Error::Auth("Resumption ticket was not issued by this server".to_string()))?;
+        let contents: TicketContents = postcard::from_bytes(&plaintext)?;
+
+        let now = now_secs();
+        if now >= contents.expires_at {
+            return Err(Error::Auth("Resumption ticket has expired".to_string()));
+        }
+
+        {
+            let mut redeemed = self.redeemed.lock()
+                .map_err(|_| Error::Auth("Ticket state lock poisoned".to_string()))?;
+            redeemed.retain(|_, expires_at| now < *expires_at);
+            if redeemed.insert(contents.ticket_id, contents.expires_at).is_some() {
+                return Err(Error::Auth("Resumption ticket has already been used".to_string()));
+            }
+        }
+
+        // Suspending or revoking a device ends its sessions' resumption too
+        for device_id in &contents.device_ids {
+            let device = enrollments.active(device_id)?;
+            if device.user_id != contents.user_id {
+                return Err(Error::Auth(format!(
+                    "Device {} is no longer enrolled for {}", device_id, contents.user_id
+                )));
+            }
+        }
+
+        let mut metadata = contents.metadata;
+        metadata.insert("resumed".to_string(), "true".to_string());
+        Ok(ResumedSession {
+            result: AuthResult {
+                success: true,
+                user_id: Some(contents.user_id),
+                device_id: contents.device_ids.first().cloned(),
+                timestamp: now,
+                metadata,
+                signature: None,
+                error: None,
+            },
+            device_ids: contents.device_ids,
+            authenticated_at: contents.authenticated_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::HardwareType;
+    use crate::enrollment::{DeviceStatus, EnrolledDevice};
+
+    fn store() -> EnrollmentStore {
+        let mut store = EnrollmentStore::default();
+        store.insert(EnrolledDevice::fixture("yubikey-1-9a", HardwareType::YubiKey, "alice"));
+        store
+    }
+
+    fn result() -> AuthResult {
+        AuthResult {
+            success: true,
+            user_id: Some("alice".to_string()),
+            device_id: Some("yubikey-1-9a".to_string()),
+            timestamp: 0,
+            metadata: HashMap::from([("factors".to_string(), "yubikey:yubikey-1-9a".to_string())]),
+            signature: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_ticket_single_use() {
+        let issuer = TicketIssuer::new(Duration::from_secs(300)).unwrap();
+        let devices = vec!["yubikey-1-9a".to_string()];
+        let issued = issuer.issue(&result(), &devices, now_secs()).unwrap();
+
+        let resumed = issuer.redeem(&issued.ticket, &store()).unwrap();
+        assert_eq!(resumed.result.user_id.as_deref(), Some("alice"));
+        assert_eq!(resumed.result.metadata["resumed"], "true");
+        assert_eq!(resumed.result.metadata["factors"], "yubikey:yubikey-1-9a");
+        assert_eq!(resumed.device_ids, devices);
+
+        assert!(issuer.redeem(&issued.ticket, &store()).is_err());
+    }
+
+    #[test]
+    fn test_resumption_keeps_original_expiry() {
+        let issuer = TicketIssuer::new(Duration::from_secs(300)).unwrap();
+        let devices = vec!["yubikey-1-9a".to_string()];
+        let authenticated_at = now_secs() - 200;
+        let issued = issuer.issue(&result(), &devices, authenticated_at).unwrap();
+        assert_eq!(issued.expires_at, authenticated_at + 300);
+
+        // The ticket issued on resumption expires with the first one
+        let resumed = issuer.redeem(&issued.ticket, &store()).unwrap();
+        assert_eq!(resumed.authenticated_at, authenticated_at);
+        let reissued = issuer.issue(&resumed.result, &resumed.device_ids, resumed.authenticated_at).unwrap();
+        assert_eq!(reissued.expires_at, issued.expires_at);
+
+        // Nothing is issued once the hardware authentication is a lifetime old
+        assert!(issuer.issue(&result(), &devices, now_secs() - 300).is_err());
+    }
+
+    #[test]
+    fn test_ticket_rejections() {
+        let issuer = TicketIssuer::new(Duration::from_secs(300)).unwrap();
+        let devices = vec!["yubikey-1-9a".to_string()];
+
+        // Another server instance cannot open the ticket
+        let other = TicketIssuer::new(Duration::from_secs(300)).unwrap();
+        let issued = other.issue(&result(), &devices, now_secs()).unwrap();
+        assert!(issuer.redeem(&issued.ticket, &store()).is_err());
+
+        let mut issued = issuer.issue(&result(), &devices, now_secs()).unwrap();
+        let last = issued.ticket.len() - 1;
+        issued.ticket[last] ^= 1;
+        assert!(issuer.redeem(&issued.ticket, &store()).is_err());
+        assert!(issuer.redeem(&[0u8; 4], &store()).is_err());
+
+        let expired = TicketIssuer::new(Duration::from_secs(1)).unwrap();
+        let issued = expired.issue(&result(), &devices, now_secs()).unwrap();
+        std::thread::sleep(Duration::from_secs(1));
+        assert!(expired.redeem(&issued.ticket, &store()).is_err());
+
+        // A suspended device cannot resume
+        let issued = issuer.issue(&result(), &devices, now_secs()).unwrap();
+        let mut suspended = store();
+        suspended.set_status("yubikey-1-9a", DeviceStatus::Suspended, None).unwrap();
+        assert!(issuer.redeem(&issued.ticket, &suspended).is_err());
+
+        assert!(issuer.issue(&result(), &[], now_secs()).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! Secure memory for LSFTP key material
+//!
+//! This module provides `SecretBuffer`, a fixed-size buffer for private keys,
+//! session keys and KEM shared secrets. The buffer lives in its own anonymous
+//! mapping surrounded by inaccessible guard pages, is locked into RAM,
+//! excluded from core dumps, and zeroized and unlocked on drop.
+
+use crate::error::{Error, Result};
+use std::fmt;
+use std::ptr::NonNull;
+use zeroize::Zeroize;
+
+/// Page-aligned, locked, guard-paged buffer holding secret bytes
+pub struct SecretBuffer {
+    /// Start of the data pages (one page past the mapping base)
+    data: NonNull<u8>,
+    /// Number of secret bytes
+    len: usize,
+    /// Base of the whole mapping, including both guard pages
+    mapping: NonNull<libc::c_void>,
+    /// Size of the whole mapping in bytes
+    mapping_len: usize,
+    /// Size of the data pages in bytes
+    data_len: usize,
+}
+
+// The buffer owns its mapping exclusively; shared access is read-only.
+unsafe impl Send for SecretBuffer {}
+unsafe impl Sync for SecretBuffer {}
+
+impl SecretBuffer {
+    /// Allocate a zero-filled secret buffer of `len` bytes
+    pub fn new(len: usize) -> Result<Self> {
+        let page_size = page_size()?;
+        let data_len = (len.max(1) + page_size - 1) / page_size * page_size;
+        let mapping_len = data_len + 2 * page_size;
+
+        // Anonymous mappings are page-aligned and zero-filled by the kernel
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapping_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(Error::System(format!(
+                "Failed to map secret memory: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        let mapping = NonNull::new(base)
+            .ok_or_else(|| Error::System("Secret memory mapping returned null".to_string()))?;
+        let data = NonNull::new(unsafe { (base as *mut u8).add(page_size) })
+            .ok_or_else(|| Error::System("Secret memory mapping returned null".to_string()))?;
+
+        let buffer = Self {
+            data,
+            len,
+            mapping,
+            mapping_len,
+            data_len,
+        };
+
+        // From here on, Drop releases the mapping on any failure
+        buffer.protect_guard_pages(page_size)?;
+        buffer.lock()?;
+        buffer.exclude_from_core_dumps()?;
+
+        Ok(buffer)
+    }
+
+    /// Copy `bytes` into a new secret buffer
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        let mut buffer = Self::new(bytes.len())?;
+        buffer.as_mut_slice().copy_from_slice(bytes);
+        Ok(buffer)
+    }
+
+    /// Move `bytes` into a new secret buffer, zeroizing the source vector
+    pub fn from_vec(mut bytes: Vec<u8>) -> Result<Self> {
+        let buffer = Self::from_slice(&bytes);
+        bytes.zeroize();
+        buffer
+    }
+
+    /// Concatenate several secrets into one buffer without intermediate copies
+    pub fn concat(parts: &[&[u8]]) -> Result<Self> {
+        let len = parts.iter().map(|part| part.len()).sum();
+        let mut buffer = Self::new(len)?;
+
+        let mut offset = 0;
+        for part in parts {
+            buffer.as_mut_slice()[offset..offset + part.len()].copy_from_slice(part);
+            offset += part.len();
+        }
+
+        Ok(buffer)
+    }
+
+    /// Copy this secret into a new, independently locked buffer
+    pub fn try_clone(&self) -> Result<Self> {
+        Self::from_slice(self.as_slice())
+    }
+
+    /// Secret bytes
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.data.as_ptr(), self.len) }
+    }
+
+    /// Mutable secret bytes
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.data.as_ptr(), self.len) }
+    }
+
+    /// Number of secret bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the buffer holds no bytes
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Constant-time comparison against another secret
+    pub fn ct_eq(&self, other: &[u8]) -> bool {
+        bool::from(subtle::ConstantTimeEq::ct_eq(self.as_slice(), other))
+    }
+
+    /// Make the pages before and after the data inaccessible
+    fn protect_guard_pages(&self, page_size: usize) -> Result<()> {
+        let base = self.mapping.as_ptr() as *mut u8;
+        let trailing = unsafe { base.add(page_size + self.data_len) };
+
+        for guard in [base, trailing] {
+            if unsafe { libc::mprotect(guard as *mut libc::c_void, page_size, libc::PROT_NONE) } != 0 {
+                return Err(Error::System(format!(
+                    "Failed to protect guard page: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lock the data pages to prevent swap
+    fn lock(&self) -> Result<()> {
+        if unsafe { libc::mlock(self.data.as_ptr() as *const libc::c_void, self.data_len) } != 0 {
+            return Err(Error::System(format!(
+                "Failed to lock memory pages: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Exclude the data pages from core dumps
+    fn exclude_from_core_dumps(&self) -> Result<()> {
+        if unsafe {
+            libc::madvise(
+                self.data.as_ptr() as *mut libc::c_void,
+                self.data_len,
+                libc::MADV_DONTDUMP,
+            )
+        } != 0
+        {
+            return Err(Error::System(format!(
+                "Failed to exclude memory from core dumps: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SecretBuffer {
+    fn drop(&mut self) {
+        // Zeroize the whole data region, including slack after `len`
+        let data = unsafe { std::slice::from_raw_parts_mut(self.data.as_ptr(), self.data_len) };
+        data.zeroize();
+
+        unsafe {
+            libc::munlock(self.data.as_ptr() as *const libc::c_void, self.data_len);
+            libc::munmap(self.mapping.as_ptr(), self.mapping_len);
+        }
+    }
+}
+
+impl fmt::Debug for SecretBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretBuffer")
+            .field("len", &self.len)
+            .field("data", &"[REDACTED]")
+            .finish()
+    }
+}
+
+/// System page size
+fn page_size() -> Result<usize> {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size <= 0 {
+        return Err(Error::System("Failed to query page size".to_string()));
+    }
+    Ok(size as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_buffer_roundtrip() {
+        let secret = SecretBuffer::from_slice(b"session key material").unwrap();
+        assert_eq!(secret.as_slice(), b"session key material");
+        assert_eq!(secret.len(), 20);
+        assert!(secret.ct_eq(b"session key material"));
+        assert!(!secret.ct_eq(b"session key materiaL"));
+    }
+
+    #[test]
+    fn test_secret_buffer_page_aligned() {
+        let secret = SecretBuffer::new(32).unwrap();
+        let page = page_size().unwrap();
+        assert_eq!(secret.as_slice().as_ptr() as usize % page, 0);
+        assert!(secret.as_slice().iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_secret_buffer_concat_and_clone() {
+        let secret = SecretBuffer::concat(&[b"classical", b"|", b"pq"]).unwrap();
+        assert_eq!(secret.as_slice(), b"classical|pq");
+
+        let copy = secret.try_clone().unwrap();
+        assert_ne!(copy.as_slice().as_ptr(), secret.as_slice().as_ptr());
+        assert_eq!(copy.as_slice(), secret.as_slice());
+    }
+
+    #[test]
+    fn test_secret_buffer_debug_redacted() {
+        let secret = SecretBuffer::from_vec(b"hunter2".to_vec()).unwrap();
+        let debug = format!("{:?}", secret);
+        assert!(!debug.contains("hunter2"));
+        assert!(debug.contains("REDACTED"));
+    }
+}
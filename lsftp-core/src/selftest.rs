@@ -0,0 +1,443 @@
+//! Cryptographic power-on self-tests for LSFTP
+//!
+//! This module runs known-answer tests (KATs) and pairwise consistency
+//! tests for every algorithm enabled in a `CryptoSuite` before the process
+//! is allowed to send or accept traffic. A failure latches the module into
+//! an error state in which the transport layer refuses connections.
+
+use crate::crypto::{AeadAlgorithm, CryptoOperations, CryptoSuite, HashAlgorithm, KemAlgorithm, SignatureAlgorithm};
+use crate::encryption::SecurityLevel;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Fixed plaintext shared by the AEAD known-answer tests
+const AEAD_KAT_PLAINTEXT: &[u8] = b"LSFTP power-on self-test";
+
+/// ChaCha20-Poly1305 ciphertext and tag for key 00..1f, nonce 00..0b
+const CHACHA20_POLY1305_KAT: &str =
+    "c5a84e547937d52fc0e64ddef7732e10ac1cd4ca2511decd8db010a3b477cdc4e02aed3f258412f8";
+
+/// AES-256-GCM ciphertext and tag for key 00..1f, nonce 00..0b
+const AES_256_GCM_KAT: &str =
+    "0b51904f95c5b274fa24e5a6de87581ee6bae119841e2c082cff5eb03cffc3c30391371b57235c78";
+
+/// BLAKE3("abc")
+const BLAKE3_KAT: &str = "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85";
+
+/// SHA3-256("abc") from FIPS 202
+const SHA3_256_KAT: &str = "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532";
+
+/// SHA-256("abc") from FIPS 180-4
+const SHA_256_KAT: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+/// RFC 8032 section 7.1, test 1: secret key, public key and signature of the empty message
+const ED25519_KAT_SEED: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+const ED25519_KAT_PUBLIC_KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+const ED25519_KAT_SIGNATURE: &str = "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b";
+
+/// Operational state of the cryptographic module
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum ModuleState {
+    /// Self-tests have not been run yet
+    Uninitialized = 0,
+    /// All self-tests passed
+    Operational = 1,
+    /// At least one self-test failed; no traffic may be processed
+    Error = 2,
+}
+
+impl From<u8> for ModuleState {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => ModuleState::Operational,
+            2 => ModuleState::Error,
+            _ => ModuleState::Uninitialized,
+        }
+    }
+}
+
+/// Process-wide module state, latched by `run_power_on_self_tests`
+static MODULE_STATE: AtomicU8 = AtomicU8::new(ModuleState::Uninitialized as u8);
+
+/// Result of a single self-test
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelfTestResult {
+    /// Test name
+    pub name: String,
+    /// Test passed
+    pub passed: bool,
+    /// Failure reason
+    pub error: Option<String>,
+    /// Test duration in microseconds
+    pub duration_us: u64,
+}
+
+/// Aggregated self-test report
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelfTestReport {
+    /// Crypto suite under test
+    pub crypto_suite: CryptoSuite,
+    /// Individual test results
+    pub results: Vec<SelfTestResult>,
+    /// Report timestamp
+    pub timestamp: u64,
+}
+
+impl SelfTestReport {
+    /// Whether every test passed
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| result.passed)
+    }
+
+    /// Failed tests
+    pub fn failures(&self) -> Vec<&SelfTestResult> {
+        self.results.iter().filter(|result| !result.passed).collect()
+    }
+}
+
+/// Self-test runner for a crypto suite
+pub struct SelfTest {
+    crypto_suite: CryptoSuite,
+}
+
+impl SelfTest {
+    /// Create new self-test runner
+    pub fn new(crypto_suite: CryptoSuite) -> Self {
+        Self { crypto_suite }
+    }
+
+    /// Run all self-tests without changing the module state
+    pub fn run(&self) -> SelfTestReport {
+        let mut results = Vec::new();
+
+        results.push(Self::execute(
+            format!("aead-kat-{:?}", self.crypto_suite.aead),
+            || self.aead_known_answer_test(),
+        ));
+        results.push(Self::execute(
+            format!("hash-kat-{:?}", self.crypto_suite.hash),
+            || self.hash_known_answer_test(),
+        ));
+        if matches!(
+            self.crypto_suite.signature,
+            SignatureAlgorithm::Ed25519 | SignatureAlgorithm::HybridEd25519MlDsa65
+        ) {
+            results.push(Self::execute("signature-kat-Ed25519".to_string(), Self::ed25519_known_answer_test));
+        }
+        results.push(Self::execute(
+            format!("signature-pct-{:?}", self.crypto_suite.signature),
+            || self.signature_consistency_test(),
+        ));
+        results.push(Self::execute(
+            format!("kem-roundtrip-{:?}", self.crypto_suite.kem),
+            || self.kem_round_trip_test(),
+        ));
+
+        SelfTestReport {
+            crypto_suite: self.crypto_suite.clone(),
+            results,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+
+    /// Time a single test and capture its outcome
+    fn execute<F>(name: String, test: F) -> SelfTestResult
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let start = Instant::now();
+        let outcome = test();
+
+        SelfTestResult {
+            name,
+            passed: outcome.is_ok(),
+            error: outcome.err().map(|e| e.to_string()),
+            duration_us: start.elapsed().as_micros() as u64,
+        }
+    }
+
+    /// AEAD encrypt/decrypt against a fixed vector, plus tamper detection
+    fn aead_known_answer_test(&self) -> Result<()> {
+        let key: Vec<u8> = (0u8..32).collect();
+        let nonce: Vec<u8> = (0u8..12).collect();
+        let expected = match self.crypto_suite.aead {
+            AeadAlgorithm::ChaCha20Poly1305 => CHACHA20_POLY1305_KAT,
+            AeadAlgorithm::Aes256Gcm => AES_256_GCM_KAT,
+        };
+
+        let ciphertext = self.crypto_suite.encrypt(AEAD_KAT_PLAINTEXT, &key, &nonce)?;
+        expect_hex(&ciphertext, expected, "AEAD ciphertext mismatch")?;
+
+        let plaintext = self.crypto_suite.decrypt(&ciphertext, &key, &nonce)?;
+        if plaintext != AEAD_KAT_PLAINTEXT {
+            return Err(Error::Crypto("AEAD plaintext mismatch".to_string()));
+        }
+
+        let mut tampered = ciphertext;
+        tampered[0] ^= 0x01;
+        if self.crypto_suite.decrypt(&tampered, &key, &nonce).is_ok() {
+            return Err(Error::Crypto("AEAD accepted tampered ciphertext".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Hash of "abc" against the published digest
+    fn hash_known_answer_test(&self) -> Result<()> {
+        let expected = match self.crypto_suite.hash {
+            HashAlgorithm::Blake3 => BLAKE3_KAT,
+            HashAlgorithm::Sha3256 => SHA3_256_KAT,
+            HashAlgorithm::Sha256 => SHA_256_KAT,
+        };
+
+        let digest = self.crypto_suite.hash(b"abc")?;
+        expect_hex(&digest, expected, "Hash digest mismatch")
+    }
+
+    /// Ed25519 deterministic signature against RFC 8032
+    fn ed25519_known_answer_test() -> Result<()> {
+        let seed = decode_hex(ED25519_KAT_SEED)?;
+        let key_pair = ring::signature::Ed25519KeyPair::from_seed_unchecked(&seed)?;
+        expect_hex(key_pair.public_key().as_ref(), ED25519_KAT_PUBLIC_KEY, "Ed25519 public key mismatch")?;
+
+        let signature = key_pair.sign(b"");
+        expect_hex(signature.as_ref(), ED25519_KAT_SIGNATURE, "Ed25519 signature mismatch")?;
+
+        let public_key = ring::signature::UnparsedPublicKey::new(
+            &ring::signature::ED25519,
+            key_pair.public_key().as_ref(),
+        );
+        public_key.verify(b"", signature.as_ref())
+            .map_err(|_| Error::Crypto("Ed25519 verification of known answer failed".to_string()))
+    }
+
+    /// Sign/verify pairwise consistency; a modified message must be rejected
+    fn signature_consistency_test(&self) -> Result<()> {
+        let message = b"LSFTP signature self-test";
+        let signature = self.crypto_suite.sign(message)?;
+
+        if !self.crypto_suite.verify(message, &signature.signature, &signature.public_key)? {
+            return Err(Error::Crypto("Signature did not verify".to_string()));
+        }
+
+        if self.crypto_suite.verify(b"LSFTP signature self-tesT", &signature.signature, &signature.public_key)? {
+            return Err(Error::Crypto("Signature verified over a modified message".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// KEM encapsulate/decapsulate round trip for the configured algorithm
+    fn kem_round_trip_test(&self) -> Result<()> {
+        match self.crypto_suite.kem {
+            KemAlgorithm::EcdheP256 => Self::x25519_round_trip(),
+            KemAlgorithm::MlKem768 => Self::ml_kem_round_trip(oqs::kem::Algorithm::Kyber768),
+            KemAlgorithm::MlKem1024 => Self::ml_kem_round_trip(oqs::kem::Algorithm::Kyber1024),
+            KemAlgorithm::HybridEcdheP256MlKem768 => {
+                Self::x25519_round_trip()?;
+                Self::ml_kem_round_trip(oqs::kem::Algorithm::Kyber768)
+            }
+        }
+    }
+
+    /// Both parties of an ephemeral agreement derive the same secret
+    fn x25519_round_trip() -> Result<()> {
+        use ring::agreement::{agree_ephemeral, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+
+        let rng = ring::rand::SystemRandom::new();
+        let alice = EphemeralPrivateKey::generate(&X25519, &rng)?;
+        let bob = EphemeralPrivateKey::generate(&X25519, &rng)?;
+        let alice_public = alice.compute_public_key()?;
+        let bob_public = bob.compute_public_key()?;
+
+        let alice_secret = agree_ephemeral(
+            alice,
+            &UnparsedPublicKey::new(&X25519, bob_public.as_ref()),
+            |material| material.to_vec(),
+        )?;
+        let bob_secret = agree_ephemeral(
+            bob,
+            &UnparsedPublicKey::new(&X25519, alice_public.as_ref()),
+            |material| material.to_vec(),
+        )?;
+
+        if alice_secret != bob_secret {
+            return Err(Error::Crypto("X25519 shared secrets differ".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Decapsulation recovers the encapsulated secret
+    fn ml_kem_round_trip(algorithm: oqs::kem::Algorithm) -> Result<()> {
+        let kem = oqs::kem::Kem::new(algorithm)
+            .map_err(|e| Error::Crypto(format!("Failed to initialize KEM: {}", e)))?;
+        let (public_key, secret_key) = kem.keypair()
+            .map_err(|e| Error::Crypto(format!("Failed to generate keypair: {}", e)))?;
+        let (ciphertext, encapsulated) = kem.encapsulate(&public_key)
+            .map_err(|e| Error::Crypto(format!("Failed to encapsulate: {}", e)))?;
+        let decapsulated = kem.decapsulate(&secret_key, &ciphertext)
+            .map_err(|e| Error::Crypto(format!("Failed to decapsulate: {}", e)))?;
+
+        if encapsulated.as_ref() != decapsulated.as_ref() {
+            return Err(Error::Crypto("KEM shared secrets differ".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Suites that between them exercise every algorithm at or above a security level
+pub fn accepted_suites(minimum: SecurityLevel) -> Vec<CryptoSuite> {
+    let kems: Vec<KemAlgorithm> = [
+        KemAlgorithm::EcdheP256,
+        KemAlgorithm::HybridEcdheP256MlKem768,
+        KemAlgorithm::MlKem768,
+        KemAlgorithm::MlKem1024,
+    ].into_iter().filter(|kem| kem.security_level() >= minimum).collect();
+    let signatures: Vec<SignatureAlgorithm> = [
+        SignatureAlgorithm::Ed25519,
+        SignatureAlgorithm::HybridEd25519MlDsa65,
+        SignatureAlgorithm::MlDsa65,
+        SignatureAlgorithm::MlDsa87,
+    ].into_iter().filter(|signature| signature.security_level() >= minimum).collect();
+    let aeads = [AeadAlgorithm::ChaCha20Poly1305, AeadAlgorithm::Aes256Gcm];
+    let hashes = [HashAlgorithm::Blake3, HashAlgorithm::Sha3256, HashAlgorithm::Sha256];
+
+    let count = [kems.len(), signatures.len(), aeads.len(), hashes.len()].into_iter().max().unwrap_or(0);
+    (0..count)
+        .map(|i| CryptoSuite {
+            kem: kems[i % kems.len()],
+            signature: signatures[i % signatures.len()],
+            aead: aeads[i % aeads.len()],
+            hash: hashes[i % hashes.len()],
+            ..CryptoSuite::default()
+        })
+        .collect()
+}
+
+/// Run the power-on self-tests, log every result and latch the module state
+pub fn run_power_on_self_tests(crypto_suite: &CryptoSuite) -> SelfTestReport {
+    let report = SelfTest::new(crypto_suite.clone()).run();
+
+    for result in &report.results {
+        if result.passed {
+            tracing::info!("Self-test {} passed ({} us)", result.name, result.duration_us);
+        } else {
+            tracing::error!(
+                "Self-test {} FAILED: {}",
+                result.name,
+                result.error.as_deref().unwrap_or("unknown error")
+            );
+        }
+    }
+
+    if report.passed() {
+        // Never leave a latched error state
+        let _ = MODULE_STATE.compare_exchange(
+            ModuleState::Uninitialized as u8,
+            ModuleState::Operational as u8,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+    } else {
+        enter_error_state();
+    }
+
+    report
+}
+
+/// Current module state
+pub fn module_state() -> ModuleState {
+    ModuleState::from(MODULE_STATE.load(Ordering::SeqCst))
+}
+
+/// Latch the module into the error state
+pub fn enter_error_state() {
+    MODULE_STATE.store(ModuleState::Error as u8, Ordering::SeqCst);
+    tracing::error!("Cryptographic module entered error state; refusing all connections");
+}
+
+/// Fail unless the self-tests have run and passed
+pub fn ensure_operational() -> Result<()> {
+    match module_state() {
+        ModuleState::Operational => Ok(()),
+        ModuleState::Uninitialized => Err(Error::Crypto(
+            "Cryptographic self-tests have not been run".to_string(),
+        )),
+        ModuleState::Error => Err(Error::Crypto(
+            "Cryptographic module is in error state".to_string(),
+        )),
+    }
+}
+
+/// Compare bytes against an expected hex string
+fn expect_hex(actual: &[u8], expected: &str, context: &str) -> Result<()> {
+    let expected = decode_hex(expected)?;
+    if !bool::from(subtle::ConstantTimeEq::ct_eq(actual, expected.as_slice())) {
+        return Err(Error::Crypto(context.to_string()));
+    }
+    Ok(())
+}
+
+/// Decode a built-in test vector
+fn decode_hex(value: &str) -> Result<Vec<u8>> {
+    hex::decode(value).map_err(|e| Error::Internal(format!("Invalid test vector: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aead_known_answers() {
+        for aead in [AeadAlgorithm::ChaCha20Poly1305, AeadAlgorithm::Aes256Gcm] {
+            let suite = CryptoSuite { aead, ..Default::default() };
+            assert!(SelfTest::new(suite).aead_known_answer_test().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_hash_known_answers() {
+        for hash in [HashAlgorithm::Blake3, HashAlgorithm::Sha3256, HashAlgorithm::Sha256] {
+            let suite = CryptoSuite { hash, ..Default::default() };
+            assert!(SelfTest::new(suite).hash_known_answer_test().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_ed25519_known_answer() {
+        assert!(SelfTest::ed25519_known_answer_test().is_ok());
+    }
+
+    #[test]
+    fn test_default_suite_passes() {
+        let report = SelfTest::new(CryptoSuite::default()).run();
+        assert!(report.passed(), "{:?}", report.failures());
+    }
+
+    #[test]
+    fn test_accepted_suites_pass() {
+        let suites = accepted_suites(SecurityLevel::Classical);
+        assert_eq!(suites.len(), 4);
+        for suite in suites {
+            let report = SelfTest::new(suite).run();
+            assert!(report.passed(), "{:?}", report.failures());
+        }
+
+        let post_quantum = accepted_suites(SecurityLevel::PostQuantum);
+        assert!(post_quantum.iter().all(|suite| suite.security_level() == SecurityLevel::PostQuantum));
+    }
+
+    #[test]
+    fn test_expect_hex_rejects_mismatch() {
+        assert!(expect_hex(&[0x00, 0x01], "0001", "mismatch").is_ok());
+        assert!(expect_hex(&[0x00, 0x02], "0001", "mismatch").is_err());
+    }
+}
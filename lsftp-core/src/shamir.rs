@@ -0,0 +1,406 @@
+//! Shamir secret sharing for LSFTP root key backup
+//!
+//! Splits a key into N shares over GF(256) so that any `threshold` of them
+//! reconstruct it and fewer reveal nothing. Each share can be sealed into a
+//! passphrase-encrypted, checksummed share file for its custodian.
+
+use crate::crypto::{AeadAlgorithm, CryptoOperations, CryptoSuite};
+use crate::error::{Error, Result};
+use crate::secmem::SecretBuffer;
+use ring::rand::SecureRandom;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use uuid::Uuid;
+
+/// Share file format version
+pub const SHARE_FILE_VERSION: u32 = 1;
+
+/// Default PBKDF2-HMAC-SHA256 iterations for share files
+pub const DEFAULT_KDF_ITERATIONS: u32 = 600_000;
+
+/// Context string for key fingerprints
+const FINGERPRINT_CONTEXT: &str = "lsftp shamir key fingerprint v1";
+
+/// Share header length inside the sealed plaintext: set ID, index, threshold
+const SEALED_HEADER_LEN: usize = 16 + 2;
+
+/// One share of a split secret
+pub struct Share {
+    index: u8,
+    threshold: u8,
+    value: SecretBuffer,
+}
+
+impl Share {
+    /// Evaluation point of this share (never zero)
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Number of shares needed to recover the secret
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    /// Share value
+    pub fn value(&self) -> &[u8] {
+        self.value.as_slice()
+    }
+}
+
+/// Split a secret into `shares` shares, any `threshold` of which recover it
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Result<Vec<Share>> {
+    if secret.is_empty() {
+        return Err(Error::Crypto("Cannot split an empty secret".to_string()));
+    }
+    if threshold < 2 {
+        return Err(Error::Config("Threshold must be at least 2".to_string()));
+    }
+    if shares < threshold {
+        return Err(Error::Config(format!(
+            "Share count {} is below threshold {}", shares, threshold
+        )));
+    }
+
+    // Random coefficients a1..a(k-1) for every secret byte; a0 is the secret
+    let degree = threshold as usize - 1;
+    let mut coefficients = SecretBuffer::new(secret.len() * degree)?;
+    ring::rand::SystemRandom::new().fill(coefficients.as_mut_slice())?;
+
+    let mut result = Vec::with_capacity(shares as usize);
+    for index in 1..=shares {
+        let mut value = SecretBuffer::new(secret.len())?;
+        for (i, byte) in value.as_mut_slice().iter_mut().enumerate() {
+            let poly = &coefficients.as_slice()[i * degree..(i + 1) * degree];
+
+            // Horner's rule from the highest coefficient down to the secret
+            let mut y = 0u8;
+            for &coefficient in poly.iter().rev() {
+                y = gf_mul(y, index) ^ coefficient;
+            }
+            *byte = gf_mul(y, index) ^ secret[i];
+        }
+
+        result.push(Share { index, threshold, value });
+    }
+
+    Ok(result)
+}
+
+/// Recover a secret from a quorum of shares
+pub fn combine(shares: &[Share]) -> Result<SecretBuffer> {
+    let first = shares.first()
+        .ok_or_else(|| Error::Crypto("No shares provided".to_string()))?;
+    let threshold = first.threshold;
+    let len = first.value.len();
+
+    if shares.len() < threshold as usize {
+        return Err(Error::Crypto(format!(
+            "Need {} shares to recover, got {}", threshold, shares.len()
+        )));
+    }
+
+    for (i, share) in shares.iter().enumerate() {
+        if share.index == 0 {
+            return Err(Error::Crypto("Invalid share index 0".to_string()));
+        }
+        if share.threshold != threshold || share.value.len() != len {
+            return Err(Error::Crypto("Shares belong to different splits".to_string()));
+        }
+        if shares[..i].iter().any(|other| other.index == share.index) {
+            return Err(Error::Crypto(format!("Duplicate share index {}", share.index)));
+        }
+    }
+
+    // Lagrange basis at x = 0; subtraction is XOR in GF(256)
+    let basis: Vec<u8> = shares.iter()
+        .map(|share| {
+            shares.iter()
+                .filter(|other| other.index != share.index)
+                .fold(1u8, |acc, other| {
+                    gf_mul(acc, gf_mul(other.index, gf_inv(other.index ^ share.index)))
+                })
+        })
+        .collect();
+
+    let mut secret = SecretBuffer::new(len)?;
+    for (i, byte) in secret.as_mut_slice().iter_mut().enumerate() {
+        *byte = shares.iter()
+            .zip(&basis)
+            .fold(0u8, |acc, (share, &l)| acc ^ gf_mul(share.value.as_slice()[i], l));
+    }
+
+    Ok(secret)
+}
+
+/// Fingerprint identifying a split key without revealing it
+pub fn key_fingerprint(secret: &[u8]) -> String {
+    hex::encode(blake3::derive_key(FINGERPRINT_CONTEXT, secret))
+}
+
+/// Multiply in GF(2^8) modulo x^8 + x^4 + x^3 + x + 1, in constant time
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    product
+}
+
+/// Multiplicative inverse in GF(2^8) as a^254
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1u8;
+    let mut base = a;
+    let mut exponent = 254u8;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+/// Passphrase-encrypted, checksummed share file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareFile {
+    /// Format version
+    pub version: u32,
+    /// ID shared by all shares of one split
+    pub set_id: Uuid,
+    /// Fingerprint of the split key
+    pub key_fingerprint: String,
+    /// Share index
+    pub index: u8,
+    /// Shares needed to recover
+    pub threshold: u8,
+    /// Shares created
+    pub total_shares: u8,
+    /// PBKDF2-HMAC-SHA256 iterations
+    pub kdf_iterations: u32,
+    /// PBKDF2 salt (hex)
+    pub salt: String,
+    /// ChaCha20-Poly1305 nonce (hex)
+    pub nonce: String,
+    /// Encrypted share (hex)
+    pub ciphertext: String,
+    /// BLAKE3 checksum over all other fields (hex)
+    pub checksum: String,
+}
+
+impl ShareFile {
+    /// Encrypt a share under a custodian passphrase
+    pub fn seal(
+        share: &Share,
+        set_id: Uuid,
+        key_fingerprint: String,
+        total_shares: u8,
+        passphrase: &[u8],
+        kdf_iterations: u32,
+    ) -> Result<Self> {
+        let rng = ring::rand::SystemRandom::new();
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 12];
+        rng.fill(&mut salt)?;
+        rng.fill(&mut nonce)?;
+
+        // Bind the header fields inside the authenticated plaintext
+        let mut plaintext = SecretBuffer::new(SEALED_HEADER_LEN + share.value.len())?;
+        {
+            let buf = plaintext.as_mut_slice();
+            buf[..16].copy_from_slice(set_id.as_bytes());
+            buf[16] = share.index;
+            buf[17] = share.threshold;
+            buf[SEALED_HEADER_LEN..].copy_from_slice(share.value.as_slice());
+        }
+
+        let key = Self::derive_key(passphrase, &salt, kdf_iterations)?;
+        let ciphertext = Self::aead().encrypt(plaintext.as_slice(), key.as_slice(), &nonce)?;
+
+        let mut file = Self {
+            version: SHARE_FILE_VERSION,
+            set_id,
+            key_fingerprint,
+            index: share.index,
+            threshold: share.threshold,
+            total_shares,
+            kdf_iterations,
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+            checksum: String::new(),
+        };
+        file.checksum = file.compute_checksum();
+
+        Ok(file)
+    }
+
+    /// Check the file checksum, detecting corruption before decryption
+    pub fn verify_checksum(&self) -> Result<()> {
+        let expected = self.compute_checksum();
+        let matches: bool = subtle::ConstantTimeEq::ct_eq(expected.as_bytes(), self.checksum.as_bytes()).into();
+        if !matches {
+            return Err(Error::Crypto(format!("Share {} checksum mismatch", self.index)));
+        }
+        Ok(())
+    }
+
+    /// Decrypt the share with the custodian passphrase
+    pub fn open(&self, passphrase: &[u8]) -> Result<Share> {
+        if self.version != SHARE_FILE_VERSION {
+            return Err(Error::Config(format!("Unsupported share file version {}", self.version)));
+        }
+        self.verify_checksum()?;
+
+        let salt = decode_field(&self.salt)?;
+        let nonce = decode_field(&self.nonce)?;
+        let ciphertext = decode_field(&self.ciphertext)?;
+
+        let key = Self::derive_key(passphrase, &salt, self.kdf_iterations)?;
+        let plaintext = Self::aead().decrypt(&ciphertext, key.as_slice(), &nonce)
+            .map_err(|_| Error::Crypto(format!("Share {}: wrong passphrase or corrupted share", self.index)))?;
+        let plaintext = SecretBuffer::from_vec(plaintext)?;
+        let buf = plaintext.as_slice();
+
+        if buf.len() <= SEALED_HEADER_LEN
+            || &buf[..16] != self.set_id.as_bytes()
+            || buf[16] != self.index
+            || buf[17] != self.threshold
+        {
+            return Err(Error::Crypto(format!("Share {} header does not match its contents", self.index)));
+        }
+
+        Ok(Share {
+            index: buf[16],
+            threshold: buf[17],
+            value: SecretBuffer::from_slice(&buf[SEALED_HEADER_LEN..])?,
+        })
+    }
+
+    /// Checksum over every field except the checksum itself
+    fn compute_checksum(&self) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.version.to_be_bytes());
+        hasher.update(self.set_id.as_bytes());
+        hasher.update(self.key_fingerprint.as_bytes());
+        hasher.update(&[self.index, self.threshold, self.total_shares]);
+        hasher.update(&self.kdf_iterations.to_be_bytes());
+        hasher.update(self.salt.as_bytes());
+        hasher.update(self.nonce.as_bytes());
+        hasher.update(self.ciphertext.as_bytes());
+        hasher.finalize().to_hex().to_string()
+    }
+
+    /// Derive the share encryption key from the passphrase
+    fn derive_key(passphrase: &[u8], salt: &[u8], iterations: u32) -> Result<SecretBuffer> {
+        let iterations = NonZeroU32::new(iterations)
+            .ok_or_else(|| Error::Config("KDF iterations must be non-zero".to_string()))?;
+
+        let mut key = SecretBuffer::new(32)?;
+        ring::pbkdf2::derive(ring::pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase, key.as_mut_slice());
+        Ok(key)
+    }
+
+    /// Share files are always sealed with ChaCha20-Poly1305
+    fn aead() -> CryptoSuite {
+        CryptoSuite {
+            aead: AeadAlgorithm::ChaCha20Poly1305,
+            ..Default::default()
+        }
+    }
+}
+
+/// Decode a hex field of a share file
+fn decode_field(value: &str) -> Result<Vec<u8>> {
+    hex::decode(value).map_err(|e| Error::Crypto(format!("Malformed share file: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_ITERATIONS: u32 = 1_000;
+
+    #[test]
+    fn test_gf256_arithmetic() {
+        // Inverse pair from the AES specification
+        assert_eq!(gf_mul(0x53, 0xca), 0x01);
+        assert_eq!(gf_inv(0x53), 0xca);
+        for a in 1..=255u8 {
+            assert_eq!(gf_mul(a, gf_inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn test_split_and_combine() {
+        let secret = b"lsftp server root signing key material";
+        let shares = split(secret, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+
+        // Any quorum recovers the secret
+        for quorum in [[0, 1, 2], [1, 3, 4], [0, 2, 4]] {
+            let subset: Vec<Share> = quorum.iter()
+                .map(|&i| Share {
+                    index: shares[i].index,
+                    threshold: shares[i].threshold,
+                    value: shares[i].value.try_clone().unwrap(),
+                })
+                .collect();
+            assert_eq!(combine(&subset).unwrap().as_slice(), secret);
+        }
+
+        assert_eq!(combine(&shares).unwrap().as_slice(), secret);
+        assert!(combine(&shares[..2]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        assert!(split(b"key", 1, 3).is_err());
+        assert!(split(b"key", 4, 3).is_err());
+        assert!(split(b"", 2, 3).is_err());
+
+        let mut shares = split(b"key", 2, 3).unwrap();
+        shares[1].index = shares[0].index;
+        assert!(combine(&shares[..2]).is_err());
+    }
+
+    #[test]
+    fn test_share_file_roundtrip() {
+        let secret = b"root key";
+        let shares = split(secret, 2, 3).unwrap();
+        let set_id = Uuid::new_v4();
+        let fingerprint = key_fingerprint(secret);
+
+        let files: Vec<ShareFile> = shares.iter()
+            .map(|share| ShareFile::seal(share, set_id, fingerprint.clone(), 3, b"custodian", TEST_ITERATIONS).unwrap())
+            .collect();
+
+        let json = serde_json::to_string(&files[2]).unwrap();
+        let parsed: ShareFile = serde_json::from_str(&json).unwrap();
+
+        let opened = vec![files[0].open(b"custodian").unwrap(), parsed.open(b"custodian").unwrap()];
+        let recovered = combine(&opened).unwrap();
+        assert_eq!(recovered.as_slice(), secret);
+        assert_eq!(key_fingerprint(recovered.as_slice()), fingerprint);
+    }
+
+    #[test]
+    fn test_share_file_rejects_tampering() {
+        let shares = split(b"root key", 2, 2).unwrap();
+        let file = ShareFile::seal(&shares[0], Uuid::new_v4(), key_fingerprint(b"root key"), 2, b"custodian", TEST_ITERATIONS).unwrap();
+
+        assert!(file.open(b"wrong passphrase").is_err());
+
+        let mut corrupted = file.clone();
+        corrupted.index = 2;
+        assert!(corrupted.verify_checksum().is_err());
+
+        // A consistent checksum does not help once the sealed header disagrees
+        corrupted.checksum = corrupted.compute_checksum();
+        assert!(corrupted.open(b"custodian").is_err());
+    }
+}
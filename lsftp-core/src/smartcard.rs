@@ -0,0 +1,673 @@
+//! PIV/CAC smart card support for LSFTP
+//!
+//! Talks to the card's PIV applet with APDUs (SP 800-73-4): SELECT the
+//! applet, read the CHUID and the PIV authentication certificate, VERIFY
+//! the PIN and sign challenges with GENERAL AUTHENTICATE. Certificates are
+//! validated against a configured CA bundle, and its CRLs when given, and the
+//! user identity is taken from the certificate's UPN, or its subject common name.
+
+use crate::error::{Error, Result};
+use crate::piv::PivAlgorithm;
+use std::path::Path;
+use x509_parser::prelude::*;
+
+/// PIV applet identifier
+pub const PIV_AID: [u8; 11] = [0xa0, 0x00, 0x00, 0x03, 0x08, 0x00, 0x00, 0x10, 0x00, 0x01, 0x00];
+
+/// Card Holder Unique Identifier data object
+pub const OBJECT_CHUID: u32 = 0x5f_c102;
+
+/// X.509 certificate for PIV authentication (key 9A)
+pub const OBJECT_AUTHENTICATION_CERTIFICATE: u32 = 0x5f_c105;
+
+/// PIV authentication key reference
+pub const KEY_AUTHENTICATION: u8 = 0x9a;
+
+/// PIV card application PIN reference
+const PIN_REFERENCE: u8 = 0x80;
+
+/// PIN field length; shorter PINs are padded with 0xff
+const PIN_LEN: usize = 8;
+
+/// Largest command data field without command chaining
+const MAX_SHORT_DATA: usize = 255;
+
+/// Microsoft User Principal Name (otherName in subjectAltName)
+const OID_UPN: &str = "1.3.6.1.4.1.311.20.2.3";
+
+/// Microsoft smart card logon extended key usage
+const OID_SMARTCARD_LOGON: &str = "1.3.6.1.4.1.311.20.2.2";
+
+const OID_RSA_ENCRYPTION: &str = "1.2.840.113549.1.1.1";
+const OID_EC_PUBLIC_KEY: &str = "1.2.840.10045.2.1";
+const OID_P256: &str = "1.2.840.10045.3.1.7";
+const OID_P384: &str = "1.3.132.0.34";
+
+/// Longest issuer chain followed up to a trust anchor
+const MAX_CHAIN_DEPTH: usize = 4;
+
+/// Command APDU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Apdu {
+    pub cla: u8,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    pub data: Vec<u8>,
+    /// Whether a response is expected (Le = 00)
+    pub le: bool,
+}
+
+impl Apdu {
+    /// SELECT the PIV applet
+    pub fn select_piv() -> Self {
+        Self { cla: 0x00, ins: 0xa4, p1: 0x04, p2: 0x00, data: PIV_AID.to_vec(), le: true }
+    }
+
+    /// GET DATA for a PIV data object
+    pub fn get_data(object_id: u32) -> Self {
+        let tag = object_id.to_be_bytes();
+        Self { cla: 0x00, ins: 0xcb, p1: 0x3f, p2: 0xff, data: tlv(0x5c, &tag[1..]), le: true }
+    }
+
+    /// VERIFY the card application PIN
+    pub fn verify_pin(pin: &[u8]) -> Result<Self> {
+        if pin.is_empty() || pin.len() > PIN_LEN {
+            return Err(Error::HardwareAuth("PIV PIN must be 1 to 8 characters".to_string()));
+        }
+
+        let mut data = pin.to_vec();
+        data.resize(PIN_LEN, 0xff);
+        Ok(Self { cla: 0x00, ins: 0x20, p1: 0x00, p2: PIN_REFERENCE, data, le: false })
+    }
+
+    /// GENERAL AUTHENTICATE: sign `input` with the key in `key_reference`
+    pub fn general_authenticate(algorithm: PivAlgorithm, key_reference: u8, input: &[u8]) -> Self {
+        // Dynamic authentication template: empty response (82), challenge (81)
+        let mut template = vec![0x82, 0x00];
+        template.extend_from_slice(&tlv(0x81, input));
+        Self {
+            cla: 0x00,
+            ins: 0x87,
+            p1: algorithm.reference(),
+            p2: key_reference,
+            data: tlv(0x7c, &template),
+            le: true,
+        }
+    }
+
+    /// GET RESPONSE for the remaining bytes announced by SW 61xx
+    fn get_response(len: u8) -> Vec<u8> {
+        vec![0x00, 0xc0, 0x00, 0x00, len]
+    }
+
+    /// Encoded short APDUs, using command chaining for long data fields
+    pub fn encode(&self) -> Vec<Vec<u8>> {
+        let chunks: Vec<&[u8]> = if self.data.is_empty() {
+            vec![&[]]
+        } else {
+            self.data.chunks(MAX_SHORT_DATA).collect()
+        };
+
+        let last = chunks.len() - 1;
+        chunks.into_iter().enumerate().map(|(index, chunk)| {
+            let cla = if index < last { self.cla | 0x10 } else { self.cla };
+            let mut apdu = vec![cla, self.ins, self.p1, self.p2];
+            if !chunk.is_empty() {
+                apdu.push(chunk.len() as u8);
+                apdu.extend_from_slice(chunk);
+            }
+            if index == last && self.le {
+                apdu.push(0x00);
+            }
+            apdu
+        }).collect()
+    }
+}
+
+/// Send an APDU and collect the full response, following SW 61xx
+pub fn transmit(card: &pcsc::Card, apdu: &Apdu) -> Result<Vec<u8>> {
+    let mut buffer = [0u8; pcsc::MAX_BUFFER_SIZE_EXTENDED];
+    let mut data = Vec::new();
+
+    let mut status = (0, 0);
+    for command in apdu.encode() {
+        let response = card.transmit(&command, &mut buffer)
+            .map_err(|e| Error::HardwareAuth(format!("Failed to transmit APDU: {}", e)))?;
+        let (body, sw) = split_status(response)?;
+        status = sw;
+        if command[0] & 0x10 != 0 && status != (0x90, 0x00) {
+            break;
+        }
+        data.extend_from_slice(body);
+    }
+
+    while status.0 == 0x61 {
+        let response = card.transmit(&Apdu::get_response(status.1), &mut buffer)
+            .map_err(|e| Error::HardwareAuth(format!("Failed to transmit APDU: {}", e)))?;
+        let (body, sw) = split_status(response)?;
+        data.extend_from_slice(body);
+        status = sw;
+    }
+
+    check_status(status)?;
+    Ok(data)
+}
+
+/// Response body and status word
+fn split_status(response: &[u8]) -> Result<(&[u8], (u8, u8))> {
+    match response {
+        [body @ .., sw1, sw2] => Ok((body, (*sw1, *sw2))),
+        _ => Err(Error::HardwareAuth("Truncated APDU response".to_string())),
+    }
+}
+
+/// Map a status word to an error
+pub fn check_status(status: (u8, u8)) -> Result<()> {
+    match status {
+        (0x90, 0x00) => Ok(()),
+        (0x63, sw2) if sw2 & 0xf0 == 0xc0 => Err(Error::HardwareAuth(format!(
+            "Wrong PIV PIN, {} tries left", sw2 & 0x0f
+        ))),
+        (0x69, 0x83) => Err(Error::HardwareAuth("PIV PIN is blocked".to_string())),
+        (0x69, 0x82) => Err(Error::HardwareAuth("PIV security status not satisfied (PIN required)".to_string())),
+        (0x6a, 0x82) => Err(Error::HardwareAuth("PIV applet or data object not found".to_string())),
+        (sw1, sw2) => Err(Error::HardwareAuth(format!("PIV card returned status {:02x}{:02x}", sw1, sw2))),
+    }
+}
+
+/// BER-TLV objects in `data`, as (tag, value) pairs
+pub fn parse_tlv(mut data: &[u8]) -> Result<Vec<(u32, &[u8])>> {
+    let invalid = || Error::HardwareAuth("Malformed PIV TLV data".to_string());
+    let mut objects = Vec::new();
+
+    while !data.is_empty() {
+        // Multi-byte tags continue while the high bit is set
+        let mut tag = data[0] as u32;
+        let mut offset = 1;
+        if data[0] & 0x1f == 0x1f {
+            loop {
+                let byte = *data.get(offset).ok_or_else(invalid)?;
+                tag = (tag << 8) | byte as u32;
+                offset += 1;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+        }
+
+        let first = *data.get(offset).ok_or_else(invalid)?;
+        offset += 1;
+        let len = match first {
+            0x00..=0x7f => first as usize,
+            0x81 => {
+                offset += 1;
+                *data.get(offset - 1).ok_or_else(invalid)? as usize
+            }
+            0x82 => {
+                let bytes = data.get(offset..offset + 2).ok_or_else(invalid)?;
+                offset += 2;
+                u16::from_be_bytes([bytes[0], bytes[1]]) as usize
+            }
+            _ => return Err(invalid()),
+        };
+
+        let value = data.get(offset..offset + len).ok_or_else(invalid)?;
+        objects.push((tag, value));
+        data = &data[offset + len..];
+    }
+
+    Ok(objects)
+}
+
+/// First object with `tag` in `data`
+pub fn find_tlv(data: &[u8], tag: u32) -> Result<Option<&[u8]>> {
+    Ok(parse_tlv(data)?.into_iter().find(|(t, _)| *t == tag).map(|(_, value)| value))
+}
+
+/// Content of a GET DATA response (tag 53)
+pub fn data_object(response: &[u8]) -> Result<&[u8]> {
+    find_tlv(response, 0x53)?
+        .ok_or_else(|| Error::HardwareAuth("PIV data object missing".to_string()))
+}
+
+/// Card Holder Unique Identifier
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chuid {
+    /// Federal Agency Smart Credential Number
+    pub fasc_n: Vec<u8>,
+    /// Card UUID
+    pub guid: Option<[u8; 16]>,
+    /// Expiration date (YYYYMMDD)
+    pub expiration: Option<String>,
+}
+
+impl Chuid {
+    /// Parse the CHUID data object content
+    pub fn parse(object: &[u8]) -> Result<Self> {
+        let mut chuid = Chuid { fasc_n: Vec::new(), guid: None, expiration: None };
+        for (tag, value) in parse_tlv(object)? {
+            match tag {
+                0x30 => chuid.fasc_n = value.to_vec(),
+                0x34 => chuid.guid = value.try_into().ok().filter(|guid: &[u8; 16]| guid.iter().any(|&b| b != 0)),
+                0x35 => chuid.expiration = Some(String::from_utf8_lossy(value).into_owned()),
+                _ => {}
+            }
+        }
+
+        if chuid.fasc_n.is_empty() && chuid.guid.is_none() {
+            return Err(Error::HardwareAuth("CHUID has neither FASC-N nor GUID".to_string()));
+        }
+        Ok(chuid)
+    }
+
+    /// Device identifier: the card UUID, or the FASC-N when the card has none
+    pub fn device_id(&self) -> String {
+        match &self.guid {
+            Some(guid) => format!("smartcard-{}", hex::encode(guid)),
+            None => format!("smartcard-{}", hex::encode(&self.fasc_n)),
+        }
+    }
+}
+
+/// DER certificate from the content of a PIV certificate object
+pub fn certificate_from_object(object: &[u8]) -> Result<Vec<u8>> {
+    let certificate = find_tlv(object, 0x70)?
+        .ok_or_else(|| Error::HardwareAuth("PIV certificate object has no certificate".to_string()))?;
+
+    // CertInfo bit 0 marks a gzip-compressed certificate
+    if find_tlv(object, 0x71)?.map_or(false, |info| info.first().map_or(false, |b| b & 0x01 != 0)) {
+        return Err(Error::HardwareAuth("Compressed PIV certificates are not supported".to_string()));
+    }
+
+    Ok(certificate.to_vec())
+}
+
+/// Signature from a GENERAL AUTHENTICATE response
+pub fn signature_from_response(response: &[u8]) -> Result<Vec<u8>> {
+    let template = find_tlv(response, 0x7c)?
+        .ok_or_else(|| Error::HardwareAuth("GENERAL AUTHENTICATE returned no template".to_string()))?;
+    find_tlv(template, 0x82)?
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| Error::HardwareAuth("GENERAL AUTHENTICATE returned no signature".to_string()))
+}
+
+/// Key and identity carried by a PIV authentication certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateInfo {
+    /// Key algorithm
+    pub algorithm: PivAlgorithm,
+    /// Public key in the form the enrollment registry stores
+    pub public_key: Vec<u8>,
+    /// User identity: the UPN, or the subject common name
+    pub identity: String,
+    /// Issuer organization
+    pub issuer_organization: Option<String>,
+}
+
+impl CertificateInfo {
+    /// Extract the key and identity from a DER certificate
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let (_, certificate) = X509Certificate::from_der(der)
+            .map_err(|e| Error::HardwareAuth(format!("Invalid certificate: {}", e)))?;
+
+        let spki = certificate.public_key();
+        let algorithm = match spki.algorithm.algorithm.to_id_string().as_str() {
+            OID_RSA_ENCRYPTION => match spki.parsed() {
+                Ok(x509_parser::public_key::PublicKey::RSA(rsa)) if rsa.key_size() == 2048 => PivAlgorithm::Rsa2048,
+                _ => return Err(Error::HardwareAuth("Only RSA-2048 certificate keys are supported".to_string())),
+            },
+            OID_EC_PUBLIC_KEY => {
+                let curve = spki.algorithm.parameters.as_ref()
+                    .and_then(|parameters| parameters.as_oid().ok())
+                    .map(|oid| oid.to_id_string());
+                match curve.as_deref() {
+                    Some(OID_P256) => PivAlgorithm::EccP256,
+                    Some(OID_P384) => PivAlgorithm::EccP384,
+                    _ => return Err(Error::HardwareAuth("Unsupported certificate EC curve".to_string())),
+                }
+            }
+            other => return Err(Error::HardwareAuth(format!("Unsupported certificate key algorithm {}", other))),
+        };
+
+        Ok(Self {
+            algorithm,
+            public_key: spki.subject_public_key.data.to_vec(),
+            identity: certificate_identity(&certificate)?,
+            issuer_organization: certificate.issuer().iter_organization().next()
+                .and_then(|o| o.as_str().ok())
+                .map(str::to_string),
+        })
+    }
+}
+
+/// User identity of a certificate: the UPN, or the subject common name
+pub(crate) fn certificate_identity(certificate: &X509Certificate<'_>) -> Result<String> {
+    if let Ok(Some(names)) = certificate.subject_alternative_name() {
+        for name in &names.value.general_names {
+            if let GeneralName::OtherName(oid, value) = name {
+                if oid.to_id_string() == OID_UPN {
+                    if let Some(upn) = upn_from_other_name(value)? {
+                        return Ok(upn);
+                    }
+                }
+            }
+        }
+    }
+
+    certificate.subject().iter_common_name().next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| Error::HardwareAuth("Certificate has neither a UPN nor a subject common name".to_string()))
+}
+
+/// UTF8String value of a UPN otherName, with or without its `[0] EXPLICIT` wrapper
+pub fn upn_from_other_name(value: &[u8]) -> Result<Option<String>> {
+    let value = match find_tlv(value, 0xa0)? {
+        Some(inner) => inner,
+        None => value,
+    };
+    Ok(find_tlv(value, 0x0c)?.and_then(|upn| std::str::from_utf8(upn).ok()).map(str::to_string))
+}
+
+/// Trusted CA certificates for smart card and client certificates
+#[derive(Debug, Clone)]
+pub struct CaBundle {
+    certificates: Vec<Vec<u8>>,
+    crls: Vec<Vec<u8>>,
+}
+
+impl CaBundle {
+    /// Load a PEM bundle
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .map_err(|e| Error::Config(format!("Failed to open CA bundle {:?}: {}", path, e)))?;
+        let certificates = rustls_pemfile::certs(&mut std::io::BufReader::new(file))
+            .map_err(|e| Error::Config(format!("Failed to read CA bundle {:?}: {}", path, e)))?;
+        if certificates.is_empty() {
+            return Err(Error::Config(format!("CA bundle {:?} holds no certificates", path)));
+        }
+        Ok(Self { certificates, crls: Vec::new() })
+    }
+
+    /// Bundle from DER certificates
+    pub fn from_der(certificates: Vec<Vec<u8>>) -> Self {
+        Self { certificates, crls: Vec::new() }
+    }
+
+    /// Check revocation against DER CRL files; once any is given, every
+    /// certificate in a chain needs a current CRL from its issuer
+    pub fn with_crls(mut self, paths: &[std::path::PathBuf]) -> Result<Self> {
+        for path in paths {
+            let der = std::fs::read(path)
+                .map_err(|e| Error::Config(format!("Failed to read CRL {:?}: {}", path, e)))?;
+            CertificateRevocationList::from_der(&der)
+                .map_err(|e| Error::Config(format!("Invalid CRL {:?}: {}", path, e)))?;
+            self.crls.push(der);
+        }
+        Ok(self)
+    }
+
+    /// Number of loaded CRLs
+    pub fn crl_count(&self) -> usize {
+        self.crls.len()
+    }
+
+    /// Number of trusted certificates
+    pub fn len(&self) -> usize {
+        self.certificates.len()
+    }
+
+    /// Whether the bundle is empty
+    pub fn is_empty(&self) -> bool {
+        self.certificates.is_empty()
+    }
+
+    /// Validate a smart card logon certificate, with optional intermediates, up to a bundle CA
+    pub fn validate(&self, certificate: &[u8], intermediates: &[Vec<u8>]) -> Result<CertificateInfo> {
+        self.verify_chain(certificate, intermediates)?;
+
+        let (_, parsed) = X509Certificate::from_der(certificate)
+            .map_err(|e| Error::Auth(format!("Invalid certificate: {}", e)))?;
+        check_logon_usage(&parsed)?;
+        CertificateInfo::from_der(certificate).map_err(|e| Error::Auth(e.to_string()))
+    }
+
+    /// DER subjects of the trusted CAs
+    pub fn subjects(&self) -> Result<Vec<Vec<u8>>> {
+        self.certificates.iter()
+            .map(|der| X509Certificate::from_der(der)
+                .map(|(_, certificate)| certificate.subject().as_raw().to_vec())
+                .map_err(|e| Error::Config(format!("Invalid CA certificate: {}", e))))
+            .collect()
+    }
+
+    /// Check signatures, validity periods and, with CRLs, revocation from a
+    /// certificate up to a bundle CA
+    pub fn verify_chain(&self, certificate: &[u8], intermediates: &[Vec<u8>]) -> Result<()> {
+        let parse = |der: &[u8]| X509Certificate::from_der(der)
+            .map(|(_, certificate)| certificate)
+            .map_err(|e| Error::Auth(format!("Invalid certificate: {}", e)));
+        let anchors = self.certificates.iter().map(|der| parse(der)).collect::<Result<Vec<_>>>()?;
+        let intermediates = intermediates.iter().map(|der| parse(der)).collect::<Result<Vec<_>>>()?;
+        let crls = self.crls.iter()
+            .map(|der| CertificateRevocationList::from_der(der)
+                .map(|(_, crl)| crl)
+                .map_err(|e| Error::Config(format!("Invalid CRL: {}", e))))
+            .collect::<Result<Vec<_>>>()?;
+
+        let leaf = parse(certificate)?;
+        let mut current = &leaf;
+        for _ in 0..MAX_CHAIN_DEPTH {
+            if !current.validity().is_valid() {
+                return Err(Error::Auth(format!("Certificate {} is expired or not yet valid", current.subject())));
+            }
+
+            let issued_by = |issuer: &&X509Certificate<'_>| {
+                issuer.subject().as_raw() == current.issuer().as_raw()
+                    && issuer.is_ca()
+                    && current.verify_signature(Some(issuer.public_key())).is_ok()
+            };
+
+            if let Some(anchor) = anchors.iter().find(issued_by) {
+                if !anchor.validity().is_valid() {
+                    return Err(Error::Auth(format!("CA certificate {} is expired", anchor.subject())));
+                }
+                return check_revocation(current, anchor, &crls);
+            }
+
+            let issuer = intermediates.iter().find(issued_by)
+                .ok_or_else(|| Error::Auth(format!("Certificate {} is not issued by a trusted CA", current.subject())))?;
+            check_revocation(current, issuer, &crls)?;
+            current = issuer;
+        }
+
+        Err(Error::Auth("Certificate chain too long".to_string()))
+    }
+}
+
+/// Refuse a certificate its issuer's current CRL lists; with no CRLs loaded
+/// revocation is not checked
+fn check_revocation(
+    certificate: &X509Certificate<'_>,
+    issuer: &X509Certificate<'_>,
+    crls: &[CertificateRevocationList<'_>],
+) -> Result<()> {
+    if crls.is_empty() {
+        return Ok(());
+    }
+
+    let now = ASN1Time::now();
+    let issued: Vec<_> = crls.iter()
+        .filter(|crl| crl.issuer().as_raw() == issuer.subject().as_raw()
+            && crl.verify_signature(issuer.public_key()).is_ok()
+            && crl.next_update().map_or(true, |next_update| next_update > now))
+        .collect();
+    if issued.is_empty() {
+        return Err(Error::Auth(format!("No current CRL from {} to check {}", issuer.subject(), certificate.subject())));
+    }
+
+    let serial = certificate.raw_serial();
+    if issued.iter().any(|crl| crl.iter_revoked_certificates().any(|revoked| revoked.raw_serial() == serial)) {
+        return Err(Error::Auth(format!(
+            "Certificate {} (serial {}) is revoked", certificate.subject(), certificate.raw_serial_as_string()
+        )));
+    }
+    Ok(())
+}
+
+/// A logon certificate must carry the digitalSignature key usage and the smart
+/// card logon or client authentication extended key usage
+fn check_logon_usage(certificate: &X509Certificate<'_>) -> Result<()> {
+    let digital_signature = match certificate.key_usage() {
+        Ok(Some(key_usage)) => key_usage.value.digital_signature(),
+        Ok(None) => false,
+        Err(e) => return Err(Error::Auth(format!("Invalid key usage in {}: {}", certificate.subject(), e))),
+    };
+    if !digital_signature {
+        return Err(Error::Auth(format!("Certificate {} does not allow digital signatures", certificate.subject())));
+    }
+
+    let logon = match certificate.extended_key_usage() {
+        Ok(Some(eku)) => eku.value.client_auth
+            || eku.value.other.iter().any(|oid| oid.to_id_string() == OID_SMARTCARD_LOGON),
+        Ok(None) => false,
+        Err(e) => return Err(Error::Auth(format!("Invalid extended key usage in {}: {}", certificate.subject(), e))),
+    };
+    if !logon {
+        return Err(Error::Auth(format!(
+            "Certificate {} is valid for neither smart card logon nor client authentication", certificate.subject()
+        )));
+    }
+    Ok(())
+}
+
+/// Encode a single-byte-tag TLV
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    match value.len() {
+        len @ 0..=0x7f => out.push(len as u8),
+        len @ 0x80..=0xff => out.extend_from_slice(&[0x81, len as u8]),
+        len => {
+            out.push(0x82);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apdu_encoding() {
+        assert_eq!(Apdu::select_piv().encode(), vec![
+            [&[0x00, 0xa4, 0x04, 0x00, 0x0b][..], &PIV_AID[..], &[0x00][..]].concat()
+        ]);
+        assert_eq!(Apdu::get_data(OBJECT_CHUID).encode(), vec![
+            vec![0x00, 0xcb, 0x3f, 0xff, 0x05, 0x5c, 0x03, 0x5f, 0xc1, 0x02, 0x00]
+        ]);
+        assert_eq!(Apdu::verify_pin(b"123456").unwrap().encode(), vec![
+            vec![0x00, 0x20, 0x00, 0x80, 0x08, b'1', b'2', b'3', b'4', b'5', b'6', 0xff, 0xff]
+        ]);
+        assert!(Apdu::verify_pin(b"123456789").is_err());
+        assert!(Apdu::verify_pin(b"").is_err());
+    }
+
+    #[test]
+    fn test_general_authenticate_chaining() {
+        // An RSA-2048 block does not fit a short APDU and is sent in two parts
+        let block = vec![0xab; 256];
+        let commands = Apdu::general_authenticate(PivAlgorithm::Rsa2048, KEY_AUTHENTICATION, &block).encode();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(&commands[0][..4], &[0x10, 0x87, 0x07, 0x9a]);
+        assert_eq!(&commands[1][..4], &[0x00, 0x87, 0x07, 0x9a]);
+        assert_eq!(*commands[1].last().unwrap(), 0x00);
+
+        let data: Vec<u8> = commands.iter().flat_map(|c| c[5..c.len() - usize::from(c[0] == 0x00)].to_vec()).collect();
+        let template = find_tlv(&data, 0x7c).unwrap().unwrap();
+        assert_eq!(find_tlv(template, 0x81).unwrap().unwrap(), &block[..]);
+        assert_eq!(find_tlv(template, 0x82).unwrap().unwrap(), &[] as &[u8]);
+
+        let digest = [0x11; 32];
+        let commands = Apdu::general_authenticate(PivAlgorithm::EccP256, KEY_AUTHENTICATION, &digest).encode();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(&commands[0][..5], &[0x00, 0x87, 0x11, 0x9a, 0x26]);
+    }
+
+    #[test]
+    fn test_tlv_parsing() {
+        let data = [0x5f, 0xc1, 0x02, 0x02, 0xaa, 0xbb, 0x53, 0x81, 0x01, 0xcc];
+        let objects = parse_tlv(&data).unwrap();
+        assert_eq!(objects, vec![(0x5fc102, &[0xaa, 0xbb][..]), (0x53, &[0xcc][..])]);
+
+        assert!(parse_tlv(&[0x53, 0x05, 0x00]).is_err());
+        assert!(parse_tlv(&[0x5f]).is_err());
+        assert!(parse_tlv(&[0x53, 0x83, 0x00, 0x00, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_chuid_device_id() {
+        let guid = [0x42; 16];
+        let mut object = tlv(0x30, &[0xd4; 25]);
+        object.extend_from_slice(&tlv(0x34, &guid));
+        object.extend_from_slice(&tlv(0x35, b"20301231"));
+        object.extend_from_slice(&tlv(0x3e, &[]));
+
+        let chuid = Chuid::parse(&object).unwrap();
+        assert_eq!(chuid.device_id(), format!("smartcard-{}", hex::encode(guid)));
+        assert_eq!(chuid.expiration.as_deref(), Some("20301231"));
+
+        // An all-zero GUID falls back to the FASC-N
+        let mut object = tlv(0x30, &[0xd4; 25]);
+        object.extend_from_slice(&tlv(0x34, &[0; 16]));
+        assert_eq!(Chuid::parse(&object).unwrap().device_id(), format!("smartcard-{}", hex::encode([0xd4; 25])));
+
+        assert!(Chuid::parse(&tlv(0x3e, &[])).is_err());
+    }
+
+    #[test]
+    fn test_certificate_object() {
+        let mut object = tlv(0x70, &[0x30, 0x03, 0x01, 0x02, 0x03]);
+        object.extend_from_slice(&tlv(0x71, &[0x00]));
+        object.extend_from_slice(&tlv(0xfe, &[]));
+        let response = tlv(0x53, &object);
+        assert_eq!(certificate_from_object(data_object(&response).unwrap()).unwrap(), vec![0x30, 0x03, 0x01, 0x02, 0x03]);
+
+        let mut compressed = tlv(0x70, &[0x1f, 0x8b]);
+        compressed.extend_from_slice(&tlv(0x71, &[0x01]));
+        assert!(certificate_from_object(&compressed).is_err());
+
+        // Long-form lengths, as YubiKey attestation certificates use
+        let mut long = vec![0x70, 0x82, 0x01, 0x2c];
+        long.extend_from_slice(&[0x30; 300]);
+        assert_eq!(certificate_from_object(&long).unwrap(), vec![0x30; 300]);
+        assert!(certificate_from_object(&[0x70, 0x05, 0x30]).is_err());
+        assert!(certificate_from_object(&tlv(0x71, &[0x00])).is_err());
+        assert!(certificate_from_object(&[]).is_err());
+    }
+
+    #[test]
+    fn test_signature_from_response() {
+        let response = tlv(0x7c, &tlv(0x82, &[0x30, 0x44, 0x02]));
+        assert_eq!(signature_from_response(&response).unwrap(), vec![0x30, 0x44, 0x02]);
+        assert!(signature_from_response(&tlv(0x7c, &[])).is_err());
+    }
+
+    #[test]
+    fn test_upn_from_other_name() {
+        let upn = tlv(0x0c, b"alice@example.gov");
+        assert_eq!(upn_from_other_name(&upn).unwrap().as_deref(), Some("alice@example.gov"));
+        assert_eq!(upn_from_other_name(&tlv(0xa0, &upn)).unwrap().as_deref(), Some("alice@example.gov"));
+        assert_eq!(upn_from_other_name(&tlv(0x04, b"x")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_status_words() {
+        assert!(check_status((0x90, 0x00)).is_ok());
+        assert!(check_status((0x63, 0xc2)).unwrap_err().to_string().contains("2 tries left"));
+        assert!(check_status((0x69, 0x83)).is_err());
+    }
+}
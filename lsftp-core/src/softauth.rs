@@ -0,0 +1,348 @@
+//! Software-emulated hardware authenticator for LSFTP
+//!
+//! Implements `HardwareAuth` with Ed25519 keys kept in a passphrase-encrypted
+//! key file, so authentication flows can run on development machines and CI
+//! without a TPM, YubiKey or smart card. Only compiled with the `insecure-dev`
+//! feature; the factory additionally requires an explicit runtime opt-in.
+
+use crate::auth::{AuthResult, DeviceInfo, HardwareAttestation, HardwareAuth, HardwareType};
+use crate::crypto::{AeadAlgorithm, CryptoOperations, CryptoSuite};
+use crate::error::{Error, Result};
+use crate::secmem::SecretBuffer;
+use ring::rand::SecureRandom;
+use ring::signature::KeyPair;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::num::NonZeroU32;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Default key file location
+pub const DEFAULT_KEY_PATH: &str = "/var/lib/lsftp/softauth.json";
+
+/// Environment variable holding the key file passphrase
+pub const PASSPHRASE_ENV: &str = "LSFTP_SOFTAUTH_PASSPHRASE";
+
+/// Key file format version
+const KEY_FILE_VERSION: u32 = 1;
+
+/// PBKDF2-HMAC-SHA256 iterations for the key file
+const KDF_ITERATIONS: u32 = 100_000;
+
+/// Domain separator for emulated attestation statements
+const ATTESTATION_CONTEXT: &[u8] = b"LSFTP-SOFTAUTH-ATTESTATION-v1";
+
+/// Encrypted key file
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyFile {
+    version: u32,
+    device_id: Uuid,
+    kdf_iterations: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Decrypted key file contents
+#[derive(Serialize, Deserialize)]
+struct KeyMaterial {
+    auth_pkcs8: Vec<u8>,
+    attestation_pkcs8: Vec<u8>,
+}
+
+/// Loaded signing keys
+struct SoftwareKeys {
+    device_id: String,
+    auth_key: ring::signature::Ed25519KeyPair,
+    attestation_key: ring::signature::Ed25519KeyPair,
+}
+
+/// Software authenticator for development and CI
+pub struct SoftwareAuth {
+    key_path: PathBuf,
+    passphrase: SecretBuffer,
+    keys: Option<SoftwareKeys>,
+    device_info: Option<DeviceInfo>,
+}
+
+impl SoftwareAuth {
+    pub fn new(key_path: impl Into<PathBuf>, passphrase: SecretBuffer) -> Self {
+        Self {
+            key_path: key_path.into(),
+            passphrase,
+            keys: None,
+            device_info: None,
+        }
+    }
+
+    /// Create a new encrypted key file; refuses to overwrite an existing one
+    pub fn provision(key_path: &Path, passphrase: &[u8]) -> Result<()> {
+        let rng = ring::rand::SystemRandom::new();
+        let auth_pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng)?;
+        let attestation_pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng)?;
+
+        let material = KeyMaterial {
+            auth_pkcs8: auth_pkcs8.as_ref().to_vec(),
+            attestation_pkcs8: attestation_pkcs8.as_ref().to_vec(),
+        };
+        let plaintext = SecretBuffer::from_vec(serde_json::to_vec(&material)?)?;
+        drop(material);
+
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 12];
+        rng.fill(&mut salt)?;
+        rng.fill(&mut nonce)?;
+
+        let key = derive_key(passphrase, &salt, KDF_ITERATIONS)?;
+        let ciphertext = aead().encrypt(plaintext.as_slice(), key.as_slice(), &nonce)?;
+
+        let key_file = KeyFile {
+            version: KEY_FILE_VERSION,
+            device_id: Uuid::new_v4(),
+            kdf_iterations: KDF_ITERATIONS,
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        };
+
+        if let Some(parent) = key_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(key_path)?;
+        file.write_all(&serde_json::to_vec_pretty(&key_file)?)?;
+
+        Ok(())
+    }
+
+    /// Ed25519 public key used for challenge responses
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        Ok(self.keys()?.auth_key.public_key().as_ref().to_vec())
+    }
+
+    /// Decrypt and load the key file
+    fn load_keys(&self) -> Result<SoftwareKeys> {
+        let key_file: KeyFile = serde_json::from_slice(&std::fs::read(&self.key_path)?)?;
+        if key_file.version != KEY_FILE_VERSION {
+            return Err(Error::HardwareAuth(format!("Unsupported key file version {}", key_file.version)));
+        }
+
+        let salt = decode_field(&key_file.salt)?;
+        let nonce = decode_field(&key_file.nonce)?;
+        let ciphertext = decode_field(&key_file.ciphertext)?;
+
+        let key = derive_key(self.passphrase.as_slice(), &salt, key_file.kdf_iterations)?;
+        let plaintext = aead().decrypt(&ciphertext, key.as_slice(), &nonce)
+            .map_err(|_| Error::HardwareAuth("Wrong passphrase or corrupted key file".to_string()))?;
+        let plaintext = SecretBuffer::from_vec(plaintext)?;
+
+        let material: KeyMaterial = serde_json::from_slice(plaintext.as_slice())?;
+        let auth_pkcs8 = SecretBuffer::from_vec(material.auth_pkcs8)?;
+        let attestation_pkcs8 = SecretBuffer::from_vec(material.attestation_pkcs8)?;
+
+        Ok(SoftwareKeys {
+            device_id: format!("software-{}", key_file.device_id),
+            auth_key: ring::signature::Ed25519KeyPair::from_pkcs8(auth_pkcs8.as_slice())?,
+            attestation_key: ring::signature::Ed25519KeyPair::from_pkcs8(attestation_pkcs8.as_slice())?,
+        })
+    }
+
+    fn keys(&self) -> Result<&SoftwareKeys> {
+        self.keys.as_ref()
+            .ok_or_else(|| Error::HardwareAuth("Software authenticator not initialized".to_string()))
+    }
+
+    /// Statement signed by the emulated attestation key
+    fn attestation_statement(device_id: &str, auth_public_key: &[u8], timestamp: u64, nonce: &[u8]) -> Vec<u8> {
+        let mut data = ATTESTATION_CONTEXT.to_vec();
+        data.extend_from_slice(&(device_id.len() as u32).to_be_bytes());
+        data.extend_from_slice(device_id.as_bytes());
+        data.extend_from_slice(auth_public_key);
+        data.extend_from_slice(&timestamp.to_be_bytes());
+        data.extend_from_slice(nonce);
+        data
+    }
+}
+
+#[async_trait::async_trait]
+impl HardwareAuth for SoftwareAuth {
+    async fn initialize(&mut self) -> Result<()> {
+        if !self.key_path.exists() {
+            tracing::warn!("Provisioning new INSECURE software authenticator key file at {:?}", self.key_path);
+            Self::provision(&self.key_path, self.passphrase.as_slice())?;
+        }
+
+        let keys = self.load_keys()?;
+        self.device_info = Some(DeviceInfo {
+            device_type: HardwareType::Software,
+            device_id: keys.device_id.clone(),
+            manufacturer: "LSFTP".to_string(),
+            model: "Software Authenticator (insecure, development only)".to_string(),
+            firmware_version: env!("CARGO_PKG_VERSION").to_string(),
+            supported_algorithms: vec!["Ed25519".to_string()],
+            capabilities: vec![
+                "Authentication".to_string(),
+                "DigitalSignature".to_string(),
+                "Attestation".to_string(),
+            ],
+        });
+        self.keys = Some(keys);
+
+        Ok(())
+    }
+
+    async fn authenticate(&self, challenge: &[u8]) -> Result<AuthResult> {
+        let keys = self.keys()?;
+        let signature = keys.auth_key.sign(challenge);
+
+        Ok(AuthResult {
+            success: true,
+            user_id: None,
+            device_id: Some(keys.device_id.clone()),
+            timestamp: crate::now_secs(),
+            metadata: {
+                let mut map = HashMap::new();
+                map.insert("signature_algorithm".to_string(), "Ed25519".to_string());
+                map.insert("public_key".to_string(), hex::encode(keys.auth_key.public_key().as_ref()));
+                map.insert("insecure_dev".to_string(), "true".to_string());
+                map
+            },
+            signature: Some(signature.as_ref().to_vec()),
+            error: None,
+        })
+    }
+
+    async fn generate_attestation(&self, nonce: &[u8]) -> Result<HardwareAttestation> {
+        let keys = self.keys()?;
+        let timestamp = crate::now_secs();
+
+        let attestation_data = Self::attestation_statement(
+            &keys.device_id,
+            keys.auth_key.public_key().as_ref(),
+            timestamp,
+            nonce,
+        );
+        let signature = keys.attestation_key.sign(&attestation_data);
+
+        Ok(HardwareAttestation {
+            device_type: HardwareType::Software,
+            device_id: keys.device_id.clone(),
+            attestation_data,
+            signature: signature.as_ref().to_vec(),
+            certificate_chain: vec![keys.attestation_key.public_key().as_ref().to_vec()],
+        })
+    }
+
+    async fn verify_attestation(&self, attestation: &HardwareAttestation) -> Result<bool> {
+        if attestation.device_type != HardwareType::Software
+            || !attestation.attestation_data.starts_with(ATTESTATION_CONTEXT)
+        {
+            return Ok(false);
+        }
+
+        // The emulated attestation key is self-signed; it is only trusted to be consistent
+        let attestation_key = match attestation.certificate_chain.first() {
+            Some(key) => key,
+            None => return Ok(false),
+        };
+
+        let public_key = ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, attestation_key);
+        Ok(public_key.verify(&attestation.attestation_data, &attestation.signature).is_ok())
+    }
+
+    async fn get_device_info(&self) -> Result<DeviceInfo> {
+        self.device_info.clone()
+            .ok_or_else(|| Error::HardwareAuth("Device info not available".to_string()))
+    }
+}
+
+/// Derive the key file encryption key from the passphrase
+fn derive_key(passphrase: &[u8], salt: &[u8], iterations: u32) -> Result<SecretBuffer> {
+    let iterations = NonZeroU32::new(iterations)
+        .ok_or_else(|| Error::Config("KDF iterations must be non-zero".to_string()))?;
+
+    let mut key = SecretBuffer::new(32)?;
+    ring::pbkdf2::derive(ring::pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase, key.as_mut_slice());
+    Ok(key)
+}
+
+/// Key files are always sealed with ChaCha20-Poly1305
+fn aead() -> CryptoSuite {
+    CryptoSuite {
+        aead: AeadAlgorithm::ChaCha20Poly1305,
+        ..Default::default()
+    }
+}
+
+/// Decode a hex field of the key file
+fn decode_field(value: &str) -> Result<Vec<u8>> {
+    hex::decode(value).map_err(|e| Error::HardwareAuth(format!("Malformed key file: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_key_path() -> PathBuf {
+        std::env::temp_dir().join(format!("lsftp-softauth-{}.json", Uuid::new_v4()))
+    }
+
+    fn passphrase(value: &[u8]) -> SecretBuffer {
+        SecretBuffer::from_slice(value).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_signs_challenge() {
+        let path = temp_key_path();
+        let mut auth = SoftwareAuth::new(&path, passphrase(b"dev"));
+        auth.initialize().await.unwrap();
+
+        let result = auth.authenticate(b"challenge").await.unwrap();
+        assert!(result.success);
+
+        let signature = result.signature.unwrap();
+        let public_key = ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, auth.public_key().unwrap());
+        assert!(public_key.verify(b"challenge", &signature).is_ok());
+
+        // Keys survive a reload from the encrypted file
+        let mut reloaded = SoftwareAuth::new(&path, passphrase(b"dev"));
+        reloaded.initialize().await.unwrap();
+        assert_eq!(reloaded.public_key().unwrap(), auth.public_key().unwrap());
+        assert_eq!(reloaded.get_device_info().await.unwrap().device_type, HardwareType::Software);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wrong_passphrase_rejected() {
+        let path = temp_key_path();
+        SoftwareAuth::provision(&path, b"dev").unwrap();
+
+        let mut auth = SoftwareAuth::new(&path, passphrase(b"not dev"));
+        assert!(auth.initialize().await.is_err());
+        assert!(SoftwareAuth::provision(&path, b"dev").is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_attestation_roundtrip() {
+        let path = temp_key_path();
+        let mut auth = SoftwareAuth::new(&path, passphrase(b"dev"));
+        auth.initialize().await.unwrap();
+
+        let attestation = auth.generate_attestation(b"nonce").await.unwrap();
+        assert!(auth.verify_attestation(&attestation).await.unwrap());
+
+        let mut tampered = attestation.clone();
+        tampered.attestation_data.push(0);
+        assert!(!auth.verify_attestation(&tampered).await.unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,395 @@
+//! Persistent TPM attestation key for LSFTP
+//!
+//! Each TPM client has one restricted signing key (AK), created once in the
+//! endorsement hierarchy and persisted at an NV handle with `EvictControl`.
+//! The same AK signs authentication challenges and PCR quotes, so its
+//! public key can be enrolled on the server. RSA-2048 and ECC P-256 AKs are
+//! supported.
+
+use crate::der::rsa_public_key_der;
+use crate::enrollment::DeviceKeyAlgorithm;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::str::FromStr;
+use tss_esapi::attributes::ObjectAttributesBuilder;
+use tss_esapi::handles::{KeyHandle, PersistentTpmHandle, TpmHandle};
+use tss_esapi::interface_types::algorithm::{
+    EccSchemeAlgorithm, HashingAlgorithm, PublicAlgorithm, RsaSchemeAlgorithm,
+};
+use tss_esapi::interface_types::dynamic_handles::Persistent;
+use tss_esapi::interface_types::ecc::EccCurve;
+use tss_esapi::interface_types::key_bits::RsaKeyBits;
+use tss_esapi::interface_types::resource_handles::{Hierarchy, Provision};
+use tss_esapi::structures::{
+    Data, EccPoint, EccScheme, HashScheme, KeyDerivationFunctionScheme, MaxBuffer, PcrSelectionList,
+    Public, PublicBuilder, PublicEccParametersBuilder, PublicKeyRsa, PublicRsaParametersBuilder,
+    RsaExponent, RsaScheme, Signature, SignatureScheme, SymmetricDefinitionObject,
+};
+use tss_esapi::traits::Marshall;
+use tss_esapi::Context;
+
+/// Default persistent handle for the AK (owner-range persistent object)
+pub const DEFAULT_AK_HANDLE: u32 = 0x8101_0002;
+
+/// Environment variable overriding the AK handle
+pub const AK_HANDLE_ENV: &str = "LSFTP_TPM_AK_HANDLE";
+
+/// AK key algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TpmKeyAlgorithm {
+    /// RSA-2048, RSASSA-PKCS1-v1_5 with SHA-256
+    #[default]
+    Rsa2048,
+    /// ECC NIST P-256, ECDSA with SHA-256
+    EccP256,
+}
+
+impl FromStr for TpmKeyAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "rsa" | "rsa2048" => Ok(TpmKeyAlgorithm::Rsa2048),
+            "ecc" | "p256" | "ecc-p256" => Ok(TpmKeyAlgorithm::EccP256),
+            other => Err(Error::Config(format!("Unknown TPM key algorithm: {}", other))),
+        }
+    }
+}
+
+impl TpmKeyAlgorithm {
+    /// Algorithm the server verifies AK signatures with
+    pub fn device_key_algorithm(&self) -> DeviceKeyAlgorithm {
+        match self {
+            TpmKeyAlgorithm::Rsa2048 => DeviceKeyAlgorithm::RsaPkcs1Sha256,
+            TpmKeyAlgorithm::EccP256 => DeviceKeyAlgorithm::EcdsaP256Sha256Fixed,
+        }
+    }
+
+    fn signature_scheme(&self) -> SignatureScheme {
+        let hash_scheme = HashScheme::new(HashingAlgorithm::Sha256);
+        match self {
+            TpmKeyAlgorithm::Rsa2048 => SignatureScheme::RsaSsa { hash_scheme },
+            TpmKeyAlgorithm::EccP256 => SignatureScheme::EcDsa { hash_scheme },
+        }
+    }
+}
+
+/// Where the AK lives and which algorithm to create it with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TpmAkConfig {
+    /// Persistent handle
+    pub handle: u32,
+    /// Algorithm used when the AK has to be created
+    pub algorithm: TpmKeyAlgorithm,
+}
+
+impl Default for TpmAkConfig {
+    fn default() -> Self {
+        Self {
+            handle: DEFAULT_AK_HANDLE,
+            algorithm: TpmKeyAlgorithm::default(),
+        }
+    }
+}
+
+impl TpmAkConfig {
+    /// Default configuration with the handle from `LSFTP_TPM_AK_HANDLE`, if set
+    pub fn from_env() -> Result<Self> {
+        let mut config = Self::default();
+        if let Ok(handle) = std::env::var(AK_HANDLE_ENV) {
+            config.handle = parse_handle(&handle)?;
+        }
+        Ok(config)
+    }
+}
+
+/// Parse a persistent handle such as `0x81010002`
+pub fn parse_handle(handle: &str) -> Result<u32> {
+    let digits = handle.trim_start_matches("0x").trim_start_matches("0X");
+    let value = u32::from_str_radix(digits, 16)
+        .map_err(|_| Error::Config(format!("Invalid TPM handle: {}", handle)))?;
+    if value >> 24 != 0x81 {
+        return Err(Error::Config(format!("{:#010x} is not a persistent handle", value)));
+    }
+    Ok(value)
+}
+
+/// The persistent AK, loaded or created
+pub struct AttestationKey {
+    /// Key handle for TPM commands
+    pub handle: KeyHandle,
+    /// Key algorithm
+    pub algorithm: TpmKeyAlgorithm,
+    /// Public key as the server verifies it (PKCS#1 RSAPublicKey or SEC1 point)
+    pub public_key: Vec<u8>,
+}
+
+impl AttestationKey {
+    /// Device ID derived from the AK, stable across reboots
+    pub fn device_id(&self) -> String {
+        let digest = Sha256::digest(&self.public_key);
+        format!("tpm-{}", hex::encode(&digest[..8]))
+    }
+
+    /// Load the AK at the configured handle, creating and persisting it if absent
+    pub fn load_or_create(context: &mut Context, config: &TpmAkConfig) -> Result<Self> {
+        let persistent = PersistentTpmHandle::new(config.handle)
+            .map_err(|e| Error::HardwareAuth(format!("Invalid persistent handle {:#010x}: {}", config.handle, e)))?;
+
+        let handle = match context.tr_from_tpm_public(TpmHandle::Persistent(persistent)) {
+            Ok(object) => KeyHandle::from(object),
+            Err(_) => {
+                tracing::info!("Creating {:?} attestation key at {:#010x}", config.algorithm, config.handle);
+                Self::create(context, config.algorithm, persistent)?
+            }
+        };
+
+        let (public, _, _) = context.read_public(handle)
+            .map_err(|e| Error::HardwareAuth(format!("Failed to read attestation key: {}", e)))?;
+        // Whatever sits at the handle must be an AK, or its quotes prove nothing
+        check_ak(&public)
+            .map_err(|e| Error::HardwareAuth(format!("Refusing the key at {:#010x}: {}", config.handle, e)))?;
+        let (algorithm, public_key) = public_key_of(&public)?;
+
+        Ok(Self { handle, algorithm, public_key })
+    }
+
+    /// Sign a message; the TPM hashes it first so the restricted key accepts the digest
+    pub fn sign(&self, context: &mut Context, message: &[u8]) -> Result<Vec<u8>> {
+        let data = MaxBuffer::try_from(message.to_vec())
+            .map_err(|e| Error::HardwareAuth(format!("Message too large for the TPM: {}", e)))?;
+        let (digest, ticket) = context.hash(data, HashingAlgorithm::Sha256, Hierarchy::Endorsement)
+            .map_err(|e| Error::HardwareAuth(format!("TPM hash failed: {}", e)))?;
+
+        let signature = context.execute_with_nullauth_session(|context| {
+            context.sign(self.handle, digest, self.algorithm.signature_scheme(), ticket)
+        }).map_err(|e| Error::HardwareAuth(format!("Failed to sign challenge: {}", e)))?;
+
+        signature_bytes(signature)
+    }
+
+    /// Quote PCRs, qualified with the verifier's nonce; returns the marshalled quote and signature
+    pub fn quote(&self, context: &mut Context, nonce: &[u8], pcrs: PcrSelectionList) -> Result<(Vec<u8>, Vec<u8>)> {
+        let qualifying_data = Data::try_from(nonce.to_vec())
+            .map_err(|e| Error::HardwareAuth(format!("Failed to create qualifying data: {}", e)))?;
+
+        let (attest, signature) = context.execute_with_nullauth_session(|context| {
+            context.quote(self.handle, qualifying_data, self.algorithm.signature_scheme(), pcrs)
+        }).map_err(|e| Error::HardwareAuth(format!("Failed to generate quote: {}", e)))?;
+
+        let quote = attest.marshall()
+            .map_err(|e| Error::HardwareAuth(format!("Failed to marshal quote: {}", e)))?;
+        Ok((quote, signature_bytes(signature)?))
+    }
+
+    /// Create a restricted signing key and persist it with EvictControl
+    fn create(context: &mut Context, algorithm: TpmKeyAlgorithm, persistent: PersistentTpmHandle) -> Result<KeyHandle> {
+        let template = ak_template(algorithm)?;
+        let transient = context.execute_with_nullauth_session(|context| {
+            context.create_primary(Hierarchy::Endorsement, template, None, None, None, None)
+        }).map_err(|e| Error::HardwareAuth(format!("Failed to create attestation key: {}", e)))?;
+
+        let persisted = context.execute_with_nullauth_session(|context| {
+            context.evict_control(Provision::Owner, transient.key_handle.into(), Persistent::Persistent(persistent))
+        });
+        let _ = context.flush_context(transient.key_handle.into());
+        let persisted = persisted.map_err(|e| Error::HardwareAuth(format!("Failed to persist attestation key: {}", e)))?;
+
+        Ok(KeyHandle::from(persisted))
+    }
+}
+
+/// Template for a restricted SHA-256 signing key
+fn ak_template(algorithm: TpmKeyAlgorithm) -> Result<Public> {
+    let attributes = ObjectAttributesBuilder::new()
+        .with_fixed_tpm(true)
+        .with_fixed_parent(true)
+        .with_sensitive_data_origin(true)
+        .with_user_with_auth(true)
+        .with_restricted(true)
+        .with_sign_encrypt(true)
+        .build()
+        .map_err(|e| Error::HardwareAuth(format!("Failed to build key attributes: {}", e)))?;
+
+    let builder = PublicBuilder::new()
+        .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+        .with_object_attributes(attributes);
+
+    let builder = match algorithm {
+        TpmKeyAlgorithm::Rsa2048 => builder
+            .with_public_algorithm(PublicAlgorithm::Rsa)
+            .with_rsa_parameters(PublicRsaParametersBuilder::new()
+                .with_scheme(RsaScheme::create(RsaSchemeAlgorithm::RsaSsa, Some(HashingAlgorithm::Sha256))
+                    .map_err(|e| Error::HardwareAuth(format!("Invalid RSA scheme: {}", e)))?)
+                .with_key_bits(RsaKeyBits::Rsa2048)
+                .with_exponent(RsaExponent::default())
+                .with_is_signing_key(true)
+                .with_restricted(true)
+                .with_symmetric(SymmetricDefinitionObject::Null)
+                .build()
+                .map_err(|e| Error::HardwareAuth(format!("Failed to build RSA parameters: {}", e)))?)
+            .with_rsa_unique_identifier(PublicKeyRsa::default()),
+        TpmKeyAlgorithm::EccP256 => builder
+            .with_public_algorithm(PublicAlgorithm::Ecc)
+            .with_ecc_parameters(PublicEccParametersBuilder::new()
+                .with_ecc_scheme(EccScheme::create(EccSchemeAlgorithm::EcDsa, Some(HashingAlgorithm::Sha256), None)
+                    .map_err(|e| Error::HardwareAuth(format!("Invalid ECC scheme: {}", e)))?)
+                .with_curve(EccCurve::NistP256)
+                .with_is_signing_key(true)
+                .with_is_decryption_key(false)
+                .with_restricted(true)
+                .with_symmetric(SymmetricDefinitionObject::Null)
+                .with_key_derivation_function_scheme(KeyDerivationFunctionScheme::Null)
+                .build()
+                .map_err(|e| Error::HardwareAuth(format!("Failed to build ECC parameters: {}", e)))?)
+            .with_ecc_unique_identifier(EccPoint::default()),
+    };
+
+    builder.build()
+        .map_err(|e| Error::HardwareAuth(format!("Failed to build attestation key template: {}", e)))
+}
+
+/// Refuse a public area that is not a restricted SHA-256 signing key fixed to this TPM
+fn check_ak(public: &Public) -> Result<()> {
+    let attributes = match public {
+        Public::Rsa { object_attributes, .. } | Public::Ecc { object_attributes, .. } => *object_attributes,
+        _ => return Err(Error::HardwareAuth("Attestation key is neither RSA nor ECC".to_string())),
+    };
+    if !attributes.restricted() || !attributes.sign_encrypt() || !attributes.fixed_tpm() || attributes.decrypt() {
+        return Err(Error::HardwareAuth("Not a restricted signing key fixed to this TPM".to_string()));
+    }
+
+    let sha256_scheme = match public {
+        Public::Rsa { parameters, .. } => matches!(
+            parameters.rsa_scheme(), RsaScheme::RsaSsa(hash) if hash.hashing_algorithm() == HashingAlgorithm::Sha256
+        ),
+        Public::Ecc { parameters, .. } => matches!(
+            parameters.ecc_scheme(), EccScheme::EcDsa(hash) if hash.hashing_algorithm() == HashingAlgorithm::Sha256
+        ),
+        _ => false,
+    };
+    if !sha256_scheme {
+        return Err(Error::HardwareAuth("Signing scheme is not RSASSA or ECDSA with SHA-256".to_string()));
+    }
+    Ok(())
+}
+
+/// Algorithm and verifier-format public key of a TPM public area
+fn public_key_of(public: &Public) -> Result<(TpmKeyAlgorithm, Vec<u8>)> {
+    match public {
+        Public::Rsa { parameters, unique, .. } => {
+            if parameters.key_bits() != RsaKeyBits::Rsa2048 {
+                return Err(Error::HardwareAuth(format!("{:?} attestation keys are not supported", parameters.key_bits())));
+            }
+            let exponent = match parameters.exponent().value() {
+                0 => 65537,
+                exponent => exponent,
+            };
+            Ok((TpmKeyAlgorithm::Rsa2048, rsa_public_key_der(unique.value(), &exponent.to_be_bytes())))
+        }
+        Public::Ecc { parameters, unique, .. } => {
+            if parameters.ecc_curve() != EccCurve::NistP256 {
+                return Err(Error::HardwareAuth(format!("{:?} attestation keys are not supported", parameters.ecc_curve())));
+            }
+            Ok((TpmKeyAlgorithm::EccP256, ecc_public_point(unique.x().value(), unique.y().value())?))
+        }
+        _ => Err(Error::HardwareAuth("Attestation key is neither RSA nor ECC".to_string())),
+    }
+}
+
+/// Signature bytes as the server verifies them
+fn signature_bytes(signature: Signature) -> Result<Vec<u8>> {
+    match signature {
+        Signature::RsaSsa(rsa) => Ok(rsa.signature().value().to_vec()),
+        Signature::EcDsa(ecc) => ecdsa_fixed_signature(ecc.signature_r().value(), ecc.signature_s().value()),
+        _ => Err(Error::HardwareAuth("Unexpected TPM signature scheme".to_string())),
+    }
+}
+
+/// Uncompressed SEC1 point from P-256 coordinates
+pub fn ecc_public_point(x: &[u8], y: &[u8]) -> Result<Vec<u8>> {
+    let mut point = vec![0x04];
+    point.extend(left_pad(x, 32)?);
+    point.extend(left_pad(y, 32)?);
+    Ok(point)
+}
+
+/// Fixed-width `r || s` ECDSA P-256 signature
+pub fn ecdsa_fixed_signature(r: &[u8], s: &[u8]) -> Result<Vec<u8>> {
+    let mut signature = left_pad(r, 32)?;
+    signature.extend(left_pad(s, 32)?);
+    Ok(signature)
+}
+
+fn left_pad(value: &[u8], width: usize) -> Result<Vec<u8>> {
+    let value = &value[value.iter().take_while(|&&b| b == 0).count()..];
+    if value.len() > width {
+        return Err(Error::HardwareAuth(format!("Value longer than {} bytes", width)));
+    }
+    let mut padded = vec![0u8; width - value.len()];
+    padded.extend_from_slice(value);
+    Ok(padded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ecdsa_encoding() {
+        let signature = ecdsa_fixed_signature(&[0x01; 31], &[0x00, 0x02]).unwrap();
+        assert_eq!(signature.len(), 64);
+        assert_eq!(signature[0], 0);
+        assert_eq!(signature[63], 2);
+        assert!(ecdsa_fixed_signature(&[0x01; 33], &[0x02]).is_err());
+
+        let point = ecc_public_point(&[0x0a; 32], &[0x0b; 32]).unwrap();
+        assert_eq!(point.len(), 65);
+        assert_eq!(point[0], 0x04);
+    }
+
+    #[test]
+    fn test_ak_public_area_checked() {
+        for algorithm in [TpmKeyAlgorithm::Rsa2048, TpmKeyAlgorithm::EccP256] {
+            assert!(check_ak(&ak_template(algorithm).unwrap()).is_ok());
+        }
+
+        // An unrestricted key could sign a forged quote
+        let attributes = ObjectAttributesBuilder::new()
+            .with_fixed_tpm(true)
+            .with_fixed_parent(true)
+            .with_sensitive_data_origin(true)
+            .with_user_with_auth(true)
+            .with_sign_encrypt(true)
+            .build()
+            .unwrap();
+        let unrestricted = PublicBuilder::new()
+            .with_public_algorithm(PublicAlgorithm::Ecc)
+            .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+            .with_object_attributes(attributes)
+            .with_ecc_parameters(PublicEccParametersBuilder::new()
+                .with_ecc_scheme(EccScheme::create(EccSchemeAlgorithm::EcDsa, Some(HashingAlgorithm::Sha256), None).unwrap())
+                .with_curve(EccCurve::NistP256)
+                .with_is_signing_key(true)
+                .with_is_decryption_key(false)
+                .with_restricted(false)
+                .with_symmetric(SymmetricDefinitionObject::Null)
+                .with_key_derivation_function_scheme(KeyDerivationFunctionScheme::Null)
+                .build()
+                .unwrap())
+            .with_ecc_unique_identifier(EccPoint::default())
+            .build()
+            .unwrap();
+        assert!(check_ak(&unrestricted).is_err());
+    }
+
+    #[test]
+    fn test_parse_handle() {
+        assert_eq!(parse_handle("0x81010002").unwrap(), DEFAULT_AK_HANDLE);
+        assert_eq!(parse_handle("81010003").unwrap(), 0x8101_0003);
+        assert!(parse_handle("0x01000000").is_err());
+        assert!(parse_handle("zz").is_err());
+    }
+}
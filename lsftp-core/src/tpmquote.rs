@@ -0,0 +1,407 @@
+//! TPM 2.0 quote verification for LSFTP
+//!
+//! A TPM client attests its boot state with a quote: a signed `TPMS_ATTEST`
+//! structure over a digest of selected PCRs. The server parses the quote,
+//! checks it is a genuine quote structure, verifies its signature with the
+//! enrolled attestation key, checks the qualifying data against the session
+//! challenge and compares the PCR digest with the golden values configured
+//! for the client's host class.
+
+use crate::enrollment::DeviceKeyAlgorithm;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use subtle::ConstantTimeEq;
+
+/// `TPM_GENERATED_VALUE`: marks structures produced inside the TPM
+pub const TPM_GENERATED_VALUE: u32 = 0xff54_4347;
+
+/// `TPM_ST_ATTEST_QUOTE`
+pub const TPM_ST_ATTEST_QUOTE: u16 = 0x8018;
+
+/// `TPM_ALG_SHA256`
+pub const TPM_ALG_SHA256: u16 = 0x000b;
+
+/// PCRs quoted by clients, from the SHA-256 bank
+pub const QUOTE_PCRS: [u8; 8] = [0, 1, 2, 3, 4, 5, 6, 7];
+
+/// Default PCR policy file
+pub const DEFAULT_PCR_POLICY_PATH: &str = "/etc/lsftp/pcr-policy.toml";
+
+/// Host class used for devices enrolled without one
+pub const DEFAULT_HOST_CLASS: &str = "default";
+
+/// PCR selection for one hash bank
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcrSelection {
+    /// `TPM_ALG_ID` of the bank
+    pub hash_algorithm: u16,
+    /// Selected PCR indices, ascending
+    pub pcrs: Vec<u8>,
+}
+
+/// Parsed `TPMS_ATTEST` of type `TPM_ST_ATTEST_QUOTE`
+#[derive(Debug, Clone)]
+pub struct Quote {
+    /// Name of the signing key
+    pub qualified_signer: Vec<u8>,
+    /// Caller-provided qualifying data
+    pub extra_data: Vec<u8>,
+    /// TPM clock in milliseconds
+    pub clock: u64,
+    /// TPM reset count
+    pub reset_count: u32,
+    /// TPM restart count
+    pub restart_count: u32,
+    /// Whether the clock is known not to have gone backwards
+    pub safe: bool,
+    /// TPM firmware version
+    pub firmware_version: u64,
+    /// PCRs covered by the digest
+    pub pcr_selections: Vec<PcrSelection>,
+    /// Digest of the selected PCR values
+    pub pcr_digest: Vec<u8>,
+}
+
+impl Quote {
+    /// Parse a marshalled `TPMS_ATTEST`; rejects anything but a TPM-generated quote
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(data);
+
+        let magic = reader.u32()?;
+        if magic != TPM_GENERATED_VALUE {
+            return Err(Error::HardwareAuth(format!("Quote has invalid magic {:#010x}", magic)));
+        }
+
+        let attest_type = reader.u16()?;
+        if attest_type != TPM_ST_ATTEST_QUOTE {
+            return Err(Error::HardwareAuth(format!("Attestation is not a quote (type {:#06x})", attest_type)));
+        }
+
+        let qualified_signer = reader.sized()?.to_vec();
+        let extra_data = reader.sized()?.to_vec();
+        let clock = reader.u64()?;
+        let reset_count = reader.u32()?;
+        let restart_count = reader.u32()?;
+        let safe = reader.u8()? != 0;
+        let firmware_version = reader.u64()?;
+
+        let selection_count = reader.u32()?;
+        let mut pcr_selections = Vec::new();
+        for _ in 0..selection_count {
+            let hash_algorithm = reader.u16()?;
+            let bitmap_len = reader.u8()? as usize;
+            let bitmap = reader.bytes(bitmap_len)?;
+
+            let pcrs = (0..bitmap_len * 8)
+                .filter(|index| bitmap[index / 8] & (1 << (index % 8)) != 0)
+                .map(|index| index as u8)
+                .collect();
+            pcr_selections.push(PcrSelection { hash_algorithm, pcrs });
+        }
+
+        let pcr_digest = reader.sized()?.to_vec();
+
+        if !reader.is_empty() {
+            return Err(Error::HardwareAuth("Trailing data after quote".to_string()));
+        }
+
+        Ok(Self {
+            qualified_signer,
+            extra_data,
+            clock,
+            reset_count,
+            restart_count,
+            safe,
+            firmware_version,
+            pcr_selections,
+            pcr_digest,
+        })
+    }
+
+    /// Whether the quote covers exactly these PCRs of the SHA-256 bank
+    pub fn selects_sha256(&self, pcrs: &[u8]) -> bool {
+        matches!(self.pcr_selections.as_slice(), [selection]
+            if selection.hash_algorithm == TPM_ALG_SHA256 && selection.pcrs == pcrs)
+    }
+}
+
+/// Golden PCR values accepted for a host class
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PcrBaseline {
+    /// Baseline name (e.g. firmware or kernel release)
+    pub name: String,
+    /// SHA-256 PCR values (hex) by PCR index
+    pub pcrs: BTreeMap<String, String>,
+}
+
+impl PcrBaseline {
+    /// PCR indices covered by this baseline, ascending
+    pub fn indices(&self) -> Result<Vec<u8>> {
+        let mut indices = self.pcrs.keys()
+            .map(|index| index.parse::<u8>()
+                .map_err(|_| Error::Config(format!("Invalid PCR index {:?} in baseline {}", index, self.name))))
+            .collect::<Result<Vec<_>>>()?;
+        indices.sort_unstable();
+        Ok(indices)
+    }
+
+    /// Expected quote digest: SHA-256 over the PCR values in ascending order
+    pub fn digest(&self) -> Result<[u8; 32]> {
+        let mut values: Vec<(u8, Vec<u8>)> = Vec::new();
+        for (index, value) in &self.pcrs {
+            let index = index.parse::<u8>()
+                .map_err(|_| Error::Config(format!("Invalid PCR index {:?} in baseline {}", index, self.name)))?;
+            let value = hex::decode(value)
+                .map_err(|e| Error::Config(format!("Invalid PCR{} value in baseline {}: {}", index, self.name, e)))?;
+            if value.len() != 32 {
+                return Err(Error::Config(format!("PCR{} value in baseline {} is not a SHA-256 digest", index, self.name)));
+            }
+            values.push((index, value));
+        }
+        values.sort_by_key(|(index, _)| *index);
+
+        let mut hasher = Sha256::new();
+        for (_, value) in &values {
+            hasher.update(value);
+        }
+        Ok(hasher.finalize().into())
+    }
+}
+
+/// Golden PCR policy for one host class
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HostClassPolicy {
+    /// Accepted baselines; a quote must match one of them
+    #[serde(default)]
+    pub baselines: Vec<PcrBaseline>,
+}
+
+/// Golden PCR policies by host class
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PcrPolicy {
+    /// Policies by host class name
+    #[serde(default)]
+    pub classes: HashMap<String, HostClassPolicy>,
+}
+
+impl PcrPolicy {
+    /// Load and check a policy file
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read PCR policy {:?}: {}", path, e)))?;
+        let policy: Self = toml::from_str(&data)?;
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    /// Check every baseline covers exactly the quoted PCRs with well-formed values
+    pub fn validate(&self) -> Result<()> {
+        for (class, policy) in &self.classes {
+            for baseline in &policy.baselines {
+                if baseline.indices()? != QUOTE_PCRS {
+                    return Err(Error::Config(format!(
+                        "Baseline {} of host class {} must define PCRs {:?}", baseline.name, class, QUOTE_PCRS
+                    )));
+                }
+                baseline.digest()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Policy for a host class
+    pub fn class(&self, host_class: &str) -> Result<&HostClassPolicy> {
+        self.classes.get(host_class)
+            .ok_or_else(|| Error::Config(format!("No PCR policy for host class {}", host_class)))
+    }
+}
+
+/// Verify a quote, its signature and its nonce, then compare it with the
+/// golden baselines if a policy is given; returns the matching baseline name
+pub fn verify_quote(
+    quote_data: &[u8],
+    signature: &[u8],
+    attestation_algorithm: DeviceKeyAlgorithm,
+    attestation_key: &[u8],
+    expected_extra_data: &[u8],
+    policy: Option<&HostClassPolicy>,
+) -> Result<Option<String>> {
+    let quote = Quote::parse(quote_data)?;
+
+    if !attestation_algorithm.verify(attestation_key, quote_data, signature) {
+        return Err(Error::HardwareAuth("Quote signature does not verify with the enrolled attestation key".to_string()));
+    }
+
+    let fresh: bool = quote.extra_data.as_slice().ct_eq(expected_extra_data).into();
+    if !fresh {
+        return Err(Error::HardwareAuth("Quote does not answer this challenge".to_string()));
+    }
+
+    if !quote.selects_sha256(&QUOTE_PCRS) {
+        return Err(Error::HardwareAuth(format!(
+            "Quote covers {:?}, expected SHA-256 PCRs {:?}", quote.pcr_selections, QUOTE_PCRS
+        )));
+    }
+
+    let policy = match policy {
+        Some(policy) => policy,
+        None => return Ok(None),
+    };
+    for baseline in &policy.baselines {
+        let golden = baseline.digest()?;
+        if bool::from(quote.pcr_digest.as_slice().ct_eq(&golden)) {
+            return Ok(Some(baseline.name.clone()));
+        }
+    }
+
+    Err(Error::HardwareAuth(format!(
+        "PCR digest {} matches no golden baseline; boot state has drifted", hex::encode(&quote.pcr_digest)
+    )))
+}
+
+/// Big-endian reader for marshalled TPM structures
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(Error::HardwareAuth("Truncated quote".to_string()));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// `TPM2B_*`: 16-bit size followed by that many bytes
+    fn sized(&mut self) -> Result<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::KeyPair;
+
+    fn baseline(fill: u8) -> PcrBaseline {
+        PcrBaseline {
+            name: format!("baseline-{}", fill),
+            pcrs: QUOTE_PCRS.iter().map(|i| (i.to_string(), hex::encode([fill; 32]))).collect(),
+        }
+    }
+
+    fn marshal_quote(extra_data: &[u8], pcr_digest: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&TPM_GENERATED_VALUE.to_be_bytes());
+        data.extend_from_slice(&TPM_ST_ATTEST_QUOTE.to_be_bytes());
+        data.extend_from_slice(&4u16.to_be_bytes());
+        data.extend_from_slice(&[0x00, 0x0b, 0xaa, 0xbb]);
+        data.extend_from_slice(&(extra_data.len() as u16).to_be_bytes());
+        data.extend_from_slice(extra_data);
+        data.extend_from_slice(&1234u64.to_be_bytes());
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.push(1);
+        data.extend_from_slice(&0x0001_0002_0003_0004u64.to_be_bytes());
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+        data.extend_from_slice(&[3, 0xff, 0x00, 0x00]);
+        data.extend_from_slice(&(pcr_digest.len() as u16).to_be_bytes());
+        data.extend_from_slice(pcr_digest);
+        data
+    }
+
+    #[test]
+    fn test_parse_quote() {
+        let quote = Quote::parse(&marshal_quote(b"nonce", &[9u8; 32])).unwrap();
+        assert_eq!(quote.extra_data, b"nonce");
+        assert_eq!(quote.reset_count, 1);
+        assert!(quote.safe);
+        assert!(quote.selects_sha256(&QUOTE_PCRS));
+        assert_eq!(quote.pcr_digest, vec![9u8; 32]);
+
+        let mut wrong_type = marshal_quote(b"nonce", &[9u8; 32]);
+        wrong_type[5] = 0x17; // TPM_ST_ATTEST_CERTIFY
+        assert!(Quote::parse(&wrong_type).is_err());
+
+        let mut wrong_magic = marshal_quote(b"nonce", &[9u8; 32]);
+        wrong_magic[0] = 0;
+        assert!(Quote::parse(&wrong_magic).is_err());
+
+        let quote = marshal_quote(b"nonce", &[9u8; 32]);
+        assert!(Quote::parse(&quote[..quote.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_verify_quote_against_policy() {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let key = ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = key.public_key().as_ref();
+
+        let policy = HostClassPolicy { baselines: vec![baseline(1), baseline(2)] };
+        let good = marshal_quote(b"challenge", &baseline(2).digest().unwrap());
+        let signature = key.sign(&good);
+
+        let matched = verify_quote(&good, signature.as_ref(), DeviceKeyAlgorithm::Ed25519, public_key, b"challenge", Some(&policy)).unwrap();
+        assert_eq!(matched.as_deref(), Some("baseline-2"));
+
+        // Replayed quote for another challenge
+        assert!(verify_quote(&good, signature.as_ref(), DeviceKeyAlgorithm::Ed25519, public_key, b"other", Some(&policy)).is_err());
+
+        // Drifted boot state, which only a policy catches
+        let drifted = marshal_quote(b"challenge", &baseline(3).digest().unwrap());
+        let drifted_signature = key.sign(&drifted);
+        assert!(verify_quote(&drifted, drifted_signature.as_ref(), DeviceKeyAlgorithm::Ed25519, public_key, b"challenge", Some(&policy)).is_err());
+        assert_eq!(verify_quote(&drifted, drifted_signature.as_ref(), DeviceKeyAlgorithm::Ed25519, public_key, b"challenge", None).unwrap(), None);
+
+        // Forged signatures and replays fail with or without a policy
+        assert!(verify_quote(&good, &[0u8; 64], DeviceKeyAlgorithm::Ed25519, public_key, b"challenge", Some(&policy)).is_err());
+        assert!(verify_quote(&good, &[0u8; 64], DeviceKeyAlgorithm::Ed25519, public_key, b"challenge", None).is_err());
+        assert!(verify_quote(&good, signature.as_ref(), DeviceKeyAlgorithm::Ed25519, public_key, b"other", None).is_err());
+    }
+
+    #[test]
+    fn test_policy_requires_all_quoted_pcrs() {
+        let mut partial = baseline(1);
+        partial.pcrs.remove("7");
+
+        let mut policy = PcrPolicy::default();
+        policy.classes.insert("web".to_string(), HostClassPolicy { baselines: vec![partial] });
+        assert!(policy.validate().is_err());
+
+        policy.classes.insert("web".to_string(), HostClassPolicy { baselines: vec![baseline(1)] });
+        assert!(policy.validate().is_ok());
+        assert!(policy.class("db").is_err());
+    }
+}
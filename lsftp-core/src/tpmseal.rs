@@ -0,0 +1,420 @@
+//! TPM-sealed server private key for LSFTP
+//!
+//! The server key is encrypted under a random wrapping key, and the wrapping
+//! key is sealed in the TPM under a PolicyPCR policy: the TPM only releases
+//! it when the selected SHA-256 PCRs hold the values they had at sealing
+//! time (or the values predicted for a planned update). The sealed blob is
+//! a JSON file holding the TPM public/private areas and the encrypted key.
+//!
+//! The same code runs against a hardware TPM or a software TPM simulator
+//! (swtpm) by choosing the TCTI, e.g. `device:/dev/tpmrm0` or
+//! `swtpm:host=localhost,port=2321`.
+
+use crate::crypto::{AeadAlgorithm, CryptoOperations, CryptoSuite};
+use crate::error::{Error, Result};
+use crate::secmem::SecretBuffer;
+use crate::tpmquote::{PcrBaseline, TPM_ALG_SHA256};
+use ring::rand::SecureRandom;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::path::Path;
+use std::str::FromStr;
+use tss_esapi::attributes::{ObjectAttributesBuilder, SessionAttributesBuilder};
+use tss_esapi::constants::SessionType;
+use tss_esapi::handles::{KeyHandle, SessionHandle};
+use tss_esapi::interface_types::algorithm::{HashingAlgorithm, PublicAlgorithm};
+use tss_esapi::interface_types::resource_handles::Hierarchy;
+use tss_esapi::interface_types::session_handles::{AuthSession, PolicySession};
+use tss_esapi::structures::{
+    Digest, KeyedHashScheme, PcrSelectionList, PcrSelectionListBuilder, PcrSlot, Private, Public,
+    PublicBuilder, PublicKeyedHashParameters, SensitiveData, SymmetricDefinition,
+};
+use tss_esapi::tcti_ldr::TctiNameConf;
+use tss_esapi::traits::{Marshall, UnMarshall};
+use tss_esapi::Context;
+
+/// Sealed key file format version
+pub const SEALED_KEY_VERSION: u32 = 1;
+
+/// PCRs sealed against by default: firmware, boot loader and Secure Boot state
+pub const DEFAULT_SEAL_PCRS: [u8; 8] = [0, 1, 2, 3, 4, 5, 6, 7];
+
+/// `TPM_CC_PolicyPCR`
+const TPM_CC_POLICY_PCR: u32 = 0x0000_017f;
+
+/// Wrapping key length
+const WRAPPING_KEY_LEN: usize = 32;
+
+/// Sealed server key file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedKey {
+    /// Format version
+    pub version: u32,
+    /// SHA-256 PCRs the policy covers
+    pub pcrs: Vec<u8>,
+    /// PolicyPCR digest the wrapping key is sealed under (hex)
+    pub policy_digest: String,
+    /// Sealed object public area, marshalled `TPMT_PUBLIC` (hex)
+    pub tpm_public: String,
+    /// Sealed object private area, `TPM2B_PRIVATE` buffer (hex)
+    pub tpm_private: String,
+    /// ChaCha20-Poly1305 nonce (hex)
+    pub nonce: String,
+    /// Encrypted private key (hex)
+    pub ciphertext: String,
+    /// Sealing time (seconds since the epoch)
+    pub sealed_at: u64,
+    /// Baseline the policy was computed from, if not the current PCRs
+    #[serde(default)]
+    pub baseline: Option<String>,
+}
+
+impl SealedKey {
+    /// Load a sealed key file
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read sealed key {:?}: {}", path, e)))?;
+        let sealed: Self = serde_json::from_str(&data)?;
+        if sealed.version != SEALED_KEY_VERSION {
+            return Err(Error::Config(format!("Unsupported sealed key version {}", sealed.version)));
+        }
+        Ok(sealed)
+    }
+}
+
+/// Marshalled `TPML_PCR_SELECTION` for a single SHA-256 bank
+pub fn marshal_pcr_selection(pcrs: &[u8]) -> Result<Vec<u8>> {
+    let mut bitmap = [0u8; 3];
+    for &pcr in pcrs {
+        if pcr >= 24 {
+            return Err(Error::Config(format!("PCR {} is out of range", pcr)));
+        }
+        bitmap[pcr as usize / 8] |= 1 << (pcr % 8);
+    }
+
+    let mut data = Vec::with_capacity(10);
+    data.extend_from_slice(&1u32.to_be_bytes());
+    data.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+    data.push(bitmap.len() as u8);
+    data.extend_from_slice(&bitmap);
+    Ok(data)
+}
+
+/// PolicyPCR digest for the given PCR values, as a trial session would compute it
+///
+/// `policyDigest = SHA-256(0^32 || TPM_CC_PolicyPCR || pcrs || SHA-256(values))`
+pub fn pcr_policy_digest(pcr_values: &[(u8, Vec<u8>)]) -> Result<[u8; 32]> {
+    let mut sorted = pcr_values.to_vec();
+    sorted.sort_by_key(|(index, _)| *index);
+
+    let mut values = Sha256::new();
+    for (index, value) in &sorted {
+        if value.len() != 32 {
+            return Err(Error::Config(format!("PCR{} value is not a SHA-256 digest", index)));
+        }
+        values.update(value);
+    }
+
+    let indices: Vec<u8> = sorted.iter().map(|(index, _)| *index).collect();
+    let mut policy = Sha256::new();
+    policy.update([0u8; 32]);
+    policy.update(TPM_CC_POLICY_PCR.to_be_bytes());
+    policy.update(marshal_pcr_selection(&indices)?);
+    policy.update(values.finalize());
+    Ok(policy.finalize().into())
+}
+
+/// PCR values from a golden baseline
+pub fn baseline_values(baseline: &PcrBaseline) -> Result<Vec<(u8, Vec<u8>)>> {
+    baseline.pcrs.iter()
+        .map(|(index, value)| {
+            let index = index.parse::<u8>()
+                .map_err(|_| Error::Config(format!("Invalid PCR index {:?} in baseline {}", index, baseline.name)))?;
+            let value = hex::decode(value)
+                .map_err(|e| Error::Config(format!("Invalid PCR{} value in baseline {}: {}", index, baseline.name, e)))?;
+            Ok((index, value))
+        })
+        .collect()
+}
+
+/// Seals and unseals the server key with a TPM
+pub struct TpmSealer {
+    context: Context,
+}
+
+impl TpmSealer {
+    /// Open the TPM through a TCTI string, or the `TCTI` environment variable
+    pub fn open(tcti: Option<&str>) -> Result<Self> {
+        let tcti = match tcti {
+            Some(tcti) => TctiNameConf::from_str(tcti)
+                .map_err(|e| Error::HardwareAuth(format!("Invalid TCTI {:?}: {}", tcti, e)))?,
+            None => TctiNameConf::from_environment_var()
+                .map_err(|e| Error::HardwareAuth(format!("Failed to load TCTI: {}", e)))?,
+        };
+
+        let context = Context::new(tcti)
+            .map_err(|e| Error::HardwareAuth(format!("Failed to create TPM context: {}", e)))?;
+        Ok(Self { context })
+    }
+
+    /// Current values of SHA-256 PCRs
+    pub fn read_pcrs(&mut self, pcrs: &[u8]) -> Result<Vec<(u8, Vec<u8>)>> {
+        let selection = Self::pcr_selection(pcrs)?;
+        let (_, _, digests) = self.context.pcr_read(selection)
+            .map_err(|e| Error::HardwareAuth(format!("Failed to read PCRs: {}", e)))?;
+
+        let values: Vec<Vec<u8>> = digests.value().iter().map(|digest| digest.value().to_vec()).collect();
+        if values.len() != pcrs.len() {
+            return Err(Error::HardwareAuth(format!("TPM returned {} of {} PCRs", values.len(), pcrs.len())));
+        }
+
+        let mut indices = pcrs.to_vec();
+        indices.sort_unstable();
+        Ok(indices.into_iter().zip(values).collect())
+    }
+
+    /// Seal a private key to the current PCR values, or to a baseline for a planned update
+    pub fn seal(&mut self, private_key: &[u8], pcrs: &[u8], baseline: Option<&PcrBaseline>) -> Result<SealedKey> {
+        let pcr_values = match baseline {
+            Some(baseline) => {
+                let values = baseline_values(baseline)?;
+                let mut covered: Vec<u8> = values.iter().map(|(index, _)| *index).collect();
+                covered.sort_unstable();
+                let mut wanted = pcrs.to_vec();
+                wanted.sort_unstable();
+                if covered != wanted {
+                    return Err(Error::Config(format!("Baseline {} must define PCRs {:?}", baseline.name, wanted)));
+                }
+                values
+            }
+            None => self.read_pcrs(pcrs)?,
+        };
+        let policy_digest = pcr_policy_digest(&pcr_values)?;
+
+        let rng = ring::rand::SystemRandom::new();
+        let mut wrapping_key = SecretBuffer::new(WRAPPING_KEY_LEN)?;
+        let mut nonce = [0u8; 12];
+        rng.fill(wrapping_key.as_mut_slice())?;
+        rng.fill(&mut nonce)?;
+
+        let ciphertext = Self::aead().encrypt(private_key, wrapping_key.as_slice(), &nonce)?;
+        let (tpm_public, tpm_private) = self.seal_wrapping_key(&wrapping_key, &policy_digest)?;
+
+        let mut indices: Vec<u8> = pcr_values.iter().map(|(index, _)| *index).collect();
+        indices.sort_unstable();
+
+        Ok(SealedKey {
+            version: SEALED_KEY_VERSION,
+            pcrs: indices,
+            policy_digest: hex::encode(policy_digest),
+            tpm_public: hex::encode(tpm_public),
+            tpm_private: hex::encode(tpm_private),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+            sealed_at: crate::now_secs(),
+            baseline: baseline.map(|baseline| baseline.name.clone()),
+        })
+    }
+
+    /// Unseal the private key; fails if the PCRs no longer match the policy
+    pub fn unseal(&mut self, sealed: &SealedKey) -> Result<SecretBuffer> {
+        let tpm_public = Public::unmarshall(&decode_field(&sealed.tpm_public)?)
+            .map_err(|e| Error::HardwareAuth(format!("Malformed sealed public area: {}", e)))?;
+        let tpm_private = Private::try_from(decode_field(&sealed.tpm_private)?)
+            .map_err(|e| Error::HardwareAuth(format!("Malformed sealed private area: {}", e)))?;
+
+        let parent = self.storage_root_key()?;
+        let object = self.context.execute_with_nullauth_session(|context| {
+            context.load(parent, tpm_private, tpm_public)
+        }).map_err(|e| Error::HardwareAuth(format!("Failed to load sealed object: {}", e)));
+
+        // Transient handles are flushed on every path so unsealing never exhausts TPM slots
+        let unsealed = object.and_then(|object| {
+            let unsealed = self.unseal_with_pcr_policy(object, &sealed.pcrs);
+            let _ = self.context.flush_context(object.into());
+            unsealed
+        });
+        let _ = self.context.flush_context(parent.into());
+
+        let wrapping_key = SecretBuffer::from_slice(unsealed?.value())?;
+
+        let nonce = decode_field(&sealed.nonce)?;
+        let plaintext = Self::aead().decrypt(&decode_field(&sealed.ciphertext)?, wrapping_key.as_slice(), &nonce)
+            .map_err(|_| Error::Crypto("Sealed server key failed to decrypt".to_string()))?;
+        SecretBuffer::from_vec(plaintext)
+    }
+
+    /// Unseal a loaded object under a PolicyPCR session, which is always flushed
+    fn unseal_with_pcr_policy(&mut self, object: KeyHandle, pcrs: &[u8]) -> Result<SensitiveData> {
+        let session = self.context.start_auth_session(
+            None,
+            None,
+            None,
+            SessionType::Policy,
+            SymmetricDefinition::AES_128_CFB,
+            HashingAlgorithm::Sha256,
+        )
+            .map_err(|e| Error::HardwareAuth(format!("Failed to start policy session: {}", e)))?
+            .ok_or_else(|| Error::HardwareAuth("TPM returned no policy session".to_string()))?;
+
+        let unsealed = self.satisfy_pcr_policy(session, pcrs).and_then(|()| {
+            self.context.execute_with_session(Some(session), |context| context.unseal(object.into()))
+                .map_err(|e| Error::HardwareAuth(format!(
+                    "TPM refused to unseal the server key; boot state differs from the sealing policy: {}", e
+                )))
+        });
+        let _ = self.context.flush_context(SessionHandle::from(session).into());
+        unsealed
+    }
+
+    /// Bind a policy session to the current values of the sealed PCRs
+    fn satisfy_pcr_policy(&mut self, session: AuthSession, pcrs: &[u8]) -> Result<()> {
+        let (attributes, mask) = SessionAttributesBuilder::new()
+            .with_decrypt(true)
+            .with_encrypt(true)
+            .build();
+        self.context.tr_sess_set_attributes(session, attributes, mask)
+            .map_err(|e| Error::HardwareAuth(format!("Failed to configure policy session: {}", e)))?;
+
+        let policy_session = PolicySession::try_from(session)
+            .map_err(|e| Error::HardwareAuth(format!("Invalid policy session: {}", e)))?;
+        self.context.policy_pcr(policy_session, Digest::default(), Self::pcr_selection(pcrs)?)
+            .map_err(|e| Error::HardwareAuth(format!("PolicyPCR failed: {}", e)))
+    }
+
+    /// Whether the current PCR values satisfy a sealed key's policy
+    pub fn policy_matches(&mut self, sealed: &SealedKey) -> Result<bool> {
+        let current = pcr_policy_digest(&self.read_pcrs(&sealed.pcrs)?)?;
+        Ok(hex::encode(current) == sealed.policy_digest)
+    }
+
+    /// Create the sealed data object under the storage root key
+    fn seal_wrapping_key(&mut self, wrapping_key: &SecretBuffer, policy_digest: &[u8; 32]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let object_attributes = ObjectAttributesBuilder::new()
+            .with_fixed_tpm(true)
+            .with_fixed_parent(true)
+            .with_no_da(true)
+            .with_admin_with_policy(true)
+            .with_user_with_auth(false)
+            .build()
+            .map_err(|e| Error::HardwareAuth(format!("Failed to build object attributes: {}", e)))?;
+
+        let public = PublicBuilder::new()
+            .with_public_algorithm(PublicAlgorithm::KeyedHash)
+            .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+            .with_object_attributes(object_attributes)
+            .with_auth_policy(Digest::try_from(policy_digest.to_vec())
+                .map_err(|e| Error::HardwareAuth(format!("Invalid policy digest: {}", e)))?)
+            .with_keyed_hash_parameters(PublicKeyedHashParameters::new(KeyedHashScheme::Null))
+            .with_keyed_hash_unique_identifier(Digest::default())
+            .build()
+            .map_err(|e| Error::HardwareAuth(format!("Failed to build sealed object template: {}", e)))?;
+
+        let sensitive = SensitiveData::try_from(wrapping_key.as_slice().to_vec())
+            .map_err(|e| Error::HardwareAuth(format!("Invalid sealed data: {}", e)))?;
+
+        let parent = self.storage_root_key()?;
+        let created = self.context.execute_with_nullauth_session(|context| {
+            context.create(parent, public, None, Some(sensitive), None, None)
+        });
+        let _ = self.context.flush_context(parent.into());
+        let created = created.map_err(|e| Error::HardwareAuth(format!("Failed to seal wrapping key: {}", e)))?;
+
+        let tpm_public = created.out_public.marshall()
+            .map_err(|e| Error::HardwareAuth(format!("Failed to marshal sealed public area: {}", e)))?;
+        Ok((tpm_public, created.out_private.value().to_vec()))
+    }
+
+    /// Deterministic RSA-2048 storage root key in the owner hierarchy
+    fn storage_root_key(&mut self) -> Result<KeyHandle> {
+        let template = tss_esapi::utils::create_restricted_decryption_rsa_public(
+            tss_esapi::structures::SymmetricDefinitionObject::AES_128_CFB,
+            tss_esapi::interface_types::key_bits::RsaKeyBits::Rsa2048,
+            tss_esapi::structures::RsaExponent::default(),
+        ).map_err(|e| Error::HardwareAuth(format!("Failed to build storage key template: {}", e)))?;
+
+        let primary = self.context.execute_with_nullauth_session(|context| {
+            context.create_primary(Hierarchy::Owner, template, None, None, None, None)
+        }).map_err(|e| Error::HardwareAuth(format!("Failed to create storage root key: {}", e)))?;
+
+        Ok(primary.key_handle)
+    }
+
+    /// SHA-256 bank selection for the given PCRs
+    fn pcr_selection(pcrs: &[u8]) -> Result<PcrSelectionList> {
+        let slots = pcrs.iter()
+            .map(|&index| PcrSlot::try_from(1u32 << index)
+                .map_err(|e| Error::Config(format!("Invalid PCR slot {}: {}", index, e))))
+            .collect::<Result<Vec<_>>>()?;
+
+        PcrSelectionListBuilder::new()
+            .with_selection(HashingAlgorithm::Sha256, &slots)
+            .build()
+            .map_err(|e| Error::HardwareAuth(format!("Failed to build PCR selection: {}", e)))
+    }
+
+    /// Wrapped keys are always encrypted with ChaCha20-Poly1305
+    fn aead() -> CryptoSuite {
+        CryptoSuite {
+            aead: AeadAlgorithm::ChaCha20Poly1305,
+            ..Default::default()
+        }
+    }
+}
+
+/// Decode a hex field of a sealed key file
+fn decode_field(value: &str) -> Result<Vec<u8>> {
+    hex::decode(value).map_err(|e| Error::Crypto(format!("Malformed sealed key file: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pcr_selection_marshalling() {
+        assert_eq!(
+            marshal_pcr_selection(&DEFAULT_SEAL_PCRS).unwrap(),
+            vec![0, 0, 0, 1, 0x00, 0x0b, 3, 0xff, 0x00, 0x00],
+        );
+        assert_eq!(marshal_pcr_selection(&[7, 14]).unwrap()[7..], [0x80, 0x40, 0x00]);
+        assert!(marshal_pcr_selection(&[24]).is_err());
+    }
+
+    #[test]
+    fn test_policy_digest_depends_on_values_not_order() {
+        let values: Vec<(u8, Vec<u8>)> = DEFAULT_SEAL_PCRS.iter().map(|&i| (i, vec![i; 32])).collect();
+        let mut reversed = values.clone();
+        reversed.reverse();
+        assert_eq!(pcr_policy_digest(&values).unwrap(), pcr_policy_digest(&reversed).unwrap());
+
+        let mut drifted = values.clone();
+        drifted[4].1[0] ^= 1;
+        assert_ne!(pcr_policy_digest(&values).unwrap(), pcr_policy_digest(&drifted).unwrap());
+
+        assert!(pcr_policy_digest(&[(0, vec![0u8; 20])]).is_err());
+    }
+
+    /// Needs a TPM simulator: `swtpm socket --tpm2 --server type=tcp,port=2321
+    /// --ctrl type=tcp,port=2322 --flags startup-clear` and
+    /// `LSFTP_TEST_TCTI=swtpm:host=localhost,port=2321`
+    #[test]
+    #[ignore = "requires swtpm; set LSFTP_TEST_TCTI"]
+    fn test_seal_unseal_with_swtpm() {
+        let tcti = std::env::var("LSFTP_TEST_TCTI").unwrap();
+        let mut sealer = TpmSealer::open(Some(&tcti)).unwrap();
+
+        let sealed = sealer.seal(b"server private key", &DEFAULT_SEAL_PCRS, None).unwrap();
+        assert!(sealer.policy_matches(&sealed).unwrap());
+        assert_eq!(sealer.unseal(&sealed).unwrap().as_slice(), b"server private key");
+
+        // Sealed to a different boot state: the TPM refuses to release it
+        let baseline = PcrBaseline {
+            name: "planned".to_string(),
+            pcrs: DEFAULT_SEAL_PCRS.iter().map(|i| (i.to_string(), hex::encode([0xaa; 32]))).collect(),
+        };
+        let future = sealer.seal(b"server private key", &DEFAULT_SEAL_PCRS, Some(&baseline)).unwrap();
+        assert!(!sealer.policy_matches(&future).unwrap());
+        assert!(sealer.unseal(&future).is_err());
+    }
+}
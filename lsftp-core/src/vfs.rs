@@ -0,0 +1,325 @@
+//! Per-user file views for LSFTP
+//!
+//! Each authenticated user sees their own virtual root, a home directory on
+//! the server named by a template such as `/var/lsftp/home/{user}`. Shared
+//! areas can be mounted into the views of several users or roles, read-only
+//! or read-write. Every client path is resolved inside the user's view; the
+//! server-side location is never sent back. Mounts live in a TOML file:
+//!
+//! ```toml
+//! [[mounts]]
+//! path = "/shared/finance"
+//! source = "/srv/lsftp/finance"
+//! roles = ["finance"]
+//!
+//! [[mounts]]
+//! path = "/shared/handbook"
+//! source = "/srv/lsftp/handbook"
+//! read_only = true
+//! roles = ["*"]
+//! ```
+
+use crate::access::{self, Subject};
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Placeholder replaced by the user ID in home directory templates
+pub const USER_PLACEHOLDER: &str = "{user}";
+
+/// Default home directory template, relative to the server root
+pub const DEFAULT_HOME_TEMPLATE: &str = "home/{user}";
+
+/// Shared directory mounted into users' views
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    /// Normalized virtual path where the mount appears
+    pub path: String,
+    /// Server directory behind the mount
+    pub source: PathBuf,
+    /// Refuse writes and deletions
+    pub read_only: bool,
+    /// Users who see the mount
+    pub users: Vec<String>,
+    /// Roles who see the mount; `*` is every user
+    pub roles: Vec<String>,
+}
+
+impl Mount {
+    fn visible_to(&self, user_id: &str, roles: &[String]) -> bool {
+        self.users.iter().any(|user| user == user_id)
+            || self.roles.iter().any(|role| role == "*" || roles.contains(role))
+    }
+}
+
+/// Mount as written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MountFile {
+    path: String,
+    source: PathBuf,
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+/// Mount table as written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MountTableFile {
+    #[serde(default)]
+    mounts: Vec<MountFile>,
+}
+
+/// Home directory template and shared mounts
+#[derive(Debug, Clone)]
+pub struct ViewConfig {
+    home_template: String,
+    mounts: Vec<Mount>,
+}
+
+impl ViewConfig {
+    /// Views with homes named by `home_template`, which must contain `{user}`
+    pub fn new(home_template: &str) -> Result<Self> {
+        if !home_template.contains(USER_PLACEHOLDER) {
+            return Err(Error::Config(format!(
+                "Home directory template must contain {}: {}", USER_PLACEHOLDER, home_template
+            )));
+        }
+        Ok(Self { home_template: home_template.to_string(), mounts: Vec::new() })
+    }
+
+    /// Add the shared mounts listed in a TOML file
+    pub fn with_mounts(mut self, path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read mount table {:?}: {}", path, e)))?;
+        let table: MountTableFile = toml::from_str(&content)
+            .map_err(|e| Error::Config(format!("Invalid mount table: {}", e)))?;
+
+        for mount in table.mounts {
+            self.add_mount(Mount {
+                path: access::normalize_path(&mount.path)?,
+                source: mount.source,
+                read_only: mount.read_only,
+                users: mount.users,
+                roles: mount.roles,
+            })?;
+        }
+        Ok(self)
+    }
+
+    /// Add a shared mount
+    pub fn add_mount(&mut self, mount: Mount) -> Result<()> {
+        if mount.path == "/" {
+            return Err(Error::Config("A mount cannot replace the whole view".to_string()));
+        }
+        if !mount.source.is_absolute() {
+            return Err(Error::Config(format!("Mount source must be absolute: {:?}", mount.source)));
+        }
+        if mount.users.is_empty() && mount.roles.is_empty() {
+            return Err(Error::Config(format!("Mount {} names no users or roles", mount.path)));
+        }
+        if self.mounts.iter().any(|existing| existing.path == mount.path) {
+            return Err(Error::Config(format!("Duplicate mount: {}", mount.path)));
+        }
+        self.mounts.push(mount);
+        Ok(())
+    }
+
+    /// Shared mounts
+    pub fn mounts(&self) -> &[Mount] {
+        &self.mounts
+    }
+
+    /// View of an authenticated user; anonymous sessions have none
+    pub fn view_for(&self, subject: &Subject) -> Result<UserView> {
+        let user_id = subject.user_id.as_deref()
+            .ok_or_else(|| Error::Auth("Anonymous sessions have no file view".to_string()))?;
+        if user_id.is_empty() || user_id == "." || user_id == ".." || user_id.contains(['/', '\0']) {
+            return Err(Error::Auth(format!("User ID {:?} cannot name a home directory", user_id)));
+        }
+
+        let mut mounts: Vec<Mount> = self.mounts.iter()
+            .filter(|mount| mount.visible_to(user_id, &subject.roles))
+            .cloned()
+            .collect();
+        // Longest prefix first, so nested mounts shadow their parents
+        mounts.sort_by_key(|mount| std::cmp::Reverse(mount.path.len()));
+
+        Ok(UserView {
+            home: PathBuf::from(self.home_template.replace(USER_PLACEHOLDER, user_id)),
+            mounts,
+        })
+    }
+}
+
+/// Client path resolved inside a view
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedPath {
+    /// Normalized path as the client sees it
+    pub virtual_path: String,
+    /// Location on the server; never sent to the client
+    pub server_path: PathBuf,
+    /// Whether the path lies in a read-only mount
+    pub read_only: bool,
+}
+
+impl ResolvedPath {
+    /// Refuse to modify read-only mounts
+    pub fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::File(format!("{} is read-only", self.virtual_path)));
+        }
+        Ok(())
+    }
+}
+
+/// One user's virtual root and the mounts they see
+#[derive(Debug, Clone)]
+pub struct UserView {
+    home: PathBuf,
+    mounts: Vec<Mount>,
+}
+
+impl UserView {
+    /// Server directory backing the user's root
+    pub fn home(&self) -> &Path {
+        &self.home
+    }
+
+    /// Resolve a client path: the longest visible mount containing it, else the home directory
+    pub fn resolve(&self, path: &str) -> Result<ResolvedPath> {
+        let virtual_path = access::normalize_path(path)?;
+
+        for mount in &self.mounts {
+            let rest = if virtual_path == mount.path {
+                Some("")
+            } else {
+                virtual_path.strip_prefix(&mount.path).and_then(|rest| rest.strip_prefix('/'))
+            };
+            if let Some(rest) = rest {
+                return Ok(ResolvedPath {
+                    server_path: mount.source.join(rest),
+                    read_only: mount.read_only,
+                    virtual_path,
+                });
+            }
+        }
+
+        Ok(ResolvedPath {
+            server_path: self.home.join(virtual_path.trim_start_matches('/')),
+            read_only: false,
+            virtual_path,
+        })
+    }
+
+    /// Names of the mounts that appear directly inside a virtual directory,
+    /// with the intermediate directories leading to deeper mounts
+    pub fn mount_points(&self, dir: &str) -> Result<Vec<String>> {
+        let dir = access::normalize_path(dir)?;
+        let mut names: Vec<String> = self.mounts.iter()
+            .filter_map(|mount| {
+                let rest = if dir == "/" {
+                    mount.path.strip_prefix('/')
+                } else {
+                    mount.path.strip_prefix(&dir).and_then(|rest| rest.strip_prefix('/'))
+                }?;
+                rest.split('/').next().map(str::to_string)
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ViewConfig {
+        let mut config = ViewConfig::new("/var/lsftp/home/{user}").unwrap();
+        config.add_mount(Mount {
+            path: "/shared/finance".to_string(),
+            source: PathBuf::from("/srv/finance"),
+            read_only: false,
+            users: vec![],
+            roles: vec!["finance".to_string()],
+        }).unwrap();
+        config.add_mount(Mount {
+            path: "/shared/handbook".to_string(),
+            source: PathBuf::from("/srv/handbook"),
+            read_only: true,
+            users: vec![],
+            roles: vec!["*".to_string()],
+        }).unwrap();
+        config
+    }
+
+    fn subject(user_id: &str, roles: &[&str]) -> Subject {
+        Subject {
+            user_id: Some(user_id.to_string()),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_home_and_mounts() {
+        let view = config().view_for(&subject("alice", &["finance"])).unwrap();
+        assert_eq!(view.home(), Path::new("/var/lsftp/home/alice"));
+
+        let resolved = view.resolve("reports//q1.pdf").unwrap();
+        assert_eq!(resolved.virtual_path, "/reports/q1.pdf");
+        assert_eq!(resolved.server_path, PathBuf::from("/var/lsftp/home/alice/reports/q1.pdf"));
+        assert!(resolved.check_writable().is_ok());
+
+        let resolved = view.resolve("/shared/finance/q1.xlsx").unwrap();
+        assert_eq!(resolved.server_path, PathBuf::from("/srv/finance/q1.xlsx"));
+
+        let resolved = view.resolve("/shared/handbook").unwrap();
+        assert_eq!(resolved.server_path, PathBuf::from("/srv/handbook"));
+        assert!(resolved.check_writable().is_err());
+
+        // A look-alike sibling of a mount stays in the home directory
+        let resolved = view.resolve("/shared/financeX").unwrap();
+        assert_eq!(resolved.server_path, PathBuf::from("/var/lsftp/home/alice/shared/financeX"));
+
+        assert!(view.resolve("/shared/../../bob/secret").is_err());
+        assert_eq!(view.mount_points("/").unwrap(), vec!["shared".to_string()]);
+        assert_eq!(view.mount_points("/shared").unwrap(), vec!["finance".to_string(), "handbook".to_string()]);
+        assert!(view.mount_points("/reports").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_mounts_follow_roles() {
+        let view = config().view_for(&subject("bob", &[])).unwrap();
+        let resolved = view.resolve("/shared/finance/q1.xlsx").unwrap();
+        assert_eq!(resolved.server_path, PathBuf::from("/var/lsftp/home/bob/shared/finance/q1.xlsx"));
+        assert_eq!(view.mount_points("/shared").unwrap(), vec!["handbook".to_string()]);
+    }
+
+    #[test]
+    fn test_invalid_views() {
+        assert!(ViewConfig::new("/var/lsftp/shared").is_err());
+        assert!(config().view_for(&Subject::default()).is_err());
+        assert!(config().view_for(&subject("../root", &[])).is_err());
+        assert!(config().view_for(&subject("..", &[])).is_err());
+
+        let mut config = config();
+        let mount = Mount {
+            path: "/shared/finance".to_string(),
+            source: PathBuf::from("/srv/other"),
+            read_only: false,
+            users: vec!["alice".to_string()],
+            roles: vec![],
+        };
+        assert!(config.add_mount(mount.clone()).is_err());
+        assert!(config.add_mount(Mount { path: "/".to_string(), ..mount.clone() }).is_err());
+        assert!(config.add_mount(Mount { path: "/x".to_string(), source: PathBuf::from("srv"), ..mount.clone() }).is_err());
+        assert!(config.add_mount(Mount { path: "/x".to_string(), users: vec![], ..mount }).is_err());
+    }
+}
@@ -1,13 +1,31 @@
 use clap::Parser;
-use lsftp_core::{TransportConfig, QuicServerTransport, Result, Message, MessageType, protocol::{Frame, FileOpenPayload, FileDataPayload, FileClosePayload, MessagePayload}};
-use std::path::PathBuf;
+use lsftp_core::{TransportConfig, QuicServerTransport, Result, Message, MessageType, protocol::{Frame, ErrorPayload, FileOpenPayload, FileDataPayload, FileClosePayload, MessagePayload, PolicyRuleType, PolicyUpdatePayload, QuotaPayload, SessionDevice, ACCESS_DENIED, QUOTA_EXCEEDED}};
+use lsftp_core::crypto::{HashAlgorithm, TaggedDigest, TransferHasher};
+use lsftp_core::{CryptoSuite, KeySchedule, AuditLogger, SecretBuffer, SecurityLogger};
+use lsftp_core::access::{AccessPolicy, AccessRequest, FileAction, Subject};
+use lsftp_core::audit::AuditConfig;
+use lsftp_core::encryption::{self, EncryptionPolicy, EncryptionViolation};
+use lsftp_core::enrollment::{EnrollmentStore, DEFAULT_ENROLLMENT_PATH};
+use lsftp_core::handshake;
+use lsftp_core::keyschedule;
+use lsftp_core::lockout::{self, AuthThrottle, LockoutPolicy, DEFAULT_LOCKOUT_STATE_PATH};
+use lsftp_core::mfa::{self, MfaPolicy};
+use lsftp_core::mtls::ClientCertPolicy;
+use lsftp_core::pkcs11::{HsmAuth, Pkcs11Config};
+use lsftp_core::quota::{subject_keys, QuotaManager, QuotaPolicy, DEFAULT_QUOTA_STATE_PATH};
+use lsftp_core::ratelimit::{Direction, RateLimitPolicy, RateLimiter};
+use lsftp_core::resumption::TicketIssuer;
+use lsftp_core::smartcard::CaBundle;
+use lsftp_core::tpmquote::PcrPolicy;
+use lsftp_core::tpmseal::{SealedKey, TpmSealer};
+use lsftp_core::vfs::{UserView, ViewConfig, DEFAULT_HOME_TEMPLATE};
+use std::path::{Path, PathBuf};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use uuid::Uuid;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use blake3::Hasher;
 use tracing::{info, warn, error};
 
 /// LSFTP Server - Secure File Transfer Protocol
@@ -33,13 +51,29 @@ pub struct Cli {
     pub cert: Option<PathBuf>,
 
     /// Private key path
-    #[arg(long)]
+    #[arg(long, conflicts_with = "sealed_key")]
     pub key: Option<PathBuf>,
 
+    /// TPM-sealed private key, unsealed at startup (see `lsftp-tools tpm-seal`)
+    #[arg(long)]
+    pub sealed_key: Option<PathBuf>,
+
+    /// TPM TCTI for the sealed key (defaults to the TCTI environment variable)
+    #[arg(long)]
+    pub tpm_tcti: Option<String>,
+
     /// Root directory for file storage
     #[arg(long, default_value = "/var/lsftp")]
     pub root_dir: PathBuf,
 
+    /// Each user's virtual root; `{user}` is replaced by the user ID, relative paths are under --root-dir
+    #[arg(long, default_value = DEFAULT_HOME_TEMPLATE)]
+    pub home_template: String,
+
+    /// Shared directories (TOML) mounted into the views of listed users and roles
+    #[arg(long)]
+    pub mounts: Option<PathBuf>,
+
     /// Verbose output
     #[arg(short, long)]
     pub verbose: bool,
@@ -51,45 +85,168 @@ pub struct Cli {
     /// Enable hardware authentication
     #[arg(long)]
     pub require_hardware_auth: bool,
+
+    /// Enrolled hardware devices; re-read on SIGHUP
+    #[arg(long, default_value = DEFAULT_ENROLLMENT_PATH)]
+    pub enrolled_devices: PathBuf,
+
+    /// Golden PCR policy; TPM clients whose boot state drifted are rejected
+    #[arg(long)]
+    pub pcr_policy: Option<PathBuf>,
+
+    /// CA bundle (PEM) smart card certificates must chain to; their UPN or CN must match the enrolled user.
+    /// Required while smart cards are enrolled
+    #[arg(long)]
+    pub smartcard_ca: Option<PathBuf>,
+
+    /// DER CRLs for the smart card CAs; with any given, every certificate needs a current CRL from its issuer
+    #[arg(long, requires = "smartcard_ca")]
+    pub smartcard_crl: Vec<PathBuf>,
+
+    /// CA bundle (PEM) for mutual TLS; clients must present a certificate with the clientAuth usage issued under it
+    #[arg(long)]
+    pub client_ca: Option<PathBuf>,
+
+    /// TOML map from client certificate subject DN, UPN or CN to user; unmapped certificates are rejected
+    #[arg(long, requires = "client_ca")]
+    pub client_subject_map: Option<PathBuf>,
+
+    /// Revoked client and intermediate certificates, one hex serial and issuer DN per line; re-read on SIGHUP
+    #[arg(long, requires = "client_ca")]
+    pub client_revocations: Option<PathBuf>,
+
+    /// Multi-factor policy (TOML) naming the device types each user or role must present
+    #[arg(long)]
+    pub mfa_policy: Option<PathBuf>,
+
+    /// Failed authentications before a source IP or device is locked out
+    #[arg(long, default_value = "5")]
+    pub auth_max_failures: u32,
+
+    /// Lockout duration in seconds
+    #[arg(long, default_value = "900")]
+    pub auth_lockout_secs: u64,
+
+    /// Failure counters and lockouts, shared with lsftp-tools
+    #[arg(long, default_value = DEFAULT_LOCKOUT_STATE_PATH)]
+    pub auth_state: PathBuf,
+
+    /// Lifetime in seconds of single-use session resumption tickets (0 disables resumption)
+    #[arg(long, default_value = "0")]
+    pub resumption_ticket_lifetime: u64,
+
+    /// Access policy (TOML) checked before every open, read, write, list and delete;
+    /// operations no rule allows are denied
+    #[arg(long)]
+    pub access_policy: PathBuf,
+
+    /// Storage quotas (TOML) per user and group; without one, usage is not tracked
+    #[arg(long)]
+    pub quota_policy: Option<PathBuf>,
+
+    /// Usage ledger the quotas are enforced against, shared with lsftp-tools
+    #[arg(long, default_value = DEFAULT_QUOTA_STATE_PATH)]
+    pub quota_state: PathBuf,
+
+    /// Bandwidth limits (TOML) for the server, each user and each session; without one only
+    /// the protocol maximum applies until an administrator sets limits live
+    #[arg(long)]
+    pub rate_limits: Option<PathBuf>,
+
+    /// Minimum crypto suite (TOML) for sessions, paths and classification labels; without one,
+    /// sessions must be at least hybrid
+    #[arg(long)]
+    pub encryption_policy: Option<PathBuf>,
+
+    /// Role whose members may change rate limits and encryption requirements live with policy updates; none if unset
+    #[arg(long)]
+    pub policy_admin_role: Option<String>,
+
+    /// Allow the software authenticator (insecure-dev builds only; never in production)
+    #[arg(long)]
+    pub insecure_dev_auth: bool,
+
+    /// Sign completed files with a PKCS#11 HSM key (module=...,token=...,key=...)
+    #[arg(long)]
+    pub file_signing_hsm: Option<Pkcs11Config>,
+
+    /// Accepted transfer hash algorithms, in order of preference
+    #[arg(long, value_delimiter = ',', default_value = "blake3,sha3-256")]
+    pub transfer_hashes: Vec<HashAlgorithm>,
 }
 
 /// File transfer session
 #[derive(Debug)]
 struct FileSession {
-    file_id: Uuid,
     file_path: String,
+    server_path: PathBuf,
+    /// Partial file the data goes to; renamed over `server_path` once the transfer checks out
+    upload_path: PathBuf,
     file_size: u64,
     chunks_received: u32,
     total_bytes: u64,
-    hasher: Hasher,
+    hasher: TransferHasher,
     file_handle: Option<File>,
 }
 
 impl FileSession {
-    fn new(file_id: Uuid, file_path: String, file_size: u64) -> Self {
+    fn new(file_path: String, server_path: PathBuf, file_size: u64, hasher: TransferHasher) -> Self {
+        let name = server_path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let upload_path = server_path.with_file_name(format!(".{}.{}.part", name, Uuid::new_v4()));
         Self {
-            file_id,
             file_path,
+            server_path,
+            upload_path,
             file_size,
             chunks_received: 0,
             total_bytes: 0,
-            hasher: Hasher::new(),
+            hasher,
             file_handle: None,
         }
     }
 }
 
+/// State shared by every session
+struct ServerState {
+    /// Uploads in flight, keyed by session and the client-chosen file ID
+    file_sessions: RwLock<HashMap<(Uuid, Uuid), FileSession>>,
+    enrollments: RwLock<Arc<EnrollmentStore>>,
+    pcr_policy: Option<PcrPolicy>,
+    /// Smart card CAs and CRLs, replaced on SIGHUP
+    smartcard_ca: RwLock<Option<CaBundle>>,
+    mfa_policy: MfaPolicy,
+    auth_throttle: Arc<AuthThrottle>,
+    ticket_issuer: Option<TicketIssuer>,
+    access_policy: AccessPolicy,
+    views: ViewConfig,
+    quota: Option<Arc<QuotaManager>>,
+    rate_limiter: RateLimiter,
+    encryption: RwLock<EncryptionPolicy>,
+    security_logger: SecurityLogger,
+    file_signer: Option<Arc<HsmAuth>>,
+    cli: Cli,
+}
+
+/// Per-session state of an authenticated client
+struct ActiveSession {
+    server: QuicServerTransport,
+    id: Uuid,
+    subject: Subject,
+    view: UserView,
+    key_schedule: KeySchedule,
+}
+
 /// LSFTP Server implementation
 struct LsftpServer {
     config: TransportConfig,
     server: QuicServerTransport,
-    file_sessions: Arc<RwLock<HashMap<Uuid, FileSession>>>,
-    cli: Cli,
+    client_policy: Option<Arc<ClientCertPolicy>>,
+    state: Arc<ServerState>,
 }
 
 impl LsftpServer {
     /// Create new server
-    fn new(cli: Cli) -> Result<Self> {
+    async fn new(cli: Cli) -> Result<Self> {
         let config = TransportConfig {
             server_address: cli.address.clone(),
             server_port: cli.port,
@@ -98,25 +255,201 @@ impl LsftpServer {
             ..Default::default()
         };
 
-        let server = QuicServerTransport::new(config)?;
+        let mut server = QuicServerTransport::new(config.clone())?;
+
+        // The sealed key is only released on an unmodified boot
+        if let Some(path) = &cli.sealed_key {
+            let sealed = SealedKey::load(path)?;
+            let private_key = TpmSealer::open(cli.tpm_tcti.as_deref())?.unseal(&sealed)
+                .map_err(|e| {
+                    error!("Refusing to start: {}", e);
+                    e
+                })?;
+            server.set_private_key(private_key);
+            info!("Unsealed server key from {:?} (PCRs {:?})", path, sealed.pcrs);
+        }
+
+        // Mutual TLS: the transport rejects clients without an acceptable certificate
+        let client_policy = match &cli.client_ca {
+            Some(path) => {
+                let mut policy = ClientCertPolicy::new(CaBundle::load(path)?)?;
+                if let Some(map) = &cli.client_subject_map {
+                    policy = policy.with_subject_map(map)?;
+                }
+                if let Some(list) = &cli.client_revocations {
+                    policy = policy.with_revocation_list(list)?;
+                }
+                info!("Requiring client certificates under {} CAs", policy.ca_count());
+                let policy = Arc::new(policy);
+                server.set_client_policy(policy.clone());
+                Some(policy)
+            }
+            None => None,
+        };
+
+        let enrollments = EnrollmentStore::load(&cli.enrolled_devices)?;
+        info!("Loaded {} enrolled devices from {:?}", enrollments.len(), cli.enrolled_devices);
+        enrollments.check_insecure_dev(lsftp_core::auth::HardwareAuthFactory::insecure_dev_allowed())
+            .and_then(|()| enrollments.check_smartcard_ca(cli.smartcard_ca.is_some()))
+            .map_err(|e| {
+                error!("Refusing to start: {}", e);
+                e
+            })?;
+
+        let pcr_policy = cli.pcr_policy.as_deref().map(PcrPolicy::load).transpose()?;
+        if let Some(policy) = &pcr_policy {
+            info!("Loaded PCR policy for {} host classes", policy.classes.len());
+        }
+
+        let smartcard_ca = cli.smartcard_ca.as_deref()
+            .map(|path| CaBundle::load(path)?.with_crls(&cli.smartcard_crl))
+            .transpose()?;
+        if let Some(ca) = &smartcard_ca {
+            info!("Loaded {} smart card CA certificates and {} CRLs", ca.len(), ca.crl_count());
+        }
+
+        let mfa_policy = cli.mfa_policy.as_deref().map(MfaPolicy::load).transpose()?.unwrap_or_default();
+        if cli.mfa_policy.is_some() {
+            info!("Loaded MFA policy for {} users and {} roles", mfa_policy.users.len(), mfa_policy.roles.len());
+        }
+
+        let lockout_policy = LockoutPolicy {
+            max_failures: cli.auth_max_failures,
+            lockout_secs: cli.auth_lockout_secs,
+            ..Default::default()
+        };
+        let auth_throttle = AuthThrottle::with_state_file(lockout_policy, &cli.auth_state)?;
+
+        let ticket_issuer = match cli.resumption_ticket_lifetime {
+            0 => None,
+            lifetime => {
+                info!("Issuing session resumption tickets valid for {}s", lifetime);
+                Some(TicketIssuer::new(std::time::Duration::from_secs(lifetime))?)
+            }
+        };
+
+        let access_policy = AccessPolicy::load(&cli.access_policy)?;
+        info!("Loaded access policy with {} rules", access_policy.rules.len());
+
+        let home_template = cli.root_dir.join(&cli.home_template);
+        let mut views = ViewConfig::new(&home_template.to_string_lossy())?;
+        if let Some(path) = &cli.mounts {
+            views = views.with_mounts(path)?;
+            info!("Loaded {} shared mounts", views.mounts().len());
+        }
+
+        let quota = match &cli.quota_policy {
+            Some(path) => {
+                let manager = QuotaManager::new(QuotaPolicy::load(path)?, &cli.quota_state)?;
+                info!("Enforcing storage quotas; usage ledger at {:?}", cli.quota_state);
+                Some(Arc::new(manager))
+            }
+            None => None,
+        };
+
+        let rate_limits = match &cli.rate_limits {
+            Some(path) => {
+                let policy = RateLimitPolicy::load(path)?;
+                info!("Loaded bandwidth limits for {} users", policy.users.len());
+                policy
+            }
+            None => RateLimitPolicy::default(),
+        };
+
+        let encryption = cli.encryption_policy.as_deref().map(EncryptionPolicy::load).transpose()?.unwrap_or_default();
+        info!("Sessions need a {} crypto suite; {} paths require more", encryption.minimum, encryption.paths.len());
+
+        // Completed files are signed inside the HSM
+        let file_signer = match &cli.file_signing_hsm {
+            Some(config) => {
+                let mut signer = HsmAuth::new(config.clone());
+                signer.initialize().await?;
+                info!("Signing files with HSM key {} on token {}", config.key_label, config.token_label);
+                Some(Arc::new(signer))
+            }
+            None => None,
+        };
+
+        let audit_logger = AuditLogger::new(AuditConfig::default(), CryptoSuite::default())?;
 
         Ok(Self {
             config,
             server,
-            file_sessions: Arc::new(RwLock::new(HashMap::new())),
-            cli,
+            client_policy,
+            state: Arc::new(ServerState {
+                file_sessions: RwLock::new(HashMap::new()),
+                enrollments: RwLock::new(Arc::new(enrollments)),
+                pcr_policy,
+                smartcard_ca: RwLock::new(smartcard_ca),
+                mfa_policy,
+                auth_throttle: Arc::new(auth_throttle),
+                ticket_issuer,
+                access_policy,
+                views,
+                quota,
+                rate_limiter: RateLimiter::new(rate_limits),
+                encryption: RwLock::new(encryption),
+                security_logger: SecurityLogger::new(audit_logger),
+                file_signer,
+                cli,
+            }),
         })
     }
 
     /// Start server
     async fn start(&mut self) -> Result<()> {
+        let cli = &self.state.cli;
+
         // Create root directory if it doesn't exist
-        tokio::fs::create_dir_all(&self.cli.root_dir).await
+        tokio::fs::create_dir_all(&cli.root_dir).await
             .map_err(|e| lsftp_core::error::Error::Config(format!("Failed to create root directory: {}", e)))?;
 
-        info!("Starting LSFTP server on {}:{}", self.cli.address, self.cli.port);
-        info!("Root directory: {:?}", self.cli.root_dir);
-        info!("Max file size: {} bytes", self.cli.max_file_size);
+        info!("Starting LSFTP server on {}:{}", cli.address, cli.port);
+        info!("Root directory: {:?}", cli.root_dir);
+        info!("Max file size: {} bytes", cli.max_file_size);
+
+        // Suspended and revoked devices, revoked client certificates and new smart card
+        // CRLs take effect on SIGHUP, without a restart; sessions authenticate against
+        // the registry current when they start
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .map_err(|e| lsftp_core::error::Error::Config(format!("Failed to install SIGHUP handler: {}", e)))?;
+        let client_policy = self.client_policy.clone();
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            let registry = &state.cli.enrolled_devices;
+            while hangup.recv().await.is_some() {
+                let reloaded = EnrollmentStore::load(registry).and_then(|store| {
+                    store.check_insecure_dev(lsftp_core::auth::HardwareAuthFactory::insecure_dev_allowed())?;
+                    store.check_smartcard_ca(state.cli.smartcard_ca.is_some())?;
+                    Ok(store)
+                });
+                match reloaded {
+                    Ok(store) => {
+                        info!("Reloaded {} enrolled devices from {:?}", store.len(), registry);
+                        *state.enrollments.write().await = Arc::new(store);
+                    }
+                    Err(e) => warn!("Keeping previous device registry: {}", e),
+                }
+
+                // CRLs expire, so fresh ones are picked up without a restart
+                if let Some(path) = &state.cli.smartcard_ca {
+                    match CaBundle::load(path).and_then(|ca| ca.with_crls(&state.cli.smartcard_crl)) {
+                        Ok(ca) => {
+                            info!("Reloaded {} smart card CA certificates and {} CRLs", ca.len(), ca.crl_count());
+                            *state.smartcard_ca.write().await = Some(ca);
+                        }
+                        Err(e) => warn!("Keeping previous smart card CAs and CRLs: {}", e),
+                    }
+                }
+
+                if let Some(policy) = &client_policy {
+                    match policy.reload_revocations() {
+                        Ok(count) => info!("Reloaded client revocation list ({} certificates)", count),
+                        Err(e) => warn!("Keeping previous client revocation list: {}", e),
+                    }
+                }
+            }
+        });
 
         // Start QUIC server
         self.server.start().await?;
@@ -129,14 +462,18 @@ impl LsftpServer {
                     info!("New connection accepted: {}", session_id);
                     
                     // Handle session in separate task
-                    let server_clone = self.server.clone();
-                    let file_sessions = self.file_sessions.clone();
-                    let cli = self.cli.clone();
-                    
+                    let server = self.server.clone();
+                    let state = self.state.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = Self::handle_session(server_clone, session_id, file_sessions, cli).await {
+                        if let Err(e) = Self::handle_session(&state, server, session_id).await {
                             error!("Session {} error: {}", session_id, e);
                         }
+                        state.rate_limiter.close_session(session_id);
+                        // Unfinished uploads are discarded and no longer hold quota
+                        Self::discard_uploads(&state, session_id).await;
+                        if let Some(quota) = &state.quota {
+                            quota.release_session(session_id);
+                        }
                     });
                 }
                 Err(e) => {
@@ -148,27 +485,64 @@ impl LsftpServer {
     }
 
     /// Handle client session
-    async fn handle_session(
-        mut server: QuicServerTransport,
-        session_id: Uuid,
-        file_sessions: Arc<RwLock<HashMap<Uuid, FileSession>>>,
-        cli: Cli,
-    ) -> Result<()> {
+    async fn handle_session(state: &ServerState, server: QuicServerTransport, session_id: Uuid) -> Result<()> {
         info!("Handling session: {}", session_id);
 
+        // No file operations until the client has answered the challenge with an acceptable suite
+        let (mut subject, crypto_suite, kem_secret) = Self::authenticate_session(state, &server, session_id).await?;
+        state.access_policy.assign_roles(&mut subject);
+
+        // Every path the client sends is resolved inside this user's view
+        let view = match state.views.view_for(&subject) {
+            Ok(view) => view,
+            Err(e) => {
+                warn!("Session {} has no file view: {}", session_id, e);
+                server.close_session(session_id).await?;
+                return Err(e);
+            }
+        };
+        tokio::fs::DirBuilder::new().recursive(true).mode(0o700).create(view.home()).await
+            .map_err(|e| lsftp_core::error::Error::File(format!("Failed to create home directory: {}", e)))?;
+        state.rate_limiter.open_session(session_id, subject.user_id.as_deref());
+
+        // Per-file keys are derived from this session's exported secret and key exchange
+        let key_schedule = KeySchedule::new(
+            keyschedule::session_secret(&server.export_session_secret(session_id).await?, &kem_secret)?,
+            crypto_suite,
+        )?;
+        let mut session = ActiveSession { server, id: session_id, subject, view, key_schedule };
+
         loop {
             // Receive message from client
-            let message = server.receive_message().await?;
-            
+            let message = session.server.receive_from_session(session_id).await?;
+            // An unencrypted frame ends the session; the refusal tells the client why
+            if !Self::enforce_encryption(state, &session, None, encryption::check_frame(&message.frame), true).await? {
+                session.server.close_session(session_id).await?;
+                return Ok(());
+            }
+
             match message.payload {
                 Some(MessagePayload::FileOpen(payload)) => {
-                    Self::handle_file_open(&server, session_id, payload, &file_sessions, &cli).await?;
+                    Self::handle_file_open(state, &mut session, payload).await?;
                 }
                 Some(MessagePayload::FileData(payload)) => {
-                    Self::handle_file_data(&server, session_id, payload, &file_sessions, &cli).await?;
+                    Self::handle_file_data(state, &mut session, payload).await?;
                 }
                 Some(MessagePayload::FileClose(payload)) => {
-                    Self::handle_file_close(&server, session_id, payload, &file_sessions, &cli).await?;
+                    Self::handle_file_close(state, &mut session, payload).await?;
+                }
+                Some(MessagePayload::PolicyUpdate(payload)) => {
+                    Self::handle_policy_update(state, &session, payload).await?;
+                }
+                Some(MessagePayload::Quota(_)) => {
+                    Self::send_quota_report(state, &session).await?;
+                }
+                Some(MessagePayload::SessionTerminate(payload)) => {
+                    // The client lost a bound device; nothing more is accepted on this session
+                    warn!("Session {} terminated by client: {}", session_id, payload.reason);
+                    state.security_logger.log_session_end(session_id, session.subject.user_id, payload.device_id, &payload.reason).await?;
+                    session.server.close_session(session_id).await?;
+                    return Ok(());
                 }
                 _ => {
                     warn!("Unknown message type: {:?}", message.frame.message_type);
@@ -177,15 +551,346 @@ impl LsftpServer {
         }
     }
 
-    /// Handle file open request
-    async fn handle_file_open(
+    /// Run the hardware challenge-response and mark the session Ready; returns the
+    /// authenticated user with the attributes access rules test, the crypto suite the
+    /// client declared and the shared secret of its key exchange
+    async fn authenticate_session(
+        state: &ServerState,
         server: &QuicServerTransport,
         session_id: Uuid,
-        payload: FileOpenPayload,
-        file_sessions: &Arc<RwLock<HashMap<Uuid, FileSession>>>,
-        cli: &Cli,
-    ) -> Result<()> {
-        info!("File open request: {} ({} bytes)", payload.path, payload.size);
+    ) -> Result<(Subject, CryptoSuite, SecretBuffer)> {
+        let enrollments = state.enrollments.read().await.clone();
+        let encryption_policy = state.encryption.read().await.clone();
+        let smartcard_ca = state.smartcard_ca.read().await.clone();
+        let (auth_throttle, security_logger) = (&state.auth_throttle, &state.security_logger);
+
+        let session = server.get_sessions().await.into_iter()
+            .find(|session| session.session_id == session_id);
+        let source_ip = session.as_ref().map(|session| session.remote_address.clone());
+
+        // Failures count against the source address and every enrolled device that answered
+        let mut throttle_keys: Vec<String> = source_ip.as_deref().map(lockout::ip_key).into_iter().collect();
+        let mut negotiated = None;
+        let mut resumed_from = None;
+        let (device_ids, outcome) = match Self::throttle(auth_throttle, &throttle_keys, AuthThrottle::check).await {
+            Ok(()) => {
+                let binding = server.export_auth_binding(session_id).await?;
+                let challenge = handshake::issue_challenge(session_id, state.cli.require_hardware_auth)?;
+
+                let challenge_message = Message::new(MessageType::AuthChallenge, Some(
+                    MessagePayload::AuthChallenge(challenge.clone())
+                ))?;
+                server.send_to_session(session_id, challenge_message).await?;
+
+                // The device has HARDWARE_AUTH_TIMEOUT_SECS to answer; a silent client counts as a failure
+                let timeout = std::time::Duration::from_secs(lsftp_core::HARDWARE_AUTH_TIMEOUT_SECS);
+                let message = match tokio::time::timeout(timeout, server.receive_from_session(session_id)).await {
+                    Ok(message) => Ok(message?),
+                    Err(_) => Err(lsftp_core::error::Error::Auth(format!(
+                        "No authentication response within {}s", lsftp_core::HARDWARE_AUTH_TIMEOUT_SECS
+                    ))),
+                };
+
+                // The user is resolved from the enrollment registry, never taken from the client;
+                // every additional factor must be enrolled for that same user
+                match message.map(|message| (message.frame, message.payload)) {
+                    Ok((frame, Some(MessagePayload::AuthResponse(response)))) => {
+                        // Only enrolled devices are throttled, so made-up IDs cannot grow the state
+                        // file; responses with more factors than device types are refused outright
+                        let device_ids: Vec<String> = response.device_id.iter()
+                            .chain(response.additional_factors.iter().take(mfa::MAX_ADDITIONAL_FACTORS).map(|factor| &factor.device_id))
+                            .cloned()
+                            .collect();
+                        throttle_keys.extend(device_ids.iter()
+                            .filter(|device_id| enrollments.get(device_id).is_some())
+                            .map(|device_id| lockout::device_key(device_id)));
+                        throttle_keys.sort();
+                        throttle_keys.dedup();
+
+                        // Unencrypted frames, weak suites and key exchanges that do not match the
+                        // declared suite are refused before any signature is checked
+                        let encryption_check = encryption::check_frame(&frame)
+                            .and_then(|()| encryption_policy.negotiate(&response.crypto_suite, &response.kem_public_key))
+                            .map(|(ciphertext, secret)| negotiated = Some((response.crypto_suite.clone(), ciphertext, secret)));
+                        if let Err(violation) = &encryption_check {
+                            security_logger.log_encryption_violation(session_id, None, violation).await?;
+                        }
+
+                        // A resumption ticket stands in for the devices that authenticated the earlier session
+                        let throttled = Self::throttle(auth_throttle, &throttle_keys, AuthThrottle::check).await;
+                        match (throttled, &response.resumption_ticket, &state.ticket_issuer) {
+                            (Err(e), _, _) => (device_ids, Err(e)),
+                            (Ok(()), _, _) if encryption_check.is_err() => (device_ids, encryption_check.map(|()| None).map_err(Into::into)),
+                            (Ok(()), Some(ticket), Some(issuer)) => match issuer.redeem(ticket, &enrollments) {
+                                Ok(resumed) => {
+                                    resumed_from = Some(resumed.authenticated_at);
+                                    (resumed.device_ids, Ok(Some(resumed.result)))
+                                }
+                                Err(e) => (device_ids, Err(e)),
+                            },
+                            (Ok(()), Some(_), None) => (device_ids, Err(lsftp_core::error::Error::Auth(
+                                "Session resumption is disabled on this server".to_string()
+                            ))),
+                            (Ok(()), None, _) => {
+                                let outcome = mfa::verify_factors(&enrollments, binding.as_slice(), &challenge, &response, state.pcr_policy.as_ref(), smartcard_ca.as_ref(), &state.mfa_policy)
+                                    .map(|factors| factors.map(|f| f.auth_result()));
+                                (device_ids, outcome)
+                            }
+                        }
+                    }
+                    Ok((frame, _)) => (Vec::new(), Err(lsftp_core::error::Error::Auth(format!(
+                        "Expected authentication response, got {:?}", frame.message_type
+                    )))),
+                    Err(e) => (Vec::new(), Err(e)),
+                }
+            }
+            Err(e) => (Vec::new(), Err(e)),
+        };
+
+        // The TLS client certificate and the hardware devices must name the same user
+        let tls_identity = session.and_then(|session| session.peer_identity);
+        let outcome = match (outcome, tls_identity) {
+            (Ok(Some(result)), Some(tls_user)) if result.user_id.as_deref() != Some(tls_user.as_str()) => {
+                Err(lsftp_core::error::Error::Auth(format!(
+                    "TLS client certificate names {}, hardware authentication resolved {}",
+                    tls_user, result.user_id.as_deref().unwrap_or("nobody")
+                )))
+            }
+            (Ok(Some(mut result)), Some(tls_user)) => {
+                result.metadata.insert("tls_identity".to_string(), tls_user);
+                Ok(Some(result))
+            }
+            // A certificate alone only suffices when no factor policy or hardware requirement
+            // applies to the user; the roles it is judged by are those of their enrollments
+            (Ok(None), Some(tls_user)) => {
+                let mut roles: Vec<String> = enrollments.devices().into_iter()
+                    .filter(|device| device.user_id == tls_user && device.is_active())
+                    .flat_map(|device| device.roles.iter().cloned())
+                    .collect();
+                roles.sort();
+                roles.dedup();
+
+                match state.mfa_policy.required(&tls_user, &roles) {
+                    Some(requirement) => Err(lsftp_core::error::Error::Auth(format!(
+                        "User {} must authenticate with {}, not a TLS client certificate alone", tls_user, requirement
+                    ))),
+                    None if state.cli.require_hardware_auth => Err(lsftp_core::error::Error::Auth(format!(
+                        "User {} presented a TLS client certificate but no hardware device", tls_user
+                    ))),
+                    None => Ok(Some(lsftp_core::AuthResult {
+                        success: true,
+                        user_id: Some(tls_user),
+                        device_id: None,
+                        timestamp: lsftp_core::now_secs(),
+                        metadata: HashMap::from([("factors".to_string(), "tls-certificate".to_string())]),
+                        signature: None,
+                        error: None,
+                    })),
+                }
+            }
+            (outcome, _) => outcome,
+        };
+
+        let (user_id, error) = match &outcome {
+            Ok(result) => (result.as_ref().and_then(|r| r.user_id.clone()), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        if outcome.is_ok() {
+            Self::throttle(auth_throttle, &throttle_keys, AuthThrottle::record_success).await?;
+        } else {
+            for lockout in Self::throttle(auth_throttle, &throttle_keys, AuthThrottle::record_failure).await? {
+                warn!("Locked out {} after {} failed authentications", lockout.key, lockout.failures);
+                security_logger.log_lockout(&lockout.key, lockout.failures, lockout.locked_until).await?;
+            }
+        }
+
+        let audit_result = match &outcome {
+            Ok(Some(result)) => result.clone(),
+            _ => lsftp_core::AuthResult {
+                success: outcome.is_ok(),
+                user_id: user_id.clone(),
+                device_id: device_ids.first().cloned(),
+                timestamp: lsftp_core::now_secs(),
+                metadata: HashMap::new(),
+                signature: None,
+                error: error.clone(),
+            },
+        };
+        security_logger.log_auth_result(&audit_result, source_ip.clone()).await?;
+
+        // Every hardware-backed success, resumed or not, gets a fresh single-use ticket that
+        // expires one lifetime after the hardware authentication, not after this resumption
+        let ticket = match (&outcome, &state.ticket_issuer) {
+            (Ok(Some(result)), Some(issuer)) if !device_ids.is_empty() => match issuer.issue(result, &device_ids, resumed_from.unwrap_or(result.timestamp)) {
+                Ok(ticket) => Some(ticket),
+                Err(e) => {
+                    warn!("Not issuing a resumption ticket for session {}: {}", session_id, e);
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let status_message = Message::new(MessageType::AuthStatus, Some(
+            MessagePayload::AuthStatus(lsftp_core::protocol::AuthStatusPayload {
+                session_id,
+                authenticated: outcome.is_ok(),
+                user_id,
+                error,
+                ticket_expires_at: ticket.as_ref().map(|ticket| ticket.expires_at),
+                resumption_ticket: ticket.map(|ticket| ticket.ticket),
+                kem_ciphertext: match (&outcome, &negotiated) {
+                    (Ok(_), Some((_, ciphertext, _))) => Some(ciphertext.clone()),
+                    _ => None,
+                },
+                // A resumed client signed nothing, so it learns here which devices to watch
+                devices: match &outcome {
+                    Ok(_) => device_ids.iter()
+                        .filter_map(|device_id| enrollments.get(device_id))
+                        .map(|device| SessionDevice { device_type: device.device_type, device_id: device.device_id.clone() })
+                        .collect(),
+                    Err(_) => Vec::new(),
+                },
+            })
+        ))?;
+        server.send_to_session(session_id, status_message).await?;
+
+        match outcome {
+            Ok(result) => {
+                let factors = result.as_ref().and_then(|r| r.metadata.get("factors").cloned());
+                info!("Session {} authenticated (factors: {})", session_id, factors.as_deref().unwrap_or("none"));
+                server.handle_session(session_id).await?;
+                let user_id = result.and_then(|r| r.user_id);
+                let (crypto_suite, _, kem_secret) = negotiated.ok_or_else(|| lsftp_core::error::Error::Protocol(
+                    "Authentication response declared no crypto suite".to_string()
+                ))?;
+                Ok((Subject::resolve(user_id, &device_ids, &enrollments, source_ip.as_deref()), crypto_suite, kem_secret))
+            }
+            Err(e) => {
+                warn!("Session {} failed authentication: {}", session_id, e);
+                server.close_session(session_id).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Run a throttle operation on the blocking pool, since it reads and rewrites the state file
+    async fn throttle<T, F>(auth_throttle: &Arc<AuthThrottle>, keys: &[String], operation: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&AuthThrottle, &[String]) -> Result<T> + Send + 'static,
+    {
+        let auth_throttle = auth_throttle.clone();
+        let keys = keys.to_vec();
+        tokio::task::spawn_blocking(move || operation(&auth_throttle, &keys)).await
+            .map_err(|e| lsftp_core::error::Error::Auth(format!("Lockout state task failed: {}", e)))?
+    }
+
+    /// Run a quota ledger update on the blocking pool, since it writes the ledger journal
+    async fn update_ledger<F>(quota: &Arc<QuotaManager>, operation: F) -> Result<()>
+    where
+        F: FnOnce(&QuotaManager) -> Result<()> + Send + 'static,
+    {
+        let quota = quota.clone();
+        tokio::task::spawn_blocking(move || operation(&quota)).await
+            .map_err(|e| lsftp_core::error::Error::File(format!("Quota ledger task failed: {}", e)))?
+    }
+
+    /// Tell the client a request was refused; unless `fatal`, the session carries on
+    async fn refuse(session: &ActiveSession, file_id: Option<Uuid>, code: &str, message: String, fatal: bool) -> Result<()> {
+        let refusal = Message::new(MessageType::Error, Some(
+            MessagePayload::Error(ErrorPayload { session_id: session.id, file_id, code: code.to_string(), message, fatal })
+        ))?;
+        session.server.send_to_session(session.id, refusal).await
+    }
+
+    /// Refuse, report and audit traffic that falls short of the encryption
+    /// requirements; returns whether the traffic may proceed
+    async fn enforce_encryption(
+        state: &ServerState,
+        session: &ActiveSession,
+        file_id: Option<Uuid>,
+        check: std::result::Result<(), EncryptionViolation>,
+        fatal: bool,
+    ) -> Result<bool> {
+        let Err(violation) = check else {
+            return Ok(true);
+        };
+
+        warn!("Session {} refused: {}", session.id, violation);
+        state.security_logger.log_encryption_violation(session.id, session.subject.user_id.clone(), &violation).await?;
+        Self::refuse(session, file_id, violation.code(), violation.to_string(), fatal).await?;
+        Ok(false)
+    }
+
+    /// Check an operation against the access policy, audit the decision and
+    /// report a denial to the client; returns whether the operation may proceed.
+    /// Allowed chunk writes are covered by the record made at open
+    async fn authorize(
+        state: &ServerState,
+        session: &ActiveSession,
+        file_id: Uuid,
+        request: &AccessRequest<'_>,
+        audit_allowed: bool,
+    ) -> Result<bool> {
+        let decision = state.access_policy.evaluate(&session.subject, request);
+        if !decision.allowed || audit_allowed {
+            state.security_logger.log_access_decision(session.id, session.subject.user_id.clone(), request.action, request.path, &decision).await?;
+        }
+        if decision.allowed {
+            return Ok(true);
+        }
+
+        warn!("Session {} denied {} {} by rule {}", session.id, request.action, request.path, decision.rule);
+        if let Err(e) = decision.into_result(request) {
+            Self::refuse(session, Some(file_id), ACCESS_DENIED, e.to_string(), false).await?;
+        }
+        Ok(false)
+    }
+
+    /// Handle file open request: uploads open a file session, downloads,
+    /// listings and deletions are carried out at once
+    async fn handle_file_open(state: &ServerState, session: &mut ActiveSession, payload: FileOpenPayload) -> Result<()> {
+        info!("File {} request: {} ({} bytes)", payload.operation, payload.path, payload.size);
+        let cli = &state.cli;
+
+        // Paths are normalized and resolved inside the user's view before they are
+        // authorized, so `..` can neither leave the view nor dodge a path rule
+        let resolved = session.view.resolve(&payload.path)?;
+        if matches!(payload.operation, FileAction::Write | FileAction::Delete) {
+            resolved.check_writable()?;
+        }
+        let path = resolved.virtual_path;
+        let file_path = resolved.server_path;
+
+        let size = match payload.operation {
+            FileAction::Write => Some(payload.size),
+            FileAction::Read | FileAction::Delete => tokio::fs::metadata(&file_path).await.ok().map(|m| m.len()),
+            FileAction::List => None,
+        };
+        // Paths and classification labels may demand a stronger suite than the session minimum
+        let classification = state.access_policy.classification(&path);
+        // Refusals are reported to the client and the session carries on
+        let check = state.encryption.read().await.check_path(session.key_schedule.crypto_suite(), &path, classification);
+        if !Self::enforce_encryption(state, session, Some(payload.file_id), check, false).await? {
+            return Ok(());
+        }
+
+        let request = AccessRequest::new(payload.operation, &path, size);
+        if !Self::authorize(state, session, payload.file_id, &request, true).await? {
+            return Ok(());
+        }
+
+        match payload.operation {
+            FileAction::Write => {}
+            FileAction::Read => return Self::send_file(state, session, payload, &file_path).await,
+            FileAction::List => {
+                let mount_points = session.view.mount_points(&path)?;
+                return Self::send_listing(session, payload, &file_path, mount_points).await;
+            }
+            FileAction::Delete => return Self::delete_file(state, session, payload, &file_path).await,
+        }
 
         // Validate file size
         if payload.size > cli.max_file_size {
@@ -195,12 +900,27 @@ impl LsftpServer {
             return Err(lsftp_core::error::Error::File(error_msg));
         }
 
-        // Create file path
-        let file_path = cli.root_dir.join(&payload.path);
-        
-        // Ensure path is within root directory (security check)
-        if !file_path.starts_with(&cli.root_dir) {
-            return Err(lsftp_core::error::Error::File("Path traversal attack detected".to_string()));
+        // File IDs are chosen by the client; one already open in this session is refused
+        // rather than replacing the upload it belongs to
+        let upload = (session.id, payload.file_id);
+        if state.file_sessions.read().await.contains_key(&upload) {
+            return Err(lsftp_core::error::Error::File(format!("File {} is already open", payload.file_id)));
+        }
+
+        // Reserve the declared size; soft-limit warnings go back with the acknowledgment
+        let mut metadata = payload.metadata;
+        if let Some(quota) = &state.quota {
+            let warnings = match quota.reserve(session.id, payload.file_id, &file_path, subject_keys(&session.subject), payload.size) {
+                Ok(warnings) => warnings,
+                Err(e) => {
+                    warn!("Session {} refused upload of {}: {}", session.id, path, e);
+                    return Self::refuse(session, Some(payload.file_id), QUOTA_EXCEEDED, e.to_string(), false).await;
+                }
+            };
+            if !warnings.is_empty() {
+                warn!("Session {}: {}", session.id, warnings.join("; "));
+                metadata.insert("quota_warning".to_string(), warnings.join("; "));
+            }
         }
 
         // Create parent directories
@@ -209,55 +929,301 @@ impl LsftpServer {
                 .map_err(|e| lsftp_core::error::Error::File(format!("Failed to create directory: {}", e)))?;
         }
 
+        // Negotiate the transfer hash: honor the client's choice when accepted,
+        // otherwise fall back to the server's preferred algorithm
+        let hash_algorithm = Self::negotiate_hash(payload.hash_algorithm, &cli.transfer_hashes)?;
+
+        // Derive the file key; fails if the ID was already used in this session
+        session.key_schedule.open_file(payload.file_id)?;
+
         // Create file session
         let file_session = FileSession::new(
-            Uuid::new_v4(),
-            payload.path.clone(),
+            path,
+            file_path,
             payload.size,
+            TransferHasher::new(hash_algorithm, payload.compliance_sha256),
         );
 
         // Store file session
-        let mut sessions = file_sessions.write().await;
-        sessions.insert(file_session.file_id, file_session);
+        state.file_sessions.write().await.insert(upload, file_session);
 
-        info!("File session created: {} for {}", file_session.file_id, payload.path);
+        info!("File session created: {} for {}", payload.file_id, payload.path);
 
         // Send acknowledgment
         let ack_message = Message::new(MessageType::FileOpen, Some(
             MessagePayload::FileOpen(FileOpenPayload {
+                file_id: payload.file_id,
+                operation: payload.operation,
                 path: payload.path,
                 size: payload.size,
-                hash: [0u8; 32],
+                hash_algorithm,
+                compliance_sha256: payload.compliance_sha256,
+                hash: payload.hash,
                 permissions: payload.permissions,
-                metadata: payload.metadata,
+                metadata,
             })
         ))?;
 
-        server.send_to_session(session_id, ack_message).await?;
+        session.server.send_to_session(session.id, ack_message).await?;
 
         Ok(())
     }
 
-    /// Handle file data
-    async fn handle_file_data(
-        server: &QuicServerTransport,
-        session_id: Uuid,
-        payload: FileDataPayload,
-        file_sessions: &Arc<RwLock<HashMap<Uuid, FileSession>>>,
-        cli: &Cli,
+    /// Stream a file to the client under its per-file key, then close it with the digests
+    async fn send_file(state: &ServerState, session: &mut ActiveSession, payload: FileOpenPayload, file_path: &Path) -> Result<()> {
+        let start_time = std::time::Instant::now();
+        let mut file = File::open(file_path).await
+            .map_err(|e| lsftp_core::error::Error::File(format!("Failed to open file: {}", e)))?;
+        let size = file.metadata().await
+            .map_err(|e| lsftp_core::error::Error::File(format!("Failed to get file metadata: {}", e)))?
+            .len();
+
+        let hash_algorithm = Self::negotiate_hash(payload.hash_algorithm, &state.cli.transfer_hashes)?;
+        session.key_schedule.open_file(payload.file_id)?;
+
+        let ack_message = Message::new(MessageType::FileOpen, Some(
+            MessagePayload::FileOpen(FileOpenPayload {
+                file_id: payload.file_id,
+                operation: payload.operation,
+                path: payload.path.clone(),
+                size,
+                hash_algorithm,
+                compliance_sha256: payload.compliance_sha256,
+                hash: None,
+                permissions: payload.permissions,
+                metadata: HashMap::new(),
+            })
+        ))?;
+        session.server.send_to_session(session.id, ack_message).await?;
+
+        let mut hasher = TransferHasher::new(hash_algorithm, payload.compliance_sha256);
+        let mut buffer = vec![0u8; lsftp_core::DEFAULT_CHUNK_SIZE];
+        let mut chunks_count = 0u32;
+        let mut total_bytes = 0u64;
+
+        loop {
+            let bytes_read = file.read(&mut buffer).await
+                .map_err(|e| lsftp_core::error::Error::File(format!("Failed to read file: {}", e)))?;
+            if bytes_read == 0 {
+                break;
+            }
+
+            tokio::time::sleep(state.rate_limiter.delay(session.id, Direction::Download, bytes_read as u64)).await;
+
+            let chunk_data = &buffer[..bytes_read];
+            hasher.update(chunk_data);
+
+            let encrypted = session.key_schedule.file_key(&payload.file_id)?
+                .encrypt_chunk(session.key_schedule.crypto_suite(), chunks_count, chunk_data)?;
+            let data_message = Message::new(MessageType::FileData, Some(
+                MessagePayload::FileData(FileDataPayload {
+                    file_id: payload.file_id,
+                    chunk_index: chunks_count,
+                    data: encrypted,
+                    chunk_hash: TaggedDigest::compute(hash_algorithm, chunk_data),
+                    chunk_signature: vec![],
+                })
+            ))?;
+            session.server.send_to_session(session.id, data_message).await?;
+
+            chunks_count += 1;
+            total_bytes += bytes_read as u64;
+        }
+
+        session.key_schedule.erase_file_key(&payload.file_id);
+        info!("File read completed: {} ({} bytes, {} chunks)", payload.path, total_bytes, chunks_count);
+
+        let duration_ms = start_time.elapsed().as_millis() as u64;
+        let close_message = Message::new(MessageType::FileClose, Some(
+            MessagePayload::FileClose(FileClosePayload {
+                file_id: payload.file_id,
+                final_hash: hasher.digest(),
+                compliance_hash: hasher.compliance_digest(),
+                global_signature: vec![],
+                statistics: lsftp_core::protocol::TransferStatistics {
+                    bytes_transferred: total_bytes,
+                    duration_ms,
+                    throughput_bps: if duration_ms > 0 { total_bytes * 1000 / duration_ms } else { 0 },
+                    chunks_count,
+                    retries_count: 0,
+                },
+            })
+        ))?;
+        session.server.send_to_session(session.id, close_message).await?;
+
+        Ok(())
+    }
+
+    /// Send a directory's entries, each name mapped to "file" or "dir" in the acknowledgment
+    /// metadata; mounts inside the directory are listed even where it does not exist on disk
+    async fn send_listing(
+        session: &ActiveSession,
+        payload: FileOpenPayload,
+        dir_path: &Path,
+        mount_points: Vec<String>,
     ) -> Result<()> {
-        let mut sessions = file_sessions.write().await;
+        let mut listing = HashMap::new();
+        match tokio::fs::read_dir(dir_path).await {
+            Ok(mut entries) => {
+                while let Some(entry) = entries.next_entry().await
+                    .map_err(|e| lsftp_core::error::Error::File(format!("Failed to list directory: {}", e)))? {
+                    let is_dir = entry.file_type().await.map(|file_type| file_type.is_dir()).unwrap_or(false);
+                    listing.insert(
+                        entry.file_name().to_string_lossy().to_string(),
+                        if is_dir { "dir" } else { "file" }.to_string(),
+                    );
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !mount_points.is_empty() => {}
+            Err(e) => return Err(lsftp_core::error::Error::File(format!("Failed to list directory: {}", e))),
+        }
+        listing.extend(mount_points.into_iter().map(|name| (name, "dir".to_string())));
+
+        let ack_message = Message::new(MessageType::FileOpen, Some(
+            MessagePayload::FileOpen(FileOpenPayload {
+                size: listing.len() as u64,
+                metadata: listing,
+                ..payload
+            })
+        ))?;
+        session.server.send_to_session(session.id, ack_message).await?;
+
+        Ok(())
+    }
+
+    /// Delete a file, credit its quota and acknowledge
+    async fn delete_file(state: &ServerState, session: &ActiveSession, payload: FileOpenPayload, file_path: &Path) -> Result<()> {
+        tokio::fs::remove_file(file_path).await
+            .map_err(|e| lsftp_core::error::Error::File(format!("Failed to delete file: {}", e)))?;
+        info!("File deleted: {}", payload.path);
+
+        if let Some(quota) = &state.quota {
+            let file_path = file_path.to_path_buf();
+            Self::update_ledger(quota, move |quota| quota.remove_file(&file_path)).await?;
+        }
+
+        let ack_message = Message::new(MessageType::FileOpen, Some(MessagePayload::FileOpen(payload)))?;
+        session.server.send_to_session(session.id, ack_message).await?;
+
+        Ok(())
+    }
+
+    /// Apply a live policy update from an administrator and echo it back as the
+    /// acknowledgment; rules take effect in order
+    async fn handle_policy_update(state: &ServerState, session: &ActiveSession, payload: PolicyUpdatePayload) -> Result<()> {
+        let is_admin = state.cli.policy_admin_role.as_ref().is_some_and(|role| session.subject.roles.contains(role));
+        let live = |rule_type: PolicyRuleType| matches!(rule_type, PolicyRuleType::RateLimit | PolicyRuleType::EncryptionRequirement);
+        let result = if !is_admin {
+            Err(lsftp_core::error::Error::Auth("Policy updates require the administrator role".to_string()))
+        } else if let Some(rule) = payload.rules.iter().find(|rule| !live(rule.rule_type)) {
+            Err(lsftp_core::error::Error::Config(format!(
+                "Rule {} of type {:?} cannot be changed live", rule.id, rule.rule_type
+            )))
+        } else {
+            // Rules are staged and swapped in together, so a rejected update changes nothing
+            let mut encryption = state.encryption.write().await;
+            let mut staged = encryption.clone();
+            let (encryption_rules, rate_rules): (Vec<_>, Vec<_>) = payload.rules.iter()
+                .cloned()
+                .partition(|rule| rule.rule_type == PolicyRuleType::EncryptionRequirement);
+            encryption_rules.iter().try_for_each(|rule| staged.apply_rule(rule))
+                .and_then(|()| state.rate_limiter.apply_rules(&rate_rules))
+                .map(|()| *encryption = staged)
+        };
+
+        state.security_logger.log_policy_update(session.id, session.subject.user_id.clone(), &payload, result.is_ok()).await?;
+        if let Err(e) = result {
+            warn!("Session {} policy update {} rejected: {}", session.id, payload.policy_id, e);
+            return Err(e);
+        }
+        info!("Policy update {} v{} applied: {} rules", payload.policy_id, payload.version, payload.rules.len());
+
+        let ack_message = Message::new(MessageType::PolicyUpdate, Some(MessagePayload::PolicyUpdate(payload)))?;
+        session.server.send_to_session(session.id, ack_message).await?;
+
+        Ok(())
+    }
+
+    /// Report the storage used by the session's user and each of their groups
+    async fn send_quota_report(state: &ServerState, session: &ActiveSession) -> Result<()> {
+        let usage = match &state.quota {
+            Some(quota) => quota.report(&subject_keys(&session.subject))?,
+            None => Vec::new(),
+        };
+
+        let message = Message::new(MessageType::Quota, Some(
+            MessagePayload::Quota(QuotaPayload { session_id: session.id, usage })
+        ))?;
+        session.server.send_to_session(session.id, message).await?;
+
+        Ok(())
+    }
+
+    /// Pick the transfer hash algorithm for a file
+    fn negotiate_hash(requested: HashAlgorithm, accepted: &[HashAlgorithm]) -> Result<HashAlgorithm> {
+        if accepted.contains(&requested) {
+            return Ok(requested);
+        }
+
+        accepted.first().copied().ok_or_else(|| {
+            lsftp_core::error::Error::Config("No transfer hash algorithms configured".to_string())
+        })
+    }
+
+    /// Handle file data
+    async fn handle_file_data(state: &ServerState, session: &mut ActiveSession, payload: FileDataPayload) -> Result<()> {
+        // Throttle before taking the shared session table, so a slow upload holds up no one else
+        tokio::time::sleep(state.rate_limiter.delay(session.id, Direction::Upload, payload.data.len() as u64)).await;
+
+        let mut sessions = state.file_sessions.write().await;
         
-        let file_session = sessions.get_mut(&payload.file_id)
+        let file_session = sessions.get_mut(&(session.id, payload.file_id))
             .ok_or_else(|| lsftp_core::error::Error::File("File session not found".to_string()))?;
 
-        // Open file if not already open
+        // Decrypt the chunk with the per-file key
+        let data = session.key_schedule.file_key(&payload.file_id)?
+            .decrypt_chunk(session.key_schedule.crypto_suite(), payload.chunk_index, &payload.data)?;
+
+        // Verify chunk integrity with the negotiated algorithm
+        let chunk_hash = TaggedDigest::compute(file_session.hasher.algorithm(), &data);
+        if !chunk_hash.matches(&payload.chunk_hash) {
+            return Err(lsftp_core::error::Error::File(format!(
+                "Chunk {} integrity check failed", payload.chunk_index
+            )));
+        }
+
+        // Every write is authorized again: time windows close and size limits are crossed mid-transfer
+        let written = file_session.total_bytes + data.len() as u64;
+        let request = AccessRequest::new(FileAction::Write, &file_session.file_path, Some(written.max(file_session.file_size)));
+        let mut allowed = Self::authorize(state, session, payload.file_id, &request, false).await?;
+        if let Some(quota) = state.quota.as_ref().filter(|_| allowed) {
+            if let Err(e) = quota.extend(session.id, payload.file_id, written) {
+                warn!("Session {} refused upload of {}: {}", session.id, file_session.file_path, e);
+                Self::refuse(session, Some(payload.file_id), QUOTA_EXCEEDED, e.to_string(), false).await?;
+                allowed = false;
+            }
+        }
+
+        // A refused upload is abandoned; the session carries on
+        if !allowed {
+            let abandoned = sessions.remove(&(session.id, payload.file_id));
+            drop(sessions);
+            if let Some(file_session) = abandoned {
+                Self::remove_partial(&file_session).await;
+            }
+            session.key_schedule.erase_file_key(&payload.file_id);
+            if let Some(quota) = &state.quota {
+                quota.release(session.id, payload.file_id);
+            }
+            return Ok(());
+        }
+
+        // Open the partial file if not already open; the destination stays untouched until close
         if file_session.file_handle.is_none() {
-            let file_path = cli.root_dir.join(&file_session.file_path);
             let file = OpenOptions::new()
-                .create(true)
+                .create_new(true)
                 .write(true)
-                .open(&file_path).await
+                .open(&file_session.upload_path).await
                 .map_err(|e| lsftp_core::error::Error::File(format!("Failed to open file: {}", e)))?;
             
             file_session.file_handle = Some(file);
@@ -265,16 +1231,16 @@ impl LsftpServer {
 
         // Write chunk to file
         if let Some(file) = &mut file_session.file_handle {
-            file.write_all(&payload.data).await
+            file.write_all(&data).await
                 .map_err(|e| lsftp_core::error::Error::File(format!("Failed to write file: {}", e)))?;
         }
 
         // Update session statistics
         file_session.chunks_received += 1;
-        file_session.total_bytes += payload.data.len() as u64;
-        file_session.hasher.update(&payload.data);
+        file_session.total_bytes += data.len() as u64;
+        file_session.hasher.update(&data);
 
-        if cli.verbose && file_session.chunks_received % 10 == 0 {
+        if state.cli.verbose && file_session.chunks_received % 10 == 0 {
             info!("File {}: {} chunks, {} bytes", 
                 file_session.file_path, file_session.chunks_received, file_session.total_bytes);
         }
@@ -290,45 +1256,65 @@ impl LsftpServer {
             })
         ))?;
 
-        server.send_to_session(session_id, ack_message).await?;
+        session.server.send_to_session(session.id, ack_message).await?;
 
         Ok(())
     }
 
     /// Handle file close
-    async fn handle_file_close(
-        server: &QuicServerTransport,
-        session_id: Uuid,
-        payload: FileClosePayload,
-        file_sessions: &Arc<RwLock<HashMap<Uuid, FileSession>>>,
-        cli: &Cli,
-    ) -> Result<()> {
-        let mut sessions = file_sessions.write().await;
-        
-        let file_session = sessions.remove(&payload.file_id)
+    async fn handle_file_close(state: &ServerState, session: &mut ActiveSession, payload: FileClosePayload) -> Result<()> {
+        let session_id = session.id;
+        // The upload leaves the shared table at once; nothing below holds up other sessions
+        let mut file_session = state.file_sessions.write().await.remove(&(session_id, payload.file_id))
             .ok_or_else(|| lsftp_core::error::Error::File("File session not found".to_string()))?;
 
-        // Close file handle
-        if let Some(mut file) = file_session.file_handle {
-            file.flush().await
-                .map_err(|e| lsftp_core::error::Error::File(format!("Failed to flush file: {}", e)))?;
-        }
+        // The file key is no longer needed; erase it before anything can fail
+        session.key_schedule.erase_file_key(&payload.file_id);
+        let quota = state.quota.as_ref();
 
-        // Verify final hash
-        let final_hash = file_session.hasher.finalize();
-        if final_hash.as_bytes() != &payload.final_hash {
-            return Err(lsftp_core::error::Error::File("File integrity check failed".to_string()));
+        // A transfer that fails its checks leaves the destination as it was
+        let written = file_session.file_handle.is_some();
+        let (final_hash, compliance_hash) = match Self::finish_upload(&mut file_session, &payload).await {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                Self::remove_partial(&file_session).await;
+                if let Some(quota) = quota {
+                    quota.release(session_id, payload.file_id);
+                }
+                return Err(e);
+            }
+        };
+
+        // Charge the file now in place; an upload that wrote nothing created no file
+        if let Some(quota) = quota {
+            if written {
+                let (file_id, bytes) = (payload.file_id, file_session.total_bytes);
+                Self::update_ledger(quota, move |quota| quota.commit(session_id, file_id, bytes)).await?;
+            } else {
+                quota.release(session_id, payload.file_id);
+            }
         }
 
         info!("File transfer completed: {} ({} bytes, {} chunks)", 
             file_session.file_path, file_session.total_bytes, file_session.chunks_received);
 
+        // PKCS#11 calls block until the HSM answers; keep them off the runtime
+        let global_signature = match &state.file_signer {
+            Some(signer) => {
+                let (signer, file_id, digest) = (signer.clone(), payload.file_id, final_hash.clone());
+                tokio::task::spawn_blocking(move || signer.sign_file(&file_id, &digest)).await
+                    .map_err(|e| lsftp_core::error::Error::HardwareAuth(format!("File signing task failed: {}", e)))??
+            }
+            None => vec![],
+        };
+
         // Send final acknowledgment
         let final_message = Message::new(MessageType::FileClose, Some(
             MessagePayload::FileClose(FileClosePayload {
                 file_id: payload.file_id,
-                final_hash: payload.final_hash,
-                global_signature: vec![], // Will be signed
+                final_hash,
+                compliance_hash,
+                global_signature,
                 statistics: lsftp_core::protocol::TransferStatistics {
                     bytes_transferred: file_session.total_bytes,
                     duration_ms: 0, // Will be calculated
@@ -339,10 +1325,70 @@ impl LsftpServer {
             })
         ))?;
 
-        server.send_to_session(session_id, final_message).await?;
+        session.server.send_to_session(session_id, final_message).await?;
 
         Ok(())
     }
+
+    /// Flush the partial file to disk, verify the transfer against the client's
+    /// digests and move the file into place; returns the final and compliance digests
+    async fn finish_upload(
+        file_session: &mut FileSession,
+        payload: &FileClosePayload,
+    ) -> Result<(TaggedDigest, Option<TaggedDigest>)> {
+        let written = file_session.file_handle.is_some();
+        if let Some(mut file) = file_session.file_handle.take() {
+            file.flush().await
+                .map_err(|e| lsftp_core::error::Error::File(format!("Failed to flush file: {}", e)))?;
+            file.sync_all().await
+                .map_err(|e| lsftp_core::error::Error::File(format!("Failed to sync file: {}", e)))?;
+        }
+
+        // Verify final hash
+        let final_hash = file_session.hasher.digest();
+        if !final_hash.matches(&payload.final_hash) {
+            return Err(lsftp_core::error::Error::File("File integrity check failed".to_string()));
+        }
+
+        // Verify the SHA-256 compliance digest when one was requested
+        let compliance_hash = file_session.hasher.compliance_digest();
+        if let Some(expected) = &compliance_hash {
+            let matches = payload.compliance_hash.as_ref()
+                .map(|actual| expected.matches(actual))
+                .unwrap_or(false);
+            if !matches {
+                return Err(lsftp_core::error::Error::File("SHA-256 compliance digest check failed".to_string()));
+            }
+        }
+
+        if written {
+            tokio::fs::rename(&file_session.upload_path, &file_session.server_path).await
+                .map_err(|e| lsftp_core::error::Error::File(format!("Failed to move upload into place: {}", e)))?;
+        }
+        Ok((final_hash, compliance_hash))
+    }
+
+    /// Delete the partial file of an upload that will not complete
+    async fn remove_partial(file_session: &FileSession) {
+        if let Err(e) = tokio::fs::remove_file(&file_session.upload_path).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove partial upload {:?}: {}", file_session.upload_path, e);
+            }
+        }
+    }
+
+    /// Drop the uploads a finished session left open, deleting their partial files
+    async fn discard_uploads(state: &ServerState, session_id: Uuid) {
+        let abandoned: Vec<FileSession> = {
+            let mut sessions = state.file_sessions.write().await;
+            let ids: Vec<(Uuid, Uuid)> = sessions.keys().filter(|(session, _)| *session == session_id).copied().collect();
+            ids.iter().filter_map(|id| sessions.remove(id)).collect()
+        };
+        for file_session in abandoned {
+            info!("Discarding unfinished upload of {}", file_session.file_path);
+            Self::remove_partial(&file_session).await;
+        }
+    }
 }
 
 #[tokio::main]
@@ -364,8 +1410,23 @@ async fn main() -> Result<()> {
     info!("Version: 1.0");
     info!("Author: Jérémy Noverraz - 1988");
 
+    // Run cryptographic power-on self-tests before accepting traffic; the session
+    // minimum can be lowered live, so every suite a client may declare is tested
+    let reports: Vec<_> = lsftp_core::selftest::accepted_suites(lsftp_core::encryption::SecurityLevel::Classical)
+        .iter()
+        .map(lsftp_core::selftest::run_power_on_self_tests)
+        .collect();
+    if !reports.iter().all(lsftp_core::SelfTestReport::passed) {
+        error!("Cryptographic self-tests failed; all connections will be refused");
+    }
+
+    // Development-only authenticators need an explicit opt-in
+    if cli.insecure_dev_auth {
+        lsftp_core::auth::HardwareAuthFactory::allow_insecure_dev()?;
+    }
+
     // Create and start server
-    let mut server = LsftpServer::new(cli)?;
+    let mut server = LsftpServer::new(cli).await?;
     server.start().await?;
 
     Ok(())
@@ -1,7 +1,20 @@
 use clap::{Parser, Subcommand};
-use lsftp_core::{Result, crypto::{CryptoSuite, KemAlgorithm, SignatureAlgorithm}};
-use std::path::PathBuf;
+use lsftp_core::{fsutil, Result, SecretBuffer, crypto::{CryptoSuite, KemAlgorithm, SignatureAlgorithm}};
+use lsftp_core::audit::{AuditConfig, AuditLogger, SecurityLogger};
+use lsftp_core::shamir::{self, ShareFile};
+use lsftp_core::auth::{HardwareAttestation, HardwareAuth, HardwareType};
+use lsftp_core::tpmquote::PcrBaseline;
+use lsftp_core::tpmkey::{self, TpmAkConfig, TpmKeyAlgorithm};
+use lsftp_core::pkcs11::{HsmAuth, HsmKeyAlgorithm, Pkcs11Config};
+use lsftp_core::piv::{self, PivAlgorithm, PivConfig, PivPinPolicy, PivSlot, PivTouchPolicy};
+use lsftp_core::tpmseal::{self, SealedKey, TpmSealer};
+use lsftp_core::enrollment::{AttestationKey, DeviceKeyAlgorithm, DeviceStatus, EnrolledDevice, EnrollmentStore, DEFAULT_ENROLLMENT_PATH};
+use lsftp_core::lockout::{self, LockoutStore, DEFAULT_LOCKOUT_STATE_PATH};
+use lsftp_core::quota::{self, QuotaLedger, QuotaPolicy, DEFAULT_QUOTA_STATE_PATH};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::fs;
+use zeroize::Zeroizing;
 use serde::{Serialize, Deserialize};
 use tracing::{info, warn, error};
 
@@ -50,7 +63,7 @@ enum Commands {
 
     /// Manage hardware security devices
     Hardware {
-        /// Hardware device type (tpm, yubikey, smartcard)
+        /// Hardware device type (tpm, yubikey, smartcard, hsm, software)
         #[arg(long)]
         device_type: String,
 
@@ -66,9 +79,45 @@ enum Commands {
         #[arg(long)]
         test: bool,
 
-        /// Device path (for TPM)
+        /// Device path (TPM device, PKCS#11 spec module=...,token=...,key=... for an HSM, or key file for the software authenticator)
         #[arg(long)]
         device_path: Option<String>,
+
+        /// Persistent handle of the TPM attestation key
+        #[arg(long, default_value = "0x81010002")]
+        ak_handle: String,
+
+        /// Algorithm for a newly created TPM attestation key (rsa, ecc)
+        #[arg(long, default_value = "rsa")]
+        ak_algorithm: TpmKeyAlgorithm,
+
+        /// YubiKey serial (required when several YubiKeys are present)
+        #[arg(long)]
+        serial: Option<u32>,
+
+        /// YubiKey PIV slot (9a, 9c or a retired slot 82-95)
+        #[arg(long, default_value = "9a")]
+        slot: PivSlot,
+
+        /// YubiKey PIV key algorithm (rsa2048, p256, p384)
+        #[arg(long, default_value = "p256")]
+        piv_algorithm: PivAlgorithm,
+
+        /// YubiKey PIN policy (never, once, always)
+        #[arg(long, default_value = "once")]
+        pin_policy: PivPinPolicy,
+
+        /// YubiKey touch policy (never, always, cached)
+        #[arg(long, default_value = "cached")]
+        touch_policy: PivTouchPolicy,
+
+        /// Algorithm for a newly generated HSM key (rsa, p256, p384)
+        #[arg(long, default_value = "p256")]
+        hsm_algorithm: HsmKeyAlgorithm,
+
+        /// Write the device attestation (JSON) for device-enroll here
+        #[arg(long)]
+        attestation_out: Option<PathBuf>,
     },
 
     /// Audit and compliance tools
@@ -94,6 +143,285 @@ enum Commands {
         format: String,
     },
 
+    /// Split a root or signing key into Shamir shares
+    KeySplit {
+        /// Key file to back up
+        #[arg(long)]
+        key: PathBuf,
+
+        /// Shares needed to recover the key
+        #[arg(long)]
+        threshold: u8,
+
+        /// Shares to create, one per custodian
+        #[arg(long)]
+        shares: u8,
+
+        /// Directory for the share files
+        #[arg(long)]
+        output_dir: PathBuf,
+
+        /// Audit log path
+        #[arg(long, default_value = "/var/log/lsftp/audit.json")]
+        audit_log: String,
+    },
+
+    /// Recover a key from a quorum of Shamir shares
+    KeyRecover {
+        /// Share files
+        #[arg(long, required = true, num_args = 1..)]
+        shares: Vec<PathBuf>,
+
+        /// Output key file
+        #[arg(long)]
+        output: PathBuf,
+
+        /// Audit log path
+        #[arg(long, default_value = "/var/log/lsftp/audit.json")]
+        audit_log: String,
+    },
+
+    /// Seal the server private key in the TPM under a PCR policy
+    TpmSeal {
+        /// Private key to seal
+        #[arg(long)]
+        key: PathBuf,
+
+        /// Sealed key file to create
+        #[arg(long)]
+        output: PathBuf,
+
+        /// SHA-256 PCRs the policy covers
+        #[arg(long, value_delimiter = ',', default_value = "0,1,2,3,4,5,6,7")]
+        pcrs: Vec<u8>,
+
+        /// Seal to a golden baseline (TOML) instead of the current PCR values
+        #[arg(long)]
+        baseline: Option<PathBuf>,
+
+        /// TPM TCTI (defaults to the TCTI environment variable)
+        #[arg(long)]
+        tcti: Option<String>,
+
+        /// Audit log path
+        #[arg(long, default_value = "/var/log/lsftp/audit.json")]
+        audit_log: String,
+    },
+
+    /// Reseal the server key, e.g. to the PCR values expected after a planned update
+    TpmReseal {
+        /// Sealed key file, replaced in place
+        #[arg(long)]
+        sealed: PathBuf,
+
+        /// Golden baseline (TOML) for the next boot; defaults to the current PCR values
+        #[arg(long)]
+        baseline: Option<PathBuf>,
+
+        /// TPM TCTI (defaults to the TCTI environment variable)
+        #[arg(long)]
+        tcti: Option<String>,
+
+        /// Audit log path
+        #[arg(long, default_value = "/var/log/lsftp/audit.json")]
+        audit_log: String,
+    },
+
+    /// Show a sealed key's PCR policy and whether the current boot satisfies it
+    TpmInspect {
+        /// Sealed key file
+        #[arg(long)]
+        sealed: PathBuf,
+
+        /// TPM TCTI (defaults to the TCTI environment variable)
+        #[arg(long)]
+        tcti: Option<String>,
+    },
+
+    /// Enroll a hardware device for a user
+    DeviceEnroll {
+        /// Device identifier as reported by the device
+        #[arg(long)]
+        device_id: String,
+
+        /// Device type (tpm, yubikey, smartcard, hsm, software)
+        #[arg(long)]
+        device_type: HardwareType,
+
+        /// Signature algorithm (ed25519, ecdsa-p256-sha256, ecdsa-p256-sha256-fixed, ecdsa-p384-sha384, ecdsa-p384-sha384-fixed, rsa-pkcs1-sha256)
+        #[arg(long)]
+        algorithm: DeviceKeyAlgorithm,
+
+        /// Device public key (hex)
+        #[arg(long)]
+        public_key: String,
+
+        /// Attestation produced by the device (JSON); required for YubiKeys and smart cards
+        #[arg(long)]
+        attestation: Option<PathBuf>,
+
+        /// Attestation key public key (hex), e.g. the TPM AK
+        #[arg(long, requires = "attestation_algorithm")]
+        attestation_key: Option<String>,
+
+        /// Attestation key algorithm (ed25519, ecdsa-p256-sha256, ecdsa-p256-sha256-fixed, ecdsa-p384-sha384, ecdsa-p384-sha384-fixed, rsa-pkcs1-sha256)
+        #[arg(long, requires = "attestation_key")]
+        attestation_algorithm: Option<DeviceKeyAlgorithm>,
+
+        /// Host class selecting the golden PCR policy
+        #[arg(long)]
+        host_class: Option<String>,
+
+        /// User the device belongs to
+        #[arg(long)]
+        user: String,
+
+        /// Roles granted to the device's sessions
+        #[arg(long, value_delimiter = ',')]
+        roles: Vec<String>,
+
+        /// Enrollment registry
+        #[arg(long, default_value = DEFAULT_ENROLLMENT_PATH)]
+        registry: PathBuf,
+
+        /// Yubico PIV root CA (PEM) that YubiKey attestations must chain to
+        #[arg(long, default_value = piv::DEFAULT_ATTESTATION_ROOT_PATH)]
+        yubico_root: PathBuf,
+
+        /// Allow enrolling the software authenticator (insecure-dev builds only; never in production)
+        #[arg(long)]
+        insecure_dev: bool,
+
+        /// Audit log path
+        #[arg(long, default_value = "/var/log/lsftp/audit.json")]
+        audit_log: String,
+    },
+
+    /// List enrolled devices
+    DeviceList {
+        /// Only devices of this user
+        #[arg(long)]
+        user: Option<String>,
+
+        /// Enrollment registry
+        #[arg(long, default_value = DEFAULT_ENROLLMENT_PATH)]
+        registry: PathBuf,
+
+        /// Print the devices as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Suspend an enrolled device, or resume it with --resume
+    DeviceSuspend {
+        /// Device identifier
+        #[arg(long)]
+        device_id: String,
+
+        /// Reason for the suspension
+        #[arg(long)]
+        reason: Option<String>,
+
+        /// Reactivate a suspended device
+        #[arg(long)]
+        resume: bool,
+
+        /// Enrollment registry
+        #[arg(long, default_value = DEFAULT_ENROLLMENT_PATH)]
+        registry: PathBuf,
+
+        /// Audit log path
+        #[arg(long, default_value = "/var/log/lsftp/audit.json")]
+        audit_log: String,
+    },
+
+    /// Permanently revoke an enrolled device
+    DeviceRevoke {
+        /// Device identifier
+        #[arg(long)]
+        device_id: String,
+
+        /// Reason for the revocation
+        #[arg(long)]
+        reason: Option<String>,
+
+        /// Enrollment registry
+        #[arg(long, default_value = DEFAULT_ENROLLMENT_PATH)]
+        registry: PathBuf,
+
+        /// Audit log path
+        #[arg(long, default_value = "/var/log/lsftp/audit.json")]
+        audit_log: String,
+    },
+
+    /// List authentication failure counters and lockouts
+    LockoutList {
+        /// Server lockout state file
+        #[arg(long, default_value = DEFAULT_LOCKOUT_STATE_PATH)]
+        state: PathBuf,
+
+        /// Print the records as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Clear authentication lockouts for a source IP, a device, or everything
+    LockoutClear {
+        /// Source IP address
+        #[arg(long, conflicts_with_all = ["device", "all"])]
+        ip: Option<String>,
+
+        /// Device identifier
+        #[arg(long, conflicts_with = "all")]
+        device: Option<String>,
+
+        /// Clear every record
+        #[arg(long)]
+        all: bool,
+
+        /// Server lockout state file
+        #[arg(long, default_value = DEFAULT_LOCKOUT_STATE_PATH)]
+        state: PathBuf,
+
+        /// Audit log path
+        #[arg(long, default_value = "/var/log/lsftp/audit.json")]
+        audit_log: String,
+    },
+
+    /// Report storage usage of users and groups against their quotas
+    QuotaReport {
+        /// Only this user
+        #[arg(long, conflicts_with = "group")]
+        user: Option<String>,
+
+        /// Only this group
+        #[arg(long)]
+        group: Option<String>,
+
+        /// Server quota usage ledger
+        #[arg(long, default_value = DEFAULT_QUOTA_STATE_PATH)]
+        state: PathBuf,
+
+        /// Server quota policy, to show limits next to usage
+        #[arg(long)]
+        policy: Option<PathBuf>,
+
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Run cryptographic power-on self-tests
+    SelfTest {
+        /// Crypto suite to test (classical, hybrid, post_quantum)
+        #[arg(long, default_value = "hybrid")]
+        suite: String,
+
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
     /// System configuration
     Config {
         /// Configuration file path
@@ -161,24 +489,7 @@ impl LsftpTools {
         info!("Generating {} keys with size {}", config.key_type, config.key_size);
 
         // Create crypto suite based on key type
-        let crypto_suite = match config.key_type.as_str() {
-            "classical" => CryptoSuite {
-                kem: KemAlgorithm::EcdheP256,
-                signature: SignatureAlgorithm::Ed25519,
-                ..Default::default()
-            },
-            "hybrid" => CryptoSuite {
-                kem: KemAlgorithm::HybridEcdheP256MlKem768,
-                signature: SignatureAlgorithm::HybridEd25519MlDsa65,
-                ..Default::default()
-            },
-            "post_quantum" => CryptoSuite {
-                kem: KemAlgorithm::MlKem768,
-                signature: SignatureAlgorithm::MlDsa65,
-                ..Default::default()
-            },
-            _ => return Err(lsftp_core::error::Error::Config(format!("Unknown key type: {}", config.key_type))),
-        };
+        let crypto_suite = Self::crypto_suite_for(&config.key_type)?;
 
         // Generate key pair
         let private_key = crypto_suite.generate_key_pair()?;
@@ -203,6 +514,563 @@ impl LsftpTools {
         Ok(())
     }
 
+    /// Map a key type name to its crypto suite
+    fn crypto_suite_for(key_type: &str) -> Result<CryptoSuite> {
+        match key_type {
+            "classical" => Ok(CryptoSuite {
+                kem: KemAlgorithm::EcdheP256,
+                signature: SignatureAlgorithm::Ed25519,
+                ..Default::default()
+            }),
+            "hybrid" => Ok(CryptoSuite {
+                kem: KemAlgorithm::HybridEcdheP256MlKem768,
+                signature: SignatureAlgorithm::HybridEd25519MlDsa65,
+                ..Default::default()
+            }),
+            "post_quantum" => Ok(CryptoSuite {
+                kem: KemAlgorithm::MlKem768,
+                signature: SignatureAlgorithm::MlDsa65,
+                ..Default::default()
+            }),
+            _ => Err(lsftp_core::error::Error::Config(format!("Unknown key type: {}", key_type))),
+        }
+    }
+
+    /// Run cryptographic self-tests and report the results
+    async fn self_test(suite: &str, json: bool) -> Result<()> {
+        let crypto_suite = Self::crypto_suite_for(suite)?;
+        info!("Running cryptographic self-tests for {} suite...", suite);
+
+        let report = lsftp_core::selftest::run_power_on_self_tests(&crypto_suite);
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            for result in &report.results {
+                let status = if result.passed { "PASS" } else { "FAIL" };
+                println!("  [{}] {} ({} us)", status, result.name, result.duration_us);
+                if let Some(error) = &result.error {
+                    println!("         {}", error);
+                }
+            }
+        }
+
+        if !report.passed() {
+            error!("{} self-test(s) failed", report.failures().len());
+            return Err(lsftp_core::error::Error::Crypto("Cryptographic self-tests failed".to_string()));
+        }
+
+        info!("All {} self-tests passed", report.results.len());
+        Ok(())
+    }
+
+    /// Split a key into passphrase-protected share files
+    async fn key_split(key_path: &Path, threshold: u8, shares: u8, output_dir: &Path, audit_log: &str) -> Result<()> {
+        let logger = Self::security_logger(audit_log)?;
+        let key = SecretBuffer::from_vec(fs::read(key_path)
+            .map_err(|e| lsftp_core::error::Error::File(format!("Failed to read key: {}", e)))?)?;
+        let fingerprint = shamir::key_fingerprint(key.as_slice());
+
+        let mut details = HashMap::new();
+        details.insert("key_path".to_string(), key_path.display().to_string());
+        details.insert("threshold".to_string(), threshold.to_string());
+        details.insert("shares".to_string(), shares.to_string());
+
+        let result = Self::write_shares(&key, &fingerprint, threshold, shares, output_dir);
+        logger.log_key_management(
+            "shamir_split",
+            Some(fingerprint.clone()),
+            details,
+            result.is_ok(),
+            result.as_ref().err().map(|e| e.to_string()),
+        ).await?;
+        result?;
+
+        info!("Key {} split into {} shares, {} needed to recover", fingerprint, shares, threshold);
+        info!("  Share files: {:?}", output_dir);
+        Ok(())
+    }
+
+    /// Seal each share under its custodian's passphrase
+    fn write_shares(key: &SecretBuffer, fingerprint: &str, threshold: u8, shares: u8, output_dir: &Path) -> Result<()> {
+        let split = shamir::split(key.as_slice(), threshold, shares)?;
+        let set_id = uuid::Uuid::new_v4();
+
+        fs::create_dir_all(output_dir)
+            .map_err(|e| lsftp_core::error::Error::File(format!("Failed to create output directory: {}", e)))?;
+
+        let mut written = Vec::new();
+        let result: Result<()> = split.iter().try_for_each(|share| {
+            let passphrase = Self::prompt_new_passphrase(&format!("share {} of {}", share.index(), shares))?;
+            let share_file = ShareFile::seal(
+                share,
+                set_id,
+                fingerprint.to_string(),
+                shares,
+                passphrase.as_bytes(),
+                shamir::DEFAULT_KDF_ITERATIONS,
+            )?;
+
+            let path = output_dir.join(format!("share-{}-of-{}.json", share.index(), shares));
+            Self::write_private_file(&path, serde_json::to_string_pretty(&share_file)?.as_bytes())?;
+            info!("  Wrote {:?}", path);
+            written.push(path);
+            Ok(())
+        });
+
+        // An incomplete set cannot be recovered, so do not leave part of one behind
+        if result.is_err() {
+            for path in &written {
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("Failed to remove partial share {:?}: {}", path, e);
+                }
+            }
+        }
+        result
+    }
+
+    /// Reconstruct a key from share files
+    async fn key_recover(share_paths: &[PathBuf], output: &Path, audit_log: &str) -> Result<()> {
+        let logger = Self::security_logger(audit_log)?;
+
+        let mut details = HashMap::new();
+        details.insert("output".to_string(), output.display().to_string());
+        details.insert("shares".to_string(), share_paths.len().to_string());
+
+        let result = Self::recover_shares(share_paths, output);
+        let fingerprint = match &result {
+            Ok(fingerprint) => Some(fingerprint.clone()),
+            Err(_) => Self::read_share_file(&share_paths[0]).ok().map(|file| file.key_fingerprint),
+        };
+        logger.log_key_management(
+            "shamir_recover",
+            fingerprint,
+            details,
+            result.is_ok(),
+            result.as_ref().err().map(|e| e.to_string()),
+        ).await?;
+        let fingerprint = result?;
+
+        info!("Key {} recovered to {:?}", fingerprint, output);
+        Ok(())
+    }
+
+    /// Open each share and combine the quorum
+    fn recover_shares(share_paths: &[PathBuf], output: &Path) -> Result<String> {
+        let files = share_paths.iter()
+            .map(|path| Self::read_share_file(path))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Reject corrupted or mismatched files before asking for any passphrase
+        let first = &files[0];
+        for file in &files {
+            file.verify_checksum()?;
+            if file.set_id != first.set_id || file.key_fingerprint != first.key_fingerprint {
+                return Err(lsftp_core::error::Error::Crypto(format!(
+                    "Share {} belongs to a different key", file.index
+                )));
+            }
+        }
+
+        let shares = files.iter()
+            .map(|file| {
+                let passphrase = Self::prompt_passphrase(&format!("Passphrase for share {} of {}: ", file.index, file.total_shares))?;
+                file.open(passphrase.as_bytes())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let key = shamir::combine(&shares)?;
+        if shamir::key_fingerprint(key.as_slice()) != first.key_fingerprint {
+            return Err(lsftp_core::error::Error::Crypto("Recovered key does not match its fingerprint".to_string()));
+        }
+
+        Self::write_private_file(output, key.as_slice())?;
+        Ok(first.key_fingerprint.clone())
+    }
+
+    /// Load a share file
+    fn read_share_file(path: &Path) -> Result<ShareFile> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| lsftp_core::error::Error::File(format!("Failed to read share {:?}: {}", path, e)))?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    /// Create a new owner-only file, refusing to overwrite
+    fn write_private_file(path: &Path, data: &[u8]) -> Result<()> {
+        if path.exists() {
+            return Err(lsftp_core::error::Error::File(format!("Refusing to overwrite {:?}", path)));
+        }
+        fsutil::write_private(path, data)
+    }
+
+    /// Prompt for a passphrase without echo
+    fn prompt_passphrase(prompt: &str) -> Result<Zeroizing<String>> {
+        rpassword::prompt_password(prompt)
+            .map(Zeroizing::new)
+            .map_err(|e| lsftp_core::error::Error::Config(format!("Failed to read passphrase: {}", e)))
+    }
+
+    /// Prompt for a new passphrase twice
+    fn prompt_new_passphrase(label: &str) -> Result<Zeroizing<String>> {
+        let passphrase = Self::prompt_passphrase(&format!("Custodian passphrase for {}: ", label))?;
+        let confirmation = Self::prompt_passphrase(&format!("Confirm passphrase for {}: ", label))?;
+
+        if passphrase.is_empty() || *passphrase != *confirmation {
+            return Err(lsftp_core::error::Error::Config(format!("Passphrases for {} are empty or do not match", label)));
+        }
+        Ok(passphrase)
+    }
+
+    /// Seal a private key file in the TPM
+    async fn tpm_seal(key_path: &Path, output: &Path, pcrs: &[u8], baseline: Option<&Path>, tcti: Option<&str>, audit_log: &str) -> Result<()> {
+        let logger = Self::security_logger(audit_log)?;
+
+        let mut details = HashMap::new();
+        details.insert("key_path".to_string(), key_path.display().to_string());
+        details.insert("output".to_string(), output.display().to_string());
+        details.insert("pcrs".to_string(), format!("{:?}", pcrs));
+
+        let result = (|| {
+            let key = SecretBuffer::from_vec(fs::read(key_path)
+                .map_err(|e| lsftp_core::error::Error::File(format!("Failed to read key: {}", e)))?)?;
+            let baseline = baseline.map(Self::read_baseline).transpose()?;
+
+            let sealed = TpmSealer::open(tcti)?.seal(key.as_slice(), pcrs, baseline.as_ref())?;
+            Self::write_private_file(output, serde_json::to_string_pretty(&sealed)?.as_bytes())?;
+            Ok::<_, lsftp_core::error::Error>((shamir::key_fingerprint(key.as_slice()), sealed.policy_digest))
+        })();
+        if let Ok((_, policy_digest)) = &result {
+            details.insert("policy_digest".to_string(), policy_digest.clone());
+        }
+
+        logger.log_key_management(
+            "tpm_seal",
+            result.as_ref().ok().map(|(fingerprint, _)| fingerprint.clone()),
+            details,
+            result.is_ok(),
+            result.as_ref().err().map(|e| e.to_string()),
+        ).await?;
+        let (_, policy_digest) = result?;
+
+        info!("Sealed {:?} to PCRs {:?} (policy {})", key_path, pcrs, policy_digest);
+        warn!("Remove the plaintext key {:?} once the server starts from the sealed key", key_path);
+        Ok(())
+    }
+
+    /// Unseal with the current boot state and seal again to a new policy
+    async fn tpm_reseal(sealed_path: &Path, baseline: Option<&Path>, tcti: Option<&str>, audit_log: &str) -> Result<()> {
+        let logger = Self::security_logger(audit_log)?;
+
+        let mut details = HashMap::new();
+        details.insert("sealed".to_string(), sealed_path.display().to_string());
+        if let Some(baseline) = baseline {
+            details.insert("baseline".to_string(), baseline.display().to_string());
+        }
+
+        let result = (|| {
+            let sealed = SealedKey::load(sealed_path)?;
+            let baseline = baseline.map(Self::read_baseline).transpose()?;
+
+            let mut sealer = TpmSealer::open(tcti)?;
+            let key = sealer.unseal(&sealed)?;
+            let resealed = sealer.seal(key.as_slice(), &sealed.pcrs, baseline.as_ref())?;
+
+            // Replaced atomically so a failed write never loses the only sealed copy
+            fsutil::write_private(sealed_path, serde_json::to_string_pretty(&resealed)?.as_bytes())?;
+            Ok::<_, lsftp_core::error::Error>((sealed.policy_digest, resealed.policy_digest))
+        })();
+        if let Ok((previous, current)) = &result {
+            details.insert("previous_policy_digest".to_string(), previous.clone());
+            details.insert("policy_digest".to_string(), current.clone());
+        }
+
+        logger.log_key_management(
+            "tpm_reseal",
+            None,
+            details,
+            result.is_ok(),
+            result.as_ref().err().map(|e| e.to_string()),
+        ).await?;
+        let (_, policy_digest) = result?;
+
+        info!("Resealed {:?} (policy {})", sealed_path, policy_digest);
+        Ok(())
+    }
+
+    /// Print a sealed key's policy and compare it with the current PCRs
+    async fn tpm_inspect(sealed_path: &Path, tcti: Option<&str>) -> Result<()> {
+        let sealed = SealedKey::load(sealed_path)?;
+
+        println!("Sealed key: {:?}", sealed_path);
+        println!("  Version:       {}", sealed.version);
+        println!("  PCRs (SHA-256): {:?}", sealed.pcrs);
+        println!("  Policy digest: {}", sealed.policy_digest);
+        println!("  Sealed at:     {}", sealed.sealed_at);
+        println!("  Baseline:      {}", sealed.baseline.as_deref().unwrap_or("current PCR values"));
+
+        let mut sealer = match TpmSealer::open(tcti) {
+            Ok(sealer) => sealer,
+            Err(e) => {
+                warn!("TPM unavailable, cannot compare with the current boot: {}", e);
+                return Ok(());
+            }
+        };
+
+        let current = sealer.read_pcrs(&sealed.pcrs)?;
+        for (index, value) in &current {
+            println!("  PCR{:<2} {}", index, hex::encode(value));
+        }
+        let current_digest = hex::encode(tpmseal::pcr_policy_digest(&current)?);
+        if current_digest == sealed.policy_digest {
+            println!("  Current boot satisfies the policy");
+        } else {
+            println!("  Current boot does NOT satisfy the policy (current digest {})", current_digest);
+        }
+        Ok(())
+    }
+
+    /// Read a golden PCR baseline
+    fn read_baseline(path: &Path) -> Result<PcrBaseline> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| lsftp_core::error::Error::File(format!("Failed to read baseline: {}", e)))?;
+        Ok(toml::from_str(&data)?)
+    }
+
+    /// Enroll a device in the registry
+    async fn device_enroll(device: EnrolledDevice, attestation: Option<&Path>, yubico_root: &Path, registry: &Path, insecure_dev: bool, audit_log: &str) -> Result<()> {
+        let logger = Self::security_logger(audit_log)?;
+
+        let mut details = HashMap::new();
+        details.insert("device_id".to_string(), device.device_id.clone());
+        details.insert("device_type".to_string(), format!("{:?}", device.device_type));
+        details.insert("user_id".to_string(), device.user_id.clone());
+        details.insert("roles".to_string(), device.roles.join(","));
+        if let Some(host_class) = &device.host_class {
+            details.insert("host_class".to_string(), host_class.clone());
+        }
+
+        let summary = format!("{:?} device {} for {}", device.device_type, device.device_id, device.user_id);
+        let result = Self::enroll_device(device, attestation, yubico_root, registry, insecure_dev);
+        logger.log_key_management(
+            "device_enroll",
+            None,
+            details,
+            result.is_ok(),
+            result.as_ref().err().map(|e| e.to_string()),
+        ).await?;
+        result?;
+
+        info!("Enrolled {}", summary);
+        Ok(())
+    }
+
+    /// Check the attestation and add the device to the registry
+    fn enroll_device(mut device: EnrolledDevice, attestation: Option<&Path>, yubico_root: &Path, registry: &Path, insecure_dev: bool) -> Result<()> {
+        // The server refuses to start with an active software authenticator unless it opted in too
+        lsftp_core::auth::HardwareAuthFactory::validate_config(&[device.device_type], insecure_dev)?;
+
+        // Without an attestation nothing ties the given public key to the device
+        let path = match (attestation, device.device_type) {
+            (Some(path), _) => Some(path),
+            (None, HardwareType::YubiKey | HardwareType::SmartCard) => {
+                return Err(lsftp_core::error::Error::Config(format!(
+                    "{:?} devices are only enrolled with --attestation", device.device_type
+                )));
+            }
+            (None, _) => None,
+        };
+
+        if let Some(path) = path {
+            let data = fs::read_to_string(path)
+                .map_err(|e| lsftp_core::error::Error::File(format!("Failed to read attestation: {}", e)))?;
+            let attestation: HardwareAttestation = serde_json::from_str(&data)?;
+            if attestation.device_id != device.device_id || attestation.device_type != device.device_type {
+                return Err(lsftp_core::error::Error::Config(format!(
+                    "Attestation belongs to {:?} device {}", attestation.device_type, attestation.device_id
+                )));
+            }
+            device.attestation_chain = attestation.certificate_chain.iter().map(hex::encode).collect();
+
+            // A smart card is enrolled for the key and identity in its PIV certificate
+            if device.device_type == HardwareType::SmartCard {
+                let certificate = attestation.certificate_chain.first()
+                    .ok_or_else(|| lsftp_core::error::Error::Config("Smart card attestation carries no certificate".to_string()))?;
+                let info = lsftp_core::smartcard::CertificateInfo::from_der(certificate)?;
+                if !hex::encode(&info.public_key).eq_ignore_ascii_case(&device.public_key) || info.identity != device.user_id {
+                    return Err(lsftp_core::error::Error::Config(format!(
+                        "Certificate holds another key or names {}, not {}", info.identity, device.user_id
+                    )));
+                }
+            }
+
+            // A YubiKey is enrolled for the key its Yubico-rooted slot attestation vouches for
+            if device.device_type == HardwareType::YubiKey {
+                let info = piv::verify_attestation_chain(&attestation.certificate_chain, &lsftp_core::smartcard::CaBundle::load(yubico_root)?)?;
+                if !hex::encode(&info.public_key).eq_ignore_ascii_case(&device.public_key)
+                    || info.algorithm.device_key_algorithm() != device.algorithm
+                {
+                    return Err(lsftp_core::error::Error::Config(format!(
+                        "Slot attestation certificate holds another key than device {}", device.device_id
+                    )));
+                }
+            }
+        }
+
+        if let Some(attestation_key) = &device.attestation_key {
+            attestation_key.public_key_bytes()?;
+        }
+
+        device.public_key = device.public_key.to_ascii_lowercase();
+        device.enrolled_at = lsftp_core::now_secs();
+
+        let mut store = EnrollmentStore::load(registry)?;
+        store.enroll(device)?;
+        store.save(registry)
+    }
+
+    /// List enrolled devices
+    async fn device_list(user: Option<&str>, registry: &Path, json: bool) -> Result<()> {
+        let store = EnrollmentStore::load(registry)?;
+        let devices: Vec<_> = store.devices().into_iter()
+            .filter(|device| user.map_or(true, |user| device.user_id == user))
+            .collect();
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&devices)?);
+            return Ok(());
+        }
+
+        for device in devices {
+            println!("  {} {:?} {} user={} roles={} [{}]",
+                device.device_id,
+                device.device_type,
+                serde_json::to_string(&device.algorithm)?.trim_matches('"'),
+                device.user_id,
+                device.roles.join(","),
+                device.status,
+            );
+            if let Some(reason) = &device.status_reason {
+                println!("         {}", reason);
+            }
+        }
+        Ok(())
+    }
+
+    /// Change a device's enrollment status
+    async fn device_set_status(
+        device_id: &str,
+        status: DeviceStatus,
+        reason: Option<String>,
+        registry: &Path,
+        audit_log: &str,
+    ) -> Result<()> {
+        let logger = Self::security_logger(audit_log)?;
+
+        let mut details = HashMap::new();
+        details.insert("device_id".to_string(), device_id.to_string());
+        details.insert("status".to_string(), status.to_string());
+        if let Some(reason) = &reason {
+            details.insert("reason".to_string(), reason.clone());
+        }
+
+        let result = EnrollmentStore::load(registry).and_then(|mut store| {
+            let user_id = store.set_status(device_id, status, reason)?.user_id.clone();
+            store.save(registry)?;
+            Ok(user_id)
+        });
+        if let Ok(user_id) = &result {
+            details.insert("user_id".to_string(), user_id.clone());
+        }
+
+        logger.log_key_management(
+            &format!("device_{}", status),
+            None,
+            details,
+            result.is_ok(),
+            result.as_ref().err().map(|e| e.to_string()),
+        ).await?;
+        let user_id = result?;
+
+        info!("Device {} of {} is now {}; running servers pick this up on SIGHUP", device_id, user_id, status);
+        Ok(())
+    }
+
+    /// List failure counters and lockouts
+    async fn lockout_list(state: &Path, json: bool) -> Result<()> {
+        let store = LockoutStore::load(state)?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&store)?);
+            return Ok(());
+        }
+
+        let now = lsftp_core::now_secs();
+        for (key, record) in store.records() {
+            match record.locked_until {
+                Some(until) if record.is_locked(now) => {
+                    println!("  {} failures={} locked for {}s", key, record.failures, until - now)
+                }
+                _ => println!("  {} failures={}", key, record.failures),
+            }
+        }
+        Ok(())
+    }
+
+    /// Clear lockouts; the running server picks up the change on the next attempt
+    async fn lockout_clear(key: Option<String>, state: &Path, audit_log: &str) -> Result<()> {
+        let logger = Self::security_logger(audit_log)?;
+
+        // The server may be recording failures in the same file
+        let lock = fsutil::StateLock::acquire(state)?;
+        let mut store = LockoutStore::load(state)?;
+        let cleared = store.clear(key.as_deref());
+        store.save(state)?;
+        drop(lock);
+
+        let subject = key.as_deref().unwrap_or("all");
+        logger.log_lockout_cleared(subject, cleared).await?;
+        info!("Cleared {} lockout records for {}", cleared, subject);
+        Ok(())
+    }
+
+    /// Report usage from the ledger; without a policy no limits are shown
+    async fn quota_report(key: Option<String>, state: &Path, policy: Option<&Path>, json: bool) -> Result<()> {
+        let ledger = QuotaLedger::load(state)?;
+        let policy = policy.map(QuotaPolicy::load).transpose()?.unwrap_or_default();
+
+        let report: Vec<_> = match &key {
+            Some(key) => vec![quota::usage_report(key, ledger.get(key), &policy)],
+            None => ledger.usage().map(|(key, usage)| quota::usage_report(key, *usage, &policy)).collect(),
+        };
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(());
+        }
+
+        let now = lsftp_core::now_secs();
+        let limit = |limit: Option<u64>| limit.map_or("-".to_string(), |limit| limit.to_string());
+        for entry in report {
+            println!("  {} bytes={}/{}/{} files={}/{}/{}",
+                entry.subject,
+                entry.bytes, limit(entry.limits.soft_bytes), limit(entry.limits.hard_bytes),
+                entry.files, limit(entry.limits.soft_files), limit(entry.limits.hard_files));
+            match entry.grace_expires_at {
+                Some(expires) if expires > now => println!("    over soft quota, grace ends in {}s", expires - now),
+                Some(_) => println!("    over soft quota, grace expired"),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Security logger writing to the given audit log
+    fn security_logger(audit_log: &str) -> Result<SecurityLogger> {
+        let config = AuditConfig {
+            audit_log_path: audit_log.to_string(),
+            ..Default::default()
+        };
+        Ok(SecurityLogger::new(AuditLogger::new(config, CryptoSuite::default())?))
+    }
+
     /// Create X.509 certificate
     fn create_certificate(config: &KeygenConfig, public_key: &[u8]) -> Result<Vec<u8>> {
         // This is a simplified certificate creation
@@ -236,17 +1104,30 @@ impl LsftpTools {
     }
 
     /// Manage hardware security devices
-    async fn hardware(device_type: &str, list: bool, init: bool, test: bool, device_path: Option<String>) -> Result<()> {
+    #[allow(clippy::too_many_arguments)]
+    async fn hardware(
+        device_type: &str,
+        list: bool,
+        init: bool,
+        test: bool,
+        device_path: Option<String>,
+        ak_config: TpmAkConfig,
+        piv_config: PivConfig,
+        hsm_algorithm: HsmKeyAlgorithm,
+        attestation_out: Option<&Path>,
+    ) -> Result<()> {
         match device_type {
-            "tpm" => Self::manage_tpm(list, init, test, device_path).await,
-            "yubikey" => Self::manage_yubikey(list, init, test).await,
-            "smartcard" => Self::manage_smartcard(list, init, test).await,
+            "tpm" => Self::manage_tpm(list, init, test, device_path, ak_config).await,
+            "yubikey" => Self::manage_yubikey(list, init, test, piv_config, attestation_out).await,
+            "smartcard" => Self::manage_smartcard(list, init, test, device_path, attestation_out).await,
+            "hsm" => Self::manage_hsm(init, test, device_path, hsm_algorithm, attestation_out).await,
+            "software" => Self::manage_software(init, test, device_path).await,
             _ => return Err(lsftp_core::error::Error::Config(format!("Unknown device type: {}", device_type))),
         }
     }
 
     /// Manage TPM 2.0 device
-    async fn manage_tpm(list: bool, init: bool, test: bool, device_path: Option<String>) -> Result<()> {
+    async fn manage_tpm(list: bool, init: bool, test: bool, device_path: Option<String>, ak_config: TpmAkConfig) -> Result<()> {
         let tpm_path = device_path.unwrap_or_else(|| "/dev/tpmrm0".to_string());
 
         if list {
@@ -262,15 +1143,37 @@ impl LsftpTools {
             }
         }
 
+        if !init && !test {
+            return Ok(());
+        }
+
+        // Loading the AK creates and persists it on first use
+        let mut auth = lsftp_core::auth::TpmAuth::with_ak_config(tpm_path, ak_config);
+        auth.initialize().await?;
+
         if init {
-            info!("Initializing TPM 2.0...");
-            // Initialize TPM context and PCR values
-            info!("TPM initialization completed");
+            let key = auth.attestation_key()
+                .ok_or_else(|| lsftp_core::error::Error::HardwareAuth("TPM attestation key not loaded".to_string()))?;
+            let algorithm = serde_json::to_string(&key.algorithm.device_key_algorithm())?
+                .trim_matches('"')
+                .replace('_', "-");
+            info!("TPM attestation key ready at {:#010x}", ak_config.handle);
+            info!("  Device ID:  {}", key.device_id());
+            info!("  Algorithm:  {}", algorithm);
+            info!("  Public key: {}", hex::encode(&key.public_key));
+            info!("Enroll it with: lsftp-tools device-enroll --device-type tpm --device-id {id} --algorithm {alg} \
+                --public-key <public key> --attestation-key <public key> --attestation-algorithm {alg}",
+                id = key.device_id(), alg = algorithm);
         }
 
         if test {
             info!("Testing TPM 2.0 functionality...");
-            // Test TPM operations
+            let challenge = b"lsftp-tools self test";
+            let result = auth.authenticate(challenge).await?;
+            let attestation = auth.generate_attestation(challenge).await?;
+            if result.signature.is_none() || !auth.verify_attestation(&attestation).await? {
+                return Err(lsftp_core::error::Error::HardwareAuth("TPM signature or quote check failed".to_string()));
+            }
             info!("TPM test completed successfully");
         }
 
@@ -278,51 +1181,199 @@ impl LsftpTools {
     }
 
     /// Manage YubiKey device
-    async fn manage_yubikey(list: bool, init: bool, test: bool) -> Result<()> {
+    async fn manage_yubikey(list: bool, init: bool, test: bool, config: PivConfig, attestation_out: Option<&Path>) -> Result<()> {
         if list {
             info!("YubiKey devices:");
-            // List available YubiKey devices
-            info!("  Status: Scanning for devices...");
+            for yubikey in piv::list_devices()? {
+                let version = yubikey.version();
+                info!("  Serial {}: firmware {}.{}.{}", yubikey.serial(), version.major, version.minor, version.patch);
+            }
         }
 
+        if !init && !test {
+            return Ok(());
+        }
+
+        let mut auth = lsftp_core::auth::YubiKeyAuth::new(config);
+        auth.initialize().await?;
+        let device_id = auth.get_device_info().await?.device_id;
+
         if init {
-            info!("Initializing YubiKey...");
-            // Initialize YubiKey PIV interface
-            info!("YubiKey initialization completed");
+            info!("Generating {:?} key in PIV slot {} of {}...", config.algorithm, config.slot, device_id);
+            let public_key = auth.provision()?;
+            let attestation = auth.generate_attestation(&public_key).await?;
+            if !auth.verify_attestation(&attestation).await? {
+                return Err(lsftp_core::error::Error::HardwareAuth("PIV attestation check failed".to_string()));
+            }
+
+            if let Some(path) = attestation_out {
+                fs::write(path, serde_json::to_string_pretty(&attestation)?)?;
+                info!("Attestation written to {:?}", path);
+            }
+
+            let algorithm = serde_json::to_string(&config.algorithm.device_key_algorithm())?
+                .trim_matches('"')
+                .replace('_', "-");
+            info!("YubiKey PIV key ready in slot {} (PIN policy {:?}, touch policy {:?})", config.slot, config.pin_policy, config.touch_policy);
+            info!("  Device ID:  {}", device_id);
+            info!("  Algorithm:  {}", algorithm);
+            info!("  Public key: {}", hex::encode(&public_key));
+            info!("Enroll it with: lsftp-tools device-enroll --device-type yubikey --device-id {} --algorithm {} \
+                --public-key <public key> --attestation <attestation file>", device_id, algorithm);
         }
 
         if test {
             info!("Testing YubiKey functionality...");
-            // Test YubiKey operations
+            let challenge = b"lsftp-tools self test";
+            let result = auth.authenticate(challenge).await?;
+            let attestation = auth.generate_attestation(challenge).await?;
+            if result.signature.is_none() || !auth.verify_attestation(&attestation).await? {
+                return Err(lsftp_core::error::Error::HardwareAuth("YubiKey signature or attestation check failed".to_string()));
+            }
             info!("YubiKey test completed successfully");
         }
 
         Ok(())
     }
 
+    /// Manage a PKCS#11 HSM key
+    async fn manage_hsm(init: bool, test: bool, device_path: Option<String>, algorithm: HsmKeyAlgorithm, attestation_out: Option<&Path>) -> Result<()> {
+        let config: Pkcs11Config = device_path.as_deref().unwrap_or_default().parse()?;
+
+        if init {
+            info!("Generating {:?} key {} on token {}...", algorithm, config.key_label, config.token_label);
+            HsmAuth::provision(&config, algorithm)?;
+        }
+
+        if !init && !test {
+            return Ok(());
+        }
+
+        let mut auth = HsmAuth::new(config);
+        auth.initialize().await?;
+        let device_id = auth.get_device_info().await?.device_id;
+
+        if init {
+            let attestation = auth.generate_attestation(&auth.public_key()?).await?;
+            if let Some(path) = attestation_out {
+                fs::write(path, serde_json::to_string_pretty(&attestation)?)?;
+                info!("Attestation written to {:?}", path);
+            }
+
+            let algorithm = serde_json::to_string(&auth.algorithm()?.device_key_algorithm())?
+                .trim_matches('"')
+                .replace('_', "-");
+            info!("HSM key ready");
+            info!("  Device ID:  {}", device_id);
+            info!("  Algorithm:  {}", algorithm);
+            info!("  Public key: {}", hex::encode(auth.public_key()?));
+            info!("Enroll it with: lsftp-tools device-enroll --device-type hsm --device-id {} --algorithm {} \
+                --public-key <public key>", device_id, algorithm);
+        }
+
+        if test {
+            info!("Testing HSM functionality...");
+            let challenge = b"lsftp-tools self test";
+            let result = auth.authenticate(challenge).await?;
+            let attestation = auth.generate_attestation(challenge).await?;
+            if result.signature.is_none() || !auth.verify_attestation_nonce(&attestation, challenge)? {
+                return Err(lsftp_core::error::Error::HardwareAuth("HSM signature or attestation check failed".to_string()));
+            }
+            info!("HSM test completed successfully");
+        }
+
+        Ok(())
+    }
+
     /// Manage Smart Card device
-    async fn manage_smartcard(list: bool, init: bool, test: bool) -> Result<()> {
+    async fn manage_smartcard(list: bool, init: bool, test: bool, device_path: Option<String>, attestation_out: Option<&Path>) -> Result<()> {
         if list {
             info!("Smart Card devices:");
-            // List available smart card readers
-            info!("  Status: Scanning for devices...");
+            for device in lsftp_core::auth::HardwareAuthFactory::detect_devices().await? {
+                if device.device_type == HardwareType::SmartCard {
+                    info!("  {} ({}, issued by {})", device.device_id, device.model, device.manufacturer);
+                }
+            }
         }
 
+        if !init && !test {
+            return Ok(());
+        }
+
+        let mut auth = lsftp_core::auth::SmartCardAuth::new(device_path.unwrap_or_default());
+        auth.initialize().await?;
+        let device_id = auth.get_device_info().await?.device_id;
+
         if init {
-            info!("Initializing Smart Card...");
-            // Initialize smart card interface
-            info!("Smart Card initialization completed");
+            let certificate = auth.certificate_info()
+                .ok_or_else(|| lsftp_core::error::Error::HardwareAuth("Smart card certificate not loaded".to_string()))?
+                .clone();
+            let attestation = auth.generate_attestation(&certificate.public_key).await?;
+            if let Some(path) = attestation_out {
+                fs::write(path, serde_json::to_string_pretty(&attestation)?)?;
+                info!("Attestation written to {:?}", path);
+            }
+
+            let algorithm = serde_json::to_string(&certificate.algorithm.device_key_algorithm())?
+                .trim_matches('"')
+                .replace('_', "-");
+            info!("PIV authentication certificate read");
+            info!("  Device ID:  {}", device_id);
+            info!("  Identity:   {}", certificate.identity);
+            info!("  Algorithm:  {}", algorithm);
+            info!("  Public key: {}", hex::encode(&certificate.public_key));
+            info!("Enroll it with: lsftp-tools device-enroll --device-type smartcard --device-id {} --algorithm {} \
+                --public-key <public key> --attestation <attestation file> --user {}", device_id, algorithm, certificate.identity);
         }
 
         if test {
             info!("Testing Smart Card functionality...");
-            // Test smart card operations
+            let challenge = b"lsftp-tools self test";
+            let result = auth.authenticate(challenge).await?;
+            let attestation = auth.generate_attestation(challenge).await?;
+            if result.signature.is_none() || !auth.verify_attestation(&attestation).await? {
+                return Err(lsftp_core::error::Error::HardwareAuth("Smart card signature or certificate check failed".to_string()));
+            }
             info!("Smart Card test completed successfully");
         }
 
         Ok(())
     }
 
+    /// Manage the software authenticator (insecure-dev builds only)
+    #[cfg(feature = "insecure-dev")]
+    async fn manage_software(init: bool, test: bool, device_path: Option<String>) -> Result<()> {
+        use lsftp_core::softauth::{SoftwareAuth, DEFAULT_KEY_PATH};
+
+        let key_path = PathBuf::from(device_path.unwrap_or_else(|| DEFAULT_KEY_PATH.to_string()));
+        warn!("The software authenticator is INSECURE and for development and CI only");
+
+        if init {
+            let passphrase = Self::prompt_new_passphrase("software authenticator key file")?;
+            SoftwareAuth::provision(&key_path, passphrase.as_bytes())?;
+            info!("Software authenticator key file created: {:?}", key_path);
+        }
+
+        if test {
+            let passphrase = Self::prompt_passphrase("Software authenticator passphrase: ")?;
+            let mut auth = SoftwareAuth::new(&key_path, SecretBuffer::from_slice(passphrase.as_bytes())?);
+            auth.initialize().await?;
+
+            let attestation = auth.generate_attestation(b"lsftp-tools self test").await?;
+            if !auth.verify_attestation(&attestation).await? {
+                return Err(lsftp_core::error::Error::HardwareAuth("Software attestation did not verify".to_string()));
+            }
+            info!("Software authenticator {} test completed successfully", attestation.device_id);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "insecure-dev"))]
+    async fn manage_software(_init: bool, _test: bool, _device_path: Option<String>) -> Result<()> {
+        Err(lsftp_core::error::Error::Config("The software authenticator requires a build with the insecure-dev feature".to_string()))
+    }
+
     /// Audit and compliance tools
     async fn audit(log_path: &PathBuf, report: bool, verify: bool, export: bool, format: &str) -> Result<()> {
         if !log_path.exists() {
@@ -418,6 +1469,28 @@ log_level = "info"
             return Err(lsftp_core::error::Error::Config("Configuration file not found".to_string()));
         }
 
+        let config: toml::Value = toml::from_str(&fs::read_to_string(config_file)
+            .map_err(|e| lsftp_core::error::Error::Config(format!("Failed to read config: {}", e)))?)?;
+        let security = config.get("security");
+
+        let supported_hardware = security
+            .and_then(|s| s.get("supported_hardware"))
+            .and_then(|v| v.as_array())
+            .map(|names| names.iter()
+                .map(|name| name.as_str()
+                    .ok_or_else(|| lsftp_core::error::Error::Config("supported_hardware entries must be strings".to_string()))?
+                    .parse())
+                .collect::<Result<Vec<lsftp_core::HardwareType>>>())
+            .transpose()?
+            .unwrap_or_default();
+        let insecure_dev = security
+            .and_then(|s| s.get("insecure_dev"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
+        // Production configuration refuses development-only authenticators
+        lsftp_core::auth::HardwareAuthFactory::validate_config(&supported_hardware, insecure_dev)?;
+
         info!("Configuration validation completed successfully");
         Ok(())
     }
@@ -457,14 +1530,100 @@ async fn main() -> Result<()> {
             LsftpTools::keygen(config, &output_cert, &output_key).await?;
         }
 
-        Commands::Hardware { device_type, list, init, test, device_path } => {
-            LsftpTools::hardware(&device_type, list, init, test, device_path).await?;
+        Commands::Hardware {
+            device_type, list, init, test, device_path, ak_handle, ak_algorithm,
+            serial, slot, piv_algorithm, pin_policy, touch_policy, hsm_algorithm, attestation_out,
+        } => {
+            let ak_config = TpmAkConfig { handle: tpmkey::parse_handle(&ak_handle)?, algorithm: ak_algorithm };
+            let piv_config = PivConfig { serial, slot, algorithm: piv_algorithm, pin_policy, touch_policy };
+            LsftpTools::hardware(&device_type, list, init, test, device_path, ak_config, piv_config, hsm_algorithm, attestation_out.as_deref()).await?;
         }
 
         Commands::Audit { log_path, report, verify, export, format } => {
             LsftpTools::audit(&log_path, report, verify, export, &format).await?;
         }
 
+        Commands::KeySplit { key, threshold, shares, output_dir, audit_log } => {
+            LsftpTools::key_split(&key, threshold, shares, &output_dir, &audit_log).await?;
+        }
+
+        Commands::KeyRecover { shares, output, audit_log } => {
+            LsftpTools::key_recover(&shares, &output, &audit_log).await?;
+        }
+
+        Commands::TpmSeal { key, output, pcrs, baseline, tcti, audit_log } => {
+            LsftpTools::tpm_seal(&key, &output, &pcrs, baseline.as_deref(), tcti.as_deref(), &audit_log).await?;
+        }
+
+        Commands::TpmReseal { sealed, baseline, tcti, audit_log } => {
+            LsftpTools::tpm_reseal(&sealed, baseline.as_deref(), tcti.as_deref(), &audit_log).await?;
+        }
+
+        Commands::TpmInspect { sealed, tcti } => {
+            LsftpTools::tpm_inspect(&sealed, tcti.as_deref()).await?;
+        }
+
+        Commands::DeviceEnroll {
+            device_id, device_type, algorithm, public_key, attestation, attestation_key, attestation_algorithm,
+            host_class, user, roles, registry, yubico_root, insecure_dev, audit_log,
+        } => {
+            let device = EnrolledDevice {
+                device_id,
+                device_type,
+                algorithm,
+                public_key,
+                attestation_chain: Vec::new(),
+                attestation_key: attestation_key.zip(attestation_algorithm)
+                    .map(|(public_key, algorithm)| AttestationKey { algorithm, public_key: public_key.to_ascii_lowercase() }),
+                host_class,
+                user_id: user,
+                roles,
+                enrolled_at: 0,
+                status: DeviceStatus::Active,
+                status_changed_at: None,
+                status_reason: None,
+            };
+            LsftpTools::device_enroll(device, attestation.as_deref(), &yubico_root, &registry, insecure_dev, &audit_log).await?;
+        }
+
+        Commands::DeviceList { user, registry, json } => {
+            LsftpTools::device_list(user.as_deref(), &registry, json).await?;
+        }
+
+        Commands::DeviceSuspend { device_id, reason, resume, registry, audit_log } => {
+            let status = if resume { DeviceStatus::Active } else { DeviceStatus::Suspended };
+            LsftpTools::device_set_status(&device_id, status, reason, &registry, &audit_log).await?;
+        }
+
+        Commands::DeviceRevoke { device_id, reason, registry, audit_log } => {
+            LsftpTools::device_set_status(&device_id, DeviceStatus::Revoked, reason, &registry, &audit_log).await?;
+        }
+
+        Commands::LockoutList { state, json } => {
+            LsftpTools::lockout_list(&state, json).await?;
+        }
+
+        Commands::LockoutClear { ip, device, all, state, audit_log } => {
+            let key = match (ip, device) {
+                (Some(ip), _) => Some(lockout::ip_key(&ip)),
+                (None, Some(device)) => Some(lockout::device_key(&device)),
+                (None, None) if all => None,
+                (None, None) => return Err(lsftp_core::error::Error::Config(
+                    "Specify --ip, --device or --all".to_string()
+                )),
+            };
+            LsftpTools::lockout_clear(key, &state, &audit_log).await?;
+        }
+
+        Commands::QuotaReport { user, group, state, policy, json } => {
+            let key = user.as_deref().map(quota::user_key).or(group.as_deref().map(quota::group_key));
+            LsftpTools::quota_report(key, &state, policy.as_deref(), json).await?;
+        }
+
+        Commands::SelfTest { suite, json } => {
+            LsftpTools::self_test(&suite, json).await?;
+        }
+
         Commands::Config { config_file, validate, generate, test } => {
             LsftpTools::config(&config_file, validate, generate, test).await?;
         }
@@ -213,7 +213,7 @@ docker load < lsftp-v${VERSION}.tar.gz
 lsftp-tools keygen --key-type hybrid --output-cert server.crt --output-key server.key
 
 # Start server
-lsftp-server --cert server.crt --key server.key
+lsftp-server --cert server.crt --key server.key --access-policy access.toml
 
 # Connect client
 lsftp-client --server-address localhost:8443